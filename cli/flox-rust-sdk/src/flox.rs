@@ -8,8 +8,12 @@ use serde_with::DeserializeFromStr;
 use thiserror::Error;
 use url::Url;
 
+use crate::models::environment::build_cache::BuildCache;
 pub use crate::models::environment_ref::{self, *};
+use crate::models::remote_builder::RemoteBuilder;
 use crate::providers::catalog;
+use crate::utils::events::Events;
+use crate::utils::progress::Progress;
 
 pub static FLOX_VERSION: Lazy<String> =
     Lazy::new(|| std::env::var("FLOX_VERSION").unwrap_or(env!("FLOX_VERSION").to_string()));
@@ -54,9 +58,50 @@ pub struct Flox {
     pub floxhub_token: Option<FloxhubToken>,
 
     pub catalog_client: Option<catalog::Client>,
+
+    /// Package resolutions fetched ahead of time by
+    /// [CoreEnvironment::prefetch_resolution](crate::models::environment::CoreEnvironment::prefetch_resolution)
+    pub resolution_cache: catalog::ResolutionCache,
+
+    /// Receives progress of long running operations such as locking and building
+    pub progress: Progress,
+
+    /// Receives the outcomes of operations such as installing and building
+    pub events: Events,
+
+    /// Remote machines nix may delegate builds to,
+    /// e.g. to build linux environments on macOS
+    pub remote_builders: Vec<RemoteBuilder>,
+
+    /// Keep the build directories of failed builds for inspection
+    pub keep_failed: bool,
+
+    /// Resolve packages only from the [catalog::CatalogSnapshot]
+    /// of previous locks, without contacting the catalog
+    pub offline: bool,
+
+    /// Where transactions prepare modified copies of environments
+    ///
+    /// If unset, [Self::temp_dir] is used if it is on the same filesystem
+    /// as the environment, or otherwise the directory containing the environment.
+    pub transaction_dir: Option<PathBuf>,
+
+    /// The shell that checks the syntax of `profile.common` scripts when they are edited,
+    /// see [crate::models::environment::hook_check::DEFAULT_HOOK_CHECK_SHELL]
+    pub hook_check_shell: Option<String>,
 }
 
-impl Flox {}
+impl Flox {
+    /// The resolutions recorded by previous online locks
+    pub fn catalog_snapshot(&self) -> catalog::CatalogSnapshot {
+        catalog::CatalogSnapshot::new(self.cache_dir.join("catalog-snapshot.json"))
+    }
+
+    /// The store paths of previous builds of lockfiles
+    pub fn build_cache(&self) -> BuildCache {
+        BuildCache::new(self.cache_dir.join("build-cache.json"))
+    }
+}
 
 pub static DEFAULT_FLOXHUB_URL: Lazy<Url> =
     Lazy::new(|| Url::parse("https://hub.flox.dev").unwrap());
@@ -314,6 +359,14 @@ pub mod test_helpers {
             } else {
                 None
             },
+            resolution_cache: Default::default(),
+            progress: Default::default(),
+            events: Default::default(),
+            remote_builders: Vec::new(),
+            keep_failed: false,
+            offline: false,
+            transaction_dir: None,
+            hook_check_shell: None,
         };
 
         init_global_manifest(&global_manifest_path(&flox)).unwrap();
@@ -0,0 +1,222 @@
+//! Diagnostics for packages that fail to build
+//!
+//! `pkgdb buildenv` reports a failing package as a [PkgDbError]
+//! with a generic message per category of failure
+//! and the error of nix as the caught message.
+//! [BuildFailure] extracts the package, derivation, and relevant log lines
+//! from such an error, and suggests a change to the manifest if one is known to help.
+
+use std::fmt::Display;
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+use super::pkgdb::{error_codes, BuildLogLine, PkgDbError};
+
+/// What went wrong while building a package
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildFailureKind {
+    /// The package could not be evaluated
+    Evaluation,
+    /// The derivation of the package failed to build
+    Build,
+    /// The package is not allowed by the `options.allow` of the manifest
+    Disallowed,
+}
+
+/// A change to the manifest that is expected to fix a [BuildFailure]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Remediation {
+    /// The package has an unfree license
+    AllowUnfree,
+    /// The package is marked as broken
+    AllowBroken,
+    /// The package is not available on the current system
+    RestrictSystems,
+}
+
+impl Display for Remediation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Remediation::AllowUnfree => write!(
+                f,
+                "Allow unfree packages by setting 'options.allow.unfree = true' in manifest.toml"
+            ),
+            Remediation::AllowBroken => write!(
+                f,
+                "Allow broken packages by setting 'options.allow.broken = true' in manifest.toml"
+            ),
+            Remediation::RestrictSystems => write!(
+                f,
+                "Restrict the package to the systems it supports by setting its 'systems' in manifest.toml"
+            ),
+        }
+    }
+}
+
+/// A package of an environment that failed to evaluate or build
+#[derive(Debug, Error)]
+#[error("{}", self.summary())]
+pub struct BuildFailure {
+    pub kind: BuildFailureKind,
+    /// The install id of the failing package, if pkgdb named it
+    pub install_id: Option<String>,
+    /// The derivation that failed to build, if nix named it
+    pub derivation: Option<String>,
+    /// The lines of the build log that were produced by failing builds,
+    /// or the last lines of the log if no build produced output
+    pub log_excerpt: Vec<String>,
+    pub remediation: Option<Remediation>,
+    /// The build directories that nix kept for inspection
+    pub kept_build_dirs: Vec<PathBuf>,
+    #[source]
+    pub error: PkgDbError,
+}
+
+impl BuildFailure {
+    /// Extract a [BuildFailure] from a [PkgDbError] of `pkgdb buildenv`
+    ///
+    /// Errors that are not caused by a single package are returned unchanged.
+    pub fn from_pkgdb_error(error: PkgDbError) -> Result<Self, PkgDbError> {
+        let kind = match error.exit_code {
+            error_codes::PACKAGE_EVAL_FAILURE => BuildFailureKind::Evaluation,
+            error_codes::PACKAGE_BUILD_FAILURE => BuildFailureKind::Build,
+            error_codes::BAD_PACKAGE_FAILURE => BuildFailureKind::Disallowed,
+            _ => return Err(error),
+        };
+
+        let context = error
+            .context_message
+            .as_ref()
+            .map(|context| context.message.as_str())
+            .unwrap_or_default();
+        let caught = error
+            .context_message
+            .as_ref()
+            .and_then(|context| context.caught.as_ref())
+            .map(|caught| caught.message.as_str())
+            .unwrap_or_default();
+
+        let install_id = first_quoted(context);
+        let derivation = caught
+            .split(|c: char| c.is_whitespace() || "'‘’\"".contains(c))
+            .find(|word| word.starts_with("/nix/store/") && word.ends_with(".drv"))
+            .map(ToString::to_string);
+        let remediation = remediation(context).or_else(|| remediation(caught));
+
+        let build_output = error
+            .log_tail
+            .iter()
+            .filter(|line| BuildLogLine::parse(line).derivation.is_some())
+            .cloned()
+            .collect::<Vec<_>>();
+        let log_excerpt = if build_output.is_empty() {
+            error.log_tail.clone()
+        } else {
+            build_output
+        };
+
+        Ok(BuildFailure {
+            kind,
+            install_id,
+            derivation,
+            log_excerpt,
+            remediation,
+            kept_build_dirs: error.kept_build_dirs.clone(),
+            error,
+        })
+    }
+
+    fn summary(&self) -> String {
+        let package = self
+            .install_id
+            .as_ref()
+            .map(|install_id| format!("package '{install_id}'"))
+            .unwrap_or_else(|| "a package".to_string());
+        match self.kind {
+            BuildFailureKind::Evaluation => format!("{package} failed to evaluate"),
+            BuildFailureKind::Build => format!("{package} failed to build"),
+            BuildFailureKind::Disallowed => {
+                format!("{package} is not allowed by the manifest")
+            },
+        }
+    }
+}
+
+/// The first string in single quotes in `message`
+fn first_quoted(message: &str) -> Option<String> {
+    let (_, rest) = message.split_once('\'')?;
+    let (quoted, _) = rest.split_once('\'')?;
+    Some(quoted.to_string())
+}
+
+/// The [Remediation] suggested by a message of pkgdb or nix, if any
+fn remediation(message: &str) -> Option<Remediation> {
+    if message.contains("has an unfree license") {
+        Some(Remediation::AllowUnfree)
+    } else if message.contains("is marked as broken") {
+        Some(Remediation::AllowBroken)
+    } else if message.contains("is not available on the requested hostPlatform") {
+        Some(Remediation::RestrictSystems)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::pkgdb::{CaughtMsgError, ContextMsgError};
+
+    fn pkgdb_error(exit_code: u64, context: &str, caught: Option<&str>) -> PkgDbError {
+        PkgDbError {
+            exit_code,
+            category_message: "category".to_string(),
+            context_message: Some(ContextMsgError {
+                message: context.to_string(),
+                caught: caught.map(|caught| CaughtMsgError {
+                    message: caught.to_string(),
+                }),
+            }),
+            log_tail: vec![
+                "building '/nix/store/abc-hello-2.12.drv'...".to_string(),
+                "hello> make: *** [Makefile:10: all] Error 2".to_string(),
+            ],
+            kept_build_dirs: vec![],
+        }
+    }
+
+    #[test]
+    fn diagnoses_package_failures() {
+        let failure = BuildFailure::from_pkgdb_error(pkgdb_error(
+            error_codes::PACKAGE_BUILD_FAILURE,
+            "Failed to build package 'hello'",
+            Some("builder for '/nix/store/abc-hello-2.12.drv' failed with exit code 2"),
+        ))
+        .unwrap();
+        assert_eq!(failure.kind, BuildFailureKind::Build);
+        assert_eq!(failure.install_id.as_deref(), Some("hello"));
+        assert_eq!(
+            failure.derivation.as_deref(),
+            Some("/nix/store/abc-hello-2.12.drv")
+        );
+        assert_eq!(failure.log_excerpt, vec![
+            "hello> make: *** [Makefile:10: all] Error 2"
+        ]);
+        assert_eq!(failure.remediation, None);
+        assert_eq!(failure.to_string(), "package 'hello' failed to build");
+
+        let failure = BuildFailure::from_pkgdb_error(pkgdb_error(
+            error_codes::BAD_PACKAGE_FAILURE,
+            "The package 'vscode' has an unfree license.",
+            None,
+        ))
+        .unwrap();
+        assert_eq!(failure.kind, BuildFailureKind::Disallowed);
+        assert_eq!(failure.install_id.as_deref(), Some("vscode"));
+        assert_eq!(failure.remediation, Some(Remediation::AllowUnfree));
+
+        let not_a_package = pkgdb_error(error_codes::BUILDENV_CONFLICT, "conflict", None);
+        assert!(BuildFailure::from_pkgdb_error(not_a_package).is_err());
+    }
+}
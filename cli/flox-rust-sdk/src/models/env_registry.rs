@@ -312,6 +312,36 @@ pub fn deregister(
     Ok(())
 }
 
+/// Moves the registration of an environment to `new_dot_flox_path` and `new_pointer`,
+/// e.g. after the environment was relocated or renamed.
+///
+/// The old registration is removed and the new one is added in a single write.
+/// An environment that was not registered before is only registered under its new location.
+pub fn move_registration(
+    flox: &Flox,
+    old_dot_flox_path: &Path,
+    old_pointer: &EnvironmentPointer,
+    new_dot_flox_path: &CanonicalPath,
+    new_pointer: &EnvironmentPointer,
+) -> Result<(), EnvRegistryError> {
+    let lock = acquire_env_registry_lock(flox)?;
+    let reg_path = env_registry_path(flox);
+    let mut reg = read_environment_registry(&reg_path)?.unwrap_or_default();
+    if let Err(err) = reg.deregister_env(&path_hash(&old_dot_flox_path), old_pointer) {
+        debug!(
+            old_path = traceable_path(&old_dot_flox_path),
+            "environment was not registered: {err}"
+        );
+    }
+    reg.register_env(
+        new_dot_flox_path,
+        &path_hash(&new_dot_flox_path),
+        new_pointer,
+    )?;
+    write_environment_registry(&reg, &reg_path, lock)?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
     use std::fs::OpenOptions;
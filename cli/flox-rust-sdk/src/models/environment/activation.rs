@@ -0,0 +1,458 @@
+//! Compute the environment of an activation without starting a shell.
+//!
+//! `flox activate` sources the activation scripts of a built environment
+//! in a shell, which also runs user provided hooks and profile scripts.
+//! Non-interactive consumers (e.g. CI) often only need the resulting variables.
+//! [ActivationEnv] computes the variables set by the static parts of the activation,
+//! and records which parts that run arbitrary shell code were skipped.
+//! [ActivationArtifacts] additionally carries the skipped scripts,
+//! so that consumers can run them in a shell of their choosing.
+
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use indexmap::IndexMap;
+use serde::Serialize;
+use thiserror::Error;
+
+use super::{
+    Environment,
+    EnvironmentError,
+    FLOX_ENV_CACHE_VAR,
+    FLOX_ENV_DIRS_VAR,
+    FLOX_ENV_LIB_DIRS_VAR,
+    FLOX_ENV_PROJECT_VAR,
+    FLOX_ENV_VAR,
+};
+use crate::flox::Flox;
+
+/// The profile.d script of the environment that sets common search paths.
+/// Its effect is computed by [ActivationEnv::new] rather than skipped.
+const COMMON_PATHS_SCRIPT: &str = "0100_common-paths.sh";
+
+#[derive(Debug, Error)]
+pub enum ActivationError {
+    #[error(transparent)]
+    Environment(#[from] EnvironmentError),
+    #[error("couldn't parse manifest")]
+    ParseManifest(#[source] toml::de::Error),
+    #[error("couldn't read profile scripts of the environment")]
+    ReadProfileScripts(#[source] std::io::Error),
+    #[error("activation path contains an invalid character")]
+    JoinPaths(#[source] env::JoinPathsError),
+    #[error("couldn't write activation artifacts")]
+    WriteArtifacts(#[source] std::io::Error),
+}
+
+/// A step of the activation that was not performed by [ActivationEnv::new]
+/// because it runs arbitrary shell code.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum SkippedStep {
+    /// The `hook.on-activate` script of the manifest
+    OnActivateHook,
+    /// A script in the `profile` section of the manifest,
+    /// i.e. `common`, `bash`, or `zsh`
+    Profile { shell: String },
+    /// A script in `etc/profile.d` of the built environment
+    ProfileScript { path: PathBuf },
+}
+
+/// The variables set by activating an environment,
+/// computed without running any shell code.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ActivationEnv {
+    /// Variables exported by the activation, in the order they are set
+    pub exports: IndexMap<String, String>,
+    /// Steps of the activation that were skipped
+    pub skipped: Vec<SkippedStep>,
+}
+
+impl ActivationEnv {
+    /// Compute the activation environment of the environment built at `activation_path`
+    ///
+    /// `inherited` is the environment the activation is applied to,
+    /// search paths such as `PATH` are prepended to their inherited values.
+    pub fn new(
+        activation_path: &Path,
+        manifest_contents: &str,
+        inherited: &HashMap<String, String>,
+    ) -> Result<Self, ActivationError> {
+        let manifest: toml::Table =
+            toml::from_str(manifest_contents).map_err(ActivationError::ParseManifest)?;
+
+        let mut exports = IndexMap::new();
+        let flox_env = activation_path.to_string_lossy().to_string();
+        exports.insert(FLOX_ENV_VAR.to_string(), flox_env.clone());
+
+        let prepend_dir = |var: &str, dir: PathBuf| -> Result<String, ActivationError> {
+            let mut dirs = vec![dir.clone()];
+            if let Some(existing) = inherited.get(var).filter(|existing| !existing.is_empty()) {
+                dirs.extend(env::split_paths(existing).filter(|d| *d != dir));
+            }
+            let joined: OsString = env::join_paths(dirs).map_err(ActivationError::JoinPaths)?;
+            Ok(joined.to_string_lossy().to_string())
+        };
+        exports.insert(
+            FLOX_ENV_DIRS_VAR.to_string(),
+            prepend_dir(FLOX_ENV_DIRS_VAR, activation_path.to_path_buf())?,
+        );
+        exports.insert(
+            FLOX_ENV_LIB_DIRS_VAR.to_string(),
+            prepend_dir(FLOX_ENV_LIB_DIRS_VAR, activation_path.join("lib"))?,
+        );
+
+        // Mirrors etc/profile.d/0100_common-paths.sh
+        let prepend = |var: &str, value: String| match inherited.get(var) {
+            Some(existing) if !existing.is_empty() => format!("{value}:{existing}"),
+            _ => value,
+        };
+        for (var, subdir) in [
+            ("INFOPATH", "share/info"),
+            ("CPATH", "include"),
+            ("LIBRARY_PATH", "lib"),
+            ("ACLOCAL_PATH", "share/aclocal"),
+            ("XDG_DATA_DIRS", "share"),
+        ] {
+            exports.insert(
+                var.to_string(),
+                prepend(var, format!("{flox_env}/{subdir}")),
+            );
+        }
+        exports.insert(
+            "PKG_CONFIG_PATH".to_string(),
+            format!(
+                "{flox_env}/lib/pkgconfig:{}",
+                prepend("PKG_CONFIG_PATH", format!("{flox_env}/share/pkgconfig"))
+            ),
+        );
+        exports.insert(
+            "PATH".to_string(),
+            prepend("PATH", format!("{flox_env}/bin:{flox_env}/sbin")),
+        );
+        // A trailing colon keeps the default man search path, see 0100_common-paths.sh
+        exports.insert(
+            "MANPATH".to_string(),
+            format!(
+                "{flox_env}/share/man:{}",
+                inherited
+                    .get("MANPATH")
+                    .map(String::as_str)
+                    .unwrap_or_default()
+            ),
+        );
+
+        // Static variables of the manifest, these are not expanded by the shell
+        if let Some(vars) = manifest.get("vars").and_then(toml::Value::as_table) {
+            for (name, value) in vars {
+                let value = match value {
+                    toml::Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                };
+                exports.insert(name.clone(), value);
+            }
+        }
+
+        let mut skipped = vec![];
+        let profile_dir = activation_path.join("etc/profile.d");
+        if profile_dir.is_dir() {
+            let mut scripts = std::fs::read_dir(&profile_dir)
+                .map_err(ActivationError::ReadProfileScripts)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(ActivationError::ReadProfileScripts)?;
+            scripts.sort();
+            skipped.extend(
+                scripts
+                    .into_iter()
+                    .filter(|path| path.extension().is_some_and(|ext| ext == "sh"))
+                    .filter(|path| !path.ends_with(COMMON_PATHS_SCRIPT))
+                    .map(|path| SkippedStep::ProfileScript { path }),
+            );
+        }
+        if let Some(profile) = manifest.get("profile").and_then(toml::Value::as_table) {
+            skipped.extend(profile.keys().map(|shell| SkippedStep::Profile {
+                shell: shell.clone(),
+            }));
+        }
+        if manifest
+            .get("hook")
+            .and_then(|hook| hook.get("on-activate"))
+            .is_some()
+        {
+            skipped.push(SkippedStep::OnActivateHook);
+        }
+
+        Ok(Self { exports, skipped })
+    }
+
+    /// Render the activation environment as a POSIX shell script of `export` statements
+    ///
+    /// Skipped steps are listed as comments.
+    pub fn to_posix_script(&self) -> String {
+        let mut script = String::new();
+        for step in &self.skipped {
+            let description = match step {
+                SkippedStep::OnActivateHook => "hook.on-activate".to_string(),
+                SkippedStep::Profile { shell } => format!("profile.{shell}"),
+                SkippedStep::ProfileScript { path } => path.display().to_string(),
+            };
+            script.push_str(&format!("# skipped: {description}\n"));
+        }
+        script.push_str(&export_statements(&self.exports));
+        script
+    }
+}
+
+/// `export` statements for `exports`, one per line
+fn export_statements(exports: &IndexMap<String, String>) -> String {
+    exports
+        .iter()
+        .map(|(name, value)| format!("export {name}={};\n", shell_escape::escape(value.into())))
+        .collect()
+}
+
+/// Everything needed to activate a built environment without `flox activate`
+///
+/// Unlike [ActivationEnv], the scripts that run arbitrary shell code are included,
+/// either to be run by the consumer or rendered to a directory with
+/// [ActivationArtifacts::render_to_dir].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ActivationArtifacts {
+    /// Variables exported by the static parts of the activation, in the order they are set
+    pub exports: IndexMap<String, String>,
+    /// Scripts in `etc/profile.d` of the built environment, in the order they are sourced
+    pub profile_d_scripts: Vec<PathBuf>,
+    /// The `hook.on-activate` script of the manifest, run in bash
+    pub on_activate: Option<String>,
+    /// The scripts of the `profile` section of the manifest by shell,
+    /// i.e. `common`, `bash`, or `zsh`
+    pub profile: IndexMap<String, String>,
+}
+
+impl ActivationArtifacts {
+    /// Collect the artifacts of an activation
+    /// from its computed environment and the manifest of the environment
+    pub fn new(
+        activation_env: ActivationEnv,
+        manifest_contents: &str,
+    ) -> Result<Self, ActivationError> {
+        let manifest: toml::Table =
+            toml::from_str(manifest_contents).map_err(ActivationError::ParseManifest)?;
+
+        let profile_d_scripts = activation_env
+            .skipped
+            .into_iter()
+            .filter_map(|step| match step {
+                SkippedStep::ProfileScript { path } => Some(path),
+                _ => None,
+            })
+            .collect();
+        let on_activate = manifest
+            .get("hook")
+            .and_then(|hook| hook.get("on-activate"))
+            .and_then(toml::Value::as_str)
+            .map(ToString::to_string);
+        // `common` runs before the shell specific scripts
+        let mut profile = IndexMap::new();
+        if let Some(scripts) = manifest.get("profile").and_then(toml::Value::as_table) {
+            for shell in ["common", "bash", "zsh"] {
+                if let Some(script) = scripts.get(shell).and_then(toml::Value::as_str) {
+                    profile.insert(shell.to_string(), script.to_string());
+                }
+            }
+        }
+
+        Ok(Self {
+            exports: activation_env.exports,
+            profile_d_scripts,
+            on_activate,
+            profile,
+        })
+    }
+
+    /// Write the artifacts to `dir` and return the paths of the written files
+    ///
+    /// * `env.sh` and `env.json` contain the exported variables
+    /// * `hook-on-activate.sh` and `profile-<shell>.sh` contain the scripts of the manifest
+    /// * `activate.bash` sources all of the above and the `etc/profile.d` scripts
+    ///   in the order `flox activate` runs them in a bash shell
+    pub fn render_to_dir(&self, dir: &Path) -> Result<Vec<PathBuf>, ActivationError> {
+        std::fs::create_dir_all(dir).map_err(ActivationError::WriteArtifacts)?;
+
+        let mut written = vec![];
+        let mut write = |name: &str, contents: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, contents).map_err(ActivationError::WriteArtifacts)?;
+            written.push(path.clone());
+            Ok::<_, ActivationError>(path)
+        };
+
+        let mut activate = vec![write("env.sh", &export_statements(&self.exports))?];
+        write(
+            "env.json",
+            &serde_json::to_string_pretty(&self.exports).expect("exports serialize to JSON"),
+        )?;
+        activate.extend(self.profile_d_scripts.iter().cloned());
+        if let Some(on_activate) = &self.on_activate {
+            activate.push(write("hook-on-activate.sh", on_activate)?);
+        }
+        for shell in ["common", "bash"] {
+            if let Some(script) = self.profile.get(shell) {
+                activate.push(write(&format!("profile-{shell}.sh"), script)?);
+            }
+        }
+        if let Some(script) = self.profile.get("zsh") {
+            write("profile-zsh.sh", script)?;
+        }
+
+        let activate_script = activate
+            .iter()
+            .map(|path| format!("source {}\n", shell_escape::escape(path.to_string_lossy())))
+            .collect::<String>();
+        write("activate.bash", &activate_script)?;
+
+        Ok(written)
+    }
+}
+
+/// Compute the activation environment of `environment`,
+/// building it if necessary, based on the environment of the current process.
+pub fn activation_env(
+    environment: &mut dyn Environment,
+    flox: &Flox,
+) -> Result<ActivationEnv, ActivationError> {
+    let activation_path = environment.activation_path(flox)?;
+    let manifest_contents = environment.manifest_content(flox)?;
+    let inherited = env::vars().collect();
+
+    let mut activation_env = ActivationEnv::new(&activation_path, &manifest_contents, &inherited)?;
+    activation_env.exports.insert(
+        FLOX_ENV_CACHE_VAR.to_string(),
+        environment.cache_path()?.to_string_lossy().to_string(),
+    );
+    activation_env.exports.insert(
+        FLOX_ENV_PROJECT_VAR.to_string(),
+        environment.project_path()?.to_string_lossy().to_string(),
+    );
+    Ok(activation_env)
+}
+
+/// Collect the activation artifacts of `environment`,
+/// building it if necessary, based on the environment of the current process.
+pub fn activation_artifacts(
+    environment: &mut dyn Environment,
+    flox: &Flox,
+) -> Result<ActivationArtifacts, ActivationError> {
+    let activation_env = activation_env(environment, flox)?;
+    let manifest_contents = environment.manifest_content(flox)?;
+    ActivationArtifacts::new(activation_env, &manifest_contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn computes_exports_and_skipped_steps() {
+        let activation_path = tempfile::tempdir().unwrap();
+        let profile_dir = activation_path.path().join("etc/profile.d");
+        std::fs::create_dir_all(&profile_dir).unwrap();
+        std::fs::write(profile_dir.join(COMMON_PATHS_SCRIPT), "").unwrap();
+        std::fs::write(profile_dir.join("0500_python.sh"), "").unwrap();
+
+        let manifest = indoc! {r#"
+            version = 1
+
+            [vars]
+            GREETING = "hello $USER"
+
+            [hook]
+            on-activate = "echo hello"
+
+            [profile]
+            bash = "echo bash"
+        "#};
+        let inherited = HashMap::from([("PATH".to_string(), "/usr/bin".to_string())]);
+
+        let activation_env =
+            ActivationEnv::new(activation_path.path(), manifest, &inherited).unwrap();
+        let flox_env = activation_path.path().display();
+
+        assert_eq!(
+            activation_env.exports["PATH"],
+            format!("{flox_env}/bin:{flox_env}/sbin:/usr/bin")
+        );
+        assert_eq!(activation_env.exports["GREETING"], "hello $USER");
+        assert_eq!(activation_env.skipped, vec![
+            SkippedStep::ProfileScript {
+                path: profile_dir.join("0500_python.sh")
+            },
+            SkippedStep::Profile {
+                shell: "bash".to_string()
+            },
+            SkippedStep::OnActivateHook,
+        ]);
+
+        let script = activation_env.to_posix_script();
+        assert!(script.contains("# skipped: hook.on-activate\n"));
+        assert!(script.contains("export GREETING='hello $USER';\n"));
+    }
+
+    #[test]
+    fn renders_activation_artifacts() {
+        let activation_path = tempfile::tempdir().unwrap();
+        let profile_dir = activation_path.path().join("etc/profile.d");
+        std::fs::create_dir_all(&profile_dir).unwrap();
+        std::fs::write(profile_dir.join("0500_python.sh"), "").unwrap();
+
+        let manifest = indoc! {r#"
+            version = 1
+
+            [hook]
+            on-activate = "echo hello"
+
+            [profile]
+            zsh = "echo zsh"
+            common = "echo common"
+        "#};
+        let activation_env =
+            ActivationEnv::new(activation_path.path(), manifest, &HashMap::new()).unwrap();
+        let artifacts = ActivationArtifacts::new(activation_env, manifest).unwrap();
+        assert_eq!(artifacts.profile_d_scripts, vec![
+            profile_dir.join("0500_python.sh")
+        ]);
+        assert_eq!(artifacts.on_activate.as_deref(), Some("echo hello"));
+        assert_eq!(artifacts.profile.keys().collect::<Vec<_>>(), [
+            "common", "zsh"
+        ]);
+
+        let render_dir = tempfile::tempdir().unwrap();
+        let dir = render_dir.path();
+        artifacts.render_to_dir(dir).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("activate.bash")).unwrap(),
+            [
+                dir.join("env.sh"),
+                profile_dir.join("0500_python.sh"),
+                dir.join("hook-on-activate.sh"),
+                dir.join("profile-common.sh"),
+            ]
+            .iter()
+            .map(|path| format!("source {}\n", path.display()))
+            .collect::<String>()
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("profile-zsh.sh")).unwrap(),
+            "echo zsh"
+        );
+        let exports: IndexMap<String, String> =
+            serde_json::from_str(&std::fs::read_to_string(dir.join("env.json")).unwrap()).unwrap();
+        assert_eq!(exports, artifacts.exports);
+    }
+}
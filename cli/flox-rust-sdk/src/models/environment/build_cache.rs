@@ -0,0 +1,158 @@
+//! Skip builds of lockfiles that were built before
+//!
+//! Building an unchanged lockfile produces the same store path,
+//! but still evaluates every package with pkgdb.
+//! [BuildCache] records the store path built for a lockfile on a system
+//! with the same build settings,
+//! so that e.g. editing a comment in the manifest doesn't rebuild the environment.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::data::System;
+
+/// The number of builds that are remembered,
+/// older builds are forgotten first
+const MAX_ENTRIES: usize = 64;
+
+#[derive(Debug, Error)]
+pub enum BuildCacheError {
+    #[error("couldn't read build cache")]
+    Read(#[source] std::io::Error),
+    #[error("couldn't parse build cache")]
+    Parse(#[source] serde_json::Error),
+    #[error("couldn't write build cache")]
+    Write(#[source] std::io::Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct BuildCacheEntry {
+    /// The blake3 hash of the lockfile and the settings it was built with, as hex
+    ///
+    /// Caches written by older versions call it `lockfile_hash`.
+    #[serde(alias = "lockfile_hash")]
+    build_hash: String,
+    system: System,
+    store_path: PathBuf,
+}
+
+/// The store paths of previous successful builds, by lockfile and system
+#[derive(Debug, Clone)]
+pub struct BuildCache {
+    path: PathBuf,
+}
+
+impl BuildCache {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    fn read(&self) -> Result<Vec<BuildCacheEntry>, BuildCacheError> {
+        let contents = match std::fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(BuildCacheError::Read(e)),
+        };
+        serde_json::from_str(&contents).map_err(BuildCacheError::Parse)
+    }
+
+    /// The store path the build hashed to `build_hash` was last built to for `system`
+    ///
+    /// The store path may have since been garbage collected.
+    pub fn get(
+        &self,
+        build_hash: &blake3::Hash,
+        system: &System,
+    ) -> Result<Option<PathBuf>, BuildCacheError> {
+        let build_hash = build_hash.to_hex();
+        let store_path = self
+            .read()?
+            .into_iter()
+            .rev()
+            .find(|entry| entry.build_hash == build_hash.as_str() && &entry.system == system)
+            .map(|entry| entry.store_path);
+        Ok(store_path)
+    }
+
+    /// Record that the build hashed to `build_hash` was built to `store_path`
+    pub fn insert(
+        &self,
+        build_hash: &blake3::Hash,
+        system: &System,
+        store_path: &Path,
+    ) -> Result<(), BuildCacheError> {
+        let build_hash = build_hash.to_hex().to_string();
+        let mut entries = self.read()?;
+        entries.retain(|entry| !(entry.build_hash == build_hash && &entry.system == system));
+        entries.push(BuildCacheEntry {
+            build_hash,
+            system: system.clone(),
+            store_path: store_path.to_path_buf(),
+        });
+        if entries.len() > MAX_ENTRIES {
+            entries.drain(..entries.len() - MAX_ENTRIES);
+        }
+
+        let parent = self.path.parent().unwrap_or(Path::new("."));
+        std::fs::create_dir_all(parent).map_err(BuildCacheError::Write)?;
+        let mut temp_file =
+            tempfile::NamedTempFile::new_in(parent).map_err(BuildCacheError::Write)?;
+        serde_json::to_writer(&mut temp_file, &entries)
+            .map_err(|e| BuildCacheError::Write(e.into()))?;
+        temp_file
+            .persist(&self.path)
+            .map_err(|e| BuildCacheError::Write(e.error))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remembers_store_paths_per_system() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let cache = BuildCache::new(tempdir.path().join("build-cache.json"));
+        let store_path = tempdir.path().join("environment");
+        std::fs::create_dir(&store_path).unwrap();
+        let system = "x86_64-linux".to_string();
+
+        let build_hash = blake3::hash(b"lockfile");
+        assert_eq!(cache.get(&build_hash, &system).unwrap(), None);
+
+        cache.insert(&build_hash, &system, &store_path).unwrap();
+        assert_eq!(
+            cache.get(&build_hash, &system).unwrap(),
+            Some(store_path.clone())
+        );
+        assert_eq!(
+            cache
+                .get(&build_hash, &"aarch64-darwin".to_string())
+                .unwrap(),
+            None
+        );
+        assert_eq!(cache.get(&blake3::hash(b"changed"), &system).unwrap(), None);
+    }
+
+    #[test]
+    fn reads_entries_written_by_older_versions() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let cache_path = tempdir.path().join("build-cache.json");
+        let build_hash = blake3::hash(b"lockfile");
+        let entries = serde_json::json!([{
+            "lockfile_hash": build_hash.to_hex().as_str(),
+            "system": "x86_64-linux",
+            "store_path": "/nix/store/environment",
+        }]);
+        std::fs::write(&cache_path, entries.to_string()).unwrap();
+
+        let cache = BuildCache::new(cache_path);
+        assert_eq!(
+            cache.get(&build_hash, &"x86_64-linux".to_string()).unwrap(),
+            Some(PathBuf::from("/nix/store/environment"))
+        );
+    }
+}
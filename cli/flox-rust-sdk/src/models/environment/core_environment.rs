@@ -1,52 +1,85 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Display;
 use std::fs;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
+use fslock::LockFile;
 use log::debug;
 use pollster::FutureExt;
 use thiserror::Error;
 use tracing::warn;
 
+use super::doctor::{self, DoctorReport};
+use super::hook_check::{check_changed_scripts, HookCheckError, HookSyntaxError};
+use super::local_generations::{LocalGenerations, LocalGenerationsError};
+use super::reproducibility::{rebuild_and_compare, ReproducibilityError, ReproducibilityReport};
+use super::snapshots::{SnapshotError, SnapshotMetadata, Snapshots, SNAPSHOTS_DIR_NAME};
+use super::store::{EnvironmentStore, FileSystemStore};
+use super::store_verify::{
+    repair_store_paths,
+    verify_store_paths,
+    StoreVerification,
+    StoreVerifyError,
+};
+use super::templates::find_template;
 use super::{
-    copy_dir_recursive,
+    clone_dir_recursive,
+    out_links,
     CanonicalizeError,
     InstallationAttempt,
     UninstallationAttempt,
     UpdateResult,
+    DOT_FLOX,
+    ENV_DIR_NAME,
     LOCKFILE_FILENAME,
     MANIFEST_FILENAME,
 };
-use crate::data::CanonicalPath;
+use crate::data::{CanonicalPath, System};
 use crate::flox::Flox;
+use crate::models::build_failure::BuildFailure;
 use crate::models::container_builder::ContainerBuilder;
 use crate::models::environment::{call_pkgdb, global_manifest_path};
+use crate::models::include::{resolve_includes, IncludeError, LockedInclude};
 use crate::models::lockfile::{
+    InstalledPackage,
     LockedManifest,
     LockedManifestCatalog,
     LockedManifestError,
     LockedManifestPkgdb,
     LockedPackageCatalog,
+    TypedLockedManifestPkgdb,
 };
 use crate::models::manifest::{
+    apply_manifest_edits,
     insert_packages,
+    migrate_manifest_to_catalog,
     remove_packages,
-    Manifest,
+    ManifestEdit,
+    ManifestPackageDescriptor,
+    MigrateManifestError,
     PackageToInstall,
+    ServiceDescriptor,
     TomlEditError,
     TypedManifest,
     TypedManifestCatalog,
+    UninstallResult,
 };
 use crate::models::pkgdb::{
     error_codes,
+    BuildLogLine,
     CallPkgDbError,
     PkgDbError,
     UpgradeResult,
     UpgradeResultJSON,
     PKGDB_BIN,
 };
-use crate::providers::catalog::{self, ClientTrait};
-use crate::utils::CommandExt;
+use crate::models::remote_builder;
+use crate::providers::catalog::{CachedResolutionClient, ClientTrait, PackageGroup};
+use crate::utils::events::{ErrorCategory, Operation};
+use crate::utils::progress::{Progress, ProgressEvent};
+use crate::utils::{same_filesystem, CommandExt};
 
 pub struct ReadOnly {}
 struct ReadWrite {}
@@ -63,6 +96,12 @@ pub struct CoreEnvironment<State = ReadOnly> {
     ///
     /// Commonly /.../.flox/env/
     env_dir: PathBuf,
+    /// The environment directory that this view was copied from,
+    /// or `env_dir` if it is not a temporary copy
+    origin_dir: PathBuf,
+    /// Whether transactions record [Self::generations],
+    /// see [CoreEnvironment::with_local_generations]
+    local_generations: bool,
     _state: State,
 }
 
@@ -84,9 +123,163 @@ impl<State> CoreEnvironment<State> {
         self.env_dir.join(LOCKFILE_FILENAME)
     }
 
+    /// The directory that local includes of the manifest are resolved relative to
+    ///
+    /// This is the project directory for environments in `<project>/.flox/env`,
+    /// and the environment directory otherwise.
+    /// Temporary copies of an environment resolve includes like the original environment.
+    fn include_dir(&self) -> &Path {
+        let project_dir = self
+            .origin_dir
+            .parent()
+            .filter(|dot_flox| {
+                dot_flox.ends_with(DOT_FLOX) && self.origin_dir.ends_with(ENV_DIR_NAME)
+            })
+            .and_then(Path::parent);
+        project_dir.unwrap_or(&self.origin_dir)
+    }
+
+    /// Merge the environments included by `manifest` into it,
+    /// see [resolve_includes]
+    fn resolve_includes(
+        &self,
+        manifest: &TypedManifestCatalog,
+        seed: Option<&LockedManifestCatalog>,
+    ) -> Result<(TypedManifestCatalog, Vec<LockedInclude>), CoreEnvironmentError> {
+        let seed = seed
+            .map(|seed| seed.includes.as_slice())
+            .unwrap_or_default();
+        resolve_includes(manifest, self.include_dir(), seed).map_err(CoreEnvironmentError::Include)
+    }
+
     /// Read the manifest file
     fn manifest_content(&self) -> Result<String, CoreEnvironmentError> {
-        fs::read_to_string(self.manifest_path()).map_err(CoreEnvironmentError::OpenManifest)
+        self.store().read_manifest()
+    }
+
+    /// The [EnvironmentStore] of the environment directory
+    ///
+    /// The store records generations if enabled with [Self::with_local_generations].
+    pub fn store(&self) -> FileSystemStore {
+        let store = FileSystemStore::new(&self.env_dir);
+        if self.local_generations {
+            store.with_local_generations()
+        } else {
+            store
+        }
+    }
+
+    /// A content hash of the inputs of this environment,
+    /// i.e. its manifest and lockfile, if it is locked
+    ///
+    /// Both are hashed in their parsed form,
+    /// so comments and formatting of the manifest don't affect the fingerprint,
+    /// while any change to their contents does.
+    /// This makes the fingerprint suitable as a cache key for the built environment.
+    pub fn fingerprint(&self) -> Result<blake3::Hash, CoreEnvironmentError> {
+        let manifest: toml::Table = toml::from_str(&self.manifest_content()?)
+            .map_err(CoreEnvironmentError::DeserializeManifest)?;
+        let lockfile = match CanonicalPath::new(self.lockfile_path()) {
+            Ok(lockfile_path) => Some(
+                LockedManifest::read_from_file(&lockfile_path)
+                    .map_err(CoreEnvironmentError::LockedManifest)?,
+            ),
+            Err(_) => None,
+        };
+
+        // Converting to a JSON value sorts the keys of all tables and objects
+        let inputs = serde_json::json!({
+            "manifest": manifest,
+            "lockfile": lockfile,
+        });
+        Ok(blake3::hash(inputs.to_string().as_bytes()))
+    }
+
+    /// The generations recorded for this environment
+    ///
+    /// Generations are stored next to the environment directory,
+    /// e.g. in `.flox/env.generations` for an environment in `.flox/env`.
+    pub fn generations(&self) -> LocalGenerations {
+        LocalGenerations::new(self.env_dir.with_extension("generations"))
+    }
+
+    /// The snapshots taken of this environment
+    ///
+    /// Snapshots are stored next to the environment directory,
+    /// e.g. in `.flox/snapshots` for an environment in `.flox/env`.
+    pub fn snapshots(&self) -> Snapshots {
+        Snapshots::new(self.env_dir.with_file_name(SNAPSHOTS_DIR_NAME))
+    }
+
+    /// Take a snapshot named `name` of the manifest and lockfile of the environment
+    ///
+    /// `store_path` is the store path the environment is currently linked to, if any.
+    pub fn snapshot(
+        &self,
+        name: &str,
+        description: String,
+        store_path: Option<PathBuf>,
+    ) -> Result<SnapshotMetadata, CoreEnvironmentError> {
+        self.snapshots()
+            .create(&self.env_dir, name, description, store_path)
+            .map_err(CoreEnvironmentError::Snapshots)
+    }
+
+    /// Record the current state of the environment as a new generation
+    ///
+    /// The environment has already been replaced at this point,
+    /// so a failure to record the generation is not a failure of the transaction.
+    fn record_generation(&self, store_path: &Path, description: String) {
+        if !self.local_generations {
+            return;
+        }
+        if let Err(err) = self.generations().add_generation(
+            &self.env_dir,
+            Some(store_path.to_path_buf()),
+            description,
+        ) {
+            warn!("failed to record generation: {err}");
+        }
+    }
+
+    /// Acquire the transaction lock of the environment
+    ///
+    /// Transactions hold this lock from copying the environment until it was replaced,
+    /// so that concurrent transactions on the same environment,
+    /// e.g. two `flox install` invocations, don't race on the transaction backup.
+    /// Fails immediately if another transaction holds the lock.
+    pub(super) fn lock_transaction(&self) -> Result<LockFile, CoreEnvironmentError> {
+        let lock_path = self.env_dir.with_extension("lock");
+        let mut lock =
+            LockFile::open(lock_path.as_os_str()).map_err(CoreEnvironmentError::TransactionLock)?;
+        if !lock
+            .try_lock()
+            .map_err(CoreEnvironmentError::TransactionLock)?
+        {
+            debug!("transaction lock is held: {}", lock_path.display());
+            return Err(CoreEnvironmentError::EnvironmentBusy(self.env_dir.clone()));
+        }
+        Ok(lock)
+    }
+
+    /// Hash the current contents of the manifest file
+    fn manifest_hash(&self) -> Result<blake3::Hash, CoreEnvironmentError> {
+        Ok(blake3::hash(self.manifest_content()?.as_bytes()))
+    }
+
+    /// Fail if the manifest changed since `hash` was taken,
+    /// e.g. because the user edited it while a transaction was building.
+    fn ensure_manifest_unchanged(&self, hash: &blake3::Hash) -> Result<(), CoreEnvironmentError> {
+        if self.manifest_hash()? != *hash {
+            debug!(
+                "manifest was modified during transaction: {}",
+                self.manifest_path().display()
+            );
+            return Err(CoreEnvironmentError::ManifestModifiedConcurrently(
+                self.manifest_path(),
+            ));
+        }
+        Ok(())
     }
 
     /// Lock the environment.
@@ -98,18 +291,29 @@ impl<State> CoreEnvironment<State> {
     ///
     /// This re-writes the lock if it exists.
     ///
-    /// Technically this does write to disk as a side effect for now.
+    /// Technically this does write to disk as a side effect.
     /// It's included in the [ReadOnly] struct for ergonomic reasons
     /// and because it doesn't modify the manifest.
-    ///
-    /// todo: should we always write the lockfile to disk?
+    /// Use [Self::lock_pure] to only compute the lockfile.
     pub fn lock(&mut self, flox: &Flox) -> Result<LockedManifest, CoreEnvironmentError> {
+        let lockfile = self.lock_pure(flox)?;
+        self.write_lockfile(&lockfile)?;
+        Ok(lockfile)
+    }
+
+    /// Compute the lockfile of the environment without writing it to disk
+    ///
+    /// Locks the manifest the same way as [Self::lock],
+    /// using the existing lockfile as a base if there is one.
+    /// The result can be persisted with [Self::write_lockfile].
+    pub fn lock_pure(&self, flox: &Flox) -> Result<LockedManifest, CoreEnvironmentError> {
         let manifest: TypedManifest = toml::from_str(&self.manifest_content()?)
             .map_err(CoreEnvironmentError::DeserializeManifest)?;
 
         let lockfile = match manifest {
             TypedManifest::Pkgdb(_) => {
                 tracing::debug!("using pkgdb to lock");
+                flox.progress.emit(ProgressEvent::LockingWithPkgdb);
                 LockedManifest::Pkgdb(self.lock_with_pkgdb(flox)?)
             },
             TypedManifest::Catalog(manifest) => {
@@ -117,25 +321,70 @@ impl<State> CoreEnvironment<State> {
                     return Err(CoreEnvironmentError::CatalogClientMissing);
                 };
                 tracing::debug!("using catalog client to lock");
-                LockedManifest::Catalog(self.lock_with_catalog_client(client, *manifest)?)
+                let client = CachedResolutionClient::new(client, &flox.resolution_cache)
+                    .with_snapshot(flox.catalog_snapshot(), flox.offline);
+                LockedManifest::Catalog(Box::new(self.lock_with_catalog_client(
+                    &client,
+                    *manifest,
+                    &flox.progress,
+                )?))
             },
         };
 
-        let environment_lockfile_path = self.lockfile_path();
+        Ok(lockfile)
+    }
+
+    /// Lock the environment like [Self::lock]
+    /// and list the locked packages for every system the environment supports
+    ///
+    /// Catalog manifests are resolved for all systems in `options.systems`
+    /// by a single lock, so this allows validating a cross-platform environment
+    /// (e.g. in CI) before any of it is built.
+    /// Systems without any locked packages are included with an empty list.
+    pub fn lock_all_systems(&mut self, flox: &Flox) -> Result<SystemsLock, CoreEnvironmentError> {
+        let lockfile = self.lock(flox)?;
+
+        let packages = match &lockfile {
+            LockedManifest::Catalog(locked) => locked
+                .systems()
+                .into_iter()
+                .map(|system| {
+                    let packages = locked.list_packages(&system);
+                    (system, packages)
+                })
+                .collect(),
+            LockedManifest::Pkgdb(locked) => {
+                let locked = TypedLockedManifestPkgdb::try_from(locked.clone())
+                    .map_err(CoreEnvironmentError::LockedManifest)?;
+                locked
+                    .systems()
+                    .into_iter()
+                    .map(|system| {
+                        let packages = locked.list_packages(&system);
+                        (system, packages)
+                    })
+                    .collect()
+            },
+        };
+
+        Ok(SystemsLock { lockfile, packages })
+    }
 
-        // Write the lockfile to disk
-        // todo: do we always want to do this?
+    /// Write a lockfile to the environment, replacing any existing lockfile
+    pub fn write_lockfile(
+        &mut self,
+        lockfile: &LockedManifest,
+    ) -> Result<(), CoreEnvironmentError> {
+        let environment_lockfile_path = self.lockfile_path();
         debug!(
-            "generated lockfile, writing to {}",
+            "writing lockfile to {}",
             environment_lockfile_path.display()
         );
         std::fs::write(
             &environment_lockfile_path,
-            serde_json::to_string_pretty(&lockfile).unwrap(),
+            serde_json::to_string_pretty(lockfile).unwrap(),
         )
-        .map_err(CoreEnvironmentError::WriteLockfile)?;
-
-        Ok(lockfile)
+        .map_err(CoreEnvironmentError::WriteLockfile)
     }
 
     /// Lock the environment with the pkgdb
@@ -143,10 +392,7 @@ impl<State> CoreEnvironment<State> {
     /// Passes the manifest and the existing lockfile to `pkgdb manifest lock`.
     /// The lockfile is used to lock the underlying package registry.
     /// If the environment has no lockfile, the global lockfile is used as a base instead.
-    fn lock_with_pkgdb(
-        &mut self,
-        flox: &Flox,
-    ) -> Result<LockedManifestPkgdb, CoreEnvironmentError> {
+    fn lock_with_pkgdb(&self, flox: &Flox) -> Result<LockedManifestPkgdb, CoreEnvironmentError> {
         let manifest_path = self.manifest_path();
         let environment_lockfile_path = self.lockfile_path();
         let existing_lockfile_path = if environment_lockfile_path.exists() {
@@ -180,31 +426,125 @@ impl<State> CoreEnvironment<State> {
     /// If a lockfile exists, it is used as a base.
     /// If the manifest should be locked without a base,
     /// remove the lockfile before calling this function or use [Self::upgrade].
+    /// The groups that are resolved for the manifest with its includes merged
+    /// are reported to `progress`.
     fn lock_with_catalog_client(
         &self,
-        client: &catalog::Client,
+        client: &impl ClientTrait,
         manifest: TypedManifestCatalog,
+        progress: &Progress,
     ) -> Result<LockedManifestCatalog, CoreEnvironmentError> {
-        let existing_lockfile = 'lockfile: {
-            let Ok(lockfile_path) = CanonicalPath::new(self.lockfile_path()) else {
-                break 'lockfile None;
-            };
-            let lockfile = LockedManifest::read_from_file(&lockfile_path)
+        let existing_lockfile = self.existing_catalog_lockfile()?;
+        let (manifest, includes) = self.resolve_includes(&manifest, existing_lockfile.as_ref())?;
+        for group in LockedManifestCatalog::groups_to_resolve(&manifest, existing_lockfile.as_ref())
+        {
+            progress.emit(ProgressEvent::ResolvingGroup { name: group.name });
+        }
+
+        let mut lockfile =
+            LockedManifestCatalog::lock_manifest(&manifest, existing_lockfile.as_ref(), client)
+                .block_on()
                 .map_err(CoreEnvironmentError::LockedManifest)?;
-            match lockfile {
-                LockedManifest::Catalog(lockfile) => Some(lockfile),
-                _ => {
-                    warn!(
-                        "Found version 1 manifest, but lockfile doesn't match: Ignoring lockfile."
-                    );
-                    None
+        lockfile.includes = includes;
+        Ok(lockfile)
+    }
+
+    /// Read the existing lockfile if it is a catalog lockfile
+    fn existing_catalog_lockfile(
+        &self,
+    ) -> Result<Option<LockedManifestCatalog>, CoreEnvironmentError> {
+        let Ok(lockfile_path) = CanonicalPath::new(self.lockfile_path()) else {
+            return Ok(None);
+        };
+        let lockfile = LockedManifest::read_from_file(&lockfile_path)
+            .map_err(CoreEnvironmentError::LockedManifest)?;
+        match lockfile {
+            LockedManifest::Catalog(lockfile) => Ok(Some(*lockfile)),
+            _ => {
+                warn!("Found version 1 manifest, but lockfile doesn't match: Ignoring lockfile.");
+                Ok(None)
+            },
+        }
+    }
+
+    /// Resolve the packages of the environment in the background
+    ///
+    /// Starts fetching the resolutions of the package groups that
+    /// locking the current manifest would request,
+    /// as well as the unconstrained groups that an upgrade would request,
+    /// and stores them in [Flox::resolution_cache].
+    /// A following [Self::lock], e.g. after an edit,
+    /// uses the prefetched resolutions for all groups that were not changed.
+    /// [Self::upgrade] clears the cache once it used it.
+    ///
+    /// Prefetching is best effort, failures are only logged.
+    /// Manifests that are not locked with the catalog,
+    /// or when [Flox::offline] is set, are not prefetched.
+    /// Dropping the returned [PrefetchHandle] does not cancel the prefetch.
+    pub fn prefetch_resolution(&self, flox: &Flox) -> Result<PrefetchHandle, CoreEnvironmentError> {
+        let Some(client) = flox.catalog_client.clone() else {
+            return Ok(PrefetchHandle(None));
+        };
+        let groups = self.groups_to_prefetch(flox)?;
+        if groups.is_empty() {
+            return Ok(PrefetchHandle(None));
+        }
+
+        let cache = flox.resolution_cache.clone();
+        let snapshot = flox.catalog_snapshot();
+        let handle = std::thread::spawn(move || {
+            // The caller may or may not run inside a tokio runtime,
+            // so the prefetch gets its own.
+            let runtime = match tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+            {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    debug!("could not start runtime to prefetch resolution: {e}");
+                    return;
+                },
+            };
+            debug!("prefetching {} package group(s)", groups.len());
+            match runtime.block_on(client.resolve(groups.clone())) {
+                Ok(resolved) => {
+                    if let Err(e) = snapshot.record(&groups, &resolved) {
+                        debug!("couldn't record resolution in catalog snapshot: {e}");
+                    }
+                    cache.insert(groups, resolved)
                 },
+                Err(e) => debug!("failed to prefetch resolution: {e}"),
             }
+        });
+
+        Ok(PrefetchHandle(Some(handle)))
+    }
+
+    /// The package groups that [Self::prefetch_resolution] resolves
+    ///
+    /// Empty if the manifest is not locked with the catalog,
+    /// or if [Flox::offline] is set.
+    pub(super) fn groups_to_prefetch(
+        &self,
+        flox: &Flox,
+    ) -> Result<Vec<PackageGroup>, CoreEnvironmentError> {
+        let manifest: TypedManifest = toml::from_str(&self.manifest_content()?)
+            .map_err(CoreEnvironmentError::DeserializeManifest)?;
+        let TypedManifest::Catalog(manifest) = manifest else {
+            return Ok(vec![]);
         };
+        if flox.catalog_client.is_none() || flox.offline {
+            return Ok(vec![]);
+        }
 
-        LockedManifestCatalog::lock_manifest(&manifest, existing_lockfile.as_ref(), client)
-            .block_on()
-            .map_err(CoreEnvironmentError::LockedManifest)
+        let seed = self.existing_catalog_lockfile()?;
+        let mut groups = LockedManifestCatalog::groups_to_resolve(&manifest, seed.as_ref());
+        for group in LockedManifestCatalog::groups_to_resolve(&manifest, None) {
+            if !groups.contains(&group) {
+                groups.push(group);
+            }
+        }
+        Ok(groups)
     }
 
     /// Build the environment.
@@ -231,6 +571,205 @@ impl<State> CoreEnvironment<State> {
     /// ```
     #[must_use = "don't discard the store path of built environments"]
     pub fn build(&mut self, flox: &Flox) -> Result<PathBuf, CoreEnvironmentError> {
+        self.build_with_log(flox, |_| {})
+    }
+
+    /// Build the environment like [Self::build],
+    /// passing every line of build output to `on_line` as it is produced.
+    ///
+    /// If the build fails, the last lines of output are available
+    /// from [CoreEnvironmentError::build_log_tail].
+    /// If [Flox::keep_failed] is set, the build directories of failed builds are kept
+    /// and available from [CoreEnvironmentError::kept_build_dirs].
+    ///
+    /// A lockfile that was built before is not built again,
+    /// the store path of the previous build is returned without output,
+    /// see [Flox::build_cache].
+    #[must_use = "don't discard the store path of built environments"]
+    pub fn build_with_log(
+        &mut self,
+        flox: &Flox,
+        on_line: impl FnMut(BuildLogLine) + Send,
+    ) -> Result<PathBuf, CoreEnvironmentError> {
+        flox.events
+            .record(Operation::Build, CoreEnvironmentError::category, || {
+                let lockfile_path = CanonicalPath::new(self.lockfile_path())
+                    .map_err(CoreEnvironmentError::BadLockfilePath)?;
+                let lockfile = LockedManifest::read_from_file(&lockfile_path)
+                    .map_err(CoreEnvironmentError::LockedManifest)?;
+
+                debug!(
+                    "building environment: system={}, lockfilePath={}",
+                    &flox.system,
+                    lockfile_path.display()
+                );
+
+                let store_path =
+                    Self::build_cached(flox, &lockfile_path, &lockfile, &flox.system, || {
+                        flox.progress.emit(ProgressEvent::Building);
+                        lockfile
+                            .build_with_log(
+                                Path::new(&*PKGDB_BIN),
+                                None,
+                                &None,
+                                &flox.remote_builders,
+                                flox.keep_failed,
+                                on_line,
+                            )
+                            .map_err(CoreEnvironmentError::LockedManifest)
+                    })?;
+
+                debug!(
+                    "built locked environment, store path={}",
+                    store_path.display()
+                );
+
+                Ok(store_path)
+            })
+    }
+
+    /// Build only the packages of the pkg-group `group` and link them to `out_link_path`
+    ///
+    /// Large environments can be iterated on by rebuilding a single group,
+    /// e.g. `toolchain`, without building and linking all other packages.
+    /// The result is linked to its own out-link,
+    /// the out-link of the whole environment is not modified.
+    /// Only environments locked with the catalog have pkg-groups.
+    pub fn build_group(
+        &mut self,
+        flox: &Flox,
+        group: &str,
+        out_link_path: impl AsRef<Path>,
+    ) -> Result<PathBuf, CoreEnvironmentError> {
+        flox.events
+            .record(Operation::Build, CoreEnvironmentError::category, || {
+                let lockfile_path = CanonicalPath::new(self.lockfile_path())
+                    .map_err(CoreEnvironmentError::BadLockfilePath)?;
+                let LockedManifest::Catalog(lockfile) =
+                    LockedManifest::read_from_file(&lockfile_path)
+                        .map_err(CoreEnvironmentError::LockedManifest)?
+                else {
+                    return Err(CoreEnvironmentError::GroupBuildRequiresCatalog);
+                };
+                let group_lockfile = lockfile
+                    .group(group)
+                    .ok_or_else(|| CoreEnvironmentError::GroupNotFound(group.to_string()))?;
+
+                debug!(
+                    "building group: group={group}, system={}, outLinkPath={}",
+                    &flox.system,
+                    out_link_path.as_ref().display()
+                );
+
+                flox.progress.emit(ProgressEvent::Building);
+                let store_path = LockedManifest::Catalog(Box::new(group_lockfile))
+                    .build_with_log(
+                        Path::new(&*PKGDB_BIN),
+                        Some(out_link_path.as_ref()),
+                        &None,
+                        &flox.remote_builders,
+                        flox.keep_failed,
+                        |_| {},
+                    )
+                    .map_err(CoreEnvironmentError::LockedManifest)?;
+
+                debug!("built group '{group}', store path={}", store_path.display());
+
+                Ok(store_path)
+            })
+    }
+
+    /// Hash everything a build of the lockfile with `lockfile_contents` depends on
+    ///
+    /// Besides the lockfile itself, that is the substituters the build can fetch from,
+    /// the [Flox::remote_builders] it can delegate to,
+    /// and whether [Flox::keep_failed] builds are kept.
+    fn build_hash(
+        flox: &Flox,
+        lockfile_contents: &[u8],
+        lockfile: &LockedManifest,
+    ) -> blake3::Hash {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(lockfile_contents);
+        for substituter in lockfile.substituters() {
+            hasher.update(b"\0substituter=");
+            hasher.update(substituter.as_bytes());
+        }
+        for builder in &flox.remote_builders {
+            hasher.update(b"\0builder=");
+            hasher.update(&serde_json::to_vec(builder).unwrap_or_default());
+        }
+        hasher.update(format!("\0keep-failed={}", flox.keep_failed).as_bytes());
+        hasher.finalize()
+    }
+
+    /// Return the store path `lockfile_path` was previously built to for `system`
+    /// with the same build settings,
+    /// or `build` it and remember the result in [Flox::build_cache]
+    ///
+    /// Previous builds whose store path no longer exists,
+    /// e.g. because it was garbage collected, are built again.
+    /// The build cache is an optimization,
+    /// failures to read or write it are logged and otherwise ignored.
+    fn build_cached(
+        flox: &Flox,
+        lockfile_path: &Path,
+        lockfile: &LockedManifest,
+        system: &System,
+        build: impl FnOnce() -> Result<PathBuf, CoreEnvironmentError>,
+    ) -> Result<PathBuf, CoreEnvironmentError> {
+        let build_cache = flox.build_cache();
+        let build_hash = match fs::read(lockfile_path) {
+            Ok(contents) => Some(Self::build_hash(flox, &contents, lockfile)),
+            Err(err) => {
+                debug!("couldn't hash lockfile, not using build cache: {err}");
+                None
+            },
+        };
+
+        if let Some(build_hash) = &build_hash {
+            match build_cache.get(build_hash, system) {
+                Ok(Some(store_path)) if !store_path.exists() => {
+                    debug!(
+                        "previous build was removed, rebuilding: store path={}",
+                        store_path.display()
+                    );
+                },
+                Ok(Some(store_path)) => {
+                    debug!(
+                        "lockfile was built before, skipping build: store path={}",
+                        store_path.display()
+                    );
+                    return Ok(store_path);
+                },
+                Ok(None) => {},
+                Err(err) => debug!("couldn't read build cache: {err}"),
+            }
+        }
+
+        let store_path = build()?;
+
+        if let Some(build_hash) = &build_hash {
+            if let Err(err) = build_cache.insert(build_hash, system, &store_path) {
+                debug!("couldn't record build in build cache: {err}");
+            }
+        }
+        Ok(store_path)
+    }
+
+    /// Build the environment for `system` instead of the current system
+    ///
+    /// Like [Self::build], this requires the environment to be locked.
+    /// Building for a system other than the current one only succeeds
+    /// if every package can be substituted from a binary cache,
+    /// or one of [Flox::remote_builders] can build for that system.
+    /// The result is not linked, it only verifies that the environment builds.
+    #[must_use = "don't discard the store path of built environments"]
+    pub fn build_for_system(
+        &mut self,
+        flox: &Flox,
+        system: &System,
+    ) -> Result<PathBuf, CoreEnvironmentError> {
         let lockfile_path = CanonicalPath::new(self.lockfile_path())
             .map_err(CoreEnvironmentError::BadLockfilePath)?;
         let lockfile = LockedManifest::read_from_file(&lockfile_path)
@@ -238,20 +777,22 @@ impl<State> CoreEnvironment<State> {
 
         debug!(
             "building environment: system={}, lockfilePath={}",
-            &flox.system,
+            system,
             lockfile_path.display()
         );
 
-        let store_path = lockfile
-            .build(Path::new(&*PKGDB_BIN), None, &None)
-            .map_err(CoreEnvironmentError::LockedManifest)?;
-
-        debug!(
-            "built locked environment, store path={}",
-            store_path.display()
-        );
-
-        Ok(store_path)
+        Self::build_cached(flox, &lockfile_path, &lockfile, system, || {
+            flox.progress.emit(ProgressEvent::Building);
+            lockfile
+                .build_for_system(
+                    Path::new(&*PKGDB_BIN),
+                    system,
+                    &flox.remote_builders,
+                    flox.keep_failed,
+                    |_| {},
+                )
+                .map_err(CoreEnvironmentError::LockedManifest)
+        })
     }
 
     /// Creates a [ContainerBuilder] from the environment.
@@ -264,10 +805,11 @@ impl<State> CoreEnvironment<State> {
     /// Building an environment for linux on a non-linux platform (macos),
     /// will likely fail unless all packages in the environment can be substituted.
     ///
-    /// There are mitigations for this, such as building within a VM or container.
-    /// Such solutions are out of scope at this point.
-    /// Until then, this function will error with [CoreEnvironmentError::ContainerizeUnsupportedSystem]
-    /// if the environment is not linux.
+    /// To build linux containers on other platforms,
+    /// the environment is built for the linux system of the same architecture
+    /// using [Flox::remote_builders].
+    /// If none of them can build for that system,
+    /// this function will error with [CoreEnvironmentError::ContainerizeUnsupportedSystem].
     ///
     /// [Self::lock]s if necessary.
     ///
@@ -280,22 +822,32 @@ impl<State> CoreEnvironment<State> {
         &mut self,
         flox: &Flox,
     ) -> Result<ContainerBuilder, CoreEnvironmentError> {
-        if std::env::consts::OS != "linux" {
-            return Err(CoreEnvironmentError::ContainerizeUnsupportedSystem(
-                std::env::consts::OS.to_string(),
-            ));
-        }
+        let container_system = if std::env::consts::OS == "linux" {
+            None
+        } else {
+            let linux_system = format!("{}-linux", std::env::consts::ARCH);
+            if !remote_builder::supports_system(&flox.remote_builders, &linux_system) {
+                return Err(CoreEnvironmentError::ContainerizeUnsupportedSystem(
+                    std::env::consts::OS.to_string(),
+                ));
+            }
+            Some(linux_system)
+        };
 
         let lockfile = self.lock(flox)?;
 
         debug!(
             "building container: system={}, lockfilePath={}",
-            &flox.system,
+            container_system.as_ref().unwrap_or(&flox.system),
             self.lockfile_path().display()
         );
 
         let builder = lockfile
-            .build_container(Path::new(&*PKGDB_BIN))
+            .build_container(
+                Path::new(&*PKGDB_BIN),
+                container_system.as_ref(),
+                &flox.remote_builders,
+            )
             .map_err(CoreEnvironmentError::LockedManifest)?;
         Ok(builder)
     }
@@ -319,6 +871,18 @@ impl<State> CoreEnvironment<State> {
         out_link_path: impl AsRef<Path>,
         store_path: &Option<PathBuf>,
     ) -> Result<(), CoreEnvironmentError> {
+        // Linking the store path the out-link already points to is a no-op
+        if let Some(store_path) = store_path {
+            let current = fs::canonicalize(out_link_path.as_ref()).ok();
+            if current.is_some() && current == fs::canonicalize(store_path).ok() {
+                debug!(
+                    "out-link {} is up to date, skipping link",
+                    out_link_path.as_ref().display()
+                );
+                return Ok(());
+            }
+        }
+
         let lockfile_path = CanonicalPath::new(self.lockfile_path())
             .map_err(CoreEnvironmentError::BadLockfilePath)?;
         let lockfile = LockedManifest::read_from_file(&lockfile_path)
@@ -331,6 +895,9 @@ impl<State> CoreEnvironment<State> {
             out_link_path.as_ref().display()
         );
 
+        flox.progress.emit(ProgressEvent::Linking {
+            out_link: out_link_path.as_ref().to_path_buf(),
+        });
         // Note: when `store_path` is `Some`, `--store-path` is passed to `pkgdb buildenv`
         // which skips the build and only attempts to link the environment.
         lockfile
@@ -338,10 +905,147 @@ impl<State> CoreEnvironment<State> {
                 Path::new(&*PKGDB_BIN),
                 Some(out_link_path.as_ref()),
                 store_path,
+                &flox.remote_builders,
             )
             .map_err(CoreEnvironmentError::LockedManifest)?;
         Ok(())
     }
+
+    /// Check that the store paths the environment depends on
+    /// still exist in the local store and are not corrupted
+    ///
+    /// The store path `out_link` points to is checked including its closure.
+    /// Packages locked for the current system are checked by their outputs,
+    /// which, unlike their derivations, are needed to use the environment.
+    /// Lockfiles created by pkgdb don't record store paths,
+    /// so only the out-link is checked for them.
+    ///
+    /// If `repair` is set, corrupted paths are repaired,
+    /// and if anything is missing the environment is rebuilt and linked to `out_link`.
+    /// The returned [StoreVerification] describes the environment before repairing.
+    pub fn verify(
+        &mut self,
+        flox: &Flox,
+        out_link: Option<&Path>,
+        repair: bool,
+    ) -> Result<StoreVerification, CoreEnvironmentError> {
+        let lockfile_path = CanonicalPath::new(self.lockfile_path())
+            .map_err(CoreEnvironmentError::BadLockfilePath)?;
+        let lockfile = LockedManifest::read_from_file(&lockfile_path)
+            .map_err(CoreEnvironmentError::LockedManifest)?;
+
+        let mut verification = StoreVerification::default();
+        if let Some(out_link) = out_link {
+            match fs::read_link(out_link) {
+                Ok(store_path) => {
+                    let linked = verify_store_paths(&[store_path], true)
+                        .map_err(CoreEnvironmentError::VerifyStore)?;
+                    verification.missing.extend(linked.missing);
+                    verification.corrupted.extend(linked.corrupted);
+                },
+                // Nothing was linked yet, which is not a defect of the store
+                Err(_) => debug!("no out-link at {}", out_link.display()),
+            }
+        }
+        if let LockedManifest::Catalog(lockfile) = &lockfile {
+            let packages = verify_store_paths(&lockfile.store_paths(&flox.system), false)
+                .map_err(CoreEnvironmentError::VerifyStore)?;
+            verification.missing.extend(packages.missing);
+            for path in packages.corrupted {
+                if !verification.corrupted.contains(&path) {
+                    verification.corrupted.push(path);
+                }
+            }
+        }
+
+        if !repair || verification.is_valid() {
+            return Ok(verification);
+        }
+
+        repair_store_paths(&verification.corrupted).map_err(CoreEnvironmentError::VerifyStore)?;
+        if !verification.missing.is_empty() {
+            debug!(
+                "rebuilding environment to restore {} missing store path(s)",
+                verification.missing.len()
+            );
+            let store_path = self.build(flox)?;
+            if let Some(out_link) = out_link {
+                self.link(flox, out_link, &Some(store_path))?;
+            }
+        }
+
+        Ok(verification)
+    }
+
+    /// The realized output store paths of each package of the environment
+    /// for the current system, by install id and output name
+    ///
+    /// Unlike the store path of the environment,
+    /// which links all packages together,
+    /// these point to the individual packages,
+    /// e.g. to configure the toolchain paths of an IDE.
+    /// Outputs that are not in the local store are left out,
+    /// so after [Self::build] all outputs needed by the environment are included.
+    /// Lockfiles created by pkgdb don't record store paths,
+    /// so only environments locked with the catalog are supported.
+    pub fn package_store_paths(
+        &self,
+        flox: &Flox,
+    ) -> Result<BTreeMap<String, BTreeMap<String, PathBuf>>, CoreEnvironmentError> {
+        let lockfile_path = CanonicalPath::new(self.lockfile_path())
+            .map_err(CoreEnvironmentError::BadLockfilePath)?;
+        let LockedManifest::Catalog(lockfile) = LockedManifest::read_from_file(&lockfile_path)
+            .map_err(CoreEnvironmentError::LockedManifest)?
+        else {
+            return Err(CoreEnvironmentError::PackageStorePathsRequireCatalog);
+        };
+
+        let mut package_outputs = lockfile.package_outputs(&flox.system);
+        for outputs in package_outputs.values_mut() {
+            outputs.retain(|_, path| path.exists());
+        }
+        Ok(package_outputs)
+    }
+
+    /// Rebuild the packages of the environment locked for the current system
+    /// without substitutes and compare them to the previously built outputs
+    ///
+    /// Only the packages are rebuilt,
+    /// the environment itself only links their outputs together.
+    /// Packages whose outputs are not in the local store can't be compared,
+    /// build the environment first to check all of them.
+    /// Lockfiles created by pkgdb don't record derivations,
+    /// so only environments locked with the catalog can be checked.
+    pub fn verify_reproducible(
+        &self,
+        flox: &Flox,
+    ) -> Result<ReproducibilityReport, CoreEnvironmentError> {
+        let lockfile_path = CanonicalPath::new(self.lockfile_path())
+            .map_err(CoreEnvironmentError::BadLockfilePath)?;
+        let LockedManifest::Catalog(lockfile) = LockedManifest::read_from_file(&lockfile_path)
+            .map_err(CoreEnvironmentError::LockedManifest)?
+        else {
+            return Err(CoreEnvironmentError::ReproducibilityRequiresCatalog);
+        };
+
+        let derivations = lockfile.derivations(&flox.system);
+        debug!(
+            "checking reproducibility of {} derivation(s)",
+            derivations.len()
+        );
+        rebuild_and_compare(&derivations).map_err(CoreEnvironmentError::Reproducibility)
+    }
+
+    /// Find common problems of the environment,
+    /// e.g. a lockfile that is out of date with the manifest
+    ///
+    /// If `out_link` is given, it is checked to exist and be up to date.
+    /// The environment is not modified,
+    /// each problem comes with a remediation that the caller can apply.
+    /// See [doctor::diagnose].
+    pub fn diagnose(&self, flox: &Flox, out_link: Option<&Path>) -> DoctorReport {
+        doctor::diagnose(&self.env_dir, &flox.system, out_link)
+    }
 }
 
 /// Environment modifying methods do not link the new environment to an out path.
@@ -349,7 +1053,7 @@ impl<State> CoreEnvironment<State> {
 /// Since files referenced by the environment are ingested into the nix store,
 /// the same [CoreEnvironment] instance can be used
 /// even if the concrete [super::Environment] tracks the files in a different way
-/// such as a git repository or a database.
+/// such as a git repository or a database, see [EnvironmentStore].
 impl CoreEnvironment<ReadOnly> {
     /// Create a new environment view for the given directory
     ///
@@ -357,10 +1061,68 @@ impl CoreEnvironment<ReadOnly> {
     pub fn new(env_dir: impl AsRef<Path>) -> Self {
         CoreEnvironment {
             env_dir: env_dir.as_ref().to_path_buf(),
+            origin_dir: env_dir.as_ref().to_path_buf(),
+            local_generations: false,
             _state: ReadOnly {},
         }
     }
 
+    /// Record [Self::generations] for transactions on this environment
+    ///
+    /// Only path environments keep local generations,
+    /// managed environments track their generations in floxmeta.
+    pub(super) fn with_local_generations(mut self) -> Self {
+        self.local_generations = true;
+        self
+    }
+
+    /// Move `dir`, the environment directory or a directory containing it, to `new_dir`
+    ///
+    /// The transaction lock is held while moving,
+    /// so that no transaction replaces the environment at its old location.
+    /// `dir` is renamed if possible, or copied if `new_dir` is on another filesystem.
+    pub fn relocate(
+        &mut self,
+        dir: impl AsRef<Path>,
+        new_dir: impl AsRef<Path>,
+    ) -> Result<(), CoreEnvironmentError> {
+        let (dir, new_dir) = (dir.as_ref(), new_dir.as_ref());
+        let relocated = |path: &Path| -> Option<PathBuf> {
+            let relative = path.strip_prefix(dir).ok()?;
+            Some(if relative.as_os_str().is_empty() {
+                new_dir.to_path_buf()
+            } else {
+                new_dir.join(relative)
+            })
+        };
+        let env_dir = relocated(&self.env_dir).ok_or_else(|| {
+            CoreEnvironmentError::Relocate(
+                new_dir.to_path_buf(),
+                std::io::ErrorKind::InvalidInput.into(),
+            )
+        })?;
+        if new_dir.exists() {
+            return Err(CoreEnvironmentError::RelocateTargetExists(
+                new_dir.to_path_buf(),
+            ));
+        }
+
+        let _lock = self.lock_transaction()?;
+        debug!(
+            "relocating environment from {} to {}",
+            dir.display(),
+            new_dir.display()
+        );
+        super::move_dir(dir, new_dir)
+            .map_err(|e| CoreEnvironmentError::Relocate(new_dir.to_path_buf(), e))?;
+
+        if let Some(origin_dir) = relocated(&self.origin_dir) {
+            self.origin_dir = origin_dir;
+        }
+        self.env_dir = env_dir;
+        Ok(())
+    }
+
     /// Install packages to the environment atomically
     ///
     /// Returns the new manifest content if the environment was modified. Also
@@ -372,39 +1134,144 @@ impl CoreEnvironment<ReadOnly> {
         packages: &[PackageToInstall],
         flox: &Flox,
     ) -> Result<InstallationAttempt, CoreEnvironmentError> {
-        let current_manifest_contents = self.manifest_content()?;
-        let mut installation = insert_packages(&current_manifest_contents, packages)
-            .map(|insertion| InstallationAttempt {
-                new_manifest: insertion.new_toml.map(|toml| toml.to_string()),
-                already_installed: insertion.already_installed,
-                store_path: None,
+        flox.events
+            .record(Operation::Install, CoreEnvironmentError::category, || {
+                let current_manifest_contents = self.manifest_content()?;
+                let description = format!(
+                    "installed packages: {}",
+                    packages
+                        .iter()
+                        .map(|package| package.id.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                let mut installation = insert_packages(&current_manifest_contents, packages)
+                    .map(|insertion| InstallationAttempt {
+                        new_manifest: insertion.new_toml.map(|toml| toml.to_string()),
+                        already_installed: insertion.already_installed,
+                        store_path: None,
+                    })
+                    .map_err(CoreEnvironmentError::ModifyToml)?;
+                if let Some(ref new_manifest) = installation.new_manifest {
+                    let store_path =
+                        self.transact_with_manifest_contents(new_manifest, flox, description)?;
+                    installation.store_path = Some(store_path);
+                }
+                Ok(installation)
             })
+    }
+
+    /// Resolve the packages that [Self::install] would add,
+    /// without building or modifying the environment
+    ///
+    /// Only manifests locked with the catalog are supported.
+    pub fn install_dry_run(
+        &self,
+        packages: &[PackageToInstall],
+        flox: &Flox,
+    ) -> Result<InstallDryRun, CoreEnvironmentError> {
+        let current_manifest_contents = self.manifest_content()?;
+        let insertion = insert_packages(&current_manifest_contents, packages)
             .map_err(CoreEnvironmentError::ModifyToml)?;
-        if let Some(ref new_manifest) = installation.new_manifest {
-            let store_path = self.transact_with_manifest_contents(new_manifest, flox)?;
-            installation.store_path = Some(store_path);
+        let Some(new_toml) = insertion.new_toml else {
+            return Ok(InstallDryRun {
+                new_manifest: None,
+                already_installed: insertion.already_installed,
+                added: vec![],
+                closure_sizes: BTreeMap::new(),
+            });
+        };
+        let new_manifest = new_toml.to_string();
+
+        let manifest: TypedManifest =
+            toml::from_str(&new_manifest).map_err(CoreEnvironmentError::DeserializeManifest)?;
+        let TypedManifest::Catalog(manifest) = manifest else {
+            return Err(CoreEnvironmentError::DryRunRequiresCatalog);
+        };
+        let client = flox
+            .catalog_client
+            .as_ref()
+            .ok_or(CoreEnvironmentError::CatalogClientMissing)?;
+        let client = CachedResolutionClient::new(client, &flox.resolution_cache)
+            .with_snapshot(flox.catalog_snapshot(), flox.offline);
+
+        let previous_packages = self
+            .existing_catalog_lockfile()?
+            .map(|lockfile| lockfile.packages)
+            .unwrap_or_default();
+        let lockfile = self.lock_with_catalog_client(&client, *manifest, &flox.progress)?;
+        let added: Vec<LockedPackageCatalog> = lockfile
+            .packages
+            .into_iter()
+            .filter(|pkg| {
+                !previous_packages
+                    .iter()
+                    .any(|prev| prev.install_id == pkg.install_id && prev.system == pkg.system)
+            })
+            .collect();
+        let closure_sizes = added
+            .iter()
+            .filter(|pkg| pkg.system == flox.system)
+            .map(|pkg| (pkg.install_id.clone(), Self::package_closure_size(pkg)))
+            .collect();
+
+        Ok(InstallDryRun {
+            new_manifest: Some(new_manifest),
+            already_installed: insertion.already_installed,
+            added,
+            closure_sizes,
+        })
+    }
+
+    /// The size of the closure of the outputs of `package`,
+    /// `None` if its outputs are unknown or not in the local store
+    fn package_closure_size(package: &LockedPackageCatalog) -> Option<u64> {
+        let outputs = package.outputs.as_ref()?.values().collect::<Vec<_>>();
+        if outputs.is_empty() {
+            return None;
+        }
+        match out_links::closure_size(&outputs) {
+            Ok(size) => size,
+            Err(err) => {
+                debug!(
+                    "couldn't query the closure size of {}: {err}",
+                    package.install_id
+                );
+                None
+            },
         }
-        Ok(installation)
     }
 
     /// Uninstall packages from the environment atomically
     ///
-    /// Returns true if the environment was modified and false otherwise.
-    /// TODO: this should return a list of packages that were actually
-    /// uninstalled rather than a bool.
+    /// Returns the outcome for each requested package.
+    /// Fails if none of the requested packages are installed.
     pub fn uninstall(
         &mut self,
         packages: Vec<String>,
         flox: &Flox,
     ) -> Result<UninstallationAttempt, CoreEnvironmentError> {
-        let current_manifest_contents = self.manifest_content()?;
-        let toml = remove_packages(&current_manifest_contents, &packages)
-            .map_err(CoreEnvironmentError::ModifyToml)?;
-        let store_path = self.transact_with_manifest_contents(toml.to_string(), flox)?;
-        Ok(UninstallationAttempt {
-            new_manifest: Some(toml.to_string()),
-            store_path: Some(store_path),
-        })
+        flox.events
+            .record(Operation::Uninstall, CoreEnvironmentError::category, || {
+                let current_manifest_contents = self.manifest_content()?;
+                let removal = remove_packages(&current_manifest_contents, &packages)
+                    .map_err(CoreEnvironmentError::ModifyToml)?;
+                let removed = removal
+                    .results
+                    .iter()
+                    .filter(|(_, result)| **result != UninstallResult::NotFound)
+                    .map(|(install_id, _)| install_id.as_str())
+                    .collect::<Vec<_>>();
+                let description = format!("uninstalled packages: {}", removed.join(", "));
+                let new_manifest = removal.new_toml.to_string();
+                let store_path =
+                    self.transact_with_manifest_contents(&new_manifest, flox, description)?;
+                Ok(UninstallationAttempt {
+                    new_manifest: Some(new_manifest),
+                    results: removal.results,
+                    store_path: Some(store_path),
+                })
+            })
     }
 
     /// Atomically edit this environment, ensuring that it still builds
@@ -422,11 +1289,190 @@ impl CoreEnvironment<ReadOnly> {
             return Ok(EditResult::Unchanged);
         }
 
-        let store_path = self.transact_with_manifest_contents(&contents, flox)?;
+        let store_path =
+            self.transact_with_manifest_contents(&contents, flox, "manually edited".to_string())?;
+
+        EditResult::new(&old_contents, &contents, Some(store_path))
+    }
+
+    /// Atomically apply structured `edits` to the manifest of this environment,
+    /// ensuring that it still builds
+    ///
+    /// The edits are applied to the manifest as it is when the transaction starts,
+    /// so concurrent changes to other parts of the manifest are not overwritten.
+    pub fn edit_with_patch(
+        &mut self,
+        flox: &Flox,
+        edits: &[ManifestEdit],
+    ) -> Result<EditResult, CoreEnvironmentError> {
+        self.transact_with_edits(flox, edits, "edited manifest".to_string())
+    }
+
+    /// Atomically set variables in the `[vars]` table of the manifest,
+    /// ensuring that the environment still builds
+    pub fn set_vars(
+        &mut self,
+        flox: &Flox,
+        vars: &[(String, String)],
+    ) -> Result<EditResult, CoreEnvironmentError> {
+        vars.iter()
+            .fold(self.transaction(), |transaction, (name, value)| {
+                transaction.set_var(name, value)
+            })
+            .commit(flox)
+    }
+
+    /// Atomically remove variables from the `[vars]` table of the manifest,
+    /// ensuring that the environment still builds
+    ///
+    /// Variables that are not set are ignored.
+    pub fn unset_vars(
+        &mut self,
+        flox: &Flox,
+        names: &[String],
+    ) -> Result<EditResult, CoreEnvironmentError> {
+        names
+            .iter()
+            .fold(self.transaction(), |transaction, name| {
+                transaction.unset_var(name)
+            })
+            .commit(flox)
+    }
+
+    /// Batch several changes to this environment into a single transaction,
+    /// see [EnvironmentTransaction]
+    pub fn transaction(&mut self) -> EnvironmentTransaction<'_> {
+        EnvironmentTransaction {
+            env: self,
+            edits: vec![],
+            descriptions: vec![],
+        }
+    }
+
+    /// Apply `edits` to the current manifest,
+    /// then lock, build, and replace the environment once
+    fn transact_with_edits(
+        &mut self,
+        flox: &Flox,
+        edits: &[ManifestEdit],
+        description: String,
+    ) -> Result<EditResult, CoreEnvironmentError> {
+        let _lock = self.lock_transaction()?;
+        let old_contents = self.manifest_content()?;
+        let contents = apply_manifest_edits(&old_contents, edits)
+            .map_err(CoreEnvironmentError::ModifyToml)?
+            .to_string();
+
+        if contents == old_contents {
+            return Ok(EditResult::Unchanged);
+        }
+
+        let manifest_hash = blake3::hash(old_contents.as_bytes());
+        let store_path = self.transact_with_manifest_contents_locked(
+            &contents,
+            flox,
+            description,
+            &manifest_hash,
+        )?;
 
         EditResult::new(&old_contents, &contents, Some(store_path))
     }
 
+    /// Replace the manifest of this environment with the template `name`,
+    /// ensuring that the environment builds
+    ///
+    /// Templates are listed in [TEMPLATES](super::templates::TEMPLATES).
+    /// A version 1 manifest is rendered if a catalog client is configured.
+    #[must_use = "don't discard the store path of built environments"]
+    pub fn init_from_template(
+        &mut self,
+        flox: &Flox,
+        name: &str,
+    ) -> Result<PathBuf, CoreEnvironmentError> {
+        let template = find_template(name)
+            .ok_or_else(|| CoreEnvironmentError::TemplateNotFound(name.to_string()))?;
+        let contents = template
+            .render(flox.catalog_client.is_some())
+            .map_err(CoreEnvironmentError::ModifyToml)?;
+
+        self.transact_with_manifest_contents(
+            contents,
+            flox,
+            format!("initialized from template '{name}'"),
+        )
+    }
+
+    /// Atomically migrate a version 0 manifest, locked by pkgdb,
+    /// to a version 1 manifest that is locked with the catalog
+    ///
+    /// The manifest is translated by [migrate_manifest_to_catalog],
+    /// locked with the catalog without the pkgdb lockfile as a base, and built,
+    /// before the environment is replaced.
+    /// The catalog doesn't resolve packages from the inputs pinned by the pkgdb lockfile,
+    /// so packages whose version changed for the current system are reported.
+    pub fn migrate_to_catalog(
+        &mut self,
+        flox: &Flox,
+    ) -> Result<MigrationResult, CoreEnvironmentError> {
+        if flox.catalog_client.is_none() {
+            return Err(CoreEnvironmentError::CatalogClientMissing);
+        }
+
+        let _lock = self.lock_transaction()?;
+        let old_contents = self.manifest_content()?;
+        let manifest_hash = blake3::hash(old_contents.as_bytes());
+        let migrated = migrate_manifest_to_catalog(&old_contents)
+            .map_err(CoreEnvironmentError::MigrateManifest)?;
+
+        let old_packages = match CanonicalPath::new(self.lockfile_path()) {
+            Ok(lockfile_path) => match LockedManifest::read_from_file(&lockfile_path)
+                .map_err(CoreEnvironmentError::LockedManifest)?
+            {
+                LockedManifest::Pkgdb(lockfile) => TypedLockedManifestPkgdb::try_from(lockfile)
+                    .map_err(CoreEnvironmentError::LockedManifest)?
+                    .list_packages(&flox.system),
+                LockedManifest::Catalog(_) => vec![],
+            },
+            Err(_) => vec![],
+        };
+
+        let sandbox = self.make_sandbox(flox)?;
+        let tempdir = sandbox.path();
+        debug!(
+            "migration: making temporary environment in {}",
+            tempdir.display()
+        );
+        let mut temp_env = self.writable(tempdir)?;
+
+        debug!("migration: updating manifest");
+        temp_env.update_manifest(migrated.toml.to_string())?;
+        // The pkgdb lockfile can't be used as a base to lock with the catalog
+        temp_env.remove_lockfile()?;
+
+        debug!("migration: locking environment");
+        let new_packages = match temp_env.lock(flox)? {
+            LockedManifest::Catalog(lockfile) => lockfile.list_packages(&flox.system),
+            LockedManifest::Pkgdb(_) => {
+                unreachable!("version 1 manifests are locked with the catalog")
+            },
+        };
+
+        debug!("migration: building environment");
+        let store_path = temp_env.build(flox)?;
+
+        debug!("migration: replacing environment");
+        flox.progress.emit(ProgressEvent::Replacing);
+        self.ensure_manifest_unchanged(&manifest_hash)?;
+        self.replace_with(temp_env)?;
+        self.record_generation(&store_path, "migrated manifest to version 1".to_string());
+
+        Ok(MigrationResult {
+            store_path,
+            dropped: migrated.dropped,
+            changed_versions: MigrationResult::changed_versions(&old_packages, &new_packages),
+        })
+    }
+
     /// Atomically edit this environment, without checking that it still builds
     ///
     /// This is unsafe as it can create broken environments!
@@ -446,15 +1492,16 @@ impl CoreEnvironment<ReadOnly> {
             return Ok(Ok(EditResult::Unchanged));
         }
 
-        let tempdir = tempfile::tempdir_in(&flox.temp_dir)
-            .map_err(CoreEnvironmentError::MakeSandbox)?
-            .into_path();
+        let _lock = self.lock_transaction()?;
+        let manifest_hash = self.manifest_hash()?;
+        let sandbox = self.make_sandbox(flox)?;
+        let tempdir = sandbox.path();
 
         debug!(
             "transaction: making temporary environment in {}",
             tempdir.display()
         );
-        let mut temp_env = self.writable(&tempdir)?;
+        let mut temp_env = self.writable(tempdir)?;
 
         debug!("transaction: updating manifest");
         temp_env.update_manifest(&contents)?;
@@ -464,6 +1511,7 @@ impl CoreEnvironment<ReadOnly> {
         if let Err(lock_err) = temp_env.lock(flox) {
             debug!("transaction: lock failed: {:?}", lock_err);
             debug!("transaction: replacing environment");
+            self.ensure_manifest_unchanged(&manifest_hash)?;
             self.replace_with(temp_env)?;
             return Ok(Err(lock_err));
         };
@@ -471,6 +1519,7 @@ impl CoreEnvironment<ReadOnly> {
         let build_attempt = temp_env.build(flox);
 
         debug!("transaction: replacing environment");
+        self.ensure_manifest_unchanged(&manifest_hash)?;
         self.replace_with(temp_env)?;
 
         match build_attempt {
@@ -501,6 +1550,7 @@ impl CoreEnvironment<ReadOnly> {
         let store_path = self.transact_with_lockfile_contents(
             serde_json::to_string_pretty(&new_lockfile).unwrap(),
             flox,
+            "updated environment".to_string(),
         )?;
 
         Ok(UpdateResult {
@@ -515,44 +1565,172 @@ impl CoreEnvironment<ReadOnly> {
     /// First resolve a new lockfile with upgraded packages using either pkgdb or the catalog client.
     /// Then verify the new lockfile by building the environment.
     /// Finally replace the existing environment with the new, upgraded one.
+    ///
+    /// The transaction lock is held while resolving,
+    /// so that the upgrade fails if the manifest it was resolved for is modified.
     pub fn upgrade(
         &mut self,
         flox: &Flox,
         groups_or_iids: &[String],
     ) -> Result<UpgradeResult, CoreEnvironmentError> {
+        flox.events
+            .record(Operation::Upgrade, CoreEnvironmentError::category, || {
+                let _lock = self.lock_transaction()?;
+                let manifest_contents = self.manifest_content()?;
+                let manifest_hash = blake3::hash(manifest_contents.as_bytes());
+                let manifest = toml::from_str(&manifest_contents)
+                    .map_err(CoreEnvironmentError::DeserializeManifest)?;
+
+                let (lockfile, upgraded) = match manifest {
+                    TypedManifest::Pkgdb(_) => {
+                        if groups_or_iids
+                            .iter()
+                            .any(|group_or_iid| group_or_iid.contains('@'))
+                        {
+                            return Err(CoreEnvironmentError::UpgradeConstraintRequiresCatalog);
+                        }
+                        let (lockfile, upgraded) = self.upgrade_with_pkgdb(flox, groups_or_iids)?;
+                        (LockedManifest::Pkgdb(lockfile), upgraded)
+                    },
+                    TypedManifest::Catalog(catalog) => {
+                        let client = flox
+                            .catalog_client
+                            .as_ref()
+                            .ok_or(CoreEnvironmentError::CatalogClientMissing)?;
+                        let client = CachedResolutionClient::new(client, &flox.resolution_cache)
+                            .with_snapshot(flox.catalog_snapshot(), flox.offline);
+
+                        let (lockfile, upgraded) =
+                            self.upgrade_with_catalog_client(&client, groups_or_iids, &catalog)?;
+                        // Prefetched resolutions predate the upgrade,
+                        // later locks must not go back to them
+                        flox.resolution_cache.clear();
+
+                        let upgraded = upgraded
+                            .into_iter()
+                            .map(|(_, pkg)| pkg.install_id.clone())
+                            .collect();
+
+                        (LockedManifest::Catalog(Box::new(lockfile)), upgraded)
+                    },
+                };
+
+                let description = format!("upgraded packages: {}", upgraded.join(", "));
+                let store_path = self.transact_with_lockfile_contents_locked(
+                    serde_json::json!(&lockfile).to_string(),
+                    flox,
+                    description,
+                    &manifest_hash,
+                )?;
+
+                Ok(UpgradeResult {
+                    packages: upgraded,
+                    store_path: Some(store_path),
+                })
+            })
+    }
+
+    /// Resolve the packages that [Self::upgrade] would change,
+    /// without building or modifying the environment
+    ///
+    /// Returns the previously locked and the upgraded package
+    /// for every package whose derivation would change.
+    /// Only manifests locked with the catalog are supported.
+    pub fn upgrade_dry_run(
+        &self,
+        flox: &Flox,
+        groups_or_iids: &[String],
+    ) -> Result<Vec<(LockedPackageCatalog, LockedPackageCatalog)>, CoreEnvironmentError> {
         let manifest = toml::from_str(&self.manifest_content()?)
             .map_err(CoreEnvironmentError::DeserializeManifest)?;
+        let TypedManifest::Catalog(manifest) = manifest else {
+            return Err(CoreEnvironmentError::DryRunRequiresCatalog);
+        };
+        let client = flox
+            .catalog_client
+            .as_ref()
+            .ok_or(CoreEnvironmentError::CatalogClientMissing)?;
+        let client = CachedResolutionClient::new(client, &flox.resolution_cache)
+            .with_snapshot(flox.catalog_snapshot(), flox.offline);
 
-        let (lockfile, upgraded) = match manifest {
-            TypedManifest::Pkgdb(_) => {
-                let (lockfile, upgraded) = self.upgrade_with_pkgdb(flox, groups_or_iids)?;
-                (LockedManifest::Pkgdb(lockfile), upgraded)
-            },
-            TypedManifest::Catalog(catalog) => {
-                let client = flox
-                    .catalog_client
-                    .as_ref()
-                    .ok_or(CoreEnvironmentError::CatalogClientMissing)?;
+        let (_, upgraded) = self.upgrade_with_catalog_client(&client, groups_or_iids, &manifest)?;
+        Ok(upgraded)
+    }
 
-                let (lockfile, upgraded) =
-                    self.upgrade_with_catalog_client(client, groups_or_iids, &catalog)?;
+    /// Atomically roll back this environment to a recorded generation
+    ///
+    /// The manifest and lockfile of the generation are restored and built
+    /// before the environment is replaced,
+    /// and the generation is set as the current generation.
+    pub fn rollback(
+        &mut self,
+        flox: &Flox,
+        generation: usize,
+    ) -> Result<PathBuf, CoreEnvironmentError> {
+        let generations = self.generations();
+        let manifest_contents = generations
+            .manifest(generation)
+            .map_err(CoreEnvironmentError::Generations)?;
+        let lockfile_contents = generations
+            .lockfile(generation)
+            .map_err(CoreEnvironmentError::Generations)?;
+
+        debug!("rollback: restoring generation {generation}");
+        let store_path = self.replace_contents(flox, &manifest_contents, lockfile_contents)?;
+        generations
+            .set_current_generation(generation)
+            .map_err(CoreEnvironmentError::Generations)?;
+        Ok(store_path)
+    }
 
-                let upgraded = upgraded
-                    .into_iter()
-                    .map(|(_, pkg)| pkg.install_id.clone())
-                    .collect();
+    /// Atomically restore the manifest and lockfile of the snapshot `name`
+    ///
+    /// The restored environment is built before the environment is replaced,
+    /// and recorded as a new generation.
+    pub fn restore(&mut self, flox: &Flox, name: &str) -> Result<PathBuf, CoreEnvironmentError> {
+        let snapshots = self.snapshots();
+        let manifest_contents = snapshots
+            .manifest(name)
+            .map_err(CoreEnvironmentError::Snapshots)?;
+        let lockfile_contents = snapshots
+            .lockfile(name)
+            .map_err(CoreEnvironmentError::Snapshots)?;
+
+        debug!("restore: restoring snapshot '{name}'");
+        let store_path = self.replace_contents(flox, &manifest_contents, lockfile_contents)?;
+        self.record_generation(&store_path, format!("restored snapshot '{name}'"));
+        Ok(store_path)
+    }
 
-                (LockedManifest::Catalog(lockfile), upgraded)
-            },
-        };
+    /// Replace the manifest and lockfile of the environment and build it,
+    /// removing the lockfile if `lockfile_contents` is `None`
+    fn replace_contents(
+        &mut self,
+        flox: &Flox,
+        manifest_contents: &str,
+        lockfile_contents: Option<String>,
+    ) -> Result<PathBuf, CoreEnvironmentError> {
+        let _lock = self.lock_transaction()?;
+        let manifest_hash = self.manifest_hash()?;
+        let sandbox = self.make_sandbox(flox)?;
+        let tempdir = sandbox.path();
+
+        debug!("making temporary environment in {}", tempdir.display());
+        let mut temp_env = self.writable(tempdir)?;
+
+        temp_env.update_manifest(manifest_contents)?;
+        match lockfile_contents {
+            Some(lockfile_contents) => temp_env.update_lockfile(lockfile_contents)?,
+            None => temp_env.remove_lockfile()?,
+        }
 
-        let store_path =
-            self.transact_with_lockfile_contents(serde_json::json!(&lockfile).to_string(), flox)?;
+        debug!("building environment");
+        let store_path = temp_env.build(flox)?;
 
-        Ok(UpgradeResult {
-            packages: upgraded,
-            store_path: Some(store_path),
-        })
+        debug!("replacing environment");
+        self.ensure_manifest_unchanged(&manifest_hash)?;
+        self.replace_with(temp_env)?;
+        Ok(store_path)
     }
 
     fn upgrade_with_pkgdb(
@@ -600,8 +1778,13 @@ impl CoreEnvironment<ReadOnly> {
     /// The environment is upgraded by locking the existing manifest
     /// using [LockedManifestCatalog::lock_manifest] with the existing lockfile as a seed,
     /// where the upgraded packages have been filtered out causing them to be re-resolved.
+    ///
+    /// Install ids may carry a version constraint, e.g. `nodejs@^20`,
+    /// to re-resolve the package only within that range.
+    /// The constraint replaces the `version` of the package for this resolution only,
+    /// the manifest is not modified.
     fn upgrade_with_catalog_client(
-        &mut self,
+        &self,
         client: &impl ClientTrait,
         groups_or_iids: &[String],
         manifest: &TypedManifestCatalog,
@@ -612,34 +1795,58 @@ impl CoreEnvironment<ReadOnly> {
         ),
         CoreEnvironmentError,
     > {
-        let existing_lockfile = 'lockfile: {
-            let Ok(lockfile_path) = CanonicalPath::new(self.lockfile_path()) else {
-                break 'lockfile None;
+        let (groups_or_iids, version_constraints) = split_version_constraints(groups_or_iids)?;
+        let groups_or_iids = groups_or_iids.as_slice();
+        for install_id in version_constraints.keys() {
+            let Some(descriptor) = manifest
+                .install
+                .get(install_id)
+                .and_then(|descriptor| descriptor.as_catalog_descriptor_ref())
+            else {
+                return Err(CoreEnvironmentError::UpgradeConstraintPackageNotFound(
+                    install_id.clone(),
+                ));
             };
-            let lockfile = LockedManifest::read_from_file(&lockfile_path)
-                .map_err(CoreEnvironmentError::LockedManifest)?;
-            match lockfile {
-                LockedManifest::Catalog(lockfile) => Some(lockfile),
-                _ => {
-                    warn!(
-                        "Found version 1 manifest, but lockfile doesn't match: Ignoring lockfile."
-                    );
-                    None
-                },
+            // A constraint replaces the version of the manifest while resolving,
+            // so it could resolve a version that the manifest doesn't allow
+            if let Some(version) = &descriptor.version {
+                return Err(
+                    CoreEnvironmentError::UpgradeConstraintConflictsWithManifest(
+                        install_id.clone(),
+                        version.clone(),
+                    ),
+                );
             }
-        };
+        }
+
+        let existing_lockfile = self.existing_catalog_lockfile()?;
 
         let previous_packages = existing_lockfile
             .as_ref()
             .map(|lockfile| lockfile.packages.clone())
             .unwrap_or_default();
 
+        let has_pinned_packages = manifest.install.values().any(|descriptor| {
+            descriptor
+                .as_catalog_descriptor_ref()
+                .is_some_and(|descriptor| descriptor.pinned)
+        });
+
         // Create a seed lockfile by "unlocking" (i.e. removing the locked entries of)
         // all packages matching the given groups or iids.
-        // If no groups or iids are provided, all packages are unlocked.
-        let seed_lockfile = if groups_or_iids.is_empty() {
+        // If no groups or iids are provided, all packages but the pinned ones are unlocked.
+        // Packages that are named explicitly are upgraded even if they are pinned.
+        let seed_lockfile = if groups_or_iids.is_empty() && !has_pinned_packages {
             debug!("no groups or iids provided, unlocking all packages");
             None
+        } else if groups_or_iids.is_empty() {
+            debug!("no groups or iids provided, unlocking all unpinned packages");
+            existing_lockfile.map(|mut lockfile| {
+                lockfile.unlock_unpinned_packages(manifest);
+                // Fetch remote includes again as if all packages were upgraded
+                lockfile.includes.clear();
+                lockfile
+            })
         } else {
             existing_lockfile.map(|mut lockfile| {
                 lockfile.unlock_packages_by_group_or_iid(groups_or_iids);
@@ -647,10 +1854,32 @@ impl CoreEnvironment<ReadOnly> {
             })
         };
 
-        let upgraded =
-            LockedManifestCatalog::lock_manifest(manifest, seed_lockfile.as_ref(), client)
+        // Remote includes are fetched again if all packages are upgraded
+        let (mut manifest, includes) = self.resolve_includes(manifest, seed_lockfile.as_ref())?;
+        let mut original_versions = BTreeMap::new();
+        for (install_id, version) in &version_constraints {
+            if let Some(ManifestPackageDescriptor::Catalog(descriptor)) =
+                manifest.install.get_mut(install_id)
+            {
+                debug!("resolving '{install_id}' with version constraint '{version}'");
+                let original = descriptor.version.replace(version.clone());
+                original_versions.insert(install_id.clone(), original);
+            }
+        }
+        let mut upgraded =
+            LockedManifestCatalog::lock_manifest(&manifest, seed_lockfile.as_ref(), client)
                 .block_on()
                 .map_err(CoreEnvironmentError::LockedManifest)?;
+        upgraded.includes = includes;
+        // Record the descriptors of the manifest rather than the constraints,
+        // so that the next lock doesn't consider the constrained packages changed
+        for (install_id, version) in original_versions {
+            if let Some(ManifestPackageDescriptor::Catalog(descriptor)) =
+                upgraded.manifest.install.get_mut(&install_id)
+            {
+                descriptor.version = version;
+            }
+        }
 
         // find all packages that after upgrading have a different derivation
         let package_diff = upgraded
@@ -669,17 +1898,88 @@ impl CoreEnvironment<ReadOnly> {
         Ok((upgraded, package_diff))
     }
 
+    /// Create a directory for a transaction to prepare a copy of the environment in
+    ///
+    /// Sandboxes are created in [Flox::transaction_dir] if it is set.
+    /// Otherwise they are created in [Flox::temp_dir]
+    /// if it is on the same filesystem as the environment,
+    /// or else next to the environment,
+    /// so that [Self::writable] can share files with the environment
+    /// rather than copying them across filesystems.
+    fn make_sandbox(&self, flox: &Flox) -> Result<Sandbox, CoreEnvironmentError> {
+        let env_parent = self.env_dir.parent().unwrap_or(Path::new("."));
+        let (parent, in_temp_dir) = match &flox.transaction_dir {
+            Some(transaction_dir) => {
+                fs::create_dir_all(transaction_dir).map_err(CoreEnvironmentError::MakeSandbox)?;
+                (transaction_dir.as_path(), false)
+            },
+            None if same_filesystem(&flox.temp_dir, env_parent) => (flox.temp_dir.as_path(), true),
+            None => {
+                debug!(
+                    "{} is on a different filesystem than {}, preparing transaction next to the environment",
+                    flox.temp_dir.display(),
+                    self.env_dir.display()
+                );
+                (env_parent, false)
+            },
+        };
+
+        let tempdir = tempfile::Builder::new()
+            .prefix(".flox-transaction-")
+            .tempdir_in(parent)
+            .map_err(CoreEnvironmentError::MakeSandbox)?;
+        // Sandboxes in the temporary directory are cleaned up with it,
+        // which keeps them around for debugging,
+        // all others must not be left behind
+        Ok(if in_temp_dir {
+            Sandbox {
+                path: tempdir.into_path(),
+                _cleanup: None,
+            }
+        } else {
+            Sandbox {
+                path: tempdir.path().to_path_buf(),
+                _cleanup: Some(tempdir),
+            }
+        })
+    }
+
+    /// Check the syntax of the hook and profile scripts
+    /// that `manifest_contents` changes compared to the current manifest
+    ///
+    /// See [check_changed_scripts].
+    fn check_hooks(
+        &self,
+        flox: &Flox,
+        manifest_contents: &str,
+        work_dir: &Path,
+    ) -> Result<(), CoreEnvironmentError> {
+        let syntax_errors = check_changed_scripts(
+            &self.manifest_content()?,
+            manifest_contents,
+            flox.hook_check_shell.as_deref(),
+            work_dir,
+        )
+        .map_err(CoreEnvironmentError::HookCheck)?;
+        if !syntax_errors.is_empty() {
+            return Err(CoreEnvironmentError::HookSyntax(syntax_errors));
+        }
+        Ok(())
+    }
+
     /// Makes a temporary copy of the environment so modifications to the manifest
     /// can be applied without modifying the original environment.
     fn writable(
         &mut self,
         tempdir: impl AsRef<Path>,
     ) -> Result<CoreEnvironment<ReadWrite>, CoreEnvironmentError> {
-        copy_dir_recursive(&self.env_dir, &tempdir.as_ref(), true)
+        clone_dir_recursive(&self.env_dir, &tempdir.as_ref())
             .map_err(CoreEnvironmentError::MakeTemporaryEnv)?;
 
         Ok(CoreEnvironment {
             env_dir: tempdir.as_ref().to_path_buf(),
+            origin_dir: self.origin_dir.clone(),
+            local_generations: self.local_generations,
             _state: ReadWrite {},
         })
     }
@@ -692,43 +1992,7 @@ impl CoreEnvironment<ReadOnly> {
         &mut self,
         replacement: CoreEnvironment<ReadWrite>,
     ) -> Result<(), CoreEnvironmentError> {
-        let transaction_backup = self.env_dir.with_extension("tmp");
-
-        if transaction_backup.exists() {
-            debug!(
-                "transaction backup exists: {}",
-                transaction_backup.display()
-            );
-            return Err(CoreEnvironmentError::PriorTransaction(transaction_backup));
-        }
-        debug!(
-            "backing up env: from={}, to={}",
-            self.env_dir.display(),
-            transaction_backup.display()
-        );
-        fs::rename(&self.env_dir, &transaction_backup)
-            .map_err(CoreEnvironmentError::BackupTransaction)?;
-        // try to restore the backup if the move fails
-        debug!(
-            "replacing original env: from={}, to={}",
-            replacement.env_dir.display(),
-            self.env_dir.display()
-        );
-        if let Err(err) = copy_dir_recursive(&replacement.env_dir, &self.env_dir, true) {
-            debug!(
-                "failed to replace env ({}), restoring backup: from={}, to={}",
-                err,
-                transaction_backup.display(),
-                self.env_dir.display(),
-            );
-            fs::remove_dir_all(&self.env_dir).map_err(CoreEnvironmentError::AbortTransaction)?;
-            fs::rename(transaction_backup, &self.env_dir)
-                .map_err(CoreEnvironmentError::AbortTransaction)?;
-            return Err(CoreEnvironmentError::Move(err));
-        }
-        debug!("removing backup: path={}", transaction_backup.display());
-        fs::remove_dir_all(transaction_backup).map_err(CoreEnvironmentError::RemoveBackup)?;
-        Ok(())
+        self.store().replace_with_dir(&replacement.env_dir)
     }
 
     /// Attempt to transactionally replace the manifest contents
@@ -737,20 +2001,45 @@ impl CoreEnvironment<ReadOnly> {
         &mut self,
         manifest_contents: impl AsRef<str>,
         flox: &Flox,
+        description: String,
+    ) -> Result<PathBuf, CoreEnvironmentError> {
+        let _lock = self.lock_transaction()?;
+        let manifest_hash = self.manifest_hash()?;
+        self.transact_with_manifest_contents_locked(
+            manifest_contents,
+            flox,
+            description,
+            &manifest_hash,
+        )
+    }
+
+    /// Attempt to transactionally replace the manifest contents,
+    /// while the transaction lock is already held by the caller
+    ///
+    /// Fails if the manifest no longer hashes to `manifest_hash`
+    /// when the environment is replaced.
+    fn transact_with_manifest_contents_locked(
+        &mut self,
+        manifest_contents: impl AsRef<str>,
+        flox: &Flox,
+        description: String,
+        manifest_hash: &blake3::Hash,
     ) -> Result<PathBuf, CoreEnvironmentError> {
-        let tempdir = tempfile::tempdir_in(&flox.temp_dir)
-            .map_err(CoreEnvironmentError::MakeSandbox)?
-            .into_path();
+        let sandbox = self.make_sandbox(flox)?;
+        let tempdir = sandbox.path();
 
         debug!(
             "transaction: making temporary environment in {}",
             tempdir.display()
         );
-        let mut temp_env = self.writable(&tempdir)?;
+        let mut temp_env = self.writable(tempdir)?;
 
         debug!("transaction: updating manifest");
         temp_env.update_manifest(&manifest_contents)?;
 
+        debug!("transaction: checking hook scripts");
+        self.check_hooks(flox, manifest_contents.as_ref(), tempdir)?;
+
         debug!("transaction: locking environment");
         temp_env.lock(flox)?;
 
@@ -758,7 +2047,10 @@ impl CoreEnvironment<ReadOnly> {
         let store_path = temp_env.build(flox)?;
 
         debug!("transaction: replacing environment");
+        flox.progress.emit(ProgressEvent::Replacing);
+        self.ensure_manifest_unchanged(manifest_hash)?;
         self.replace_with(temp_env)?;
+        self.record_generation(&store_path, description);
         Ok(store_path)
     }
 
@@ -776,16 +2068,39 @@ impl CoreEnvironment<ReadOnly> {
         &mut self,
         lockfile_contents: impl AsRef<str>,
         flox: &Flox,
+        description: String,
     ) -> Result<PathBuf, CoreEnvironmentError> {
-        let tempdir = tempfile::tempdir_in(&flox.temp_dir)
-            .map_err(CoreEnvironmentError::MakeSandbox)?
-            .into_path();
+        let _lock = self.lock_transaction()?;
+        let manifest_hash = self.manifest_hash()?;
+        self.transact_with_lockfile_contents_locked(
+            lockfile_contents,
+            flox,
+            description,
+            &manifest_hash,
+        )
+    }
+
+    /// Attempt to transactionally replace the lockfile contents,
+    /// while the transaction lock is already held by the caller
+    ///
+    /// Fails if the manifest no longer hashes to `manifest_hash`
+    /// when the environment is replaced.
+    #[must_use = "don't discard the store path of built environments"]
+    fn transact_with_lockfile_contents_locked(
+        &mut self,
+        lockfile_contents: impl AsRef<str>,
+        flox: &Flox,
+        description: String,
+        manifest_hash: &blake3::Hash,
+    ) -> Result<PathBuf, CoreEnvironmentError> {
+        let sandbox = self.make_sandbox(flox)?;
+        let tempdir = sandbox.path();
 
         debug!(
             "transaction: making temporary environment in {}",
             tempdir.display()
         );
-        let mut temp_env = self.writable(&tempdir)?;
+        let mut temp_env = self.writable(tempdir)?;
 
         debug!("transaction: updating lockfile");
         temp_env.update_lockfile(&lockfile_contents)?;
@@ -794,7 +2109,10 @@ impl CoreEnvironment<ReadOnly> {
         let store_path = temp_env.build(flox)?;
 
         debug!("transaction: replacing environment");
+        flox.progress.emit(ProgressEvent::Replacing);
+        self.ensure_manifest_unchanged(manifest_hash)?;
         self.replace_with(temp_env)?;
+        self.record_generation(&store_path, description);
         Ok(store_path)
     }
 }
@@ -825,53 +2143,619 @@ impl CoreEnvironment<ReadWrite> {
             .map_err(CoreEnvironmentError::WriteLockfile)?;
         Ok(())
     }
+
+    /// Removes the environment lockfile if it exists
+    fn remove_lockfile(&mut self) -> Result<(), CoreEnvironmentError> {
+        if self.lockfile_path().exists() {
+            debug!("removing lockfile {}", self.lockfile_path().display());
+            std::fs::remove_file(self.lockfile_path())
+                .map_err(CoreEnvironmentError::WriteLockfile)?;
+        }
+        Ok(())
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum EditResult {
-    /// The manifest was not modified.
-    Unchanged,
-    /// The manifest was modified, and the user needs to re-activate it.
-    ReActivateRequired { store_path: Option<PathBuf> },
-    /// The manifest was modified, but the user does not need to re-activate it.
-    Success { store_path: Option<PathBuf> },
+/// A batch of changes to a single environment,
+/// created with [CoreEnvironment::transaction]
+///
+/// Changes are applied to the manifest in the order they were added,
+/// and the environment is locked, built, and replaced only once on
+/// [EnvironmentTransaction::commit],
+/// rather than once per change as with [CoreEnvironment::install] et al.
+/// If any change fails to apply, or the result fails to build,
+/// the environment is not modified.
+#[must_use = "transactions do nothing unless committed"]
+pub struct EnvironmentTransaction<'a> {
+    env: &'a mut CoreEnvironment,
+    edits: Vec<ManifestEdit>,
+    /// Descriptions of the changes, recorded for the new generation
+    descriptions: Vec<String>,
 }
 
-impl EditResult {
-    pub fn new(
-        old_manifest: &str,
-        new_manifest: &str,
-        store_path: Option<PathBuf>,
-    ) -> Result<Self, CoreEnvironmentError> {
-        if old_manifest == new_manifest {
-            Ok(Self::Unchanged)
-        } else {
-            // todo: use a single toml crate (toml_edit already implements serde traits)
-            // TODO: use different error variants, users _can_ fix errors in the _new_ manifest
-            //       but they _can't_ fix errors in the _old_ manifest
-            let old_manifest: Manifest =
-                toml::from_str(old_manifest).map_err(CoreEnvironmentError::DeserializeManifest)?;
-            let new_manifest: Manifest =
-                toml::from_str(new_manifest).map_err(CoreEnvironmentError::DeserializeManifest)?;
-            // TODO: some modifications to `install` currently require re-activation
-            if old_manifest.hook != new_manifest.hook || old_manifest.vars != new_manifest.vars {
-                Ok(Self::ReActivateRequired { store_path })
-            } else {
-                Ok(Self::Success { store_path })
-            }
-        }
+impl EnvironmentTransaction<'_> {
+    /// Install `packages`, packages that are already installed are skipped
+    pub fn install(mut self, packages: impl IntoIterator<Item = PackageToInstall>) -> Self {
+        let packages = packages.into_iter().collect::<Vec<_>>();
+        self.describe("installed packages", packages.iter().map(|p| p.id.as_str()));
+        self.edits
+            .extend(packages.into_iter().map(ManifestEdit::AddPackage));
+        self
     }
 
-    pub fn store_path(&self) -> Option<PathBuf> {
-        match self {
-            EditResult::Unchanged => None,
-            EditResult::ReActivateRequired { store_path } => store_path.clone(),
-            EditResult::Success { store_path } => store_path.clone(),
-        }
+    /// Uninstall the packages with the install IDs `install_ids`
+    ///
+    /// Committing fails if any of them is not installed.
+    pub fn uninstall(mut self, install_ids: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        let install_ids = install_ids.into_iter().map(Into::into).collect::<Vec<_>>();
+        self.describe(
+            "uninstalled packages",
+            install_ids.iter().map(String::as_str),
+        );
+        self.edits.extend(
+            install_ids
+                .into_iter()
+                .map(|install_id| ManifestEdit::RemovePackage { install_id }),
+        );
+        self
     }
-}
 
-#[derive(Debug, Error)]
+    /// Set the variable `name` in `[vars]` to `value`
+    pub fn set_var(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        let name = name.into();
+        self.describe("set variables", [name.as_str()]);
+        self.edits.push(ManifestEdit::SetVar {
+            name,
+            value: value.into(),
+        });
+        self
+    }
+
+    /// Remove the variable `name` from `[vars]`
+    pub fn unset_var(mut self, name: impl Into<String>) -> Self {
+        let name = name.into();
+        self.describe("unset variables", [name.as_str()]);
+        self.edits.push(ManifestEdit::UnsetVar { name });
+        self
+    }
+
+    /// Set the option at the dot-separated `path` in `[options]` to `value`
+    pub fn set_option(
+        mut self,
+        path: impl Into<String>,
+        value: impl Into<toml_edit::Value>,
+    ) -> Self {
+        let path = path.into();
+        self.describe("set options", [path.as_str()]);
+        self.edits.push(ManifestEdit::SetOption {
+            path,
+            value: value.into(),
+        });
+        self
+    }
+
+    /// Remove the option at the dot-separated `path` from `[options]`
+    pub fn unset_option(mut self, path: impl Into<String>) -> Self {
+        let path = path.into();
+        self.describe("unset options", [path.as_str()]);
+        self.edits.push(ManifestEdit::UnsetOption { path });
+        self
+    }
+
+    /// Add the service `name`, or replace it if it is already configured
+    pub fn set_service(mut self, name: impl Into<String>, service: ServiceDescriptor) -> Self {
+        let name = name.into();
+        self.describe("set services", [name.as_str()]);
+        self.edits.push(ManifestEdit::SetService { name, service });
+        self
+    }
+
+    /// Remove the service `name`
+    ///
+    /// Committing fails if it is not configured.
+    pub fn remove_service(mut self, name: impl Into<String>) -> Self {
+        let name = name.into();
+        self.describe("removed services", [name.as_str()]);
+        self.edits.push(ManifestEdit::RemoveService { name });
+        self
+    }
+
+    /// Pin the package `install_id` at its locked derivation, see [Environment::pin](super::Environment::pin)
+    pub fn pin(mut self, install_id: impl Into<String>) -> Self {
+        let install_id = install_id.into();
+        self.describe("pinned packages", [install_id.as_str()]);
+        self.edits.push(ManifestEdit::SetPinned {
+            install_id,
+            pinned: true,
+        });
+        self
+    }
+
+    /// Unpin the package `install_id`
+    pub fn unpin(mut self, install_id: impl Into<String>) -> Self {
+        let install_id = install_id.into();
+        self.describe("unpinned packages", [install_id.as_str()]);
+        self.edits.push(ManifestEdit::SetPinned {
+            install_id,
+            pinned: false,
+        });
+        self
+    }
+
+    /// Record a change as `<action>: <names>`,
+    /// merging consecutive changes of the same action
+    fn describe<'n>(&mut self, action: &str, names: impl IntoIterator<Item = &'n str>) {
+        let names = names.into_iter().collect::<Vec<_>>().join(", ");
+        match self.descriptions.last_mut() {
+            Some(last) if last.starts_with(&format!("{action}: ")) => {
+                last.push_str(", ");
+                last.push_str(&names);
+            },
+            _ => self.descriptions.push(format!("{action}: {names}")),
+        }
+    }
+
+    /// Apply all changes, lock and build the environment, and replace it
+    ///
+    /// Returns [EditResult::Unchanged] without building
+    /// if the changes don't modify the manifest.
+    pub fn commit(self, flox: &Flox) -> Result<EditResult, CoreEnvironmentError> {
+        let description = self.descriptions.join("; ");
+        self.env.transact_with_edits(flox, &self.edits, description)
+    }
+}
+
+/// Coordinates changes to multiple environments that have to be applied together,
+/// e.g. an edit of a base environment and the environments that are composed from it.
+///
+/// Changes are applied with two phase commit semantics:
+/// [ComposedTransaction::prepare] applies, locks, and builds every change
+/// in a temporary copy of its environment,
+/// and only once all environments were prepared successfully
+/// [PreparedTransaction::commit] replaces the original environments.
+/// If any environment fails to prepare, no environment is modified.
+/// If any environment fails to be replaced,
+/// all environments are restored from their backups.
+#[derive(Default)]
+pub struct ComposedTransaction<'a> {
+    members: Vec<(&'a mut CoreEnvironment, Option<String>)>,
+}
+
+impl<'a> ComposedTransaction<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the manifest of `env` with `manifest_contents` as part of the transaction
+    pub fn edit(
+        mut self,
+        env: &'a mut CoreEnvironment,
+        manifest_contents: impl Into<String>,
+    ) -> Self {
+        self.members.push((env, Some(manifest_contents.into())));
+        self
+    }
+
+    /// Lock and build `env` with its current manifest as part of the transaction
+    pub fn relock(mut self, env: &'a mut CoreEnvironment) -> Self {
+        self.members.push((env, None));
+        self
+    }
+
+    /// Prepare all environments in temporary copies, without modifying any of them
+    pub fn prepare(self, flox: &Flox) -> Result<PreparedTransaction<'a>, CoreEnvironmentError> {
+        let mut prepared = Vec::with_capacity(self.members.len());
+        for (env, manifest_contents) in self.members {
+            // Lock before copying the environment,
+            // so that concurrent edits can't land between the copy and the lock
+            let lock = env.lock_transaction()?;
+            let manifest_hash = env.manifest_hash()?;
+            let sandbox = env.make_sandbox(flox)?;
+            let tempdir = sandbox.path().to_path_buf();
+
+            debug!(
+                "composed transaction: preparing {} in {}",
+                env.env_dir.display(),
+                tempdir.display()
+            );
+            let mut replacement = env.writable(&tempdir)?;
+            if let Some(manifest_contents) = manifest_contents {
+                replacement.update_manifest(&manifest_contents)?;
+                env.check_hooks(flox, &manifest_contents, &tempdir)?;
+            }
+            replacement.lock(flox)?;
+            let store_path = replacement.build(flox)?;
+
+            prepared.push(PreparedMember {
+                env,
+                replacement,
+                store_path,
+                manifest_hash,
+                _lock: lock,
+                _sandbox: sandbox,
+            });
+        }
+        Ok(PreparedTransaction { members: prepared })
+    }
+
+    /// Prepare and commit the transaction
+    ///
+    /// Returns the store paths of the built environments
+    /// in the order they were added to the transaction.
+    #[must_use = "don't discard the store path of built environments"]
+    pub fn commit(self, flox: &Flox) -> Result<Vec<PathBuf>, CoreEnvironmentError> {
+        self.prepare(flox)?.commit()
+    }
+}
+
+/// A directory created by [CoreEnvironment::make_sandbox]
+struct Sandbox {
+    path: PathBuf,
+    /// Removes the directory when the sandbox is dropped
+    _cleanup: Option<tempfile::TempDir>,
+}
+
+impl Sandbox {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// A single environment of a [PreparedTransaction]
+struct PreparedMember<'a> {
+    env: &'a mut CoreEnvironment,
+    replacement: CoreEnvironment<ReadWrite>,
+    store_path: PathBuf,
+    /// Hash of the original manifest when the member was prepared
+    manifest_hash: blake3::Hash,
+    /// The transaction lock of `env`, held until the transaction is dropped
+    _lock: LockFile,
+    /// The directory of `replacement`, kept until the transaction is dropped
+    _sandbox: Sandbox,
+}
+
+/// A [ComposedTransaction] for which all environments have been locked and built
+pub struct PreparedTransaction<'a> {
+    members: Vec<PreparedMember<'a>>,
+}
+
+impl PreparedTransaction<'_> {
+    /// The store paths of the built environments
+    pub fn store_paths(&self) -> Vec<&Path> {
+        self.members
+            .iter()
+            .map(|member| member.store_path.as_path())
+            .collect()
+    }
+
+    /// Replace all environments with their prepared copies
+    ///
+    /// All environments are backed up before any environment is replaced,
+    /// so that a failure to replace one environment can restore all of them.
+    #[must_use = "don't discard the store path of built environments"]
+    pub fn commit(self) -> Result<Vec<PathBuf>, CoreEnvironmentError> {
+        for member in &self.members {
+            member
+                .env
+                .ensure_manifest_unchanged(&member.manifest_hash)?;
+            member.env.store().ensure_no_backup()?;
+        }
+
+        for (n, member) in self.members.iter().enumerate() {
+            if let Err(err) = member.env.store().backup() {
+                Self::restore_backups(&self.members[..n])?;
+                return Err(err);
+            }
+        }
+
+        for member in &self.members {
+            if let Err(err) = member.env.store().copy_from(&member.replacement.env_dir) {
+                debug!("failed to replace env ({err}), restoring all backups");
+                Self::restore_backups(&self.members)?;
+                return Err(err);
+            }
+        }
+
+        for member in &self.members {
+            member.env.store().remove_backup()?;
+            member
+                .env
+                .record_generation(&member.store_path, "composed transaction".to_string());
+        }
+
+        Ok(self
+            .members
+            .into_iter()
+            .map(|member| member.store_path)
+            .collect())
+    }
+
+    /// Move the backups of `members` back into place,
+    /// discarding any partially copied replacement
+    fn restore_backups(members: &[PreparedMember]) -> Result<(), CoreEnvironmentError> {
+        for member in members {
+            member.env.store().restore_backup()?;
+        }
+        Ok(())
+    }
+}
+
+/// A handle to a background prefetch started by [CoreEnvironment::prefetch_resolution]
+#[derive(Debug)]
+pub struct PrefetchHandle(Option<std::thread::JoinHandle<()>>);
+
+impl PrefetchHandle {
+    /// Block until the prefetch completed
+    pub fn wait(self) {
+        if let Some(handle) = self.0 {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// The result of [CoreEnvironment::install_dry_run]
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstallDryRun {
+    /// The manifest the install would write,
+    /// `None` if all packages are already installed
+    pub new_manifest: Option<String>,
+    pub already_installed: HashMap<String, bool>,
+    /// The packages that would be added to the lockfile, for every system
+    pub added: Vec<LockedPackageCatalog>,
+    /// The closure size in bytes of each package added for the current system,
+    /// by install id, `None` if the size couldn't be determined
+    pub closure_sizes: BTreeMap<String, Option<u64>>,
+}
+
+/// The result of [CoreEnvironment::migrate_to_catalog]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MigrationResult {
+    pub store_path: PathBuf,
+    /// Attributes of the manifest that have no equivalent in version 1
+    /// and were removed
+    pub dropped: Vec<String>,
+    /// The version locked by pkgdb and the version locked by the catalog
+    /// of every package whose version changed for the current system
+    pub changed_versions: BTreeMap<String, (Option<String>, Option<String>)>,
+}
+
+impl MigrationResult {
+    fn changed_versions(
+        old_packages: &[InstalledPackage],
+        new_packages: &[InstalledPackage],
+    ) -> BTreeMap<String, (Option<String>, Option<String>)> {
+        let versions = |packages: &[InstalledPackage]| {
+            packages
+                .iter()
+                .map(|package| (package.install_id.clone(), package.info.version.clone()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        let old_versions = versions(old_packages);
+        let new_versions = versions(new_packages);
+
+        old_versions
+            .keys()
+            .chain(new_versions.keys())
+            .filter_map(|install_id| {
+                let old_version = old_versions.get(install_id).cloned().flatten();
+                let new_version = new_versions.get(install_id).cloned().flatten();
+                (old_version != new_version)
+                    .then(|| (install_id.clone(), (old_version, new_version)))
+            })
+            .collect()
+    }
+}
+
+/// The result of [CoreEnvironment::lock_all_systems]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SystemsLock {
+    pub lockfile: LockedManifest,
+    /// The packages locked for each supported system
+    pub packages: BTreeMap<System, Vec<InstalledPackage>>,
+}
+
+/// Options that only affect how the environment is locked,
+/// changes to all other options may affect activation.
+const LOCKING_OPTIONS: [&str; 3] = ["systems", "allow", "semver"];
+
+/// A section of the manifest that takes effect when the environment is activated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ActivationChange {
+    /// `[vars]`
+    Vars,
+    /// `[hook]`
+    Hook,
+    /// `[profile]`
+    Profile,
+    /// `[services]`
+    Services,
+    /// `[options]` other than those that only affect locking
+    Options,
+}
+
+/// What needs to be done to apply an [ActivationChange]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RequiredAction {
+    /// Exit and re-activate the environment
+    ReActivate,
+    /// Restart the services of the environment
+    RestartServices,
+}
+
+impl ActivationChange {
+    pub fn required_action(&self) -> RequiredAction {
+        match self {
+            ActivationChange::Services => RequiredAction::RestartServices,
+            ActivationChange::Vars
+            | ActivationChange::Hook
+            | ActivationChange::Profile
+            | ActivationChange::Options => RequiredAction::ReActivate,
+        }
+    }
+}
+
+impl Display for ActivationChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let section = match self {
+            ActivationChange::Vars => "vars",
+            ActivationChange::Hook => "hook",
+            ActivationChange::Profile => "profile",
+            ActivationChange::Services => "services",
+            ActivationChange::Options => "options",
+        };
+        write!(f, "{section}")
+    }
+}
+
+/// The changes made by an edit of the manifest
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ManifestChanges {
+    /// Whether `[install]` changed.
+    /// Package changes are applied by building the environment.
+    pub packages: bool,
+    /// The sections that changed and only take effect on activation
+    pub activation: BTreeSet<ActivationChange>,
+}
+
+impl ManifestChanges {
+    /// Compare the sections of two manifests
+    fn new(old_manifest: &toml::Table, new_manifest: &toml::Table) -> Self {
+        let changed = |section: &str| old_manifest.get(section) != new_manifest.get(section);
+        let activation_options = |manifest: &toml::Table| {
+            let mut options = manifest
+                .get("options")
+                .and_then(toml::Value::as_table)
+                .cloned()
+                .unwrap_or_default();
+            options.retain(|option, _| !LOCKING_OPTIONS.contains(&option));
+            options
+        };
+
+        let mut activation = BTreeSet::new();
+        for (section, change) in [
+            ("vars", ActivationChange::Vars),
+            ("hook", ActivationChange::Hook),
+            ("profile", ActivationChange::Profile),
+            ("services", ActivationChange::Services),
+        ] {
+            if changed(section) {
+                activation.insert(change);
+            }
+        }
+        if activation_options(old_manifest) != activation_options(new_manifest) {
+            activation.insert(ActivationChange::Options);
+        }
+
+        ManifestChanges {
+            packages: changed("install"),
+            activation,
+        }
+    }
+
+    /// The actions needed to apply the changes to an active environment
+    pub fn required_actions(&self) -> BTreeSet<RequiredAction> {
+        self.activation
+            .iter()
+            .map(ActivationChange::required_action)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EditResult {
+    /// The manifest was not modified.
+    Unchanged,
+    /// The manifest was modified, and the user needs to re-activate it.
+    ReActivateRequired {
+        store_path: Option<PathBuf>,
+        changes: ManifestChanges,
+    },
+    /// The manifest was modified, but the user does not need to re-activate it.
+    Success {
+        store_path: Option<PathBuf>,
+        changes: ManifestChanges,
+    },
+}
+
+impl EditResult {
+    pub fn new(
+        old_manifest: &str,
+        new_manifest: &str,
+        store_path: Option<PathBuf>,
+    ) -> Result<Self, CoreEnvironmentError> {
+        if old_manifest == new_manifest {
+            Ok(Self::Unchanged)
+        } else {
+            // todo: use a single toml crate (toml_edit already implements serde traits)
+            // TODO: use different error variants, users _can_ fix errors in the _new_ manifest
+            //       but they _can't_ fix errors in the _old_ manifest
+            let old_manifest: toml::Table =
+                toml::from_str(old_manifest).map_err(CoreEnvironmentError::DeserializeManifest)?;
+            let new_manifest: toml::Table =
+                toml::from_str(new_manifest).map_err(CoreEnvironmentError::DeserializeManifest)?;
+            let changes = ManifestChanges::new(&old_manifest, &new_manifest);
+            // TODO: some modifications to `install` currently require re-activation
+            if changes
+                .required_actions()
+                .contains(&RequiredAction::ReActivate)
+            {
+                Ok(Self::ReActivateRequired {
+                    store_path,
+                    changes,
+                })
+            } else {
+                Ok(Self::Success {
+                    store_path,
+                    changes,
+                })
+            }
+        }
+    }
+
+    pub fn store_path(&self) -> Option<PathBuf> {
+        match self {
+            EditResult::Unchanged => None,
+            EditResult::ReActivateRequired { store_path, .. } => store_path.clone(),
+            EditResult::Success { store_path, .. } => store_path.clone(),
+        }
+    }
+
+    /// The changes made by the edit, if any
+    pub fn changes(&self) -> Option<&ManifestChanges> {
+        match self {
+            EditResult::Unchanged => None,
+            EditResult::ReActivateRequired { changes, .. } => Some(changes),
+            EditResult::Success { changes, .. } => Some(changes),
+        }
+    }
+}
+
+/// Split the arguments of an upgrade into groups or install ids to upgrade
+/// and the version constraints of install ids given as `<install id>@<version>`
+///
+/// Constrained install ids are included in the groups or install ids to upgrade.
+fn split_version_constraints(
+    groups_or_iids: &[String],
+) -> Result<(Vec<String>, BTreeMap<String, String>), CoreEnvironmentError> {
+    let mut targets = Vec::new();
+    let mut constraints = BTreeMap::new();
+    for group_or_iid in groups_or_iids {
+        match group_or_iid.split_once('@') {
+            None => targets.push(group_or_iid.clone()),
+            Some((install_id, version)) if !install_id.is_empty() && !version.is_empty() => {
+                targets.push(install_id.to_string());
+                constraints.insert(install_id.to_string(), version.to_string());
+            },
+            Some(_) => {
+                return Err(CoreEnvironmentError::InvalidUpgradeConstraint(
+                    group_or_iid.clone(),
+                ))
+            },
+        }
+    }
+    Ok((targets, constraints))
+}
+
+#[derive(Debug, Error)]
 pub enum CoreEnvironmentError {
     // region: immutable manifest errors
     #[error("could not modify manifest")]
@@ -900,6 +2784,24 @@ pub enum CoreEnvironmentError {
     Move(#[source] std::io::Error),
     #[error("Failed to remove transaction backup")]
     RemoveBackup(#[source] std::io::Error),
+    #[error("Failed to move ignored files into the modified environment")]
+    KeepIgnored(#[source] std::io::Error),
+    #[error("could not acquire transaction lock")]
+    TransactionLock(#[source] fslock::Error),
+    /// Another transaction on the same environment is in progress
+    #[error("environment {0} is busy with another transaction")]
+    EnvironmentBusy(PathBuf),
+    #[error("could not access generations of the environment")]
+    Generations(#[source] LocalGenerationsError),
+    #[error("could not access snapshots of the environment")]
+    Snapshots(#[source] SnapshotError),
+    /// The manifest was modified by someone else while a transaction was in progress
+    #[error("manifest {0} was modified while the environment was being changed")]
+    ManifestModifiedConcurrently(PathBuf),
+    #[error("could not move environment to {0}")]
+    Relocate(PathBuf, #[source] std::io::Error),
+    #[error("could not move environment to {0}: it already exists")]
+    RelocateTargetExists(PathBuf),
 
     // endregion
 
@@ -930,9 +2832,77 @@ pub enum CoreEnvironmentError {
 
     #[error("Could not process catalog manifest without a catalog client")]
     CatalogClientMissing,
+    #[error("dry runs are only supported for manifests locked with the catalog")]
+    DryRunRequiresCatalog,
+    #[error("invalid version constraint '{0}', expected '<install id>@<version>'")]
+    InvalidUpgradeConstraint(String),
+    #[error("version constraints are only supported for manifests locked with the catalog")]
+    UpgradeConstraintRequiresCatalog,
+    #[error("can't constrain the version of '{0}', it is not installed from the catalog")]
+    UpgradeConstraintPackageNotFound(String),
+    #[error("can't constrain the version of '{0}', the manifest requires version '{1}'")]
+    UpgradeConstraintConflictsWithManifest(String, String),
+    #[error("pkg-group '{0}' not found in lockfile")]
+    GroupNotFound(String),
+    #[error("building a single pkg-group is only supported for manifests locked with the catalog")]
+    GroupBuildRequiresCatalog,
+    #[error("no template called '{0}'")]
+    TemplateNotFound(String),
+    #[error("couldn't migrate manifest to version 1")]
+    MigrateManifest(#[source] MigrateManifestError),
+    #[error("couldn't include environment")]
+    Include(#[source] IncludeError),
+    #[error("couldn't verify the store paths of the environment")]
+    VerifyStore(#[source] StoreVerifyError),
+    #[error("package store paths are only known for manifests locked with the catalog")]
+    PackageStorePathsRequireCatalog,
+    #[error("checking reproducibility is only supported for manifests locked with the catalog")]
+    ReproducibilityRequiresCatalog,
+    #[error("couldn't check the reproducibility of the environment")]
+    Reproducibility(#[source] ReproducibilityError),
+
+    #[error("hook scripts have syntax errors")]
+    HookSyntax(Vec<HookSyntaxError>),
+    #[error("couldn't check the syntax of hook scripts")]
+    HookCheck(#[source] HookCheckError),
 }
 
 impl CoreEnvironmentError {
+    /// The [ErrorCategory] reported to [Flox::events] for this error
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            CoreEnvironmentError::ModifyToml(_)
+            | CoreEnvironmentError::DeserializeManifest(_)
+            | CoreEnvironmentError::OpenManifest(_)
+            | CoreEnvironmentError::UpdateManifest(_)
+            | CoreEnvironmentError::TemplateNotFound(_)
+            | CoreEnvironmentError::MigrateManifest(_)
+            | CoreEnvironmentError::Include(_)
+            | CoreEnvironmentError::HookSyntax(_) => ErrorCategory::Manifest,
+            CoreEnvironmentError::LockedManifest(LockedManifestError::BuildEnv(_))
+            | CoreEnvironmentError::LockedManifest(LockedManifestError::BuildFailure(_)) => {
+                ErrorCategory::Build
+            },
+            CoreEnvironmentError::LockedManifest(_)
+            | CoreEnvironmentError::UpgradeFailed(_)
+            | CoreEnvironmentError::ParseUpgradeOutput(_)
+            | CoreEnvironmentError::CatalogClientMissing => ErrorCategory::Resolution,
+            CoreEnvironmentError::MakeSandbox(_)
+            | CoreEnvironmentError::WriteLockfile(_)
+            | CoreEnvironmentError::MakeTemporaryEnv(_)
+            | CoreEnvironmentError::PriorTransaction(_)
+            | CoreEnvironmentError::BackupTransaction(_)
+            | CoreEnvironmentError::AbortTransaction(_)
+            | CoreEnvironmentError::Move(_)
+            | CoreEnvironmentError::RemoveBackup(_)
+            | CoreEnvironmentError::KeepIgnored(_)
+            | CoreEnvironmentError::TransactionLock(_)
+            | CoreEnvironmentError::EnvironmentBusy(_)
+            | CoreEnvironmentError::ManifestModifiedConcurrently(_) => ErrorCategory::Transaction,
+            _ => ErrorCategory::Other,
+        }
+    }
+
     pub fn is_incompatible_system_error(&self) -> bool {
         matches!(
             self,
@@ -962,23 +2932,60 @@ impl CoreEnvironmentError {
         }
     }
 
-    /// If the error contains a PkgDbError with an exit_code, return it.
-    /// Otherwise return None.
-    pub fn pkgdb_exit_code(&self) -> Option<&u64> {
+    /// The diagnosis of a package that failed to build, if that caused the error
+    pub fn build_failure(&self) -> Option<&BuildFailure> {
+        match self {
+            CoreEnvironmentError::LockedManifest(LockedManifestError::BuildFailure(failure)) => {
+                Some(failure)
+            },
+            _ => None,
+        }
+    }
+
+    /// The error of a failed `pkgdb buildenv` call, diagnosed or not
+    fn buildenv_error(&self) -> Option<&PkgDbError> {
         match self {
             CoreEnvironmentError::LockedManifest(LockedManifestError::BuildEnv(
-                CallPkgDbError::PkgDbError(PkgDbError { exit_code, .. }),
-            )) => Some(exit_code),
+                CallPkgDbError::PkgDbError(err),
+            )) => Some(err),
+            CoreEnvironmentError::LockedManifest(LockedManifestError::BuildFailure(failure)) => {
+                Some(&failure.error)
+            },
             _ => None,
         }
     }
+
+    /// The last lines of output of a failed build, if any were captured
+    pub fn build_log_tail(&self) -> Option<&[String]> {
+        self.buildenv_error()
+            .map(|err| err.log_tail.as_slice())
+            .filter(|log_tail| !log_tail.is_empty())
+    }
+
+    /// The build directories of failed builds that were kept for inspection,
+    /// see [Flox::keep_failed]
+    pub fn kept_build_dirs(&self) -> Option<&[PathBuf]> {
+        self.buildenv_error()
+            .map(|err| err.kept_build_dirs.as_slice())
+            .filter(|dirs| !dirs.is_empty())
+    }
+
+    /// If the error contains a PkgDbError with an exit_code, return it.
+    /// Otherwise return None.
+    pub fn pkgdb_exit_code(&self) -> Option<&u64> {
+        self.buildenv_error().map(|err| &err.exit_code)
+    }
 }
 
 pub mod test_helpers {
+    use catalog_api_v1::types::ResolvedPackageDescriptor;
+    use chrono::{DateTime, Utc};
     use indoc::indoc;
 
     use super::*;
     use crate::flox::Flox;
+    use crate::models::manifest::DEFAULT_GROUP_NAME;
+    use crate::providers::catalog::{CatalogPage, ResolvedPackageGroup};
 
     #[cfg(target_os = "macos")]
     pub const MANIFEST_INCOMPATIBLE_SYSTEM: &str = indoc! {r#"
@@ -998,11 +3005,44 @@ pub mod test_helpers {
 
         CoreEnvironment::new(&env_path)
     }
+
+    /// A resolved default group containing a single package `install_id` at `version`
+    pub fn resolved_group(install_id: &str, version: &str) -> ResolvedPackageGroup {
+        ResolvedPackageGroup {
+            name: DEFAULT_GROUP_NAME.to_string(),
+            pages: vec![CatalogPage {
+                packages: Some(vec![ResolvedPackageDescriptor {
+                    attr_path: "foo".to_string(),
+                    broken: false,
+                    derivation: format!("derivation-{version}"),
+                    description: None,
+                    install_id: install_id.to_string(),
+                    license: None,
+                    locked_url: "locked-url".to_string(),
+                    name: "foo".to_string(),
+                    outputs: None,
+                    outputs_to_install: None,
+                    pname: "foo".to_string(),
+                    rev: "rev".to_string(),
+                    rev_count: 42,
+                    rev_date: DateTime::<Utc>::MIN_UTC,
+                    scrape_date: DateTime::<Utc>::MIN_UTC,
+                    stabilities: None,
+                    unfree: None,
+                    version: version.to_string(),
+                }]),
+                page: 1,
+                url: "url".to_string(),
+            }],
+            system: "system".to_string(),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::os::unix::fs::PermissionsExt;
+    use std::sync::{Arc, Mutex};
 
     use catalog_api_v1::types::ResolvedPackageDescriptor;
     use chrono::{DateTime, Utc};
@@ -1011,13 +3051,18 @@ mod tests {
     use tempfile::{tempdir_in, TempDir};
     use tests::test_helpers::MANIFEST_INCOMPATIBLE_SYSTEM;
 
-    use self::catalog::{CatalogPage, MockClient, ResolvedPackageGroup};
-    use self::test_helpers::new_core_environment;
+    use self::test_helpers::{new_core_environment, resolved_group};
     use super::*;
     use crate::data::Version;
     use crate::flox::test_helpers::{flox_instance, flox_instance_with_global_lock};
+    use crate::models::environment::floxignore::FLOXIGNORE_FILENAME;
+    use crate::models::lockfile::tests::fake_package;
+    use crate::models::lockfile::PackageInfo;
     use crate::models::manifest::DEFAULT_GROUP_NAME;
     use crate::models::{lockfile, manifest};
+    use crate::providers::catalog::{CatalogPage, MockClient, ResolvedPackageGroup};
+    use crate::utils::events::{EventSink, Events, OperationEvent, Outcome};
+    use crate::utils::progress::{Progress, ProgressObserver};
 
     /// Create a CoreEnvironment with an empty manifest
     ///
@@ -1173,7 +3218,7 @@ mod tests {
 
         let result = env_view.edit(&flox, new_env_str.to_string()).unwrap();
 
-        assert!(matches!(result, EditResult::Success { store_path: _ }));
+        assert!(matches!(result, EditResult::Success { store_path: _, .. }));
     }
 
     /// Adding a hook with edit returns EditResult::ReActivateRequired
@@ -1190,10 +3235,71 @@ mod tests {
         let result = env_view.edit(&flox, new_env_str.to_string()).unwrap();
 
         assert!(matches!(result, EditResult::ReActivateRequired {
-            store_path: _
+            store_path: _,
+            ..
         }));
     }
 
+    /// Changes to each section of the manifest are reported separately
+    #[test]
+    fn edit_result_reports_changed_sections() {
+        let old_manifest = indoc! {r#"
+            version = 1
+
+            [install]
+            hello.pkg-path = "hello"
+
+            [options]
+            systems = ["x86_64-linux"]
+        "#};
+
+        let new_manifest = indoc! {r#"
+            version = 1
+
+            [install]
+            hello.pkg-path = "hello"
+
+            [options]
+            systems = ["x86_64-linux", "aarch64-darwin"]
+
+            [services]
+            postgres.command = "postgres"
+        "#};
+        let result = EditResult::new(old_manifest, new_manifest, None).unwrap();
+        assert_eq!(result, EditResult::Success {
+            store_path: None,
+            changes: ManifestChanges {
+                packages: false,
+                activation: BTreeSet::from([ActivationChange::Services]),
+            },
+        });
+        assert_eq!(
+            result.changes().unwrap().required_actions(),
+            BTreeSet::from([RequiredAction::RestartServices])
+        );
+
+        let new_manifest = indoc! {r#"
+            version = 1
+
+            [install]
+
+            [profile]
+            bash = "echo hello"
+
+            [options]
+            systems = ["x86_64-linux"]
+            activate.mode = "run"
+        "#};
+        let result = EditResult::new(old_manifest, new_manifest, None).unwrap();
+        assert_eq!(result, EditResult::ReActivateRequired {
+            store_path: None,
+            changes: ManifestChanges {
+                packages: true,
+                activation: BTreeSet::from([ActivationChange::Profile, ActivationChange::Options]),
+            },
+        });
+    }
+
     #[test]
     fn locking_of_v1_manifest_requires_catalog_client() {
         let (mut env_view, mut flox, _temp_dir_handle) = empty_core_environment();
@@ -1217,10 +3323,149 @@ mod tests {
     }
 
     #[test]
-    fn upgrade_with_catalog_client_requires_catalog_client() {
-        // flox already has a catalog client
-        let (mut env_view, mut flox, _temp_dir_handle) = empty_core_environment();
-        fs::write(env_view.manifest_path(), r#"version = 1"#).unwrap();
+    fn migration_reports_changed_versions() {
+        let (mut flox, _temp_dir_handle) = flox_instance();
+        flox.catalog_client = Some(MockClient::new(None::<&str>).unwrap().into());
+        let mut env_view = new_core_environment(&flox, "version = 1");
+        assert!(matches!(
+            env_view.migrate_to_catalog(&flox),
+            Err(CoreEnvironmentError::MigrateManifest(
+                MigrateManifestError::AlreadyMigrated(1)
+            ))
+        ));
+
+        let package = |install_id: &str, version: &str| InstalledPackage {
+            install_id: install_id.to_string(),
+            rel_path: install_id.to_string(),
+            info: PackageInfo {
+                description: None,
+                broken: false,
+                license: None,
+                pname: install_id.to_string(),
+                unfree: None,
+                version: Some(version.to_string()),
+            },
+            priority: None,
+        };
+        let old_packages = [package("hello", "2.12"), package("ripgrep", "14.0")];
+        let new_packages = [package("hello", "2.12.1"), package("ripgrep", "14.0")];
+        assert_eq!(
+            MigrationResult::changed_versions(&old_packages, &new_packages),
+            BTreeMap::from([(
+                "hello".to_string(),
+                (Some("2.12".to_string()), Some("2.12.1".to_string()))
+            )])
+        );
+    }
+
+    /// Migration rewrites the manifest and locks it with the catalog
+    /// in a single transaction
+    #[test]
+    fn migration_rewrites_manifest_and_lockfile() {
+        let (mut flox, tempdir) = flox_instance();
+        let manifest = indoc! {r#"
+            [install]
+            hello.pkg-path = ["hello"]
+
+            [hook]
+            script = "echo hello"
+
+            [options]
+            systems = ["system"]
+        "#};
+        let migrated = indoc! {r#"
+            version = 1
+
+            [install]
+            hello.pkg-path = "hello"
+
+            [hook]
+            on-activate = "echo hello"
+
+            [options]
+            systems = ["system"]
+        "#};
+        let mut env_view = new_core_environment(&flox, manifest);
+
+        let resolved = vec![resolved_group("hello", "2.12.1")];
+        let mut mock_client = MockClient::new(None::<&str>).unwrap();
+        mock_client.push_resolve_response(resolved.clone());
+        mock_client.push_resolve_response(resolved);
+        flox.catalog_client = Some(mock_client.into());
+
+        // Lock the expected manifest and record a build of its lockfile,
+        // so that the migration doesn't build with pkgdb
+        let mut expected = new_core_environment(&flox, migrated);
+        let expected_lockfile = expected.lock(&flox).unwrap();
+        let lockfile_contents = fs::read(expected.lockfile_path()).unwrap();
+        let store_path = tempdir.path().join("store-path");
+        fs::create_dir(&store_path).unwrap();
+        flox.build_cache()
+            .insert(
+                &CoreEnvironment::<ReadOnly>::build_hash(
+                    &flox,
+                    &lockfile_contents,
+                    &expected_lockfile,
+                ),
+                &flox.system,
+                &store_path,
+            )
+            .unwrap();
+
+        let result = env_view.migrate_to_catalog(&flox).unwrap();
+
+        assert_eq!(result.store_path, store_path);
+        assert!(result.dropped.is_empty());
+        assert_eq!(env_view.manifest_content().unwrap(), migrated);
+        assert_eq!(
+            fs::read(env_view.lockfile_path()).unwrap(),
+            lockfile_contents
+        );
+        let LockedManifest::Catalog(lockfile) = expected_lockfile else {
+            panic!("expected a catalog lockfile");
+        };
+        assert_eq!(lockfile.packages.len(), 1);
+        assert_eq!(lockfile.packages[0].install_id, "hello");
+        assert_eq!(lockfile.packages[0].version, "2.12.1");
+    }
+
+    /// Store paths of locked packages and the out-link that no longer exist
+    /// are reported as missing
+    #[test]
+    fn verify_reports_missing_store_paths() {
+        let (flox, _temp_dir_handle) = flox_instance();
+        let mut env_view = new_core_environment(&flox, "version = 1");
+
+        let (_, _, mut package) = fake_package("hello", None);
+        package.system = flox.system.clone();
+        package.outputs = Some(BTreeMap::from([(
+            "out".to_string(),
+            "/nix/store/missing-hello".to_string(),
+        )]));
+        let lockfile = serde_json::json!({
+            "lockfile-version": 1,
+            "manifest": { "version": 1 },
+            "packages": [package],
+        });
+        fs::write(env_view.lockfile_path(), lockfile.to_string()).unwrap();
+
+        let out_link = flox.temp_dir.join("out-link");
+        std::os::unix::fs::symlink("/nix/store/missing-env", &out_link).unwrap();
+
+        let verification = env_view.verify(&flox, Some(&out_link), false).unwrap();
+        assert!(!verification.is_valid());
+        assert_eq!(verification.missing, vec![
+            PathBuf::from("/nix/store/missing-env"),
+            PathBuf::from("/nix/store/missing-hello"),
+        ]);
+        assert!(verification.corrupted.is_empty());
+    }
+
+    #[test]
+    fn upgrade_with_catalog_client_requires_catalog_client() {
+        // flox already has a catalog client
+        let (mut env_view, mut flox, _temp_dir_handle) = empty_core_environment();
+        fs::write(env_view.manifest_path(), r#"version = 1"#).unwrap();
 
         flox.catalog_client = None;
         let err = env_view
@@ -1241,15 +3486,20 @@ mod tests {
     // TODO: add fixtures for resolve mocks if we add more of these tests
     #[test]
     fn upgrade_with_empty_list_upgrades_all() {
-        let (mut env_view, _flox, _temp_dir_handle) = empty_core_environment();
+        let (env_view, _flox, _temp_dir_handle) = empty_core_environment();
 
         let mut manifest = manifest::test::empty_catalog_manifest();
         let (foo_iid, foo_descriptor, foo_locked) = lockfile::tests::fake_package("foo", None);
-        manifest.install.insert(foo_iid.clone(), foo_descriptor);
+        manifest
+            .install
+            .insert(foo_iid.clone(), foo_descriptor.into());
         let lockfile = lockfile::LockedManifestCatalog {
             version: Version,
             packages: vec![foo_locked.clone()],
             manifest: manifest.clone(),
+            flake_packages: vec![],
+            store_path_packages: vec![],
+            includes: vec![],
         };
 
         let lockfile_str = serde_json::to_string_pretty(&lockfile).unwrap();
@@ -1293,6 +3543,763 @@ mod tests {
         assert!(upgraded_packages.len() == 1);
     }
 
+    /// Upgrading all packages keeps pinned packages at their locked derivation
+    #[test]
+    fn upgrade_with_empty_list_skips_pinned_packages() {
+        let (flox, _temp_dir_handle) = flox_instance();
+        let env_view = new_core_environment(&flox, "version = 1");
+
+        let mut manifest = manifest::test::empty_catalog_manifest();
+        let (foo_iid, mut foo_descriptor, foo_locked) =
+            lockfile::tests::fake_package("foo", Some("toolchain"));
+        foo_descriptor.pinned = true;
+        let (bar_iid, bar_descriptor, bar_locked) = lockfile::tests::fake_package("bar", None);
+        manifest
+            .install
+            .insert(foo_iid.clone(), foo_descriptor.into());
+        manifest
+            .install
+            .insert(bar_iid.clone(), bar_descriptor.into());
+        let lockfile = lockfile::LockedManifestCatalog {
+            version: Version,
+            packages: vec![foo_locked.clone(), bar_locked],
+            manifest: manifest.clone(),
+            flake_packages: vec![],
+            store_path_packages: vec![],
+            includes: vec![],
+        };
+        fs::write(
+            env_view.lockfile_path(),
+            serde_json::to_string_pretty(&lockfile).unwrap(),
+        )
+        .unwrap();
+
+        // only the group of the unpinned package is resolved
+        let mut mock_client = MockClient::new(None::<&str>).unwrap();
+        mock_client.push_resolve_response(vec![resolved_group(&bar_iid, "2.0")]);
+
+        let (upgraded_lockfile, upgraded_packages) = env_view
+            .upgrade_with_catalog_client(&mock_client, &[], &manifest)
+            .unwrap();
+
+        assert_eq!(upgraded_packages.len(), 1);
+        assert_eq!(upgraded_packages[0].1.install_id, bar_iid);
+        assert!(upgraded_lockfile.packages.contains(&foo_locked));
+    }
+
+    #[test]
+    fn upgrade_with_constraint_keeps_manifest_version() {
+        let (flox, _temp_dir_handle) = flox_instance();
+        let env_view = new_core_environment(&flox, "version = 1");
+
+        let mut manifest = manifest::test::empty_catalog_manifest();
+        let (bar_iid, bar_descriptor, bar_locked) = lockfile::tests::fake_package("bar", None);
+        manifest
+            .install
+            .insert(bar_iid.clone(), bar_descriptor.into());
+        let lockfile = lockfile::LockedManifestCatalog {
+            version: Version,
+            packages: vec![bar_locked],
+            manifest: manifest.clone(),
+            flake_packages: vec![],
+            store_path_packages: vec![],
+            includes: vec![],
+        };
+        fs::write(
+            env_view.lockfile_path(),
+            serde_json::to_string_pretty(&lockfile).unwrap(),
+        )
+        .unwrap();
+
+        let mut mock_client = MockClient::new(None::<&str>).unwrap();
+        mock_client.push_resolve_response(vec![resolved_group(&bar_iid, "2.0")]);
+
+        let (upgraded_lockfile, upgraded_packages) = env_view
+            .upgrade_with_catalog_client(&mock_client, &[format!("{bar_iid}@^2")], &manifest)
+            .unwrap();
+
+        assert_eq!(upgraded_packages.len(), 1);
+        assert_eq!(upgraded_packages[0].1.install_id, bar_iid);
+        // the constraint only applies to this upgrade
+        assert_eq!(upgraded_lockfile.manifest, manifest);
+    }
+
+    /// Packages with a version in the manifest can't be upgraded with a constraint
+    #[test]
+    fn upgrade_with_constraint_rejects_manifest_version() {
+        let (flox, _temp_dir_handle) = flox_instance();
+        let env_view = new_core_environment(&flox, "version = 1");
+
+        let mut manifest = manifest::test::empty_catalog_manifest();
+        let (bar_iid, mut bar_descriptor, _) = lockfile::tests::fake_package("bar", None);
+        bar_descriptor.version = Some("^1".to_string());
+        manifest
+            .install
+            .insert(bar_iid.clone(), bar_descriptor.into());
+
+        // the catalog isn't queried
+        let mock_client = MockClient::new(None::<&str>).unwrap();
+        let err = env_view
+            .upgrade_with_catalog_client(&mock_client, &[format!("{bar_iid}@^2")], &manifest)
+            .unwrap_err();
+
+        assert!(matches!(
+            err,
+            CoreEnvironmentError::UpgradeConstraintConflictsWithManifest(install_id, version)
+                if install_id == bar_iid && version == "^1"
+        ));
+    }
+
+    #[test]
+    fn split_version_constraints_rejects_incomplete_constraints() {
+        let (targets, constraints) =
+            split_version_constraints(&["toolchain".to_string(), "nodejs@^20".to_string()])
+                .unwrap();
+        assert_eq!(targets, vec!["toolchain".to_string(), "nodejs".to_string()]);
+        assert_eq!(
+            constraints,
+            BTreeMap::from([("nodejs".to_string(), "^20".to_string())])
+        );
+
+        for invalid in ["nodejs@", "@^20"] {
+            assert!(matches!(
+                split_version_constraints(&[invalid.to_string()]),
+                Err(CoreEnvironmentError::InvalidUpgradeConstraint(_))
+            ));
+        }
+    }
+
+    /// Lock `manifest` in a separate environment and record a build of its lockfile,
+    /// so that transactions producing the same lockfile don't build with pkgdb
+    ///
+    /// Returns the recorded store path.
+    fn record_build(flox: &Flox, manifest: &str) -> PathBuf {
+        let mut env_view = new_core_environment(flox, manifest);
+        let lockfile = env_view.lock(flox).unwrap();
+        let lockfile_contents = fs::read(env_view.lockfile_path()).unwrap();
+        let store_path = env_view.path().with_extension("store-path");
+        fs::create_dir(&store_path).unwrap();
+        flox.build_cache()
+            .insert(
+                &CoreEnvironment::<ReadOnly>::build_hash(flox, &lockfile_contents, &lockfile),
+                &flox.system,
+                &store_path,
+            )
+            .unwrap();
+        store_path
+    }
+
+    /// Files ignored by the `.floxignore` are not copied for transactions,
+    /// but are kept when the environment is replaced
+    #[test]
+    fn transaction_keeps_ignored_files() {
+        let (mut flox, _temp_dir_handle) = flox_instance();
+        let mut mock_client = MockClient::new(None::<&str>).unwrap();
+        mock_client.push_resolve_response(vec![]);
+        mock_client.push_resolve_response(vec![]);
+        flox.catalog_client = Some(mock_client.into());
+
+        let edited = "version = 1 # edited";
+        let store_path = record_build(&flox, edited);
+
+        let mut env_view = new_core_environment(&flox, "version = 1");
+        fs::write(env_view.path().join(FLOXIGNORE_FILENAME), "node_modules/").unwrap();
+        fs::create_dir(env_view.path().join("node_modules")).unwrap();
+        fs::write(env_view.path().join("node_modules").join("x"), "x").unwrap();
+        fs::write(env_view.path().join(".DS_Store"), "").unwrap();
+
+        let result = env_view.edit(&flox, edited.to_string()).unwrap();
+
+        assert_eq!(result.store_path(), Some(store_path));
+        assert_eq!(env_view.manifest_content().unwrap(), edited);
+        assert_eq!(
+            fs::read_to_string(env_view.path().join("node_modules").join("x")).unwrap(),
+            "x"
+        );
+        assert!(env_view.path().join(".DS_Store").exists());
+        assert!(!env_view.store().backup_path().exists());
+    }
+
+    /// Locking purely resolves the manifest without writing a lockfile
+    #[test]
+    fn lock_pure_does_not_write_lockfile() {
+        let (mut flox, _temp_dir_handle) = flox_instance();
+
+        let mut manifest = manifest::test::empty_catalog_manifest();
+        let (foo_iid, foo_descriptor, _) = lockfile::tests::fake_package("foo", None);
+        manifest
+            .install
+            .insert(foo_iid.clone(), foo_descriptor.into());
+        let mut env_view = new_core_environment(&flox, &toml::to_string(&manifest).unwrap());
+
+        let mut mock_client = MockClient::new(None::<&str>).unwrap();
+        mock_client.push_resolve_response(vec![resolved_group(&foo_iid, "1.0")]);
+        flox.catalog_client = Some(mock_client.into());
+
+        let lockfile = env_view.lock_pure(&flox).unwrap();
+        assert!(!env_view.lockfile_path().exists());
+
+        env_view.write_lockfile(&lockfile).unwrap();
+        let written =
+            LockedManifest::read_from_file(&CanonicalPath::new(env_view.lockfile_path()).unwrap())
+                .unwrap();
+        assert_eq!(
+            serde_json::to_value(written).unwrap(),
+            serde_json::to_value(lockfile).unwrap()
+        );
+    }
+
+    /// Locking for all systems lists the packages of every system in `options.systems`
+    #[test]
+    fn lock_all_systems_lists_packages_per_system() {
+        let (mut flox, _temp_dir_handle) = flox_instance();
+
+        let mut manifest = manifest::test::empty_catalog_manifest();
+        let (foo_iid, mut foo_descriptor, _) = lockfile::tests::fake_package("foo", None);
+        foo_descriptor.systems = None;
+        manifest
+            .install
+            .insert(foo_iid.clone(), foo_descriptor.into());
+        manifest.options.systems = Some(vec![
+            "system1".to_string(),
+            "system2".to_string(),
+            "system3".to_string(),
+        ]);
+        let mut env_view = new_core_environment(&flox, &toml::to_string(&manifest).unwrap());
+
+        let resolved = ["system1", "system2"]
+            .map(|system| ResolvedPackageGroup {
+                system: system.to_string(),
+                ..resolved_group(&foo_iid, "1.0")
+            })
+            .to_vec();
+        let mut mock_client = MockClient::new(None::<&str>).unwrap();
+        mock_client.push_resolve_response(resolved);
+        flox.catalog_client = Some(mock_client.into());
+
+        let systems_lock = env_view.lock_all_systems(&flox).unwrap();
+        assert!(env_view.lockfile_path().exists());
+        assert_eq!(systems_lock.packages.keys().collect::<Vec<_>>(), [
+            "system1", "system2", "system3"
+        ]);
+        assert_eq!(systems_lock.packages["system1"][0].install_id, foo_iid);
+        assert_eq!(systems_lock.packages["system2"][0].install_id, foo_iid);
+        assert!(systems_lock.packages["system3"].is_empty());
+    }
+
+    /// A dry run install resolves the new package without touching the environment
+    #[test]
+    fn install_dry_run_resolves_without_modifying() {
+        let (mut flox, _temp_dir_handle) = flox_instance();
+        let manifest = manifest::test::empty_catalog_manifest();
+        let manifest_contents = toml::to_string(&manifest).unwrap();
+        let env_view = new_core_environment(&flox, &manifest_contents);
+
+        let mut group = resolved_group("foo", "1.0");
+        group.system = flox.system.clone();
+        let mut mock_client = MockClient::new(None::<&str>).unwrap();
+        mock_client.push_resolve_response(vec![group]);
+        flox.catalog_client = Some(mock_client.into());
+
+        let dry_run = env_view
+            .install_dry_run(
+                &[PackageToInstall {
+                    id: "foo".to_string(),
+                    pkg_path: "foo".to_string(),
+                    version: None,
+                    input: None,
+                    flake: None,
+                    store_path: None,
+                }],
+                &flox,
+            )
+            .unwrap();
+
+        assert!(dry_run.new_manifest.is_some());
+        assert!(!dry_run.already_installed["foo"]);
+        assert_eq!(dry_run.added.len(), 1);
+        assert_eq!(dry_run.added[0].install_id, "foo");
+        assert_eq!(dry_run.added[0].version, "1.0");
+        // the outputs of the fake package are unknown
+        assert_eq!(
+            dry_run.closure_sizes,
+            BTreeMap::from([("foo".to_string(), None)])
+        );
+        assert_eq!(env_view.manifest_content().unwrap(), manifest_contents);
+        assert!(!env_view.lockfile_path().exists());
+    }
+
+    /// A dry run upgrade reports version changes without touching the lockfile
+    #[test]
+    fn upgrade_dry_run_reports_changes_without_modifying() {
+        let (mut flox, _temp_dir_handle) = flox_instance();
+
+        let mut manifest = manifest::test::empty_catalog_manifest();
+        let (foo_iid, foo_descriptor, foo_locked) = lockfile::tests::fake_package("foo", None);
+        manifest
+            .install
+            .insert(foo_iid.clone(), foo_descriptor.into());
+        let env_view = new_core_environment(&flox, &toml::to_string(&manifest).unwrap());
+        let lockfile_str = serde_json::to_string_pretty(&lockfile::LockedManifestCatalog {
+            version: Version,
+            packages: vec![foo_locked.clone()],
+            manifest,
+            flake_packages: vec![],
+            store_path_packages: vec![],
+            includes: vec![],
+        })
+        .unwrap();
+        fs::write(env_view.lockfile_path(), &lockfile_str).unwrap();
+
+        let mut mock_client = MockClient::new(None::<&str>).unwrap();
+        mock_client.push_resolve_response(vec![resolved_group(&foo_iid, "2.0")]);
+        flox.catalog_client = Some(mock_client.into());
+
+        let upgrades = env_view.upgrade_dry_run(&flox, &[]).unwrap();
+
+        assert_eq!(upgrades.len(), 1);
+        let (old, new) = &upgrades[0];
+        assert_eq!(old, &foo_locked);
+        assert_eq!(new.version, "2.0");
+        assert_eq!(new.derivation, "derivation-2.0");
+        assert_eq!(
+            fs::read_to_string(env_view.lockfile_path()).unwrap(),
+            lockfile_str
+        );
+    }
+
+    /// Locking with the catalog reports the groups being resolved
+    #[test]
+    fn lock_reports_progress() {
+        struct Recorder(Arc<Mutex<Vec<ProgressEvent>>>);
+        impl ProgressObserver for Recorder {
+            fn on_progress(&self, event: ProgressEvent) {
+                self.0.lock().unwrap().push(event);
+            }
+        }
+
+        let (mut flox, _temp_dir_handle) = flox_instance();
+        let mut manifest = manifest::test::empty_catalog_manifest();
+        let (foo_iid, foo_descriptor, _) = lockfile::tests::fake_package("foo", None);
+        manifest
+            .install
+            .insert(foo_iid.clone(), foo_descriptor.into());
+        let env_view = new_core_environment(&flox, &toml::to_string(&manifest).unwrap());
+
+        let mut mock_client = MockClient::new(None::<&str>).unwrap();
+        mock_client.push_resolve_response(vec![resolved_group(&foo_iid, "1.0")]);
+        flox.catalog_client = Some(mock_client.into());
+        let events = Arc::new(Mutex::new(vec![]));
+        flox.progress = Progress::new(Recorder(events.clone()));
+
+        env_view.lock_pure(&flox).unwrap();
+
+        assert_eq!(*events.lock().unwrap(), vec![
+            ProgressEvent::ResolvingGroup {
+                name: DEFAULT_GROUP_NAME.to_string()
+            }
+        ]);
+    }
+
+    /// The groups reported while locking include the packages of included environments
+    #[test]
+    fn lock_reports_progress_of_included_groups() {
+        struct Recorder(Arc<Mutex<Vec<ProgressEvent>>>);
+        impl ProgressObserver for Recorder {
+            fn on_progress(&self, event: ProgressEvent) {
+                self.0.lock().unwrap().push(event);
+            }
+        }
+
+        let (mut flox, _temp_dir_handle) = flox_instance();
+        let env_view = new_core_environment(&flox, indoc! {r#"
+            version = 1
+            include = ["base"]
+
+            [options]
+            systems = ["system"]
+        "#});
+        let base_dir = env_view.path().join("base");
+        fs::create_dir(&base_dir).unwrap();
+        fs::write(base_dir.join(MANIFEST_FILENAME), indoc! {r#"
+            version = 1
+
+            [install]
+            foo.pkg-path = "foo"
+        "#})
+        .unwrap();
+
+        let mut mock_client = MockClient::new(None::<&str>).unwrap();
+        mock_client.push_resolve_response(vec![resolved_group("foo", "1.0")]);
+        flox.catalog_client = Some(mock_client.into());
+        let events = Arc::new(Mutex::new(vec![]));
+        flox.progress = Progress::new(Recorder(events.clone()));
+
+        env_view.lock_pure(&flox).unwrap();
+
+        assert_eq!(*events.lock().unwrap(), vec![
+            ProgressEvent::ResolvingGroup {
+                name: DEFAULT_GROUP_NAME.to_string()
+            }
+        ]);
+    }
+
+    /// Failed operations are reported with the category of their error
+    #[test]
+    fn uninstall_reports_failure_event() {
+        struct Recorder(Arc<Mutex<Vec<OperationEvent>>>);
+        impl EventSink for Recorder {
+            fn on_event(&self, event: OperationEvent) {
+                self.0.lock().unwrap().push(event);
+            }
+        }
+
+        let (mut flox, _temp_dir_handle) = flox_instance();
+        let mut env_view = new_core_environment(&flox, "version = 1");
+        let events = Arc::new(Mutex::new(vec![]));
+        flox.events = Events::new(Recorder(events.clone()));
+
+        env_view
+            .uninstall(vec!["not-installed".to_string()], &flox)
+            .unwrap_err();
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].operation, Operation::Uninstall);
+        assert_eq!(events[0].outcome, Outcome::Failure(ErrorCategory::Manifest));
+    }
+
+    /// A lock following [CoreEnvironment::prefetch_resolution]
+    /// uses the prefetched resolution instead of querying the catalog
+    #[test]
+    fn lock_uses_prefetched_resolution() {
+        let (mut flox, _temp_dir_handle) = flox_instance();
+
+        let mut manifest = manifest::test::empty_catalog_manifest();
+        let (foo_iid, foo_descriptor, _) = lockfile::tests::fake_package("foo", None);
+        manifest
+            .install
+            .insert(foo_iid.clone(), foo_descriptor.into());
+        let mut env_view = new_core_environment(&flox, &toml::to_string(&manifest).unwrap());
+
+        let mut mock_client = MockClient::new(None::<&str>).unwrap();
+        mock_client.push_resolve_response(vec![ResolvedPackageGroup {
+            name: DEFAULT_GROUP_NAME.to_string(),
+            pages: vec![CatalogPage {
+                packages: Some(vec![ResolvedPackageDescriptor {
+                    attr_path: "foo".to_string(),
+                    broken: false,
+                    derivation: "derivation".to_string(),
+                    description: None,
+                    install_id: foo_iid.clone(),
+                    license: None,
+                    locked_url: "locked-url".to_string(),
+                    name: "foo".to_string(),
+                    outputs: None,
+                    outputs_to_install: None,
+                    pname: "foo".to_string(),
+                    rev: "rev".to_string(),
+                    rev_count: 42,
+                    rev_date: DateTime::<Utc>::MIN_UTC,
+                    scrape_date: DateTime::<Utc>::MIN_UTC,
+                    stabilities: None,
+                    unfree: None,
+                    version: "1.0".to_string(),
+                }]),
+                page: 1,
+                url: "url".to_string(),
+            }],
+            system: "system".to_string(),
+        }]);
+        flox.catalog_client = Some(mock_client.clone().into());
+
+        env_view.prefetch_resolution(&flox).unwrap().wait();
+        assert!(!flox.resolution_cache.is_empty());
+        assert_eq!(mock_client.resolve_calls(), 1);
+
+        let LockedManifest::Catalog(lockfile) = env_view.lock(&flox).unwrap() else {
+            panic!("expected a catalog lockfile");
+        };
+        assert_eq!(lockfile.packages.len(), 1);
+        assert_eq!(lockfile.packages[0].install_id, foo_iid);
+        assert_eq!(mock_client.resolve_calls(), 1, "lock queried the catalog");
+    }
+
+    /// Prepare a [PreparedMember] that replaces the manifest of `env` with `contents`
+    /// without locking or building it
+    fn prepared_member<'a>(
+        flox: &Flox,
+        env: &'a mut CoreEnvironment,
+        contents: &str,
+    ) -> PreparedMember<'a> {
+        let lock = env.lock_transaction().unwrap();
+        let manifest_hash = env.manifest_hash().unwrap();
+        let sandbox = env.make_sandbox(flox).unwrap();
+        let mut replacement = env.writable(sandbox.path()).unwrap();
+        replacement.update_manifest(contents).unwrap();
+        PreparedMember {
+            env,
+            replacement,
+            store_path: PathBuf::from("/store/path"),
+            manifest_hash,
+            _lock: lock,
+            _sandbox: sandbox,
+        }
+    }
+
+    /// Committing a composed transaction replaces all environments
+    #[test]
+    fn composed_transaction_replaces_all_environments() {
+        let (flox, _temp_dir_handle) = flox_instance();
+        let mut base = new_core_environment(&flox, "version = 1");
+        let mut dependent = new_core_environment(&flox, "version = 1");
+
+        let transaction = PreparedTransaction {
+            members: vec![
+                prepared_member(&flox, &mut base, "version = 1 # base"),
+                prepared_member(&flox, &mut dependent, "version = 1 # dependent"),
+            ],
+        };
+        let store_paths = transaction.commit().unwrap();
+
+        assert_eq!(store_paths.len(), 2);
+        assert_eq!(base.manifest_content().unwrap(), "version = 1 # base");
+        assert_eq!(
+            dependent.manifest_content().unwrap(),
+            "version = 1 # dependent"
+        );
+        assert!(!base.env_dir.with_extension("tmp").exists());
+        assert!(!dependent.env_dir.with_extension("tmp").exists());
+    }
+
+    /// If any environment of a composed transaction can't be replaced,
+    /// no environment is modified
+    #[test]
+    fn composed_transaction_is_atomic() {
+        let (flox, _temp_dir_handle) = flox_instance();
+        let mut base = new_core_environment(&flox, "version = 1");
+        let mut dependent = new_core_environment(&flox, "version = 1");
+        fs::create_dir(dependent.env_dir.with_extension("tmp")).unwrap();
+
+        let transaction = PreparedTransaction {
+            members: vec![
+                prepared_member(&flox, &mut base, "version = 1 # base"),
+                prepared_member(&flox, &mut dependent, "version = 1 # dependent"),
+            ],
+        };
+        let err = transaction
+            .commit()
+            .expect_err("should fail if a backup exists");
+
+        assert!(matches!(err, CoreEnvironmentError::PriorTransaction(_)));
+        assert_eq!(base.manifest_content().unwrap(), "version = 1");
+        assert_eq!(dependent.manifest_content().unwrap(), "version = 1");
+    }
+
+    /// If an environment fails to be replaced after others already were,
+    /// the replaced environments are restored
+    #[test]
+    fn composed_transaction_restores_replaced_environments() {
+        let (flox, _temp_dir_handle) = flox_instance();
+        let mut base = new_core_environment(&flox, "version = 1");
+        let mut dependent = new_core_environment(&flox, "version = 1");
+
+        let base_member = prepared_member(&flox, &mut base, "version = 1 # base");
+        let dependent_member = prepared_member(&flox, &mut dependent, "version = 1 # dependent");
+        // Copying the replacement of the second environment fails
+        fs::remove_dir_all(&dependent_member.replacement.env_dir).unwrap();
+        let transaction = PreparedTransaction {
+            members: vec![base_member, dependent_member],
+        };
+        let err = transaction
+            .commit()
+            .expect_err("should fail if a replacement can't be copied");
+
+        assert!(matches!(err, CoreEnvironmentError::Move(_)));
+        assert_eq!(base.manifest_content().unwrap(), "version = 1");
+        assert_eq!(dependent.manifest_content().unwrap(), "version = 1");
+        assert!(!base.env_dir.with_extension("tmp").exists());
+        assert!(!dependent.env_dir.with_extension("tmp").exists());
+    }
+
+    /// A transaction fails if another transaction holds the lock of the environment
+    #[test]
+    fn transaction_fails_while_environment_is_locked() {
+        let (flox, _temp_dir_handle) = flox_instance();
+        let mut env_view = new_core_environment(&flox, "version = 1");
+
+        let lock = env_view.lock_transaction().unwrap();
+        let err = env_view
+            .edit(&flox, "version = 1 # edited".to_string())
+            .expect_err("should fail while locked");
+        assert!(matches!(err, CoreEnvironmentError::EnvironmentBusy(_)));
+        assert_eq!(env_view.manifest_content().unwrap(), "version = 1");
+
+        drop(lock);
+        env_view
+            .lock_transaction()
+            .expect("lock should be released");
+    }
+
+    /// Relocating an environment moves its directory, but not onto another directory
+    #[test]
+    fn relocate_moves_environment_directory() {
+        let (flox, _temp_dir_handle) = flox_instance();
+        let mut env_view = new_core_environment(&flox, "version = 1");
+        let old_dir = env_view.path().to_path_buf();
+        let new_dir = flox.temp_dir.join("relocated");
+
+        env_view.relocate(&old_dir, &new_dir).unwrap();
+        assert!(!old_dir.exists());
+        assert_eq!(env_view.path(), new_dir);
+        assert_eq!(env_view.manifest_content().unwrap(), "version = 1");
+
+        fs::create_dir(&old_dir).unwrap();
+        let err = env_view.relocate(&new_dir, &old_dir).unwrap_err();
+        assert!(matches!(err, CoreEnvironmentError::RelocateTargetExists(_)));
+        assert_eq!(env_view.path(), new_dir);
+    }
+
+    /// An upgrade takes the transaction lock before resolving packages
+    #[test]
+    fn upgrade_fails_before_resolving_while_environment_is_locked() {
+        let (mut flox, _temp_dir_handle) = flox_instance();
+        let mut manifest = manifest::test::empty_catalog_manifest();
+        let (foo_iid, foo_descriptor, _) = lockfile::tests::fake_package("foo", None);
+        manifest.install.insert(foo_iid, foo_descriptor.into());
+        let mut env_view = new_core_environment(&flox, &toml::to_string(&manifest).unwrap());
+
+        let mock_client = MockClient::new(None::<&str>).unwrap();
+        flox.catalog_client = Some(mock_client.clone().into());
+
+        let _lock = env_view.lock_transaction().unwrap();
+        let err = env_view
+            .upgrade(&flox, &[])
+            .expect_err("should fail while locked");
+        assert!(matches!(err, CoreEnvironmentError::EnvironmentBusy(_)));
+        assert_eq!(mock_client.resolve_calls(), 0);
+    }
+
+    /// Changes of a transaction are applied together,
+    /// a failing change leaves the environment unmodified
+    #[test]
+    fn environment_transaction_applies_all_changes_or_none() {
+        let (flox, _temp_dir_handle) = flox_instance();
+        let manifest = indoc! {r#"
+            version = 1
+
+            [install]
+            hello.pkg-path = "hello"
+        "#};
+        let mut env_view = new_core_environment(&flox, manifest);
+
+        let err = env_view
+            .transaction()
+            .set_var("GREETING", "howdy")
+            .uninstall(["not-installed"])
+            .commit(&flox)
+            .expect_err("uninstalling a missing package should fail");
+        assert!(matches!(
+            err,
+            CoreEnvironmentError::ModifyToml(TomlEditError::PackageNotFound(_))
+        ));
+        assert_eq!(env_view.manifest_content().unwrap(), manifest);
+
+        let hello = PackageToInstall {
+            id: "hello".to_string(),
+            pkg_path: "hello".to_string(),
+            version: None,
+            input: None,
+            flake: None,
+            store_path: None,
+        };
+        let transaction = env_view
+            .transaction()
+            .install([hello])
+            .set_var("GREETING", "howdy")
+            .set_var("NAME", "flox")
+            .unset_option("allow.unfree");
+        assert_eq!(
+            transaction.descriptions.join("; "),
+            "installed packages: hello; set variables: GREETING, NAME; unset options: allow.unfree"
+        );
+    }
+
+    /// Fingerprints only change with the contents of the manifest
+    #[test]
+    fn fingerprint_ignores_formatting() {
+        let (flox, _temp_dir_handle) = flox_instance();
+        let env_view = new_core_environment(&flox, indoc! {r#"
+            version = 1
+
+            [vars]
+            GREETING = "howdy"
+        "#});
+        let fingerprint = env_view.fingerprint().unwrap();
+
+        fs::write(env_view.manifest_path(), indoc! {r#"
+            # a comment
+            version = 1
+            vars.GREETING   = "howdy"
+        "#})
+        .unwrap();
+        assert_eq!(env_view.fingerprint().unwrap(), fingerprint);
+
+        fs::write(env_view.manifest_path(), indoc! {r#"
+            version = 1
+            vars.GREETING = "hello"
+        "#})
+        .unwrap();
+        assert_ne!(env_view.fingerprint().unwrap(), fingerprint);
+    }
+
+    /// Unsetting variables that are not set leaves the manifest unchanged
+    #[test]
+    fn unset_vars_ignores_missing_variables() {
+        let (flox, _temp_dir_handle) = flox_instance();
+        let manifest = indoc! {r#"
+            version = 1
+
+            [vars]
+            GREETING = "howdy"
+        "#};
+        let mut env_view = new_core_environment(&flox, manifest);
+
+        let result = env_view.unset_vars(&flox, &["NAME".to_string()]).unwrap();
+        assert!(matches!(result, EditResult::Unchanged));
+        assert_eq!(env_view.manifest_content().unwrap(), manifest);
+    }
+
+    /// A transaction must not overwrite changes to the manifest
+    /// that were made while it was in progress
+    #[test]
+    fn composed_transaction_detects_concurrent_manifest_modification() {
+        let (flox, _temp_dir_handle) = flox_instance();
+        let mut env_view = new_core_environment(&flox, "version = 1");
+        let manifest_path = env_view.manifest_path();
+
+        let transaction = PreparedTransaction {
+            members: vec![prepared_member(
+                &flox,
+                &mut env_view,
+                "version = 1 # transaction",
+            )],
+        };
+        fs::write(&manifest_path, "version = 1 # user edit").unwrap();
+
+        let err = transaction
+            .commit()
+            .expect_err("should fail if the manifest was modified");
+        assert!(matches!(
+            err,
+            CoreEnvironmentError::ManifestModifiedConcurrently(_)
+        ));
+        assert_eq!(
+            fs::read_to_string(manifest_path).unwrap(),
+            "version = 1 # user edit"
+        );
+    }
+
     /// replacing an environment should fail if a backup exists
     #[test]
     fn detects_existing_backup() {
@@ -1312,6 +4319,104 @@ mod tests {
         assert!(matches!(err, CoreEnvironmentError::PriorTransaction(_)));
     }
 
+    /// edits that introduce a hook with a syntax error are rejected
+    /// before the environment is locked
+    #[test]
+    fn edit_rejects_hook_syntax_error() {
+        let (flox, _temp_dir_handle) = flox_instance();
+        let mut env_view = new_core_environment(&flox, "version = 1");
+
+        let err = env_view
+            .edit(
+                &flox,
+                "version = 1\n[hook]\non-activate = \"if true; then\"\n".to_string(),
+            )
+            .expect_err("broken hook should be rejected");
+
+        let CoreEnvironmentError::HookSyntax(errors) = err else {
+            panic!("expected a syntax error, got {err:?}");
+        };
+        assert_eq!(errors[0].script, "hook.on-activate");
+        assert_eq!(env_view.manifest_content().unwrap(), "version = 1");
+    }
+
+    /// sandboxes are created in the configured transaction directory
+    /// and removed when they are dropped
+    #[test]
+    fn sandbox_in_transaction_dir() {
+        let (mut flox, tempdir) = flox_instance();
+        let env_view = CoreEnvironment::new(tempfile::tempdir_in(&tempdir).unwrap().into_path());
+        let transaction_dir = tempdir.path().join("transactions");
+        flox.transaction_dir = Some(transaction_dir.clone());
+
+        let sandbox = env_view.make_sandbox(&flox).unwrap();
+        let sandbox_path = sandbox.path().to_path_buf();
+        assert_eq!(sandbox_path.parent(), Some(transaction_dir.as_path()));
+        assert!(same_filesystem(&sandbox_path, env_view.path()));
+
+        drop(sandbox);
+        assert!(!sandbox_path.exists());
+    }
+
+    /// Generations are only recorded for environments that keep local generations
+    #[test]
+    fn records_generations_only_if_enabled() {
+        let (flox, _temp_dir_handle) = flox_instance();
+        let env_view = new_core_environment(&flox, "version = 1");
+
+        env_view.record_generation(Path::new("/store/path"), "edited".to_string());
+        assert!(!env_view.generations().path().exists());
+
+        let env_view = env_view.with_local_generations();
+        env_view.record_generation(Path::new("/store/path"), "edited".to_string());
+        let metadata = env_view.generations().metadata().unwrap();
+        assert_eq!(metadata.generations.len(), 1);
+    }
+
+    /// Builds are reused only if their store path still exists
+    /// and the build settings didn't change
+    #[test]
+    fn build_cached_rebuilds_missing_or_differently_configured_builds() {
+        let (mut flox, tempdir) = flox_instance();
+        let lockfile_path = tempdir.path().join(LOCKFILE_FILENAME);
+        fs::write(&lockfile_path, r#"{"lockfile-version": 0}"#).unwrap();
+        let lockfile =
+            LockedManifest::read_from_file(&CanonicalPath::new(&lockfile_path).unwrap()).unwrap();
+        let store_path = tempdir.path().join("store-path");
+        fs::create_dir(&store_path).unwrap();
+
+        let builds = Mutex::new(0);
+        let build_cached = |flox: &Flox| {
+            CoreEnvironment::<ReadOnly>::build_cached(
+                flox,
+                &lockfile_path,
+                &lockfile,
+                &flox.system,
+                || {
+                    *builds.lock().unwrap() += 1;
+                    Ok(store_path.clone())
+                },
+            )
+            .unwrap()
+        };
+
+        assert_eq!(build_cached(&flox), store_path);
+        assert_eq!(build_cached(&flox), store_path);
+        assert_eq!(*builds.lock().unwrap(), 1, "unchanged build is reused");
+
+        flox.keep_failed = true;
+        build_cached(&flox);
+        assert_eq!(*builds.lock().unwrap(), 2, "keep_failed changes the build");
+
+        fs::remove_dir(&store_path).unwrap();
+        build_cached(&flox);
+        assert_eq!(
+            *builds.lock().unwrap(),
+            3,
+            "garbage collected build is rebuilt"
+        );
+    }
+
     /// creating backup should fail if env is readonly
     #[test]
     #[ignore = "On Ubuntu github runners this moving a read only directory succeeds.
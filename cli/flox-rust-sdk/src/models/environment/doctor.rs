@@ -0,0 +1,282 @@
+//! Common problems of an environment directory
+//!
+//! Environments can end up in states that make commands fail
+//! with errors that don't point to the cause,
+//! e.g. a manifest edited by hand but never locked,
+//! or the backup of an interrupted transaction blocking new ones.
+//! [diagnose] finds such problems without modifying the environment,
+//! and suggests a [Remediation] for each, e.g. for a `flox doctor` command.
+
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+use serde::Serialize;
+
+use super::out_links::OutLink;
+use crate::data::System;
+use crate::models::lockfile::{LockedManifest, LockedManifestCatalog};
+use crate::models::manifest::TypedManifest;
+use crate::utils::mtime_of;
+
+/// A problem found by [diagnose]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Problem {
+    /// The environment has no manifest
+    MissingManifest { path: PathBuf },
+    /// The manifest can't be parsed
+    InvalidManifest { message: String },
+    /// The environment was never locked
+    MissingLockfile,
+    /// The lockfile can't be parsed
+    InvalidLockfile { message: String },
+    /// The manifest was changed since the environment was locked
+    LockfileDrift,
+    /// The out-link doesn't exist, points to a store path that no longer exists,
+    /// or is older than the manifest
+    StaleOutLink { out_link: PathBuf },
+    /// The manifest doesn't support the current system
+    UnsupportedSystem {
+        system: System,
+        supported: Vec<System>,
+    },
+    /// The backup of an interrupted transaction was left behind
+    LeftoverBackup { path: PathBuf },
+    /// A file of the environment can't be read
+    Unreadable { path: PathBuf },
+}
+
+/// An action that resolves a [Problem]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Remediation {
+    /// Lock the environment, e.g. with `flox install` or `flox edit`
+    Lock,
+    /// Build and link the environment
+    Build,
+    /// Fix the manifest, e.g. with `flox edit`
+    Edit,
+    /// Restore a previous generation of the environment
+    Rollback,
+    /// Add `system` to `options.systems` in the manifest
+    AddSystem { system: System },
+    /// Remove the backup of an interrupted transaction
+    RemoveBackup { path: PathBuf },
+    /// Make `path` readable by the current user
+    FixPermissions { path: PathBuf },
+}
+
+impl Problem {
+    pub fn remediation(&self) -> Remediation {
+        match self {
+            Problem::MissingManifest { .. } => Remediation::Rollback,
+            Problem::InvalidManifest { .. } => Remediation::Edit,
+            Problem::MissingLockfile | Problem::InvalidLockfile { .. } | Problem::LockfileDrift => {
+                Remediation::Lock
+            },
+            Problem::StaleOutLink { .. } => Remediation::Build,
+            Problem::UnsupportedSystem { system, .. } => Remediation::AddSystem {
+                system: system.clone(),
+            },
+            Problem::LeftoverBackup { path } => Remediation::RemoveBackup { path: path.clone() },
+            Problem::Unreadable { path } => Remediation::FixPermissions { path: path.clone() },
+        }
+    }
+}
+
+/// A [Problem] and how to resolve it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Diagnosis {
+    pub problem: Problem,
+    pub remediation: Remediation,
+}
+
+impl From<Problem> for Diagnosis {
+    fn from(problem: Problem) -> Self {
+        Diagnosis {
+            remediation: problem.remediation(),
+            problem,
+        }
+    }
+}
+
+/// The problems found in an environment, see [diagnose]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DoctorReport {
+    pub diagnoses: Vec<Diagnosis>,
+}
+
+impl DoctorReport {
+    pub fn is_healthy(&self) -> bool {
+        self.diagnoses.is_empty()
+    }
+
+    pub fn problems(&self) -> impl Iterator<Item = &Problem> {
+        self.diagnoses.iter().map(|diagnosis| &diagnosis.problem)
+    }
+
+    fn push(&mut self, problem: Problem) {
+        self.diagnoses.push(problem.into());
+    }
+}
+
+/// Find common problems of the environment in `env_dir` on `system`
+///
+/// If `out_link` is given, it is checked to be up to date with the manifest.
+/// Checks that depend on a file that can't be read are skipped,
+/// so that only the root cause is reported.
+pub(super) fn diagnose(env_dir: &Path, system: &System, out_link: Option<&Path>) -> DoctorReport {
+    let mut report = DoctorReport::default();
+
+    let backup = env_dir.with_extension("tmp");
+    if backup.exists() {
+        report.push(Problem::LeftoverBackup { path: backup });
+    }
+
+    let manifest_path = env_dir.join(super::MANIFEST_FILENAME);
+    let manifest = match read_file(&manifest_path, &mut report) {
+        Some(contents) => match toml::from_str::<TypedManifest>(&contents) {
+            Ok(manifest) => Some(manifest),
+            Err(err) => {
+                report.push(Problem::InvalidManifest {
+                    message: err.message().to_string(),
+                });
+                None
+            },
+        },
+        None if !manifest_path.exists() => {
+            report.push(Problem::MissingManifest {
+                path: manifest_path.clone(),
+            });
+            None
+        },
+        None => None,
+    };
+
+    if let Some(TypedManifest::Catalog(manifest)) = &manifest {
+        if let Some(supported) = &manifest.options.systems {
+            if !supported.contains(system) {
+                report.push(Problem::UnsupportedSystem {
+                    system: system.clone(),
+                    supported: supported.clone(),
+                });
+            }
+        }
+    }
+
+    let lockfile_path = env_dir.join(super::LOCKFILE_FILENAME);
+    if !lockfile_path.exists() {
+        report.push(Problem::MissingLockfile);
+    } else if let Some(contents) = read_file(&lockfile_path, &mut report) {
+        match serde_json::from_str::<LockedManifest>(&contents) {
+            Ok(LockedManifest::Catalog(lockfile)) => {
+                if let Some(TypedManifest::Catalog(manifest)) = &manifest {
+                    // Included manifests are merged into the manifest of the lockfile,
+                    // so only the packages can be compared in that case
+                    let drifted =
+                        !LockedManifestCatalog::groups_to_resolve(manifest, Some(&lockfile))
+                            .is_empty()
+                            || (lockfile.includes.is_empty() && lockfile.manifest != **manifest);
+                    if drifted {
+                        report.push(Problem::LockfileDrift);
+                    }
+                }
+            },
+            // Lockfiles created by pkgdb don't have a typed manifest to compare to
+            Ok(LockedManifest::Pkgdb(_)) => {},
+            Err(err) => report.push(Problem::InvalidLockfile {
+                message: err.to_string(),
+            }),
+        }
+    }
+
+    if let Some(out_link) = out_link {
+        let linked = OutLink::read(out_link, true).and_then(|out_link| out_link.store_path);
+        let outdated = manifest.is_some() && mtime_of(&manifest_path) >= mtime_of(out_link);
+        if linked.is_none() || outdated {
+            report.push(Problem::StaleOutLink {
+                out_link: out_link.to_path_buf(),
+            });
+        }
+    }
+
+    report
+}
+
+/// Read `path`, reporting it as [Problem::Unreadable] if it exists but can't be read
+fn read_file(path: &Path, report: &mut DoctorReport) -> Option<String> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Some(contents),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(_) => {
+            report.push(Problem::Unreadable {
+                path: path.to_path_buf(),
+            });
+            None
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::models::environment::{LOCKFILE_FILENAME, MANIFEST_FILENAME};
+
+    #[test]
+    fn reports_missing_lockfile_and_leftover_backup() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let env_dir = tempdir.path().join("env");
+        fs::create_dir(&env_dir).unwrap();
+        fs::create_dir(env_dir.with_extension("tmp")).unwrap();
+        fs::write(
+            env_dir.join(MANIFEST_FILENAME),
+            "version = 1\n[options]\nsystems = [\"other-system\"]\n",
+        )
+        .unwrap();
+
+        let report = diagnose(&env_dir, &"system".to_string(), None);
+
+        assert_eq!(report.problems().cloned().collect::<Vec<_>>(), vec![
+            Problem::LeftoverBackup {
+                path: env_dir.with_extension("tmp")
+            },
+            Problem::UnsupportedSystem {
+                system: "system".to_string(),
+                supported: vec!["other-system".to_string()],
+            },
+            Problem::MissingLockfile,
+        ]);
+        assert_eq!(report.diagnoses[0].remediation, Remediation::RemoveBackup {
+            path: env_dir.with_extension("tmp")
+        });
+    }
+
+    #[test]
+    fn reports_lockfile_drift_and_missing_out_link() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let env_dir = tempdir.path().join("env");
+        fs::create_dir(&env_dir).unwrap();
+        fs::write(env_dir.join(MANIFEST_FILENAME), "version = 1").unwrap();
+        let mut lockfile = serde_json::json!({
+            "lockfile-version": 1,
+            "manifest": { "version": 1 },
+            "packages": [],
+        });
+        fs::write(env_dir.join(LOCKFILE_FILENAME), lockfile.to_string()).unwrap();
+
+        let report = diagnose(&env_dir, &"system".to_string(), None);
+        assert!(report.is_healthy(), "{report:?}");
+
+        lockfile["manifest"]["vars"] = serde_json::json!({ "FOO": "bar" });
+        fs::write(env_dir.join(LOCKFILE_FILENAME), lockfile.to_string()).unwrap();
+        let out_link = tempdir.path().join("out-link");
+
+        let report = diagnose(&env_dir, &"system".to_string(), Some(&out_link));
+        assert_eq!(report.problems().cloned().collect::<Vec<_>>(), vec![
+            Problem::LockfileDrift,
+            Problem::StaleOutLink { out_link },
+        ]);
+    }
+}
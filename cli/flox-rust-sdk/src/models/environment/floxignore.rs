@@ -0,0 +1,161 @@
+//! Files of an environment directory that are not part of the environment
+//!
+//! Tools run in an environment may leave large artifacts,
+//! e.g. `node_modules/` or `target/`, in the environment directory.
+//! A `.floxignore` file in the environment directory lists such files,
+//! so that they are not copied for transactions or into generations.
+//!
+//! Each line of a `.floxignore` is a pattern, similar to a `.gitignore`:
+//! - empty lines and lines starting with `#` are skipped
+//! - `*` matches any number of characters except `/`, `?` matches one
+//! - a trailing `/` only matches directories
+//! - patterns containing a `/` (other than a trailing one) are matched against
+//!   the path relative to the environment directory,
+//!   all other patterns are matched against the name of a file at any depth
+//!
+//! Files left behind by editors and the OS are always ignored.
+
+use std::io;
+use std::path::Path;
+
+pub const FLOXIGNORE_FILENAME: &str = ".floxignore";
+
+/// Patterns that are ignored whether or not they are listed in a `.floxignore`
+const DEFAULT_PATTERNS: &[&str] = &["*~", "*.swp", "*.swo", ".DS_Store"];
+
+#[derive(Debug, Clone, PartialEq)]
+struct IgnorePattern {
+    glob: String,
+    /// Whether the pattern is matched against the whole relative path
+    anchored: bool,
+    dir_only: bool,
+}
+
+impl IgnorePattern {
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (line, dir_only) = match line.strip_suffix('/') {
+            Some(line) => (line, true),
+            None => (line, false),
+        };
+        let anchored = line.contains('/');
+        Some(IgnorePattern {
+            glob: line.trim_start_matches('/').to_string(),
+            anchored,
+            dir_only,
+        })
+    }
+
+    fn matches(&self, relative_path: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let subject = if self.anchored {
+            relative_path.to_string_lossy()
+        } else {
+            match relative_path.file_name() {
+                Some(name) => name.to_string_lossy(),
+                None => return false,
+            }
+        };
+        glob_matches(self.glob.as_bytes(), subject.as_bytes())
+    }
+}
+
+/// Match `text` against a glob `pattern` of `*` and `?` wildcards,
+/// neither of which match a `/`
+fn glob_matches(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.first(), text.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_matches(&pattern[1..], text)
+                || (text.first().is_some_and(|c| *c != b'/') && glob_matches(pattern, &text[1..]))
+        },
+        (Some(b'?'), Some(c)) if *c != b'/' => glob_matches(&pattern[1..], &text[1..]),
+        (Some(p), Some(c)) if p == c => glob_matches(&pattern[1..], &text[1..]),
+        _ => false,
+    }
+}
+
+/// The ignored files of an environment directory
+#[derive(Debug, Clone, PartialEq)]
+pub struct FloxIgnore {
+    patterns: Vec<IgnorePattern>,
+}
+
+impl Default for FloxIgnore {
+    fn default() -> Self {
+        FloxIgnore {
+            patterns: DEFAULT_PATTERNS
+                .iter()
+                .filter_map(|pattern| IgnorePattern::parse(pattern))
+                .collect(),
+        }
+    }
+}
+
+impl FloxIgnore {
+    /// Read the `.floxignore` of `env_dir`, if it has one
+    pub fn read(env_dir: impl AsRef<Path>) -> Result<Self, io::Error> {
+        let contents = match std::fs::read_to_string(env_dir.as_ref().join(FLOXIGNORE_FILENAME)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        Ok(Self::parse(&contents))
+    }
+
+    /// Parse the contents of a `.floxignore`
+    pub fn parse(contents: &str) -> Self {
+        let mut floxignore = Self::default();
+        floxignore
+            .patterns
+            .extend(contents.lines().filter_map(IgnorePattern::parse));
+        floxignore
+    }
+
+    /// Whether the file or directory at `relative_path`,
+    /// relative to the environment directory, is ignored
+    ///
+    /// Contents of ignored directories are not checked separately,
+    /// callers are expected to skip ignored directories entirely.
+    pub fn is_ignored(&self, relative_path: impl AsRef<Path>, is_dir: bool) -> bool {
+        let relative_path = relative_path.as_ref();
+        self.patterns
+            .iter()
+            .any(|pattern| pattern.matches(relative_path, is_dir))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::*;
+
+    #[test]
+    fn ignores_matching_files() {
+        let floxignore = FloxIgnore::parse(indoc! {"
+            # build artifacts
+            node_modules/
+            *.log
+            cache/*.tmp
+        "});
+
+        assert!(floxignore.is_ignored("node_modules", true));
+        assert!(floxignore.is_ignored("nested/node_modules", true));
+        assert!(!floxignore.is_ignored("node_modules", false));
+        assert!(floxignore.is_ignored("build.log", false));
+        assert!(floxignore.is_ignored("nested/build.log", false));
+        assert!(floxignore.is_ignored("cache/a.tmp", false));
+        assert!(!floxignore.is_ignored("nested/cache/a.tmp", false));
+        assert!(!floxignore.is_ignored("cache/nested/a.tmp", false));
+        assert!(!floxignore.is_ignored("manifest.toml", false));
+        // defaults
+        assert!(floxignore.is_ignored("manifest.toml.swp", false));
+        assert!(floxignore.is_ignored(".DS_Store", false));
+    }
+}
@@ -0,0 +1,241 @@
+//! Syntax checks of the hook and profile scripts of a manifest
+//!
+//! A script with a syntax error breaks the activation of an environment
+//! for everyone who uses it.
+//! [check_changed_scripts] runs the scripts that an edit introduces or changes
+//! through the syntax check of the shell that runs them (`<shell> -n`),
+//! so that such an edit fails before it is committed.
+
+use std::fmt::Display;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+use log::debug;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::utils::CommandExt;
+
+/// The shell that checks scripts that aren't run by a specific shell,
+/// i.e. `profile.common`, if no other shell is configured
+pub const DEFAULT_HOOK_CHECK_SHELL: &str = "sh";
+
+#[derive(Debug, Error)]
+pub enum HookCheckError {
+    #[error("failed to parse manifest")]
+    ParseManifest(#[source] toml::de::Error),
+    #[error("failed to write script for syntax check")]
+    WriteScript(#[source] io::Error),
+    #[error("failed to call '{0}'")]
+    CallShell(String, #[source] io::Error),
+}
+
+/// A syntax error in a script of the manifest
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HookSyntaxError {
+    /// Where the script is defined in the manifest, e.g. `hook.on-activate`
+    pub script: String,
+    /// The shell that reported the error
+    pub shell: String,
+    /// The line of the script the error was reported for, if any
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl Display for HookSyntaxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}, line {line}: {}", self.script, self.message),
+            None => write!(f, "{}: {}", self.script, self.message),
+        }
+    }
+}
+
+/// A script of the manifest and the shell that runs it
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Script {
+    location: String,
+    shell: String,
+    contents: String,
+}
+
+/// Collect the hook and profile scripts of `manifest`
+///
+/// `hook.on-activate` is run by bash,
+/// `profile.bash` and `profile.zsh` by their respective shells,
+/// and `profile.common` by any shell, so it is checked with `common_shell`.
+fn scripts(manifest: &toml::Table, common_shell: &str) -> Vec<Script> {
+    let script = |table: &str, key: &str, shell: &str| {
+        let contents = manifest.get(table)?.get(key)?.as_str()?;
+        Some(Script {
+            location: format!("{table}.{key}"),
+            shell: shell.to_string(),
+            contents: contents.to_string(),
+        })
+    };
+    [
+        script("hook", "on-activate", "bash"),
+        script("profile", "common", common_shell),
+        script("profile", "bash", "bash"),
+        script("profile", "zsh", "zsh"),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+/// Check the syntax of the scripts of `new_manifest`
+/// that are not in `old_manifest` or differ from it
+///
+/// Scripts are written to a temporary directory in `work_dir` to be checked.
+/// `common_shell` checks `profile.common`, see [DEFAULT_HOOK_CHECK_SHELL].
+/// Scripts whose shell is not installed are skipped.
+pub(super) fn check_changed_scripts(
+    old_manifest: &str,
+    new_manifest: &str,
+    common_shell: Option<&str>,
+    work_dir: &Path,
+) -> Result<Vec<HookSyntaxError>, HookCheckError> {
+    let common_shell = common_shell.unwrap_or(DEFAULT_HOOK_CHECK_SHELL);
+    // A previous manifest that can't be parsed has no scripts to compare to
+    let old_scripts = toml::from_str(old_manifest)
+        .map(|old_manifest| scripts(&old_manifest, common_shell))
+        .unwrap_or_default();
+    let new_manifest: toml::Table =
+        toml::from_str(new_manifest).map_err(HookCheckError::ParseManifest)?;
+    let changed = scripts(&new_manifest, common_shell)
+        .into_iter()
+        .filter(|script| !old_scripts.contains(script))
+        .collect::<Vec<_>>();
+    if changed.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let script_dir = tempfile::Builder::new()
+        .prefix(".flox-hook-check-")
+        .tempdir_in(work_dir)
+        .map_err(HookCheckError::WriteScript)?;
+    let mut errors = vec![];
+    for script in changed {
+        let path = script_dir.path().join(format!("{}.sh", script.location));
+        std::fs::write(&path, &script.contents).map_err(HookCheckError::WriteScript)?;
+
+        let mut command = Command::new(&script.shell);
+        command.arg("-n").arg(&path);
+        debug!(
+            "checking syntax of {} with: {}",
+            script.location,
+            command.display()
+        );
+        let output = match command.output() {
+            Ok(output) => output,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                debug!(
+                    "{} is not installed, skipping {}",
+                    script.shell, script.location
+                );
+                continue;
+            },
+            Err(e) => return Err(HookCheckError::CallShell(script.shell, e)),
+        };
+        if output.status.success() {
+            continue;
+        }
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let mut diagnostics = parse_diagnostics(&stderr, &path);
+        if diagnostics.is_empty() {
+            diagnostics.push((None, stderr.trim().to_string()));
+        }
+        errors.extend(
+            diagnostics
+                .into_iter()
+                .map(|(line, message)| HookSyntaxError {
+                    script: script.location.clone(),
+                    shell: script.shell.clone(),
+                    line,
+                    message,
+                }),
+        );
+    }
+    Ok(errors)
+}
+
+/// Parse the errors reported for the script at `path` by `<shell> -n`
+///
+/// Shells report errors in slightly different formats:
+///
+/// * bash: `<path>: line 3: syntax error near unexpected token 'fi'`
+/// * dash: `<path>: 3: Syntax error: "fi" unexpected`
+/// * zsh: `<path>:3: parse error near 'fi'`
+fn parse_diagnostics(stderr: &str, path: &Path) -> Vec<(Option<usize>, String)> {
+    let prefix = format!("{}:", path.display());
+    stderr
+        .lines()
+        .filter_map(|line| {
+            let rest = line.strip_prefix(&prefix)?.trim_start();
+            let rest = rest.strip_prefix("line ").unwrap_or(rest);
+            match rest.split_once(':') {
+                Some((number, message)) if number.parse::<usize>().is_ok() => {
+                    Some((number.parse().ok(), message.trim().to_string()))
+                },
+                _ => Some((None, rest.trim().to_string())),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn parses_diagnostics_of_shells() {
+        let path = Path::new("/tmp/hook.on-activate.sh");
+        let stderr = "\
+/tmp/hook.on-activate.sh: line 3: syntax error near unexpected token `fi'
+/tmp/hook.on-activate.sh: 4: Syntax error: end of file unexpected
+/tmp/hook.on-activate.sh:5: parse error near `fi'
+unrelated output";
+
+        assert_eq!(parse_diagnostics(stderr, path), vec![
+            (
+                Some(3),
+                "syntax error near unexpected token `fi'".to_string()
+            ),
+            (Some(4), "Syntax error: end of file unexpected".to_string()),
+            (Some(5), "parse error near `fi'".to_string()),
+        ]);
+    }
+
+    /// Only scripts that changed are checked
+    #[test]
+    fn checks_changed_scripts() {
+        let work_dir = tempfile::tempdir().unwrap();
+        let broken = indoc::indoc! {r#"
+            version = 1
+
+            [hook]
+            on-activate = """
+              if true; then
+                echo hello
+            """
+        "#};
+
+        let errors = check_changed_scripts("version = 1", broken, None, work_dir.path()).unwrap();
+        assert_eq!(errors.len(), 1, "{errors:?}");
+        assert_eq!(errors[0].script, "hook.on-activate");
+        assert_eq!(errors[0].shell, "bash");
+        assert!(errors[0].line.is_some());
+
+        let unchanged = format!("{broken}\n[vars]\nFOO = \"bar\"\n");
+        let errors = check_changed_scripts(broken, &unchanged, None, work_dir.path()).unwrap();
+        assert_eq!(errors, vec![]);
+
+        // The scripts are removed after the check
+        assert_eq!(std::fs::read_dir(work_dir.path()).unwrap().count(), 0);
+    }
+}
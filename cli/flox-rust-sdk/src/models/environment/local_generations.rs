@@ -0,0 +1,419 @@
+//! Generations of a [CoreEnvironment](super::CoreEnvironment) recorded on the local filesystem
+//!
+//! Managed environments track their generations in a floxmeta branch,
+//! see [super::generations].
+//! In addition, every successful transaction on a `CoreEnvironment`
+//! records the resulting manifest, lockfile, and store path
+//! in a directory next to the environment directory,
+//! so that a bad change can be inspected and rolled back locally.
+//!
+//! Example file layout for an environment in `.flox/env`:
+//!
+//! ```ignore
+//! .flox/env.generations/
+//! ├── 1
+//! │  ├── manifest.toml
+//! │  └── manifest.lock
+//! ├── 2
+//! │  └── manifest.toml (lockfile is optional)
+//! ├── ... N
+//! └── metadata.json
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::debug;
+
+use super::generations::GenerationId;
+use super::{LOCKFILE_FILENAME, MANIFEST_FILENAME};
+use crate::data::{System, Version};
+use crate::models::lockfile::{
+    LockedManifest,
+    LockedManifestError,
+    PackageChange,
+    TypedLockedManifestPkgdb,
+};
+
+const LOCAL_GENERATIONS_METADATA_FILE: &str = "metadata.json";
+
+#[derive(Debug, Error)]
+pub enum LocalGenerationsError {
+    #[error("could not read generations metadata file")]
+    ReadMetadata(#[source] std::io::Error),
+    #[error("could not parse generations metadata")]
+    ParseMetadata(#[source] serde_json::Error),
+    #[error("could not open temporary file for generations metadata")]
+    OpenTmpMetadata(#[source] std::io::Error),
+    #[error("could not write generations metadata")]
+    WriteMetadata(#[source] serde_json::Error),
+    #[error("could not rename temporary generations metadata file")]
+    RenameMetadata(#[source] tempfile::PersistError),
+
+    #[error("generation {0} not found")]
+    GenerationNotFound(usize),
+    #[error("could not create generation directory")]
+    CreateGeneration(#[source] std::io::Error),
+    #[error("could not copy environment files into generation")]
+    CopyFiles(#[source] std::io::Error),
+    #[error("could not read manifest of generation {0}")]
+    ReadManifest(usize, #[source] std::io::Error),
+    #[error("could not read lockfile of generation {0}")]
+    ReadLockfile(usize, #[source] std::io::Error),
+    #[error("could not parse lockfile of generation {0}")]
+    ParseLockfile(usize, #[source] serde_json::Error),
+    #[error("could not parse lockfile of generation {0}")]
+    ParsePkgdbLockfile(usize, #[source] Box<LockedManifestError>),
+}
+
+/// Metadata for all locally recorded generations of an environment
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LocalGenerationsMetadata {
+    /// The generation the environment currently matches,
+    /// `None` if no generation was recorded yet
+    pub current_gen: Option<GenerationId>,
+    /// Metadata for all generations of the environment.
+    /// Entries in this map match up 1-to-1 with the generation folders.
+    pub generations: BTreeMap<GenerationId, LocalGenerationMetadata>,
+    /// Schema version of the metadata file
+    #[serde(default)]
+    version: Version<1>,
+}
+
+/// Metadata for a single locally recorded generation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LocalGenerationMetadata {
+    /// unix timestamp of the creation time of this generation
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub created: DateTime<Utc>,
+    /// message describing the change from the previous generation
+    pub description: String,
+    /// store path of the built generation
+    pub store_path: Option<PathBuf>,
+}
+
+/// The difference between two generations
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenerationDiff {
+    pub old_manifest: String,
+    pub new_manifest: String,
+    /// Packages added, removed, or changed in version for a single system
+    pub packages: Vec<PackageChange>,
+}
+
+impl GenerationDiff {
+    /// Whether the manifests of both generations differ
+    pub fn manifest_changed(&self) -> bool {
+        self.old_manifest != self.new_manifest
+    }
+}
+
+/// The locally recorded generations of an environment
+#[derive(Debug, Clone)]
+pub struct LocalGenerations {
+    path: PathBuf,
+}
+
+impl LocalGenerations {
+    /// Open the generations stored in `path`, which may not exist yet
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// The directory containing the generations
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read the generations metadata
+    ///
+    /// Returns empty metadata if no generation was recorded yet.
+    pub fn metadata(&self) -> Result<LocalGenerationsMetadata, LocalGenerationsError> {
+        let path = self.path.join(LOCAL_GENERATIONS_METADATA_FILE);
+        if !path.exists() {
+            debug!("no generations recorded in {}", self.path.display());
+            return Ok(LocalGenerationsMetadata::default());
+        }
+        let file = fs::File::open(path).map_err(LocalGenerationsError::ReadMetadata)?;
+        serde_json::from_reader(BufReader::new(file)).map_err(LocalGenerationsError::ParseMetadata)
+    }
+
+    /// Read the manifest of a generation
+    pub fn manifest(&self, generation: usize) -> Result<String, LocalGenerationsError> {
+        let path = self.existing_generation_path(generation)?;
+        fs::read_to_string(path.join(MANIFEST_FILENAME))
+            .map_err(|e| LocalGenerationsError::ReadManifest(generation, e))
+    }
+
+    /// Read the lockfile of a generation, if the generation was locked
+    pub fn lockfile(&self, generation: usize) -> Result<Option<String>, LocalGenerationsError> {
+        let path = self
+            .existing_generation_path(generation)?
+            .join(LOCKFILE_FILENAME);
+        if !path.exists() {
+            return Ok(None);
+        }
+        fs::read_to_string(path)
+            .map(Some)
+            .map_err(|e| LocalGenerationsError::ReadLockfile(generation, e))
+    }
+
+    /// Record the manifest and lockfile in `env_dir` as a new generation
+    /// and set it as the current generation.
+    ///
+    /// Returns the number of the new generation.
+    pub fn add_generation(
+        &self,
+        env_dir: impl AsRef<Path>,
+        store_path: Option<PathBuf>,
+        description: String,
+    ) -> Result<usize, LocalGenerationsError> {
+        let mut metadata = self.metadata()?;
+        let generation = metadata.generations.keys().max().map_or(1, |max| **max + 1);
+
+        let generation_path = self.generation_path(generation);
+        // A leftover directory of a generation that was never recorded
+        if generation_path.exists() {
+            fs::remove_dir_all(&generation_path)
+                .map_err(LocalGenerationsError::CreateGeneration)?;
+        }
+        fs::create_dir_all(&generation_path).map_err(LocalGenerationsError::CreateGeneration)?;
+        for file in [MANIFEST_FILENAME, LOCKFILE_FILENAME] {
+            let source = env_dir.as_ref().join(file);
+            if source.exists() {
+                fs::copy(source, generation_path.join(file))
+                    .map_err(LocalGenerationsError::CopyFiles)?;
+            }
+        }
+
+        metadata
+            .generations
+            .insert(generation.into(), LocalGenerationMetadata {
+                created: Utc::now(),
+                description,
+                store_path,
+            });
+        metadata.current_gen = Some(generation.into());
+        self.write_metadata(&metadata)?;
+
+        debug!(
+            "recorded generation {generation} in {}",
+            self.path.display()
+        );
+        Ok(generation)
+    }
+
+    /// Set an existing generation as the current generation
+    pub fn set_current_generation(&self, generation: usize) -> Result<(), LocalGenerationsError> {
+        let mut metadata = self.metadata()?;
+        if !metadata.generations.contains_key(&generation.into()) {
+            return Err(LocalGenerationsError::GenerationNotFound(generation));
+        }
+        metadata.current_gen = Some(generation.into());
+        self.write_metadata(&metadata)
+    }
+
+    /// Compare the manifests and the packages locked for `system`
+    /// of two generations
+    pub fn diff(
+        &self,
+        old: usize,
+        new: usize,
+        system: &System,
+    ) -> Result<GenerationDiff, LocalGenerationsError> {
+        let old_packages = self.package_versions(old, system)?;
+        let new_packages = self.package_versions(new, system)?;
+
+        let install_ids = old_packages
+            .keys()
+            .chain(new_packages.keys())
+            .collect::<BTreeSet<_>>();
+        let packages = install_ids
+            .into_iter()
+            .filter_map(|install_id| {
+                let old_version = old_packages.get(install_id);
+                let new_version = new_packages.get(install_id);
+                if old_version == new_version {
+                    return None;
+                }
+                Some(PackageChange {
+                    system: system.clone(),
+                    install_id: install_id.clone(),
+                    old_version: old_version.cloned().flatten(),
+                    new_version: new_version.cloned().flatten(),
+                })
+            })
+            .collect();
+
+        Ok(GenerationDiff {
+            old_manifest: self.manifest(old)?,
+            new_manifest: self.manifest(new)?,
+            packages,
+        })
+    }
+
+    /// The versions of the packages locked for `system` in a generation,
+    /// keyed by install id
+    fn package_versions(
+        &self,
+        generation: usize,
+        system: &System,
+    ) -> Result<BTreeMap<String, Option<String>>, LocalGenerationsError> {
+        let Some(lockfile) = self.lockfile(generation)? else {
+            return Ok(BTreeMap::new());
+        };
+        let lockfile: LockedManifest = serde_json::from_str(&lockfile)
+            .map_err(|e| LocalGenerationsError::ParseLockfile(generation, e))?;
+        let packages = match lockfile {
+            LockedManifest::Catalog(lockfile) => lockfile.list_packages(system),
+            LockedManifest::Pkgdb(lockfile) => TypedLockedManifestPkgdb::try_from(lockfile)
+                .map_err(|e| LocalGenerationsError::ParsePkgdbLockfile(generation, Box::new(e)))?
+                .list_packages(system),
+        };
+        Ok(packages
+            .into_iter()
+            .map(|package| (package.install_id, package.info.version))
+            .collect())
+    }
+
+    fn generation_path(&self, generation: usize) -> PathBuf {
+        self.path.join(generation.to_string())
+    }
+
+    fn existing_generation_path(
+        &self,
+        generation: usize,
+    ) -> Result<PathBuf, LocalGenerationsError> {
+        if !self
+            .metadata()?
+            .generations
+            .contains_key(&generation.into())
+        {
+            return Err(LocalGenerationsError::GenerationNotFound(generation));
+        }
+        Ok(self.generation_path(generation))
+    }
+
+    /// Write the metadata file atomically,
+    /// so that it only ever refers to completely written generations.
+    fn write_metadata(
+        &self,
+        metadata: &LocalGenerationsMetadata,
+    ) -> Result<(), LocalGenerationsError> {
+        let temp_file = tempfile::NamedTempFile::new_in(&self.path)
+            .map_err(LocalGenerationsError::OpenTmpMetadata)?;
+        serde_json::to_writer_pretty(BufWriter::new(&temp_file), metadata)
+            .map_err(LocalGenerationsError::WriteMetadata)?;
+        temp_file
+            .persist(self.path.join(LOCAL_GENERATIONS_METADATA_FILE))
+            .map_err(LocalGenerationsError::RenameMetadata)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::models::lockfile::tests::fake_package;
+
+    /// Write an environment with the given manifest and locked (name, version) pairs to `dir`
+    fn write_env(dir: &Path, manifest: &str, packages: Vec<(&str, &str)>) {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join(MANIFEST_FILENAME), manifest).unwrap();
+        let packages = packages
+            .into_iter()
+            .map(|(name, version)| {
+                let (_, _, mut package) = fake_package(name, None);
+                package.version = version.to_string();
+                serde_json::to_value(package).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let lockfile = serde_json::json!({
+            "lockfile-version": 1,
+            "manifest": toml::from_str::<toml::Value>(manifest).unwrap(),
+            "packages": packages,
+        });
+        fs::write(dir.join(LOCKFILE_FILENAME), lockfile.to_string()).unwrap();
+    }
+
+    #[test]
+    fn add_generation_records_files_and_sets_current() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let env_dir = tempdir.path().join("env");
+        let generations = LocalGenerations::new(tempdir.path().join("env.generations"));
+        assert_eq!(generations.metadata().unwrap().current_gen, None);
+
+        write_env(&env_dir, "version = 1", vec![]);
+        let first = generations
+            .add_generation(&env_dir, None, "first".to_string())
+            .unwrap();
+        write_env(&env_dir, "version = 1 # second", vec![]);
+        let second = generations
+            .add_generation(&env_dir, None, "second".to_string())
+            .unwrap();
+
+        assert_eq!((first, second), (1, 2));
+        let metadata = generations.metadata().unwrap();
+        assert_eq!(metadata.current_gen, Some(2.into()));
+        assert_eq!(metadata.generations[&1.into()].description, "first");
+        assert_eq!(generations.manifest(1).unwrap(), "version = 1");
+        assert!(generations.lockfile(1).unwrap().is_some());
+
+        generations.set_current_generation(1).unwrap();
+        assert_eq!(generations.metadata().unwrap().current_gen, Some(1.into()));
+        assert!(matches!(
+            generations.set_current_generation(3),
+            Err(LocalGenerationsError::GenerationNotFound(3))
+        ));
+    }
+
+    #[test]
+    fn diff_reports_package_changes() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let env_dir = tempdir.path().join("env");
+        let generations = LocalGenerations::new(tempdir.path().join("env.generations"));
+
+        write_env(&env_dir, "version = 1", vec![
+            ("hello", "2.12"),
+            ("curl", "8.0"),
+        ]);
+        generations
+            .add_generation(&env_dir, None, "first".to_string())
+            .unwrap();
+        write_env(&env_dir, "version = 1 # upgraded", vec![(
+            "hello", "2.12.1",
+        )]);
+        generations
+            .add_generation(&env_dir, None, "second".to_string())
+            .unwrap();
+
+        let system = "system".to_string();
+        let diff = generations.diff(1, 2, &system).unwrap();
+        assert!(diff.manifest_changed());
+        assert_eq!(diff.packages, vec![
+            PackageChange {
+                system: system.clone(),
+                install_id: "curl_install_id".to_string(),
+                old_version: Some("8.0".to_string()),
+                new_version: None,
+            },
+            PackageChange {
+                system: system.clone(),
+                install_id: "hello_install_id".to_string(),
+                old_version: Some("2.12".to_string()),
+                new_version: Some("2.12.1".to_string()),
+            },
+        ]);
+    }
+}
@@ -7,6 +7,7 @@ use thiserror::Error;
 
 use super::core_environment::CoreEnvironment;
 use super::generations::{Generations, GenerationsError};
+use super::out_links::OutLink;
 use super::path_environment::PathEnvironment;
 use super::{
     gcroots_dir,
@@ -39,7 +40,7 @@ use crate::models::env_registry::{
 use crate::models::environment_ref::{EnvironmentName, EnvironmentOwner};
 use crate::models::floxmeta::{floxmeta_git_options, FloxMeta, FloxMetaError};
 use crate::models::lockfile::LockedManifest;
-use crate::models::manifest::PackageToInstall;
+use crate::models::manifest::{ManifestEdit, PackageToInstall};
 use crate::models::pkgdb::UpgradeResult;
 use crate::providers::git::{
     GitCommandBranchHashError,
@@ -300,6 +301,38 @@ impl Environment for ManagedEnvironment {
         Ok(result)
     }
 
+    /// Atomically apply structured edits to the manifest of this environment,
+    /// ensuring that it still builds
+    fn edit_with_patch(
+        &mut self,
+        flox: &Flox,
+        edits: &[ManifestEdit],
+    ) -> Result<EditResult, EnvironmentError> {
+        let mut generations = self
+            .generations()
+            .writable(flox.temp_dir.clone())
+            .map_err(ManagedEnvironmentError::CreateFloxmetaDir)?;
+        let mut temporary = generations
+            .get_current_generation()
+            .map_err(ManagedEnvironmentError::CreateGenerationFiles)?;
+
+        let result = temporary.edit_with_patch(flox, edits)?;
+
+        if result == EditResult::Unchanged {
+            return Ok(result);
+        }
+
+        let store_path = result.store_path();
+
+        generations
+            .add_generation(&mut temporary, "edited manifest".to_string())
+            .map_err(ManagedEnvironmentError::CommitGeneration)?;
+        self.lock_pointer()?;
+        temporary.link(flox, &self.out_link, &store_path)?;
+
+        Ok(result)
+    }
+
     /// Atomically update this environment's inputs
     fn update(
         &mut self,
@@ -381,7 +414,9 @@ impl Environment for ManagedEnvironment {
             out_link_modified_at: {out_link_modified_at:?}"
         );
 
-        if pointer_lock_modified_at >= out_link_modified_at {
+        // The linked store path may have been garbage collected,
+        // e.g. if the project was moved, leaving a dangling out-link
+        if pointer_lock_modified_at >= out_link_modified_at || !self.out_link.exists() {
             self.build(flox)?;
         }
 
@@ -425,11 +460,27 @@ impl Environment for ManagedEnvironment {
         Ok(path)
     }
 
+    /// List the out-link of the environment in the gc roots directory of its owner
+    fn out_links(&self, _flox: &Flox) -> Result<Vec<OutLink>, EnvironmentError> {
+        Ok(OutLink::read(&self.out_link, true).into_iter().collect())
+    }
+
     /// Returns the environment name
     fn name(&self) -> EnvironmentName {
         self.pointer.name.clone()
     }
 
+    /// Managed environments are named on FloxHub and can't be renamed locally
+    fn rename(&mut self, _flox: &Flox, _new_name: EnvironmentName) -> Result<(), EnvironmentError> {
+        Err(EnvironmentError::RenameUnsupported)
+    }
+
+    /// Managed environments can't be moved,
+    /// their generations are tracked on a branch named after their location
+    fn relocate(&mut self, _flox: &Flox, _new_parent: &Path) -> Result<(), EnvironmentError> {
+        Err(EnvironmentError::RelocateUnsupported)
+    }
+
     /// Delete the Environment
     fn delete(self, flox: &Flox) -> Result<(), EnvironmentError> {
         fs::remove_dir_all(&self.path)
@@ -3,19 +3,28 @@ use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::{env, fs, io};
 
+use indexmap::IndexMap;
 use log::debug;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use url::Url;
 use walkdir::WalkDir;
 
+use self::floxignore::FloxIgnore;
 use self::managed_environment::ManagedEnvironmentError;
+use self::out_links::{remove_stale_out_links, OutLink, OutLinkError};
 use self::remote_environment::RemoteEnvironmentError;
 use super::container_builder::ContainerBuilder;
 use super::env_registry::EnvRegistryError;
 use super::environment_ref::{EnvironmentName, EnvironmentOwner};
-use super::lockfile::{LockedManifest, LockedManifestPkgdb};
-use super::manifest::PackageToInstall;
+use super::lockfile::{
+    LockedManifest,
+    LockedManifestError,
+    LockedManifestPkgdb,
+    LockfileDiff,
+    TypedLockedManifestPkgdb,
+};
+use super::manifest::{ManifestEdit, PackageToInstall, ServiceDescriptor, UninstallResult};
 use super::pkgdb::UpgradeResult;
 use crate::data::{CanonicalPath, CanonicalizeError, Version};
 use crate::flox::{Flox, Floxhub};
@@ -28,13 +37,41 @@ use crate::providers::git::{
 };
 use crate::utils::copy_file_without_permissions;
 
+pub mod activation;
+pub mod build_cache;
 mod core_environment;
-pub use core_environment::{test_helpers, CoreEnvironment, CoreEnvironmentError, EditResult};
+pub mod doctor;
+pub mod floxignore;
+pub mod hook_check;
+pub use core_environment::{
+    test_helpers,
+    ActivationChange,
+    ComposedTransaction,
+    CoreEnvironment,
+    CoreEnvironmentError,
+    EditResult,
+    EnvironmentTransaction,
+    InstallDryRun,
+    ManifestChanges,
+    MigrationResult,
+    PrefetchHandle,
+    PreparedTransaction,
+    RequiredAction,
+    SystemsLock,
+};
 
 pub mod generations;
+pub mod local_generations;
 pub mod managed_environment;
+pub mod out_links;
 pub mod path_environment;
 pub mod remote_environment;
+pub mod reproducibility;
+pub mod scheduler;
+pub mod snapshots;
+pub mod store;
+pub mod store_verify;
+pub mod templates;
 
 pub const CATALOG_JSON: &str = "catalog.json";
 // don't forget to update the man page
@@ -86,6 +123,19 @@ pub struct UpdateResult {
     pub store_path: Option<PathBuf>,
 }
 
+impl UpdateResult {
+    /// Compute which inputs and packages were changed by the update
+    pub fn diff(&self) -> Result<LockfileDiff, LockedManifestError> {
+        let old = self
+            .old_lockfile
+            .clone()
+            .map(TypedLockedManifestPkgdb::try_from)
+            .transpose()?;
+        let new = TypedLockedManifestPkgdb::try_from(self.new_lockfile.clone())?;
+        Ok(LockfileDiff::new(old.as_ref(), &new))
+    }
+}
+
 /// The result of an installation attempt that contains the new manifest contents
 /// along with whether each package was already installed
 #[derive(Debug)]
@@ -101,6 +151,8 @@ pub struct InstallationAttempt {
 #[derive(Debug)]
 pub struct UninstallationAttempt {
     pub new_manifest: Option<String>,
+    /// The outcome for each package that was requested to be uninstalled
+    pub results: IndexMap<String, UninstallResult>,
     /// The store path of environment that was built to validate the uninstall.
     /// This is used as an optimization to skip builds that we've already done.
     pub store_path: Option<PathBuf>,
@@ -133,6 +185,112 @@ pub trait Environment: Send {
     /// Atomically edit this environment, ensuring that it still builds
     fn edit(&mut self, flox: &Flox, contents: String) -> Result<EditResult, EnvironmentError>;
 
+    /// Atomically apply structured edits to the manifest of this environment,
+    /// ensuring that it still builds
+    fn edit_with_patch(
+        &mut self,
+        flox: &Flox,
+        edits: &[ManifestEdit],
+    ) -> Result<EditResult, EnvironmentError>;
+
+    /// Atomically set variables in the `[vars]` table of the manifest,
+    /// ensuring that the environment still builds
+    fn set_vars(
+        &mut self,
+        flox: &Flox,
+        vars: &[(String, String)],
+    ) -> Result<EditResult, EnvironmentError> {
+        let edits = vars
+            .iter()
+            .map(|(name, value)| ManifestEdit::SetVar {
+                name: name.clone(),
+                value: value.clone(),
+            })
+            .collect::<Vec<_>>();
+        self.edit_with_patch(flox, &edits)
+    }
+
+    /// Atomically remove variables from the `[vars]` table of the manifest,
+    /// ensuring that the environment still builds
+    ///
+    /// Variables that are not set are ignored.
+    fn unset_vars(
+        &mut self,
+        flox: &Flox,
+        names: &[String],
+    ) -> Result<EditResult, EnvironmentError> {
+        let edits = names
+            .iter()
+            .map(|name| ManifestEdit::UnsetVar { name: name.clone() })
+            .collect::<Vec<_>>();
+        self.edit_with_patch(flox, &edits)
+    }
+
+    /// Atomically add or replace services in the `[services]` table of the manifest,
+    /// ensuring that the environment still builds
+    fn set_services(
+        &mut self,
+        flox: &Flox,
+        services: &[(String, ServiceDescriptor)],
+    ) -> Result<EditResult, EnvironmentError> {
+        let edits = services
+            .iter()
+            .map(|(name, service)| ManifestEdit::SetService {
+                name: name.clone(),
+                service: service.clone(),
+            })
+            .collect::<Vec<_>>();
+        self.edit_with_patch(flox, &edits)
+    }
+
+    /// Atomically remove services from the `[services]` table of the manifest,
+    /// ensuring that the environment still builds
+    ///
+    /// Fails without modifying the environment if any of the services is not configured.
+    fn remove_services(
+        &mut self,
+        flox: &Flox,
+        names: &[String],
+    ) -> Result<EditResult, EnvironmentError> {
+        let edits = names
+            .iter()
+            .map(|name| ManifestEdit::RemoveService { name: name.clone() })
+            .collect::<Vec<_>>();
+        self.edit_with_patch(flox, &edits)
+    }
+
+    /// Atomically pin packages, so that upgrades without explicitly named
+    /// packages or groups keep them at their locked derivation
+    ///
+    /// Fails without modifying the environment if any of the packages
+    /// is not installed from the catalog.
+    fn pin(&mut self, flox: &Flox, install_ids: &[String]) -> Result<EditResult, EnvironmentError> {
+        let edits = install_ids
+            .iter()
+            .map(|install_id| ManifestEdit::SetPinned {
+                install_id: install_id.clone(),
+                pinned: true,
+            })
+            .collect::<Vec<_>>();
+        self.edit_with_patch(flox, &edits)
+    }
+
+    /// Atomically unpin packages, see [Environment::pin]
+    fn unpin(
+        &mut self,
+        flox: &Flox,
+        install_ids: &[String],
+    ) -> Result<EditResult, EnvironmentError> {
+        let edits = install_ids
+            .iter()
+            .map(|install_id| ManifestEdit::SetPinned {
+                install_id: install_id.clone(),
+                pinned: false,
+            })
+            .collect::<Vec<_>>();
+        self.edit_with_patch(flox, &edits)
+    }
+
     /// Atomically update this environment's inputs
     fn update(
         &mut self,
@@ -192,9 +350,28 @@ pub trait Environment: Send {
     /// may be located in different directories.
     fn lockfile_path(&self, flox: &Flox) -> Result<PathBuf, EnvironmentError>;
 
+    /// List the out-links created by linking this environment,
+    /// see [out_links]
+    fn out_links(&self, flox: &Flox) -> Result<Vec<OutLink>, EnvironmentError>;
+
+    /// Remove the stale out-links of this environment,
+    /// so that the store paths they refer to can be garbage collected
+    ///
+    /// Returns the removed out-links.
+    fn prune_out_links(&mut self, flox: &Flox) -> Result<Vec<OutLink>, EnvironmentError> {
+        remove_stale_out_links(self.out_links(flox)?).map_err(EnvironmentError::OutLinks)
+    }
+
     /// Returns the environment name
     fn name(&self) -> EnvironmentName;
 
+    /// Rename the environment
+    fn rename(&mut self, flox: &Flox, new_name: EnvironmentName) -> Result<(), EnvironmentError>;
+
+    /// Move the environment into `new_parent`,
+    /// which becomes its [Environment::parent_path]
+    fn relocate(&mut self, flox: &Flox, new_parent: &Path) -> Result<(), EnvironmentError>;
+
     /// Delete the Environment
     fn delete(self, flox: &Flox) -> Result<(), EnvironmentError>
     where
@@ -447,6 +624,13 @@ pub enum EnvironmentError {
     #[error("could not delete environment")]
     DeleteEnvironment(#[source] std::io::Error),
 
+    /// Names of environments on FloxHub are owned by FloxHub
+    #[error("environments on FloxHub can't be renamed")]
+    RenameUnsupported,
+    /// Environments on FloxHub are tracked by their location
+    #[error("environments on FloxHub can't be moved")]
+    RelocateUnsupported,
+
     #[error("could not read manifest")]
     ReadManifest(#[source] std::io::Error),
     #[error("couldn't write manifest")]
@@ -455,6 +639,9 @@ pub enum EnvironmentError {
     #[error("failed to create GC roots directory")]
     CreateGcRootDir(#[source] std::io::Error),
 
+    #[error("failed to manage out-links of the environment")]
+    OutLinks(#[source] OutLinkError),
+
     #[error("failed to create cache directory")]
     CreateCacheDir(#[source] std::io::Error),
 
@@ -473,6 +660,9 @@ pub enum EnvironmentError {
 
 /// Copy a whole directory recursively ignoring the original permissions
 ///
+/// Files ignored by the `.floxignore` of `from` are skipped,
+/// see [FloxIgnore].
+///
 /// We need this because:
 /// 1. Sometimes we need to copy from the Nix store
 /// 2. fs_extra::dir::copy doesn't handle symlinks.
@@ -485,7 +675,15 @@ fn copy_dir_recursive(
     if !to.as_ref().exists() {
         std::fs::create_dir(to).unwrap();
     }
-    for entry in WalkDir::new(from).into_iter().skip(1) {
+    let floxignore = FloxIgnore::read(from)?;
+    let entries = WalkDir::new(from)
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|entry| {
+            let relative_path = entry.path().strip_prefix(from).unwrap();
+            !floxignore.is_ignored(relative_path, entry.file_type().is_dir())
+        });
+    for entry in entries {
         let entry = entry.unwrap();
         let new_path = to.as_ref().join(entry.path().strip_prefix(from).unwrap());
         match entry.file_type() {
@@ -514,6 +712,139 @@ fn copy_dir_recursive(
     Ok(())
 }
 
+/// Cheaply copy the contents of an environment directory,
+/// e.g. to apply a transaction to
+///
+/// Files are reflinked if the filesystem supports it.
+/// Otherwise the manifest and lockfile, which are written by transactions,
+/// are copied, and all other files are hardlinked,
+/// falling back to a copy if `from` and `to` are on different filesystems.
+/// Files ignored by the `.floxignore` of `from` are skipped, see [FloxIgnore].
+fn clone_dir_recursive(from: &impl AsRef<Path>, to: &impl AsRef<Path>) -> Result<(), io::Error> {
+    let floxignore = FloxIgnore::read(from)?;
+    clone_dir_filtered(from, to, Some(&floxignore))
+}
+
+/// Clone the contents of `from` into `to` like [clone_dir_recursive],
+/// skipping the files ignored by `floxignore` if given
+fn clone_dir_filtered(
+    from: &impl AsRef<Path>,
+    to: &impl AsRef<Path>,
+    floxignore: Option<&FloxIgnore>,
+) -> Result<(), io::Error> {
+    if !to.as_ref().exists() {
+        fs::create_dir(to)?;
+    }
+    let entries = WalkDir::new(from)
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|entry| {
+            let Some(floxignore) = floxignore else {
+                return true;
+            };
+            let relative_path = entry
+                .path()
+                .strip_prefix(from)
+                .expect("walked paths are in 'from'");
+            !floxignore.is_ignored(relative_path, entry.file_type().is_dir())
+        });
+    for entry in entries {
+        let entry = entry?;
+        let relative_path = entry
+            .path()
+            .strip_prefix(from)
+            .expect("walked paths are in 'from'");
+        let new_path = to.as_ref().join(relative_path);
+        match entry.file_type() {
+            file_type if file_type.is_dir() => {
+                fs::create_dir(&new_path)?;
+            },
+            file_type if file_type.is_symlink() => {
+                let target = fs::read_link(entry.path())?;
+                // See copy_dir_recursive, links are expected to be absolute
+                std::os::unix::fs::symlink(target, &new_path)?;
+            },
+            _ => match crate::utils::reflink_file(entry.path(), &new_path) {
+                Ok(()) => {},
+                Err(err) if err.kind() == io::ErrorKind::Unsupported => {
+                    let written_by_transactions = entry.depth() == 1
+                        && (entry.file_name() == MANIFEST_FILENAME
+                            || entry.file_name() == LOCKFILE_FILENAME);
+                    if written_by_transactions || fs::hard_link(entry.path(), &new_path).is_err() {
+                        fs::copy(entry.path(), &new_path)?;
+                    }
+                },
+                Err(err) => return Err(err),
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Move the files ignored by the `.floxignore` of `from` into `to`,
+/// e.g. to keep them when an environment directory is replaced
+/// by a copy made with [clone_dir_recursive]
+///
+/// Ignored files that already exist in `to` are left in `from`.
+fn move_ignored(from: &Path, to: &Path) -> Result<(), io::Error> {
+    let floxignore = FloxIgnore::read(from)?;
+    let mut entries = WalkDir::new(from).min_depth(1).into_iter();
+    while let Some(entry) = entries.next() {
+        let entry = entry?;
+        let is_dir = entry.file_type().is_dir();
+        let relative_path = entry
+            .path()
+            .strip_prefix(from)
+            .expect("walked paths are in 'from'");
+        if !floxignore.is_ignored(relative_path, is_dir) {
+            continue;
+        }
+        if is_dir {
+            entries.skip_current_dir();
+        }
+        let new_path = to.join(relative_path);
+        if new_path.symlink_metadata().is_ok() {
+            continue;
+        }
+        if let Some(parent) = new_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        debug!("keeping ignored file: {}", relative_path.display());
+        fs::rename(entry.path(), &new_path)?;
+    }
+    Ok(())
+}
+
+/// Move the directory `from` to `to`
+///
+/// The directory is renamed if possible,
+/// or copied and removed if `to` is on a different filesystem than `from`.
+fn move_dir(from: &Path, to: &Path) -> Result<(), io::Error> {
+    match fs::rename(from, to) {
+        Err(err) if err.raw_os_error() == Some(libc::EXDEV) => {
+            debug!(
+                "{} and {} are on different filesystems, copying",
+                from.display(),
+                to.display()
+            );
+            copy_and_remove_dir(from, to)
+        },
+        result => result,
+    }
+}
+
+/// Move the directory `from` to `to` by copying all of its contents,
+/// including files ignored by its `.floxignore`, and removing `from`
+///
+/// If the copy fails, the partial copy at `to` is removed and `from` is kept.
+fn copy_and_remove_dir(from: &Path, to: &Path) -> Result<(), io::Error> {
+    if let Err(err) = clone_dir_filtered(&from, &to, None) {
+        let _ = fs::remove_dir_all(to);
+        return Err(err);
+    }
+    fs::remove_dir_all(from)
+}
+
 /// Initialize the global manifest if it doesn't exist already
 pub fn init_global_manifest(global_manifest_path: &Path) -> Result<(), EnvironmentError> {
     if !global_manifest_path.exists() {
@@ -868,4 +1199,61 @@ mod test {
         let found_environment = find_dot_flox(&start_path);
         assert!(found_environment.is_err());
     }
+
+    #[test]
+    fn cloned_environment_dirs_are_independent() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let from = temp_dir.path().join("from");
+        let to = temp_dir.path().join("to");
+        fs::create_dir_all(from.join("nested")).unwrap();
+        fs::write(from.join(MANIFEST_FILENAME), "version = 1").unwrap();
+        fs::write(from.join("nested").join("file"), "contents").unwrap();
+        fs::write(from.join("manifest.toml.swp"), "").unwrap();
+        fs::write(from.join(".DS_Store"), "").unwrap();
+        fs::write(from.join(floxignore::FLOXIGNORE_FILENAME), "node_modules/").unwrap();
+        fs::create_dir_all(from.join("node_modules").join("left-pad")).unwrap();
+
+        clone_dir_recursive(&from, &to).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(to.join("nested").join("file")).unwrap(),
+            "contents"
+        );
+        assert!(!to.join("manifest.toml.swp").exists());
+        assert!(!to.join(".DS_Store").exists());
+        assert!(!to.join("node_modules").exists());
+        assert!(to.join(floxignore::FLOXIGNORE_FILENAME).exists());
+
+        fs::write(to.join(MANIFEST_FILENAME), "version = 2").unwrap();
+        assert_eq!(
+            fs::read_to_string(from.join(MANIFEST_FILENAME)).unwrap(),
+            "version = 1"
+        );
+    }
+
+    #[test]
+    fn copying_moved_dirs_keeps_ignored_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let from = temp_dir.path().join("from");
+        let to = temp_dir.path().join("to");
+        fs::create_dir_all(&from).unwrap();
+        fs::write(from.join(MANIFEST_FILENAME), "version = 1").unwrap();
+        fs::write(from.join(".DS_Store"), "").unwrap();
+        fs::write(from.join(floxignore::FLOXIGNORE_FILENAME), "node_modules/").unwrap();
+        fs::create_dir_all(from.join("node_modules")).unwrap();
+        fs::write(from.join("node_modules").join("left-pad"), "left-pad").unwrap();
+
+        copy_and_remove_dir(&from, &to).unwrap();
+
+        assert!(!from.exists());
+        assert_eq!(
+            fs::read_to_string(to.join(MANIFEST_FILENAME)).unwrap(),
+            "version = 1"
+        );
+        assert!(to.join(".DS_Store").exists());
+        assert_eq!(
+            fs::read_to_string(to.join("node_modules").join("left-pad")).unwrap(),
+            "left-pad"
+        );
+    }
 }
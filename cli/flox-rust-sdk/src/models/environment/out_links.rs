@@ -0,0 +1,290 @@
+//! Out-links of built environments
+//!
+//! Linking an environment creates a symlink to the built environment, the out-link,
+//! which nix registers as a garbage collector root.
+//! Store paths that an out-link points to are kept until the out-link is removed.
+//! Out-links that an environment no longer uses, e.g. the out-link of a path environment
+//! under its previous name, keep their store paths alive until they are pruned.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::providers::flake::nix_command;
+use crate::utils::CommandExt;
+
+#[derive(Debug, Error)]
+pub enum OutLinkError {
+    #[error("couldn't read out-links in {0}")]
+    ReadDir(PathBuf, #[source] std::io::Error),
+    #[error("couldn't remove out-link {0}")]
+    Remove(PathBuf, #[source] std::io::Error),
+    #[error("failed to call nix")]
+    CallNix(#[source] std::io::Error),
+    #[error("failed to query the size of store paths:\n{0}")]
+    PathInfo(String),
+    #[error("couldn't parse the size of store paths")]
+    ParsePathInfo(#[source] serde_json::Error),
+    #[error("couldn't register out-link {0} as garbage collector root:\n{1}")]
+    RegisterGcRoot(PathBuf, String),
+}
+
+/// A symlink to a built environment that is a garbage collector root
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OutLink {
+    pub path: PathBuf,
+    /// The store path the out-link points to,
+    /// `None` if the store path no longer exists
+    pub store_path: Option<PathBuf>,
+    /// Whether the environment still uses the out-link
+    pub current: bool,
+}
+
+impl OutLink {
+    /// Read the out-link at `path`, if `path` is a symlink
+    pub(super) fn read(path: impl AsRef<Path>, current: bool) -> Option<Self> {
+        let path = path.as_ref();
+        let target = fs::read_link(path).ok()?;
+        let target = path
+            .parent()
+            .map_or(target.clone(), |dir| dir.join(&target));
+        Some(OutLink {
+            path: path.to_path_buf(),
+            store_path: target.exists().then_some(target),
+            current,
+        })
+    }
+
+    /// Whether the out-link can be removed without affecting the environment,
+    /// i.e. it is no longer used or its store path no longer exists
+    pub fn is_stale(&self) -> bool {
+        !self.current || self.store_path.is_none()
+    }
+}
+
+/// List the out-links in `dir`
+///
+/// `is_current` decides which of the out-links are still used.
+/// A missing `dir` contains no out-links.
+pub(super) fn list_out_links(
+    dir: &Path,
+    is_current: impl Fn(&Path) -> bool,
+) -> Result<Vec<OutLink>, OutLinkError> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut out_links = Vec::new();
+    for entry in fs::read_dir(dir).map_err(|e| OutLinkError::ReadDir(dir.to_path_buf(), e))? {
+        let path = entry
+            .map_err(|e| OutLinkError::ReadDir(dir.to_path_buf(), e))?
+            .path();
+        if let Some(out_link) = OutLink::read(&path, is_current(&path)) {
+            out_links.push(out_link);
+        }
+    }
+    out_links.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(out_links)
+}
+
+/// Remove the stale out-links among `out_links`
+///
+/// Returns the removed out-links.
+pub fn remove_stale_out_links(out_links: Vec<OutLink>) -> Result<Vec<OutLink>, OutLinkError> {
+    let stale = out_links
+        .into_iter()
+        .filter(OutLink::is_stale)
+        .collect::<Vec<_>>();
+    for out_link in &stale {
+        debug!("removing stale out-link: {}", out_link.path.display());
+        fs::remove_file(&out_link.path)
+            .map_err(|e| OutLinkError::Remove(out_link.path.clone(), e))?;
+    }
+    Ok(stale)
+}
+
+/// Link `path` to `store_path` and register it as a garbage collector root
+///
+/// Nix tracks garbage collector roots by the path of the out-link,
+/// so an out-link that was moved has to be registered again,
+/// otherwise its store path may be garbage collected.
+/// An existing out-link at `path` is replaced.
+pub(super) fn register_gc_root(path: &Path, store_path: &Path) -> Result<(), OutLinkError> {
+    let mut command = nix_command();
+    command
+        .args(["build", "--out-link"])
+        .arg(path)
+        .arg(store_path);
+    debug!(
+        "registering garbage collector root with command: {}",
+        command.display()
+    );
+    let output = command.output().map_err(OutLinkError::CallNix)?;
+    if !output.status.success() {
+        return Err(OutLinkError::RegisterGcRoot(
+            path.to_path_buf(),
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Move the out-link `from` to `to`, registering `to` as a garbage collector root
+///
+/// An out-link whose store path no longer exists is removed instead.
+pub(super) fn move_out_link(from: &Path, to: &Path) -> Result<(), OutLinkError> {
+    if let Some(store_path) = OutLink::read(from, true).and_then(|out_link| out_link.store_path) {
+        debug!("moving out-link {} to {}", from.display(), to.display());
+        register_gc_root(to, &store_path)?;
+    }
+    if from != to {
+        fs::remove_file(from).map_err(|e| OutLinkError::Remove(from.to_path_buf(), e))?;
+    }
+    Ok(())
+}
+
+/// The parts of the output of `nix path-info --json` that are used
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PathInfo {
+    nar_size: Option<u64>,
+}
+
+/// The disk space in bytes held by `out_links`,
+/// i.e. the size of the closure of all of their store paths
+///
+/// Store paths that are shared by several out-links are counted once.
+/// The space is only reclaimed by garbage collection
+/// if no other garbage collector root refers to the store paths.
+pub fn disk_usage(out_links: &[OutLink]) -> Result<u64, OutLinkError> {
+    let store_paths = out_links
+        .iter()
+        .filter_map(|out_link| out_link.store_path.as_ref())
+        .collect::<Vec<_>>();
+    if store_paths.is_empty() {
+        return Ok(0);
+    }
+    let sizes = query_path_info_sizes(&store_paths)?;
+    Ok(sizes.into_iter().flatten().sum())
+}
+
+/// The size in bytes of the closure of `store_paths`,
+/// `None` if any of them is not in the local store
+///
+/// Store paths that are shared by the closures of several of them are counted once.
+pub fn closure_size(store_paths: &[impl AsRef<Path>]) -> Result<Option<u64>, OutLinkError> {
+    if store_paths.is_empty() {
+        return Ok(Some(0));
+    }
+    let sizes = query_path_info_sizes(store_paths)?;
+    Ok(sizes.into_iter().sum())
+}
+
+/// Query the size of every store path in the closure of `store_paths`
+/// with `nix path-info`
+fn query_path_info_sizes(
+    store_paths: &[impl AsRef<Path>],
+) -> Result<Vec<Option<u64>>, OutLinkError> {
+    let mut command = nix_command();
+    command
+        .args(["path-info", "--json", "--recursive"])
+        .args(store_paths.iter().map(AsRef::as_ref));
+    debug!(
+        "querying store path sizes with command: {}",
+        command.display()
+    );
+    let output = command.output().map_err(OutLinkError::CallNix)?;
+    if !output.status.success() {
+        return Err(OutLinkError::PathInfo(
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        ));
+    }
+    parse_path_info_sizes(&output.stdout)
+}
+
+/// Parse the sizes in the output of `nix path-info --json`,
+/// which is a list of path infos in older versions of nix,
+/// and a map from store path to path info in newer ones
+///
+/// Paths that are not in the store have no size.
+fn parse_path_info_sizes(json: &[u8]) -> Result<Vec<Option<u64>>, OutLinkError> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum PathInfos {
+        List(Vec<PathInfo>),
+        Map(BTreeMap<String, Option<PathInfo>>),
+    }
+
+    let infos = serde_json::from_slice(json).map_err(OutLinkError::ParsePathInfo)?;
+    let sizes = match infos {
+        PathInfos::List(infos) => infos.into_iter().map(|info| info.nar_size).collect(),
+        PathInfos::Map(infos) => infos
+            .into_values()
+            .map(|info| info.and_then(|info| info.nar_size))
+            .collect(),
+    };
+    Ok(sizes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_and_prunes_stale_out_links() {
+        let dir = tempfile::tempdir().unwrap();
+        let store_path = dir.path().join("store-path");
+        fs::create_dir(&store_path).unwrap();
+        let run_dir = dir.path().join("run");
+        fs::create_dir(&run_dir).unwrap();
+        std::os::unix::fs::symlink(&store_path, run_dir.join("x86_64-linux.current")).unwrap();
+        std::os::unix::fs::symlink(&store_path, run_dir.join("x86_64-linux.renamed")).unwrap();
+        std::os::unix::fs::symlink(
+            dir.path().join("missing"),
+            run_dir.join("x86_64-linux.gone"),
+        )
+        .unwrap();
+        fs::write(run_dir.join("not-a-link"), "").unwrap();
+
+        let is_current = |path: &Path| !path.ends_with("x86_64-linux.renamed");
+        let out_links = list_out_links(&run_dir, is_current).unwrap();
+        assert_eq!(out_links, vec![
+            OutLink {
+                path: run_dir.join("x86_64-linux.current"),
+                store_path: Some(store_path.clone()),
+                current: true,
+            },
+            OutLink {
+                path: run_dir.join("x86_64-linux.gone"),
+                store_path: None,
+                current: true,
+            },
+            OutLink {
+                path: run_dir.join("x86_64-linux.renamed"),
+                store_path: Some(store_path.clone()),
+                current: false,
+            },
+        ]);
+
+        let removed = remove_stale_out_links(out_links).unwrap();
+        assert_eq!(removed.len(), 2);
+        let remaining = list_out_links(&run_dir, is_current).unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert!(!remaining[0].is_stale());
+    }
+
+    #[test]
+    fn parses_path_info_sizes() {
+        let list =
+            br#"[{"path": "/nix/store/a", "narSize": 10}, {"path": "/nix/store/b", "narSize": 5}]"#;
+        assert_eq!(parse_path_info_sizes(list).unwrap(), vec![
+            Some(10),
+            Some(5)
+        ]);
+        let map = br#"{"/nix/store/a": {"narSize": 10}, "/nix/store/b": null}"#;
+        assert_eq!(parse_path_info_sizes(map).unwrap(), vec![Some(10), None]);
+    }
+}
@@ -8,6 +8,8 @@
 //!         LOCKFILE_FILENAME
 //!     PATH_ENV_GCROOTS_DIR_NAME/
 //!         $system.$name (out link)
+//!         groups/
+//!             $system.$name.$group (out link of a single pkg-group)
 //! ```
 //!
 //! `ENVIRONMENT_DIR_NAME` contains the environment definition
@@ -22,6 +24,8 @@ use indoc::{formatdoc, indoc};
 use log::debug;
 
 use super::core_environment::CoreEnvironment;
+use super::out_links::{list_out_links, move_out_link, register_gc_root, OutLink, OutLinkError};
+use super::snapshots::{SnapshotMetadata, SNAPSHOTS_DIR_NAME};
 use super::{
     DotFlox,
     EditResult,
@@ -41,7 +45,7 @@ use super::{
 use crate::data::{CanonicalPath, System};
 use crate::flox::Flox;
 use crate::models::container_builder::ContainerBuilder;
-use crate::models::env_registry::{deregister, ensure_registered};
+use crate::models::env_registry::{deregister, ensure_registered, move_registration};
 use crate::models::environment::{
     ENV_DIR_NAME,
     FLOX_HOOK_PLACEHOLDER,
@@ -53,7 +57,7 @@ use crate::models::environment::{
 };
 use crate::models::environment_ref::EnvironmentName;
 use crate::models::lockfile::LockedManifest;
-use crate::models::manifest::PackageToInstall;
+use crate::models::manifest::{ManifestEdit, PackageToInstall};
 use crate::models::pkgdb::UpgradeResult;
 use crate::utils::mtime_of;
 
@@ -143,19 +147,91 @@ impl PathEnvironment {
         Ok(run_dir.join([system.clone(), self.name().to_string()].join(".")))
     }
 
+    /// Where to link the build of only the pkg-group `group`
+    ///
+    /// Group out-links are kept in a separate directory,
+    /// so they are never mistaken for the out-link of the whole environment.
+    fn group_out_link(&self, system: &System, group: &str) -> Result<PathBuf, EnvironmentError> {
+        let groups_dir = self.path.join(GCROOTS_DIR_NAME).join("groups");
+        if !groups_dir.exists() {
+            std::fs::create_dir_all(&groups_dir).map_err(EnvironmentError::CreateGcRootDir)?;
+        }
+        Ok(groups_dir.join([system, self.name().as_ref(), group].join(".")))
+    }
+
     /// Get a view of the environment that can be used to perform operations
     /// on the environment without side effects.
     ///
     /// This method should only be used to create [CoreEnvironment]s for a [PathEnvironment].
     /// To modify the environment, use the [PathEnvironment] methods instead.
     pub(super) fn into_core_environment(self) -> CoreEnvironment {
-        CoreEnvironment::new(self.path.join(ENV_DIR_NAME))
+        CoreEnvironment::new(self.path.join(ENV_DIR_NAME)).with_local_generations()
+    }
+
+    /// The [CoreEnvironment] of `.flox/env`, recording local generations
+    ///
+    /// Operations on it may create the transaction lock, generations, or snapshots,
+    /// so the [gitignore_entries] that environments created by older versions lack
+    /// are added first.
+    fn core_environment(&self) -> CoreEnvironment {
+        if let Err(e) = ensure_gitignore(&self.path) {
+            debug!("couldn't update .gitignore: {e}");
+        }
+        CoreEnvironment::new(self.path.join(ENV_DIR_NAME)).with_local_generations()
+    }
+
+    /// Move the out-links of the environment under `old_name` to its current name
+    ///
+    /// If an out-link can't be moved, the out-links that were already moved are moved back.
+    fn rename_out_links(&self, old_name: &EnvironmentName) -> Result<(), EnvironmentError> {
+        let run_dir = self.path.join(GCROOTS_DIR_NAME);
+        let groups_dir = run_dir.join("groups");
+        let new_name = self.name();
+
+        let mut moves = vec![];
+        for (dir, is_group_dir) in [(&run_dir, false), (&groups_dir, true)] {
+            for out_link in list_out_links(dir, |_| true).map_err(EnvironmentError::OutLinks)? {
+                let Some((system, link_name)) = out_link
+                    .path
+                    .file_name()
+                    .and_then(OsStr::to_str)
+                    .and_then(|file_name| file_name.split_once('.'))
+                else {
+                    continue;
+                };
+                // `$system.$name` or `$system.$name.$group`
+                let renamed = if is_group_dir {
+                    link_name
+                        .strip_prefix(&format!("{old_name}."))
+                        .map(|group| format!("{system}.{new_name}.{group}"))
+                } else {
+                    (link_name == old_name.as_ref()).then(|| format!("{system}.{new_name}"))
+                };
+                if let Some(renamed) = renamed {
+                    moves.push((out_link.path.clone(), dir.join(renamed)));
+                }
+            }
+        }
+
+        let mut moved: Vec<(PathBuf, PathBuf)> = vec![];
+        for (from, to) in moves {
+            if let Err(err) = move_out_link(&from, &to) {
+                for (from, to) in moved.into_iter().rev() {
+                    if let Err(e) = move_out_link(&to, &from) {
+                        debug!("failed to move back out-link {}: {e}", to.display());
+                    }
+                }
+                return Err(EnvironmentError::OutLinks(err));
+            }
+            moved.push((from, to));
+        }
+        Ok(())
     }
 
-    pub fn rename(&mut self, new_name: EnvironmentName) -> Result<(), EnvironmentError> {
-        self.pointer.name = new_name;
-        let pointer_content = serde_json::to_string_pretty(&self.pointer)
-            .map_err(EnvironmentError::SerializeEnvJson)?;
+    /// Atomically write `pointer` to the `env.json` of the environment
+    fn write_pointer(&self, pointer: &PathPointer) -> Result<(), EnvironmentError> {
+        let pointer_content =
+            serde_json::to_string_pretty(pointer).map_err(EnvironmentError::SerializeEnvJson)?;
 
         let mut tempfile =
             tempfile::NamedTempFile::new_in(&self.path).map_err(EnvironmentError::WriteEnvJson)?;
@@ -180,7 +256,7 @@ impl Environment for PathEnvironment {
     /// - Create a lockfile if one doesn't already exist, updating it with
     ///   any new packages.
     fn build(&mut self, flox: &Flox) -> Result<(), EnvironmentError> {
-        let mut env_view = CoreEnvironment::new(self.path.join(ENV_DIR_NAME));
+        let mut env_view = self.core_environment();
         env_view.lock(flox)?;
         let store_path = env_view.build(flox)?;
         env_view.link(flox, self.out_link(&flox.system)?, &Some(store_path))?;
@@ -189,12 +265,12 @@ impl Environment for PathEnvironment {
     }
 
     fn lock(&mut self, flox: &Flox) -> Result<LockedManifest, EnvironmentError> {
-        let mut env_view = CoreEnvironment::new(self.path.join(ENV_DIR_NAME));
+        let mut env_view = self.core_environment();
         Ok(env_view.lock(flox)?)
     }
 
     fn build_container(&mut self, flox: &Flox) -> Result<ContainerBuilder, EnvironmentError> {
-        let mut env_view = CoreEnvironment::new(self.path.join(ENV_DIR_NAME));
+        let mut env_view = self.core_environment();
         let builder = env_view.build_container(flox)?;
         Ok(builder)
     }
@@ -212,7 +288,7 @@ impl Environment for PathEnvironment {
         packages: &[PackageToInstall],
         flox: &Flox,
     ) -> Result<InstallationAttempt, EnvironmentError> {
-        let mut env_view = CoreEnvironment::new(self.path.join(ENV_DIR_NAME));
+        let mut env_view = self.core_environment();
         let result = env_view.install(packages, flox)?;
         env_view.link(flox, self.out_link(&flox.system)?, &result.store_path)?;
 
@@ -229,7 +305,7 @@ impl Environment for PathEnvironment {
         packages: Vec<String>,
         flox: &Flox,
     ) -> Result<UninstallationAttempt, EnvironmentError> {
-        let mut env_view = CoreEnvironment::new(self.path.join(ENV_DIR_NAME));
+        let mut env_view = self.core_environment();
         let result = env_view.uninstall(packages, flox)?;
         env_view.link(flox, self.out_link(&flox.system)?, &result.store_path)?;
 
@@ -238,7 +314,7 @@ impl Environment for PathEnvironment {
 
     /// Atomically edit this environment, ensuring that it still builds
     fn edit(&mut self, flox: &Flox, contents: String) -> Result<EditResult, EnvironmentError> {
-        let mut env_view = CoreEnvironment::new(self.path.join(ENV_DIR_NAME));
+        let mut env_view = self.core_environment();
         let result = env_view.edit(flox, contents)?;
         if result != EditResult::Unchanged {
             env_view.link(flox, self.out_link(&flox.system)?, &result.store_path())?;
@@ -246,13 +322,43 @@ impl Environment for PathEnvironment {
         Ok(result)
     }
 
+    /// Atomically apply structured edits to the manifest of this environment,
+    /// ensuring that it still builds
+    fn edit_with_patch(
+        &mut self,
+        flox: &Flox,
+        edits: &[ManifestEdit],
+    ) -> Result<EditResult, EnvironmentError> {
+        let mut env_view = self.core_environment();
+        let result = env_view.edit_with_patch(flox, edits)?;
+        if result != EditResult::Unchanged {
+            env_view.link(flox, self.out_link(&flox.system)?, &result.store_path())?;
+        }
+        Ok(result)
+    }
+
+    /// List the out-links in `.flox/run`
+    ///
+    /// Out-links for another name than the current name of the environment,
+    /// e.g. from before the environment was renamed, are no longer used.
+    fn out_links(&self, _flox: &Flox) -> Result<Vec<OutLink>, EnvironmentError> {
+        let name = self.name().to_string();
+        list_out_links(&self.path.join(GCROOTS_DIR_NAME), |path| {
+            path.file_name()
+                .and_then(OsStr::to_str)
+                .and_then(|file_name| file_name.split_once('.'))
+                .is_some_and(|(_system, link_name)| link_name == name)
+        })
+        .map_err(EnvironmentError::OutLinks)
+    }
+
     /// Atomically update this environment's inputs
     fn update(
         &mut self,
         flox: &Flox,
         inputs: Vec<String>,
     ) -> Result<UpdateResult, EnvironmentError> {
-        let mut env_view = CoreEnvironment::new(self.path.join(ENV_DIR_NAME));
+        let mut env_view = self.core_environment();
         let result = env_view.update(flox, inputs)?;
         env_view.link(flox, self.out_link(&flox.system)?, &result.store_path)?;
 
@@ -265,7 +371,7 @@ impl Environment for PathEnvironment {
         flox: &Flox,
         groups_or_iids: &[String],
     ) -> Result<UpgradeResult, EnvironmentError> {
-        let mut env_view = CoreEnvironment::new(self.path.join(ENV_DIR_NAME));
+        let mut env_view = self.core_environment();
         let result = env_view.upgrade(flox, groups_or_iids)?;
         env_view.link(flox, self.out_link(&flox.system)?, &result.store_path)?;
 
@@ -282,6 +388,91 @@ impl Environment for PathEnvironment {
         self.pointer.name.clone()
     }
 
+    /// Rename the environment
+    ///
+    /// The registration of the environment is updated,
+    /// and its out-links, including those of pkg-groups, are moved to the new name.
+    /// If the out-links can't be moved, the environment keeps its old name.
+    fn rename(&mut self, flox: &Flox, new_name: EnvironmentName) -> Result<(), EnvironmentError> {
+        let old_pointer = self.pointer.clone();
+        let new_pointer = PathPointer::new(new_name);
+        move_registration(
+            flox,
+            &self.path,
+            &old_pointer.clone().into(),
+            &self.path,
+            &new_pointer.clone().into(),
+        )?;
+
+        let renamed = self.write_pointer(&new_pointer).and_then(|()| {
+            self.pointer = new_pointer.clone();
+            self.rename_out_links(&old_pointer.name)
+        });
+        if let Err(err) = renamed {
+            // restore the old name of the environment
+            self.pointer = old_pointer.clone();
+            if let Err(e) = self.write_pointer(&old_pointer) {
+                debug!("failed to restore name of environment: {e}");
+            }
+            if let Err(e) = move_registration(
+                flox,
+                &self.path,
+                &new_pointer.into(),
+                &self.path,
+                &old_pointer.into(),
+            ) {
+                debug!("failed to restore registration of environment: {e}");
+            }
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Move the `.flox` directory of the environment into `new_parent`
+    ///
+    /// The registration of the environment is moved to the new location,
+    /// and its out-links are registered as garbage collector roots again,
+    /// as nix tracks them by their path.
+    /// If either fails, the environment is moved back.
+    fn relocate(&mut self, flox: &Flox, new_parent: &Path) -> Result<(), EnvironmentError> {
+        let new_dot_flox = new_parent.join(DOT_FLOX);
+        if new_dot_flox.exists() {
+            return Err(EnvironmentError::EnvironmentExists(new_dot_flox));
+        }
+
+        let old_path = self.path.clone();
+        let mut core_environment = self.core_environment();
+        core_environment.relocate(&old_path, &new_dot_flox)?;
+
+        let pointer = EnvironmentPointer::Path(self.pointer.clone());
+        let relocated = CanonicalPath::new(&new_dot_flox)
+            .map_err(EnvironmentError::CanonicalDotFlox)
+            .and_then(|new_path| {
+                move_registration(flox, &old_path, &pointer, &new_path, &pointer)?;
+                if let Err(err) = register_out_links(&new_path) {
+                    if let Err(e) =
+                        move_registration(flox, &new_path, &pointer, &old_path, &pointer)
+                    {
+                        debug!("failed to restore registration of environment: {e}");
+                    }
+                    return Err(EnvironmentError::OutLinks(err));
+                }
+                Ok(new_path)
+            });
+        match relocated {
+            Ok(new_path) => {
+                self.path = new_path;
+                Ok(())
+            },
+            Err(err) => {
+                if let Err(e) = core_environment.relocate(&new_dot_flox, &old_path) {
+                    debug!("failed to move environment back: {e}");
+                }
+                Err(err)
+            },
+        }
+    }
+
     /// Delete the Environment
     fn delete(self, flox: &Flox) -> Result<(), EnvironmentError> {
         let dot_flox = &self.path;
@@ -297,7 +488,9 @@ impl Environment for PathEnvironment {
     fn activation_path(&mut self, flox: &Flox) -> Result<PathBuf, EnvironmentError> {
         let out_link = self.out_link(&flox.system)?;
 
-        if self.needs_rebuild(flox)? {
+        // The linked store path may have been garbage collected,
+        // e.g. if the project was moved, leaving a dangling out-link
+        if self.needs_rebuild(flox)? || !out_link.exists() {
             self.build(flox)?;
         }
 
@@ -339,6 +532,19 @@ impl Environment for PathEnvironment {
     }
 }
 
+/// Register the out-links of the environment in `dot_flox` as garbage collector roots
+fn register_out_links(dot_flox: &Path) -> Result<(), OutLinkError> {
+    let run_dir = dot_flox.join(GCROOTS_DIR_NAME);
+    for dir in [run_dir.clone(), run_dir.join("groups")] {
+        for out_link in list_out_links(&dir, |_| true)? {
+            if let Some(store_path) = &out_link.store_path {
+                register_gc_root(&out_link.path, store_path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
 /// Constructors of PathEnvironments
 impl PathEnvironment {
     /// Open an environment at a given path
@@ -355,7 +561,6 @@ impl PathEnvironment {
             &dot_flox_path,
             &EnvironmentPointer::Path(pointer.clone()),
         )?;
-
         PathEnvironment::new(dot_flox_path, pointer, temp_dir)
     }
 
@@ -413,6 +618,56 @@ impl PathEnvironment {
         Ok(environment)
     }
 
+    /// Replace the manifest of this environment with the template `name`
+    /// and link the built environment
+    ///
+    /// See [CoreEnvironment::init_from_template].
+    pub fn init_from_template(&mut self, flox: &Flox, name: &str) -> Result<(), EnvironmentError> {
+        let mut env_view = self.core_environment();
+        let store_path = env_view.init_from_template(flox, name)?;
+        env_view.link(flox, self.out_link(&flox.system)?, &Some(store_path))?;
+        Ok(())
+    }
+
+    /// Take a snapshot named `name` of the environment,
+    /// including the store path its out-link currently points to
+    ///
+    /// See [CoreEnvironment::snapshot].
+    pub fn snapshot(
+        &self,
+        flox: &Flox,
+        name: &str,
+        description: String,
+    ) -> Result<SnapshotMetadata, EnvironmentError> {
+        let store_path = OutLink::read(self.out_link(&flox.system)?, true)
+            .and_then(|out_link| out_link.store_path);
+        let env_view = self.core_environment();
+        Ok(env_view.snapshot(name, description, store_path)?)
+    }
+
+    /// Restore the snapshot `name` and link the built environment
+    ///
+    /// See [CoreEnvironment::restore].
+    pub fn restore(&mut self, flox: &Flox, name: &str) -> Result<(), EnvironmentError> {
+        let mut env_view = self.core_environment();
+        let store_path = env_view.restore(flox, name)?;
+        env_view.link(flox, self.out_link(&flox.system)?, &Some(store_path))?;
+        Ok(())
+    }
+
+    /// Build only the pkg-group `group` and link it to its own out-link
+    ///
+    /// Returns the out-link, which can be used like the out-link of the environment,
+    /// but only contains the packages of the group.
+    /// See [CoreEnvironment::build_group].
+    pub fn build_group(&mut self, flox: &Flox, group: &str) -> Result<PathBuf, EnvironmentError> {
+        let mut env_view = self.core_environment();
+        env_view.lock(flox)?;
+        let out_link = self.group_out_link(&flox.system, group)?;
+        env_view.build_group(flox, group, &out_link)?;
+        Ok(out_link)
+    }
+
     /// Write files for a [PathEnvironment] to `dot_flox_parent_path` unchecked.
     ///
     /// * write the .flox directory
@@ -459,12 +714,7 @@ impl PathEnvironment {
             return Err(e);
         }
 
-        // write "run" and "cache" to .flox/.gitignore
-        fs::write(dot_flox_path.join(".gitignore"), formatdoc! {"
-            {GCROOTS_DIR_NAME}/
-            {CACHE_DIR_NAME}/
-            "})
-        .map_err(EnvironmentError::WriteGitignore)?;
+        ensure_gitignore(&dot_flox_path).map_err(EnvironmentError::WriteGitignore)?;
 
         let dot_flox_path = CanonicalPath::new(dot_flox_path).expect("the directory just created");
 
@@ -598,6 +848,49 @@ impl PathEnvironment {
     }
 }
 
+/// The entries of `.flox/.gitignore`
+///
+/// Out-links, caches, the transaction lock, local generations, and snapshots
+/// are specific to a machine and must not be committed.
+fn gitignore_entries() -> [String; 5] {
+    [
+        format!("{GCROOTS_DIR_NAME}/"),
+        format!("{CACHE_DIR_NAME}/"),
+        format!("{ENV_DIR_NAME}.lock"),
+        format!("{ENV_DIR_NAME}.generations/"),
+        format!("{SNAPSHOTS_DIR_NAME}/"),
+    ]
+}
+
+/// Append the [gitignore_entries] missing from `.flox/.gitignore`,
+/// creating it if necessary
+///
+/// Entries added by the user are kept.
+fn ensure_gitignore(dot_flox_path: &Path) -> std::io::Result<()> {
+    let gitignore_path = dot_flox_path.join(".gitignore");
+    let mut contents = match fs::read_to_string(&gitignore_path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let missing = gitignore_entries()
+        .into_iter()
+        .filter(|entry| !contents.lines().any(|line| line.trim() == entry))
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    if !contents.is_empty() && !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    for entry in missing {
+        contents.push_str(&entry);
+        contents.push('\n');
+    }
+    fs::write(gitignore_path, contents)
+}
+
 pub mod test_helpers {
     use tempfile::tempdir_in;
 
@@ -736,6 +1029,38 @@ mod tests {
         ));
     }
 
+    /// Modifying an environment adds missing entries to its .gitignore,
+    /// opening it doesn't
+    #[test]
+    fn modifying_adds_missing_gitignore_entries() {
+        let (flox, tmp_dir) = flox_instance();
+        let environment_temp_dir = tempfile::tempdir_in(&tmp_dir).unwrap();
+        let ptr = PathPointer::new("test".parse().unwrap());
+        let env = PathEnvironment::init(
+            ptr.clone(),
+            environment_temp_dir.path(),
+            tmp_dir.path(),
+            &flox.system,
+            &InitCustomization::default(),
+            &flox,
+        )
+        .unwrap();
+        // .gitignore as written by older versions, with an entry added by the user
+        let gitignore_path = env.path.join(".gitignore");
+        fs::write(&gitignore_path, "run/\ncache/\nnotes.txt").unwrap();
+
+        let env = PathEnvironment::open(&flox, ptr, env.path, tmp_dir.path()).unwrap();
+        let gitignore = fs::read_to_string(&gitignore_path).unwrap();
+        assert_eq!(gitignore, "run/\ncache/\nnotes.txt");
+
+        env.snapshot(&flox, "snapshot", "".to_string()).unwrap();
+        let gitignore = fs::read_to_string(&gitignore_path).unwrap();
+        assert_eq!(
+            gitignore,
+            "run/\ncache/\nnotes.txt\nenv.lock\nenv.generations/\nsnapshots/\n"
+        );
+    }
+
     #[test]
     fn deregisters_on_delete() {
         let (flox, tmp_dir) = flox_instance();
@@ -758,4 +1083,133 @@ mod tests {
         let reg = read_environment_registry(&reg_path).unwrap().unwrap();
         assert!(reg.entries.is_empty());
     }
+
+    #[test]
+    fn rename_updates_registry_and_out_links() {
+        let (flox, tmp_dir) = flox_instance();
+        let environment_temp_dir = tempfile::tempdir_in(&tmp_dir).unwrap();
+        let mut env = PathEnvironment::init(
+            PathPointer::new("test".parse().unwrap()),
+            environment_temp_dir.path(),
+            tmp_dir.path(),
+            &flox.system,
+            &InitCustomization::default(),
+            &flox,
+        )
+        .unwrap();
+        // An out-link whose store path was garbage collected is not moved
+        let out_link = env.out_link(&flox.system).unwrap();
+        std::os::unix::fs::symlink(tmp_dir.path().join("missing"), &out_link).unwrap();
+
+        env.rename(&flox, "renamed".parse().unwrap()).unwrap();
+
+        assert_eq!(env.name().to_string(), "renamed");
+        assert!(!out_link.exists() && out_link.symlink_metadata().is_err());
+        let reopened = DotFlox::open_in(environment_temp_dir.path()).unwrap();
+        assert_eq!(reopened.pointer.name().to_string(), "renamed");
+        let reg = read_environment_registry(env_registry_path(&flox))
+            .unwrap()
+            .unwrap();
+        assert_eq!(reg.entries.len(), 1);
+        assert_eq!(reg.entries[0].envs.len(), 1);
+        assert_eq!(reg.entries[0].envs[0].pointer.name().to_string(), "renamed");
+    }
+
+    #[test]
+    fn relocate_moves_dot_flox_and_registration() {
+        let (flox, tmp_dir) = flox_instance();
+        let environment_temp_dir = tempfile::tempdir_in(&tmp_dir).unwrap();
+        let new_parent = tempfile::tempdir_in(&tmp_dir).unwrap();
+        let mut env = PathEnvironment::init(
+            PathPointer::new("test".parse().unwrap()),
+            environment_temp_dir.path(),
+            tmp_dir.path(),
+            &flox.system,
+            &InitCustomization::default(),
+            &flox,
+        )
+        .unwrap();
+        let manifest = env.manifest_content(&flox).unwrap();
+
+        env.relocate(&flox, new_parent.path()).unwrap();
+
+        assert!(!environment_temp_dir.path().join(DOT_FLOX).exists());
+        assert_eq!(
+            env.path.to_path_buf(),
+            new_parent.path().join(DOT_FLOX).canonicalize().unwrap()
+        );
+        assert_eq!(env.manifest_content(&flox).unwrap(), manifest);
+        let reg = read_environment_registry(env_registry_path(&flox))
+            .unwrap()
+            .unwrap();
+        assert_eq!(reg.entries.len(), 1);
+        assert_eq!(reg.entries[0].path, env.path.to_path_buf());
+
+        // The environment can't be moved onto another one
+        let err = env.relocate(&flox, new_parent.path()).unwrap_err();
+        assert!(matches!(err, EnvironmentError::EnvironmentExists(_)));
+    }
+
+    #[test]
+    fn rename_keeps_old_name_if_out_links_cannot_be_moved() {
+        let (flox, tmp_dir) = flox_instance();
+        let environment_temp_dir = tempfile::tempdir_in(&tmp_dir).unwrap();
+        let mut env = PathEnvironment::init(
+            PathPointer::new("test".parse().unwrap()),
+            environment_temp_dir.path(),
+            tmp_dir.path(),
+            &flox.system,
+            &InitCustomization::default(),
+            &flox,
+        )
+        .unwrap();
+        // A path outside of the store can't be registered as garbage collector root
+        let out_link = env.out_link(&flox.system).unwrap();
+        std::os::unix::fs::symlink(tmp_dir.path(), &out_link).unwrap();
+
+        let err = env.rename(&flox, "renamed".parse().unwrap()).unwrap_err();
+
+        assert!(matches!(err, EnvironmentError::OutLinks(_)));
+        assert_eq!(env.name().to_string(), "test");
+        assert_eq!(fs::read_link(&out_link).unwrap(), tmp_dir.path());
+        let reopened = DotFlox::open_in(environment_temp_dir.path()).unwrap();
+        assert_eq!(reopened.pointer.name().to_string(), "test");
+        let reg = read_environment_registry(env_registry_path(&flox))
+            .unwrap()
+            .unwrap();
+        assert_eq!(reg.entries[0].envs.len(), 1);
+        assert_eq!(reg.entries[0].envs[0].pointer.name().to_string(), "test");
+    }
+
+    #[test]
+    fn relocate_moves_back_if_out_links_cannot_be_registered() {
+        let (flox, tmp_dir) = flox_instance();
+        let environment_temp_dir = tempfile::tempdir_in(&tmp_dir).unwrap();
+        let new_parent = tempfile::tempdir_in(&tmp_dir).unwrap();
+        let mut env = PathEnvironment::init(
+            PathPointer::new("test".parse().unwrap()),
+            environment_temp_dir.path(),
+            tmp_dir.path(),
+            &flox.system,
+            &InitCustomization::default(),
+            &flox,
+        )
+        .unwrap();
+        // A path outside of the store can't be registered as garbage collector root
+        let out_link = env.out_link(&flox.system).unwrap();
+        std::os::unix::fs::symlink(tmp_dir.path(), &out_link).unwrap();
+        let old_path = env.path.clone();
+
+        let err = env.relocate(&flox, new_parent.path()).unwrap_err();
+
+        assert!(matches!(err, EnvironmentError::OutLinks(_)));
+        assert_eq!(env.path, old_path);
+        assert!(old_path.join(ENV_DIR_NAME).exists());
+        assert!(!new_parent.path().join(DOT_FLOX).exists());
+        let reg = read_environment_registry(env_registry_path(&flox))
+            .unwrap()
+            .unwrap();
+        assert_eq!(reg.entries.len(), 1);
+        assert_eq!(reg.entries[0].path, old_path.to_path_buf());
+    }
 }
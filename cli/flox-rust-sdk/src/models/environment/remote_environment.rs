@@ -6,6 +6,7 @@ use tempfile::TempDir;
 use thiserror::Error;
 
 use super::managed_environment::{remote_branch_name, ManagedEnvironment, ManagedEnvironmentError};
+use super::out_links::OutLink;
 use super::{
     gcroots_dir,
     CanonicalPath,
@@ -26,7 +27,7 @@ use crate::models::container_builder::ContainerBuilder;
 use crate::models::environment_ref::EnvironmentName;
 use crate::models::floxmeta::{FloxMeta, FloxMetaError};
 use crate::models::lockfile::LockedManifest;
-use crate::models::manifest::PackageToInstall;
+use crate::models::manifest::{ManifestEdit, PackageToInstall};
 use crate::models::pkgdb::UpgradeResult;
 
 const REMOTE_ENVIRONMENT_BASE_DIR: &str = "remote";
@@ -229,6 +230,25 @@ impl Environment for RemoteEnvironment {
         Ok(result)
     }
 
+    /// Atomically apply structured edits to the manifest of this environment,
+    /// ensuring that it still builds
+    fn edit_with_patch(
+        &mut self,
+        flox: &Flox,
+        edits: &[ManifestEdit],
+    ) -> Result<EditResult, EnvironmentError> {
+        let result = self.inner.edit_with_patch(flox, edits)?;
+        if result == EditResult::Unchanged {
+            return Ok(result);
+        }
+        self.inner
+            .push(flox, false)
+            .map_err(|e| RemoteEnvironmentError::UpdateUpstream(e).into())
+            .and_then(|_| Self::update_out_link(flox, &self.out_link, &mut self.inner))?;
+
+        Ok(result)
+    }
+
     /// Atomically update this environment's inputs
     fn update(
         &mut self,
@@ -296,11 +316,29 @@ impl Environment for RemoteEnvironment {
         self.inner.lockfile_path(flox)
     }
 
+    /// List the out-link of the remote environment
+    /// and the out-link of the managed environment it links to
+    fn out_links(&self, flox: &Flox) -> Result<Vec<OutLink>, EnvironmentError> {
+        let mut out_links = self.inner.out_links(flox)?;
+        out_links.extend(OutLink::read(&self.out_link, true));
+        Ok(out_links)
+    }
+
     /// Returns the environment name
     fn name(&self) -> EnvironmentName {
         self.inner.name()
     }
 
+    /// Remote environments are named on FloxHub and can't be renamed locally
+    fn rename(&mut self, _flox: &Flox, _new_name: EnvironmentName) -> Result<(), EnvironmentError> {
+        Err(EnvironmentError::RenameUnsupported)
+    }
+
+    /// Remote environments are kept in a flox owned directory and can't be moved
+    fn relocate(&mut self, _flox: &Flox, _new_parent: &Path) -> Result<(), EnvironmentError> {
+        Err(EnvironmentError::RelocateUnsupported)
+    }
+
     /// Delete the Environment
     ///
     /// The local version of this is rather ... useless.
@@ -0,0 +1,152 @@
+//! Reproducibility of the packages of an environment
+//!
+//! A package is reproducible if building its derivation again
+//! yields outputs that are bit for bit identical to the previous build.
+//! Nix checks this with `nix build --rebuild`,
+//! which builds derivations whose outputs are already in the store once more
+//! and compares the results, rather than trusting a binary cache.
+
+use std::path::{Path, PathBuf};
+
+use log::debug;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::providers::flake::nix_command;
+use crate::utils::CommandExt;
+
+#[derive(Debug, Error)]
+pub enum ReproducibilityError {
+    #[error("failed to call nix")]
+    CallNix(#[source] std::io::Error),
+    #[error("failed to rebuild derivations:\n{0}")]
+    Rebuild(String),
+}
+
+/// An output that differed from the previous build when its derivation was rebuilt
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DivergentOutput {
+    pub derivation: PathBuf,
+    pub output: PathBuf,
+}
+
+/// The result of rebuilding the derivations of an environment
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ReproducibilityReport {
+    /// Derivations that were rebuilt and compared
+    pub checked: Vec<PathBuf>,
+    /// Outputs whose rebuild differs from the previous build
+    pub divergent: Vec<DivergentOutput>,
+    /// Derivations that could not be compared,
+    /// because their outputs are not in the local store
+    pub not_built: Vec<PathBuf>,
+}
+
+impl ReproducibilityReport {
+    pub fn is_reproducible(&self) -> bool {
+        self.divergent.is_empty() && self.not_built.is_empty()
+    }
+}
+
+/// Rebuild `derivations` without substitutes
+/// and compare their outputs to the outputs already in the store
+///
+/// All derivations are rebuilt, even if some of them diverge.
+pub(super) fn rebuild_and_compare(
+    derivations: &[PathBuf],
+) -> Result<ReproducibilityReport, ReproducibilityError> {
+    if derivations.is_empty() {
+        return Ok(ReproducibilityReport::default());
+    }
+
+    let mut command = nix_command();
+    command
+        .args(["build", "--rebuild", "--keep-going", "--no-link"])
+        .args(["--option", "substitute", "false"])
+        .args(
+            derivations
+                .iter()
+                .map(|derivation| format!("{}^*", derivation.display())),
+        );
+    debug!("rebuilding derivations with command: {}", command.display());
+    let output = command.output().map_err(ReproducibilityError::CallNix)?;
+
+    let mut report = ReproducibilityReport {
+        checked: derivations.to_vec(),
+        ..Default::default()
+    };
+    if output.status.success() {
+        return Ok(report);
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    report.divergent = parse_divergent_outputs(&stderr);
+    report.not_built = parse_not_built(&stderr);
+    // Any other failure, e.g. a derivation that fails to build at all,
+    // can't be attributed to a lack of reproducibility
+    if report.divergent.is_empty() && report.not_built.is_empty() {
+        return Err(ReproducibilityError::Rebuild(stderr.to_string()));
+    }
+    report
+        .checked
+        .retain(|derivation| !report.not_built.contains(derivation));
+    Ok(report)
+}
+
+/// Collect the outputs reported as
+/// `derivation '<drv>' may not be deterministic: output '<path>' differs`
+/// by `nix build --rebuild`
+fn parse_divergent_outputs(stderr: &str) -> Vec<DivergentOutput> {
+    stderr
+        .lines()
+        .filter_map(|line| {
+            let (_, rest) = line.split_once("derivation '")?;
+            let (derivation, rest) = rest.split_once('\'')?;
+            let (_, rest) = rest.split_once("may not be deterministic: output '")?;
+            let (output, _) = rest.split_once('\'')?;
+            Some(DivergentOutput {
+                derivation: PathBuf::from(derivation),
+                output: PathBuf::from(output),
+            })
+        })
+        .collect()
+}
+
+/// Collect the derivations reported as
+/// `some outputs of '<drv>' are not valid, so checking is not possible`
+/// by `nix build --rebuild`
+fn parse_not_built(stderr: &str) -> Vec<PathBuf> {
+    stderr
+        .lines()
+        .filter_map(|line| {
+            let (_, rest) = line.split_once("some outputs of '")?;
+            let (derivation, rest) = rest.split_once('\'')?;
+            rest.contains("are not valid")
+                .then(|| Path::new(derivation).to_path_buf())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn parses_rebuild_failures() {
+        let stderr = "\
+checking outputs of '/nix/store/aaa-hello.drv'...
+error: derivation '/nix/store/bbb-curl.drv' may not be deterministic: output '/nix/store/ccc-curl' differs
+error: some outputs of '/nix/store/ddd-git.drv' are not valid, so checking is not possible
+error: build of '/nix/store/bbb-curl.drv^*' failed";
+
+        assert_eq!(parse_divergent_outputs(stderr), vec![DivergentOutput {
+            derivation: PathBuf::from("/nix/store/bbb-curl.drv"),
+            output: PathBuf::from("/nix/store/ccc-curl"),
+        }]);
+        assert_eq!(parse_not_built(stderr), vec![PathBuf::from(
+            "/nix/store/ddd-git.drv"
+        )]);
+    }
+}
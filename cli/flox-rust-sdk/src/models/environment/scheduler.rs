@@ -0,0 +1,253 @@
+//! Locking and building many environments at once
+//!
+//! Repositories with many environments, e.g. one per project of a monorepo,
+//! may need to validate all of them in CI.
+//! Doing so one after the other spends most of the time waiting for the catalog
+//! and for builds that could run side by side.
+//!
+//! [BuildScheduler] resolves the package groups of all environments
+//! in a single catalog request up front,
+//! and then locks and builds the environments on a bounded number of threads.
+
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use log::debug;
+use pollster::FutureExt;
+
+use super::core_environment::{CoreEnvironment, CoreEnvironmentError};
+use crate::flox::Flox;
+use crate::providers::catalog::{ClientTrait, PackageGroup};
+
+/// What [BuildScheduler::run] does with each environment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScheduledOperation {
+    /// Lock the environment and write its lockfile
+    Lock,
+    /// Lock the environment and build it for the current system
+    Build,
+}
+
+/// The result of an operation on a single environment
+#[derive(Debug)]
+pub struct ScheduledResult {
+    pub env_dir: PathBuf,
+    /// The store path of the built environment if it was built
+    pub result: Result<Option<PathBuf>, CoreEnvironmentError>,
+}
+
+/// Locks and builds several environments concurrently
+#[derive(Debug, Clone)]
+pub struct BuildScheduler {
+    concurrency: NonZeroUsize,
+}
+
+impl BuildScheduler {
+    /// Create a scheduler that operates on at most `concurrency` environments at a time
+    pub fn new(concurrency: NonZeroUsize) -> Self {
+        Self { concurrency }
+    }
+
+    /// Lock, and optionally build, the environments in `env_dirs`
+    ///
+    /// Environments are not linked, which is left to the caller.
+    /// A failure of one environment doesn't affect the others,
+    /// results are returned in the order of `env_dirs`.
+    pub fn run(
+        &self,
+        flox: &Flox,
+        env_dirs: Vec<PathBuf>,
+        operation: ScheduledOperation,
+    ) -> Vec<ScheduledResult> {
+        batch_resolve(flox, &env_dirs);
+
+        let next = AtomicUsize::new(0);
+        let results = Mutex::new(
+            env_dirs
+                .iter()
+                .map(|_| None)
+                .collect::<Vec<Option<ScheduledResult>>>(),
+        );
+        let workers = self.concurrency.get().min(env_dirs.len());
+        debug!(
+            "scheduling {} environment(s) on {workers} worker(s)",
+            env_dirs.len()
+        );
+        std::thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| loop {
+                    let index = next.fetch_add(1, Ordering::SeqCst);
+                    let Some(env_dir) = env_dirs.get(index) else {
+                        break;
+                    };
+                    let result = run_operation(flox, env_dir.clone(), operation);
+                    results.lock().expect("couldn't acquire results lock")[index] =
+                        Some(ScheduledResult {
+                            env_dir: env_dir.clone(),
+                            result,
+                        });
+                });
+            }
+        });
+
+        results
+            .into_inner()
+            .expect("couldn't acquire results lock")
+            .into_iter()
+            .map(|result| result.expect("every environment is scheduled"))
+            .collect()
+    }
+}
+
+fn run_operation(
+    flox: &Flox,
+    env_dir: PathBuf,
+    operation: ScheduledOperation,
+) -> Result<Option<PathBuf>, CoreEnvironmentError> {
+    let mut env = CoreEnvironment::new(env_dir);
+    env.lock(flox)?;
+    match operation {
+        ScheduledOperation::Lock => Ok(None),
+        ScheduledOperation::Build => Ok(Some(env.build(flox)?)),
+    }
+}
+
+/// The name a group is requested under in a batched request
+///
+/// The catalog identifies resolved groups by name,
+/// but groups of different environments commonly share names, e.g. `toplevel`.
+fn batch_name(index: usize, group: &PackageGroup) -> String {
+    format!("{index}:{}", group.name)
+}
+
+/// Resolve the package groups of all environments in `env_dirs` in one request
+/// and store them in [Flox::resolution_cache]
+///
+/// Groups that are identical across environments are only requested once.
+/// This is an optimization like [CoreEnvironment::prefetch_resolution],
+/// failures are only logged and each environment resolves its groups when it is locked.
+fn batch_resolve(flox: &Flox, env_dirs: &[PathBuf]) {
+    let Some(client) = &flox.catalog_client else {
+        return;
+    };
+
+    let mut groups: Vec<PackageGroup> = vec![];
+    for env_dir in env_dirs {
+        match CoreEnvironment::new(env_dir).groups_to_prefetch(flox) {
+            Ok(env_groups) => {
+                for group in env_groups {
+                    if !groups.contains(&group) {
+                        groups.push(group);
+                    }
+                }
+            },
+            Err(e) => debug!("not batching resolution of {}: {e}", env_dir.display()),
+        }
+    }
+    if groups.is_empty() {
+        return;
+    }
+
+    let request = groups
+        .iter()
+        .enumerate()
+        .map(|(index, group)| PackageGroup {
+            name: batch_name(index, group),
+            ..group.clone()
+        })
+        .collect::<Vec<_>>();
+    debug!(
+        "resolving {} package group(s) of {} environment(s)",
+        request.len(),
+        env_dirs.len()
+    );
+    let resolved = match client.resolve(request).block_on() {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            debug!("failed to batch resolution: {e}");
+            return;
+        },
+    };
+
+    let snapshot = flox.catalog_snapshot();
+    for (index, group) in groups.into_iter().enumerate() {
+        let name = batch_name(index, &group);
+        let Some(mut resolved_group) = resolved
+            .iter()
+            .find(|resolved| resolved.name == name && resolved.system == group.system)
+            .cloned()
+        else {
+            continue;
+        };
+        resolved_group.name = group.name.clone();
+        let resolved_group = vec![resolved_group];
+        if let Err(e) = snapshot.record(std::slice::from_ref(&group), &resolved_group) {
+            debug!("couldn't record resolution in catalog snapshot: {e}");
+        }
+        flox.resolution_cache.insert(vec![group], resolved_group);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flox::test_helpers::flox_instance;
+    use crate::models::environment::test_helpers::{new_core_environment, resolved_group};
+    use crate::models::lockfile::{self, LockedManifest};
+    use crate::models::manifest::{self, DEFAULT_GROUP_NAME};
+    use crate::providers::catalog::{MockClient, ResolvedPackageGroup};
+
+    /// Environments that share a package group are resolved with a single request
+    #[test]
+    fn locks_environments_with_one_request() {
+        let (mut flox, _temp_dir_handle) = flox_instance();
+
+        let mut manifest = manifest::test::empty_catalog_manifest();
+        let (foo_iid, foo_descriptor, _) = lockfile::tests::fake_package("foo", None);
+        manifest
+            .install
+            .insert(foo_iid.clone(), foo_descriptor.into());
+        let manifest = toml::to_string(&manifest).unwrap();
+        let env_dirs = (0..3)
+            .map(|_| new_core_environment(&flox, &manifest).path().to_path_buf())
+            .collect::<Vec<_>>();
+
+        let group = PackageGroup {
+            name: DEFAULT_GROUP_NAME.to_string(),
+            system: "system".to_string(),
+            descriptors: vec![],
+        };
+        // Only one response is available,
+        // locking fails if any environment requests its groups on its own
+        let mut mock_client = MockClient::new(None::<&str>).unwrap();
+        mock_client.push_resolve_response(vec![ResolvedPackageGroup {
+            name: batch_name(0, &group),
+            ..resolved_group(&foo_iid, "1.0")
+        }]);
+        flox.catalog_client = Some(mock_client.into());
+
+        let results = BuildScheduler::new(NonZeroUsize::new(2).unwrap()).run(
+            &flox,
+            env_dirs.clone(),
+            ScheduledOperation::Lock,
+        );
+
+        assert_eq!(
+            results.iter().map(|r| &r.env_dir).collect::<Vec<_>>(),
+            env_dirs.iter().collect::<Vec<_>>()
+        );
+        for ScheduledResult { env_dir, result } in results {
+            assert!(matches!(result, Ok(None)), "{result:?}");
+            let env = CoreEnvironment::new(env_dir);
+            let lockfile_path = crate::data::CanonicalPath::new(env.lockfile_path()).unwrap();
+            let LockedManifest::Catalog(lockfile) =
+                LockedManifest::read_from_file(&lockfile_path).unwrap()
+            else {
+                panic!("expected a catalog lockfile");
+            };
+            assert_eq!(lockfile.packages[0].install_id, foo_iid);
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Named restore points of a [CoreEnvironment](super::CoreEnvironment)
+//!
+//! Unlike [generations](super::local_generations), which are recorded for every transaction,
+//! snapshots are only created on request, e.g. before a risky edit,
+//! and are kept until they are removed.
+//! A snapshot records the manifest, the lockfile,
+//! and the store path the environment was linked to when the snapshot was taken.
+//!
+//! Example file layout for an environment in `.flox/env`:
+//!
+//! ```ignore
+//! .flox/snapshots/
+//! ├── before-upgrade
+//! │  ├── manifest.toml
+//! │  ├── manifest.lock (lockfile is optional)
+//! │  └── snapshot.json
+//! └── ...
+//! ```
+
+use std::fs;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, SubsecRound, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::debug;
+
+use super::{LOCKFILE_FILENAME, MANIFEST_FILENAME};
+
+/// The directory next to the environment directory that snapshots are stored in
+pub const SNAPSHOTS_DIR_NAME: &str = "snapshots";
+
+const SNAPSHOT_METADATA_FILE: &str = "snapshot.json";
+
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error("invalid snapshot name '{0}'")]
+    InvalidName(String),
+    #[error("snapshot '{0}' already exists")]
+    AlreadyExists(String),
+    #[error("snapshot '{0}' not found")]
+    NotFound(String),
+    #[error("could not list snapshots")]
+    List(#[source] std::io::Error),
+    #[error("could not create snapshot directory")]
+    CreateSnapshot(#[source] std::io::Error),
+    #[error("could not copy environment files into snapshot")]
+    CopyFiles(#[source] std::io::Error),
+    #[error("could not write snapshot metadata")]
+    WriteMetadata(#[source] std::io::Error),
+    #[error("could not read metadata of snapshot '{0}'")]
+    ReadMetadata(String, #[source] std::io::Error),
+    #[error("could not parse metadata of snapshot '{0}'")]
+    ParseMetadata(String, #[source] serde_json::Error),
+    #[error("could not read manifest of snapshot '{0}'")]
+    ReadManifest(String, #[source] std::io::Error),
+    #[error("could not read lockfile of snapshot '{0}'")]
+    ReadLockfile(String, #[source] std::io::Error),
+    #[error("could not remove snapshot '{0}'")]
+    Remove(String, #[source] std::io::Error),
+}
+
+/// Metadata of a single snapshot
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotMetadata {
+    pub name: String,
+    /// unix timestamp of the creation time of this snapshot
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub created: DateTime<Utc>,
+    /// message describing why the snapshot was taken
+    pub description: String,
+    /// store path the environment was linked to when the snapshot was taken,
+    /// `None` if the environment was not built
+    pub store_path: Option<PathBuf>,
+}
+
+/// The snapshots of an environment
+#[derive(Debug, Clone)]
+pub struct Snapshots {
+    path: PathBuf,
+}
+
+impl Snapshots {
+    /// Open the snapshots stored in `path`, which may not exist yet
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// The directory containing the snapshots
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// List all snapshots, oldest first
+    pub fn list(&self) -> Result<Vec<SnapshotMetadata>, SnapshotError> {
+        let entries = match fs::read_dir(&self.path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(SnapshotError::List(e)),
+        };
+        let mut snapshots = vec![];
+        for entry in entries {
+            let entry = entry.map_err(SnapshotError::List)?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            // Snapshots that were not completely written have no metadata
+            if !entry.path().join(SNAPSHOT_METADATA_FILE).exists() {
+                continue;
+            }
+            snapshots.push(self.get(&name)?);
+        }
+        snapshots.sort_by(|a, b| a.created.cmp(&b.created).then(a.name.cmp(&b.name)));
+        Ok(snapshots)
+    }
+
+    /// Read the metadata of the snapshot `name`
+    pub fn get(&self, name: &str) -> Result<SnapshotMetadata, SnapshotError> {
+        let path = self.existing_snapshot_path(name)?;
+        let contents = fs::read_to_string(path.join(SNAPSHOT_METADATA_FILE))
+            .map_err(|e| SnapshotError::ReadMetadata(name.to_string(), e))?;
+        serde_json::from_str(&contents)
+            .map_err(|e| SnapshotError::ParseMetadata(name.to_string(), e))
+    }
+
+    /// Read the manifest of the snapshot `name`
+    pub fn manifest(&self, name: &str) -> Result<String, SnapshotError> {
+        let path = self.existing_snapshot_path(name)?;
+        fs::read_to_string(path.join(MANIFEST_FILENAME))
+            .map_err(|e| SnapshotError::ReadManifest(name.to_string(), e))
+    }
+
+    /// Read the lockfile of the snapshot `name`, if the environment was locked
+    pub fn lockfile(&self, name: &str) -> Result<Option<String>, SnapshotError> {
+        let path = self.existing_snapshot_path(name)?.join(LOCKFILE_FILENAME);
+        if !path.exists() {
+            return Ok(None);
+        }
+        fs::read_to_string(path)
+            .map(Some)
+            .map_err(|e| SnapshotError::ReadLockfile(name.to_string(), e))
+    }
+
+    /// Record the manifest and lockfile in `env_dir` as the snapshot `name`
+    ///
+    /// Snapshots are never overwritten, remove an existing snapshot first.
+    pub fn create(
+        &self,
+        env_dir: impl AsRef<Path>,
+        name: &str,
+        description: String,
+        store_path: Option<PathBuf>,
+    ) -> Result<SnapshotMetadata, SnapshotError> {
+        let snapshot_path = self.snapshot_path(name)?;
+        if snapshot_path.join(SNAPSHOT_METADATA_FILE).exists() {
+            return Err(SnapshotError::AlreadyExists(name.to_string()));
+        }
+        // A leftover directory of a snapshot that was never completed
+        if snapshot_path.exists() {
+            fs::remove_dir_all(&snapshot_path).map_err(SnapshotError::CreateSnapshot)?;
+        }
+        fs::create_dir_all(&snapshot_path).map_err(SnapshotError::CreateSnapshot)?;
+        for file in [MANIFEST_FILENAME, LOCKFILE_FILENAME] {
+            let source = env_dir.as_ref().join(file);
+            if source.exists() {
+                fs::copy(source, snapshot_path.join(file)).map_err(SnapshotError::CopyFiles)?;
+            }
+        }
+
+        let metadata = SnapshotMetadata {
+            name: name.to_string(),
+            // only seconds are stored
+            created: Utc::now().trunc_subsecs(0),
+            description,
+            store_path,
+        };
+        // The metadata is written last and atomically,
+        // so that only completely written snapshots are listed
+        let temp_file = tempfile::NamedTempFile::new_in(&snapshot_path)
+            .map_err(SnapshotError::WriteMetadata)?;
+        serde_json::to_writer_pretty(BufWriter::new(&temp_file), &metadata)
+            .map_err(|e| SnapshotError::WriteMetadata(e.into()))?;
+        temp_file
+            .persist(snapshot_path.join(SNAPSHOT_METADATA_FILE))
+            .map_err(|e| SnapshotError::WriteMetadata(e.error))?;
+
+        debug!("created snapshot '{name}' in {}", self.path.display());
+        Ok(metadata)
+    }
+
+    /// Remove the snapshot `name`
+    pub fn remove(&self, name: &str) -> Result<(), SnapshotError> {
+        let path = self.existing_snapshot_path(name)?;
+        fs::remove_dir_all(path).map_err(|e| SnapshotError::Remove(name.to_string(), e))
+    }
+
+    /// The directory of the snapshot `name`
+    ///
+    /// Names are used as directory names,
+    /// so they may not be empty, contain a `/`, or start with a `.`.
+    fn snapshot_path(&self, name: &str) -> Result<PathBuf, SnapshotError> {
+        if name.is_empty() || name.contains('/') || name.starts_with('.') {
+            return Err(SnapshotError::InvalidName(name.to_string()));
+        }
+        Ok(self.path.join(name))
+    }
+
+    fn existing_snapshot_path(&self, name: &str) -> Result<PathBuf, SnapshotError> {
+        let path = self.snapshot_path(name)?;
+        if !path.join(SNAPSHOT_METADATA_FILE).exists() {
+            return Err(SnapshotError::NotFound(name.to_string()));
+        }
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn create_records_files_and_metadata() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let env_dir = tempdir.path().join("env");
+        fs::create_dir_all(&env_dir).unwrap();
+        fs::write(env_dir.join(MANIFEST_FILENAME), "version = 1").unwrap();
+        let snapshots = Snapshots::new(tempdir.path().join("snapshots"));
+        assert_eq!(snapshots.list().unwrap(), vec![]);
+
+        let store_path = PathBuf::from("/nix/store/abc-environment");
+        let metadata = snapshots
+            .create(
+                &env_dir,
+                "before-upgrade",
+                "before upgrading".to_string(),
+                Some(store_path.clone()),
+            )
+            .unwrap();
+        assert_eq!(metadata.store_path, Some(store_path));
+        assert_eq!(snapshots.list().unwrap(), vec![metadata.clone()]);
+        assert_eq!(snapshots.get("before-upgrade").unwrap(), metadata);
+        assert_eq!(snapshots.manifest("before-upgrade").unwrap(), "version = 1");
+        assert_eq!(snapshots.lockfile("before-upgrade").unwrap(), None);
+
+        assert!(matches!(
+            snapshots.create(&env_dir, "before-upgrade", String::new(), None),
+            Err(SnapshotError::AlreadyExists(_))
+        ));
+        assert!(matches!(
+            snapshots.create(&env_dir, "../escape", String::new(), None),
+            Err(SnapshotError::InvalidName(_))
+        ));
+
+        snapshots.remove("before-upgrade").unwrap();
+        assert!(matches!(
+            snapshots.get("before-upgrade"),
+            Err(SnapshotError::NotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,325 @@
+//! Storage of the files that make up an environment
+//!
+//! [CoreEnvironment] builds and edits an environment directory on the local filesystem,
+//! but concrete environments may track the manifest and lockfile elsewhere,
+//! e.g. managed environments keep their generations in a git repository.
+//! [EnvironmentStore] abstracts reading, transactionally writing,
+//! and listing the history of these files,
+//! [FileSystemStore] implements it for an environment directory.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use log::{debug, warn};
+
+use super::core_environment::{CoreEnvironment, CoreEnvironmentError};
+use super::local_generations::LocalGenerations;
+use super::{clone_dir_recursive, move_ignored, LOCKFILE_FILENAME, MANIFEST_FILENAME};
+use crate::models::lockfile::LockedManifestError;
+
+/// The contents of an environment that are written to an [EnvironmentStore]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvironmentContents {
+    pub manifest: String,
+    /// `None` if the environment is not locked
+    pub lockfile: Option<String>,
+}
+
+/// A previous state of an environment recorded by an [EnvironmentStore]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Revision {
+    /// An identifier of the revision that is unique within the store,
+    /// e.g. a generation number or commit hash
+    pub id: String,
+    pub created: DateTime<Utc>,
+    pub description: String,
+}
+
+/// A backend that stores the manifest and lockfile of an environment
+pub trait EnvironmentStore {
+    type Error: std::error::Error;
+
+    /// Read the manifest of the environment
+    fn read_manifest(&self) -> Result<String, Self::Error>;
+
+    /// Read the lockfile of the environment, if it is locked
+    fn read_lockfile(&self) -> Result<Option<String>, Self::Error>;
+
+    /// Replace the manifest and lockfile of the environment
+    ///
+    /// Either both files are replaced or neither is,
+    /// readers never observe a new manifest with an old lockfile.
+    /// `description` is recorded in the history of the store.
+    fn write(
+        &mut self,
+        contents: &EnvironmentContents,
+        description: &str,
+    ) -> Result<(), Self::Error>;
+
+    /// The recorded revisions of the environment, oldest first
+    fn history(&self) -> Result<Vec<Revision>, Self::Error>;
+}
+
+/// An [EnvironmentStore] backed by an environment directory, e.g. `.flox/env`
+///
+/// Writes replace the whole directory, see [FileSystemStore::replace_with_dir],
+/// and are recorded as [generations](super::local_generations)
+/// if [FileSystemStore::with_local_generations] is set.
+#[derive(Debug, Clone)]
+pub struct FileSystemStore {
+    env_dir: PathBuf,
+    local_generations: bool,
+}
+
+impl FileSystemStore {
+    pub fn new(env_dir: impl AsRef<Path>) -> Self {
+        Self {
+            env_dir: env_dir.as_ref().to_path_buf(),
+            local_generations: false,
+        }
+    }
+
+    /// Record writes as generations of the environment
+    ///
+    /// See [CoreEnvironment::with_local_generations].
+    pub(super) fn with_local_generations(mut self) -> Self {
+        self.local_generations = true;
+        self
+    }
+
+    /// The environment directory
+    pub fn path(&self) -> &Path {
+        &self.env_dir
+    }
+
+    fn generations(&self) -> LocalGenerations {
+        LocalGenerations::new(self.env_dir.with_extension("generations"))
+    }
+
+    /// Replace the contents of the environment directory
+    /// with that of `replacement`
+    ///
+    /// The environment directory is moved to a backup first,
+    /// which is restored if `replacement` can't be copied into place.
+    pub(super) fn replace_with_dir(&self, replacement: &Path) -> Result<(), CoreEnvironmentError> {
+        self.backup()?;
+        // try to restore the backup if the move fails
+        if let Err(err) = self.copy_from(replacement) {
+            debug!("failed to replace env ({err}), restoring backup");
+            self.restore_backup()?;
+            return Err(err);
+        }
+        self.remove_backup()
+    }
+
+    /// Where [Self::backup] moves the environment directory to
+    pub(super) fn backup_path(&self) -> PathBuf {
+        self.env_dir.with_extension("tmp")
+    }
+
+    /// Fail if the backup of a previous transaction still exists
+    pub(super) fn ensure_no_backup(&self) -> Result<(), CoreEnvironmentError> {
+        let transaction_backup = self.backup_path();
+        if transaction_backup.exists() {
+            debug!(
+                "transaction backup exists: {}",
+                transaction_backup.display()
+            );
+            return Err(CoreEnvironmentError::PriorTransaction(transaction_backup));
+        }
+        Ok(())
+    }
+
+    /// Move the environment directory to [Self::backup_path]
+    pub(super) fn backup(&self) -> Result<(), CoreEnvironmentError> {
+        self.ensure_no_backup()?;
+        debug!(
+            "backing up env: from={}, to={}",
+            self.env_dir.display(),
+            self.backup_path().display()
+        );
+        fs::rename(&self.env_dir, self.backup_path())
+            .map_err(CoreEnvironmentError::BackupTransaction)
+    }
+
+    /// Copy `replacement` to the environment directory after it was backed up
+    pub(super) fn copy_from(&self, replacement: &Path) -> Result<(), CoreEnvironmentError> {
+        debug!(
+            "replacing original env: from={}, to={}",
+            replacement.display(),
+            self.env_dir.display()
+        );
+        clone_dir_recursive(&replacement, &self.env_dir).map_err(CoreEnvironmentError::Move)
+    }
+
+    /// Move the backup back into place,
+    /// discarding any partially copied replacement
+    pub(super) fn restore_backup(&self) -> Result<(), CoreEnvironmentError> {
+        debug!(
+            "restoring backup: from={}, to={}",
+            self.backup_path().display(),
+            self.env_dir.display()
+        );
+        if self.env_dir.exists() {
+            fs::remove_dir_all(&self.env_dir).map_err(CoreEnvironmentError::AbortTransaction)?;
+        }
+        fs::rename(self.backup_path(), &self.env_dir)
+            .map_err(CoreEnvironmentError::AbortTransaction)
+    }
+
+    /// Remove the backup after the environment directory was replaced
+    ///
+    /// Files ignored by the `.floxignore` of the environment are not copied
+    /// into the replacement, so they are moved from the backup first.
+    pub(super) fn remove_backup(&self) -> Result<(), CoreEnvironmentError> {
+        move_ignored(&self.backup_path(), &self.env_dir)
+            .map_err(CoreEnvironmentError::KeepIgnored)?;
+        debug!("removing backup: path={}", self.backup_path().display());
+        fs::remove_dir_all(self.backup_path()).map_err(CoreEnvironmentError::RemoveBackup)
+    }
+}
+
+impl EnvironmentStore for FileSystemStore {
+    type Error = CoreEnvironmentError;
+
+    fn read_manifest(&self) -> Result<String, Self::Error> {
+        fs::read_to_string(self.env_dir.join(MANIFEST_FILENAME))
+            .map_err(CoreEnvironmentError::OpenManifest)
+    }
+
+    fn read_lockfile(&self) -> Result<Option<String>, Self::Error> {
+        match fs::read_to_string(self.env_dir.join(LOCKFILE_FILENAME)) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(CoreEnvironmentError::LockedManifest(
+                LockedManifestError::ReadLockfile(e),
+            )),
+        }
+    }
+
+    /// Write the files to a copy of the environment directory
+    /// and replace the environment directory with it
+    ///
+    /// Holds the transaction lock of the environment while writing,
+    /// so writes don't race with transactions of [CoreEnvironment].
+    fn write(
+        &mut self,
+        contents: &EnvironmentContents,
+        description: &str,
+    ) -> Result<(), Self::Error> {
+        let _lock = CoreEnvironment::new(&self.env_dir).lock_transaction()?;
+
+        // Keep the copy on the same filesystem as the environment
+        let parent = self.env_dir.parent().unwrap_or(Path::new("."));
+        let tempdir = tempfile::tempdir_in(parent).map_err(CoreEnvironmentError::MakeSandbox)?;
+        clone_dir_recursive(&self.env_dir, &tempdir.path())
+            .map_err(CoreEnvironmentError::MakeTemporaryEnv)?;
+
+        // Files may be reflinks or copies, replace rather than modify them
+        let manifest_path = tempdir.path().join(MANIFEST_FILENAME);
+        let _ = fs::remove_file(&manifest_path);
+        fs::write(&manifest_path, &contents.manifest)
+            .map_err(CoreEnvironmentError::UpdateManifest)?;
+        let lockfile_path = tempdir.path().join(LOCKFILE_FILENAME);
+        if lockfile_path.exists() {
+            fs::remove_file(&lockfile_path).map_err(CoreEnvironmentError::WriteLockfile)?;
+        }
+        if let Some(lockfile) = &contents.lockfile {
+            fs::write(&lockfile_path, lockfile).map_err(CoreEnvironmentError::WriteLockfile)?;
+        }
+
+        self.replace_with_dir(tempdir.path())?;
+
+        if !self.local_generations {
+            return Ok(());
+        }
+        // The environment has been replaced at this point,
+        // so failing to record it is not a failure of the write
+        if let Err(err) =
+            self.generations()
+                .add_generation(&self.env_dir, None, description.to_string())
+        {
+            warn!("failed to record generation: {err}");
+        }
+        Ok(())
+    }
+
+    fn history(&self) -> Result<Vec<Revision>, Self::Error> {
+        let metadata = self
+            .generations()
+            .metadata()
+            .map_err(CoreEnvironmentError::Generations)?;
+        Ok(metadata
+            .generations
+            .into_iter()
+            .map(|(generation, metadata)| Revision {
+                id: generation.to_string(),
+                created: metadata.created,
+                description: metadata.description,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn write_replaces_files_and_records_history() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let env_dir = tempdir.path().join("env");
+        fs::create_dir(&env_dir).unwrap();
+        fs::write(env_dir.join(MANIFEST_FILENAME), "version = 1").unwrap();
+        fs::write(env_dir.join(LOCKFILE_FILENAME), "{}").unwrap();
+        fs::write(env_dir.join("asset"), "kept").unwrap();
+
+        let mut store = FileSystemStore::new(&env_dir).with_local_generations();
+        assert_eq!(store.read_lockfile().unwrap(), Some("{}".to_string()));
+        assert_eq!(store.history().unwrap(), vec![]);
+
+        store
+            .write(
+                &EnvironmentContents {
+                    manifest: "version = 1 # edited".to_string(),
+                    lockfile: None,
+                },
+                "edited",
+            )
+            .unwrap();
+
+        assert_eq!(store.read_manifest().unwrap(), "version = 1 # edited");
+        assert_eq!(store.read_lockfile().unwrap(), None);
+        assert_eq!(fs::read_to_string(env_dir.join("asset")).unwrap(), "kept");
+        let history = store.history().unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].id, "1");
+        assert_eq!(history[0].description, "edited");
+    }
+
+    #[test]
+    fn write_records_history_only_with_local_generations() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let env_dir = tempdir.path().join("env");
+        fs::create_dir(&env_dir).unwrap();
+        fs::write(env_dir.join(MANIFEST_FILENAME), "version = 1").unwrap();
+
+        let mut store = FileSystemStore::new(&env_dir);
+        store
+            .write(
+                &EnvironmentContents {
+                    manifest: "version = 1 # edited".to_string(),
+                    lockfile: None,
+                },
+                "edited",
+            )
+            .unwrap();
+
+        assert_eq!(store.read_manifest().unwrap(), "version = 1 # edited");
+        assert_eq!(store.history().unwrap(), vec![]);
+        assert!(!env_dir.with_extension("generations").exists());
+    }
+}
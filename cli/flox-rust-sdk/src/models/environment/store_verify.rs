@@ -0,0 +1,137 @@
+//! Validity of the store paths an environment depends on
+//!
+//! Out-links keep built environments alive, but store paths can still disappear,
+//! e.g. when a project is moved and its out-link no longer acts as a
+//! garbage collector root, or become corrupted on disk.
+//! Such environments fail to activate until they are rebuilt.
+
+use std::path::{Path, PathBuf};
+
+use log::debug;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::providers::flake::nix_command;
+use crate::utils::CommandExt;
+
+#[derive(Debug, Error)]
+pub enum StoreVerifyError {
+    #[error("failed to call nix")]
+    CallNix(#[source] std::io::Error),
+    #[error("failed to verify store paths:\n{0}")]
+    Verify(String),
+    #[error("failed to repair store paths:\n{0}")]
+    Repair(String),
+}
+
+/// The store paths of an environment that are no longer usable
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct StoreVerification {
+    /// Store paths that don't exist in the local store
+    pub missing: Vec<PathBuf>,
+    /// Store paths whose contents don't match the hash recorded by nix
+    pub corrupted: Vec<PathBuf>,
+}
+
+impl StoreVerification {
+    pub fn is_valid(&self) -> bool {
+        self.missing.is_empty() && self.corrupted.is_empty()
+    }
+}
+
+/// Check that `paths` exist in the local store and are not corrupted
+///
+/// If `recursive` is set, the closures of `paths` are checked for corruption as well.
+/// Signatures are not checked,
+/// paths that were built locally are as valid as substituted ones.
+pub(super) fn verify_store_paths(
+    paths: &[PathBuf],
+    recursive: bool,
+) -> Result<StoreVerification, StoreVerifyError> {
+    let (present, missing): (Vec<_>, Vec<_>) =
+        paths.iter().cloned().partition(|path| path.exists());
+
+    if present.is_empty() {
+        return Ok(StoreVerification {
+            missing,
+            corrupted: vec![],
+        });
+    }
+
+    let mut command = nix_command();
+    command.args(["store", "verify", "--no-trust"]);
+    if recursive {
+        command.arg("--recursive");
+    }
+    command.args(&present);
+    debug!("verifying store paths with command: {}", command.display());
+    let output = command.output().map_err(StoreVerifyError::CallNix)?;
+
+    // `nix store verify` fails if any path is corrupted,
+    // and names each of them on stderr.
+    // If it fails without naming any, the paths couldn't be verified at all.
+    let corrupted = if output.status.success() {
+        vec![]
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let corrupted = parse_modified_paths(&stderr);
+        if corrupted.is_empty() {
+            return Err(StoreVerifyError::Verify(stderr.to_string()));
+        }
+        corrupted
+    };
+
+    Ok(StoreVerification { missing, corrupted })
+}
+
+/// Collect the paths reported as `path '<path>' was modified!` by `nix store verify`
+fn parse_modified_paths(stderr: &str) -> Vec<PathBuf> {
+    stderr
+        .lines()
+        .filter_map(|line| {
+            let (_, rest) = line.split_once("path '")?;
+            let (path, rest) = rest.split_once('\'')?;
+            rest.trim_start()
+                .starts_with("was modified")
+                .then(|| PathBuf::from(path))
+        })
+        .collect()
+}
+
+/// Restore the contents of corrupted store paths from a substituter,
+/// or by rebuilding them
+pub(super) fn repair_store_paths(paths: &[impl AsRef<Path>]) -> Result<(), StoreVerifyError> {
+    if paths.is_empty() {
+        return Ok(());
+    }
+    let mut command = nix_command();
+    command
+        .args(["store", "repair"])
+        .args(paths.iter().map(AsRef::as_ref));
+    debug!("repairing store paths with command: {}", command.display());
+    let output = command.output().map_err(StoreVerifyError::CallNix)?;
+    if !output.status.success() {
+        return Err(StoreVerifyError::Repair(
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_modified_paths() {
+        let stderr = "\
+checking path '/nix/store/aaa-hello'...
+error: path '/nix/store/bbb-curl' was modified! expected hash 'sha256:1', got 'sha256:2'
+error: path '/nix/store/ccc-git' is not valid
+2 paths checked, 1 corrupted";
+
+        assert_eq!(parse_modified_paths(stderr), vec![PathBuf::from(
+            "/nix/store/bbb-curl"
+        )]);
+    }
+}
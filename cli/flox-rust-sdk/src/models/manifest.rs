@@ -2,13 +2,16 @@ use std::collections::{BTreeMap, HashMap};
 use std::process::Command;
 use std::str::FromStr;
 
+use indexmap::IndexMap;
 use log::debug;
 use serde::de::Error;
 use serde::{Deserialize, Serialize};
-use toml_edit::{self, DocumentMut, Formatted, InlineTable, Item, Table, Value};
+use toml_edit::{self, DocumentMut, Formatted, InlineTable, Item, Table, TableLike, Value};
 
 use crate::data::{System, Version};
 use crate::models::pkgdb::PKGDB_BIN;
+use crate::providers::flake::split_installable;
+use crate::providers::store_path::{is_store_path, split_package_name, store_path_name};
 
 pub(super) const DEFAULT_GROUP_NAME: &str = "toplevel";
 pub(super) const DEFAULT_PRIORITY: usize = 5;
@@ -90,6 +93,10 @@ pub enum TypedManifest {
 #[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct TypedManifestCatalog {
     pub(super) version: Version<1>,
+    /// Other environments that are merged into this manifest,
+    /// see [crate::models::include].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(super) include: Vec<String>,
     /// The packages to install in the form of a map from install_id
     /// to package descriptor.
     #[serde(default)]
@@ -107,6 +114,62 @@ pub struct TypedManifestCatalog {
     /// Options that control the behavior of the manifest.
     #[serde(default)]
     pub(super) options: ManifestOptions,
+    /// Long-running processes of the environment, by name.
+    #[serde(default, skip_serializing_if = "ManifestServices::is_empty")]
+    pub(super) services: ManifestServices,
+}
+
+impl TypedManifestCatalog {
+    /// Merge the manifests of included environments into this manifest
+    ///
+    /// Included manifests are merged in order, so later includes take precedence
+    /// over earlier ones, and this manifest takes precedence over all includes:
+    ///
+    /// * packages, variables, and services with the same install ID or name are replaced
+    /// * hooks and profile scripts are concatenated, the scripts of this manifest run last
+    /// * options are taken from this manifest,
+    ///   only `options.systems` falls back to the last include that sets it
+    pub(crate) fn merged_with_includes(
+        &self,
+        included: impl IntoIterator<Item = TypedManifestCatalog>,
+    ) -> TypedManifestCatalog {
+        let mut merged = TypedManifestCatalog {
+            include: self.include.clone(),
+            options: self.options.clone(),
+            ..Default::default()
+        };
+
+        for manifest in included.into_iter().chain([self.clone()]) {
+            merged.install.0.extend(manifest.install.0);
+            merged.vars.0.extend(manifest.vars.0);
+            merged.services.0.extend(manifest.services.0);
+            append_script(&mut merged.hook.on_activate, manifest.hook.on_activate);
+            append_script(&mut merged.profile.common, manifest.profile.common);
+            append_script(&mut merged.profile.bash, manifest.profile.bash);
+            append_script(&mut merged.profile.zsh, manifest.profile.zsh);
+            if manifest.options.systems.is_some() {
+                merged.options.systems = manifest.options.systems;
+            }
+        }
+
+        merged
+    }
+}
+
+/// Append `script` to the script `merged`, on a new line
+fn append_script(merged: &mut Option<String>, script: Option<String>) {
+    let Some(script) = script else {
+        return;
+    };
+    match merged {
+        Some(merged) => {
+            if !merged.ends_with('\n') {
+                merged.push('\n');
+            }
+            merged.push_str(&script);
+        },
+        None => *merged = Some(script),
+    }
 }
 
 #[derive(
@@ -122,10 +185,46 @@ pub struct TypedManifestCatalog {
 #[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct ManifestInstall(BTreeMap<String, ManifestPackageDescriptor>);
 
+/// A package in the `[install]` table of a manifest
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, derive_more::From)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+#[serde(untagged)]
+pub enum ManifestPackageDescriptor {
+    /// A package resolved with the catalog
+    Catalog(ManifestPackageDescriptorCatalog),
+    /// A package installed from a flake installable
+    FlakeRef(ManifestPackageDescriptorFlake),
+    /// A package installed from a store path
+    StorePath(ManifestPackageDescriptorStorePath),
+}
+
+impl ManifestPackageDescriptor {
+    pub fn as_catalog_descriptor_ref(&self) -> Option<&ManifestPackageDescriptorCatalog> {
+        match self {
+            ManifestPackageDescriptor::Catalog(descriptor) => Some(descriptor),
+            _ => None,
+        }
+    }
+
+    pub fn as_flake_descriptor_ref(&self) -> Option<&ManifestPackageDescriptorFlake> {
+        match self {
+            ManifestPackageDescriptor::FlakeRef(descriptor) => Some(descriptor),
+            _ => None,
+        }
+    }
+
+    pub fn as_store_path_descriptor_ref(&self) -> Option<&ManifestPackageDescriptorStorePath> {
+        match self {
+            ManifestPackageDescriptor::StorePath(descriptor) => Some(descriptor),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 #[serde(rename_all = "kebab-case")]
-pub struct ManifestPackageDescriptor {
+pub struct ManifestPackageDescriptorCatalog {
     pub(crate) pkg_path: String,
     pub(crate) pkg_group: Option<String>,
     pub(crate) priority: Option<usize>,
@@ -133,9 +232,13 @@ pub struct ManifestPackageDescriptor {
     pub(crate) systems: Option<Vec<System>>,
     #[serde(default)]
     pub(crate) optional: bool,
+    /// Keep the package at its locked derivation when upgrading,
+    /// see [ManifestEdit::SetPinned]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) pinned: bool,
 }
 
-impl ManifestPackageDescriptor {
+impl ManifestPackageDescriptorCatalog {
     /// Check if two package descriptors should have the same resolution.
     /// This is used to determine if a package needs to be re-resolved
     /// in the presence of an existing lock.
@@ -143,15 +246,17 @@ impl ManifestPackageDescriptor {
     /// * Descriptors are resolved per system,
     ///   changing the supported systems does not invalidate _existing_ resolutions.
     /// * Priority is not used in resolution, so it is ignored.
+    /// * Pinning only affects upgrades, the existing resolution is kept.
     pub(super) fn invalidates_existing_resolution(&self, other: &Self) -> bool {
         // unpack to avoid forgetting to update this method when new fields are added
-        let ManifestPackageDescriptor {
+        let ManifestPackageDescriptorCatalog {
             pkg_path,
             pkg_group,
             version,
             optional,
             systems: _,
             priority: _,
+            pinned: _,
         } = self;
 
         pkg_path != &other.pkg_path
@@ -161,10 +266,83 @@ impl ManifestPackageDescriptor {
     }
 }
 
+/// A package installed from a flake installable, e.g. `github:owner/repo#package`
+///
+/// The flake is locked to a revision when the manifest is locked.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+#[serde(rename_all = "kebab-case")]
+pub struct ManifestPackageDescriptorFlake {
+    pub(crate) flake: String,
+    pub(crate) priority: Option<usize>,
+    pub(crate) systems: Option<Vec<System>>,
+}
+
+/// A package installed from a store path that was built outside of flox,
+/// e.g. `/nix/store/<hash>-hello-2.12.1`
+///
+/// The store path may also be a derivation, in which case all of its outputs are installed.
+/// If `systems` is not set, the package is installed for the system of its derivation,
+/// or all systems of the manifest if the derivation is not known.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+#[serde(rename_all = "kebab-case")]
+pub struct ManifestPackageDescriptorStorePath {
+    pub(crate) store_path: String,
+    pub(crate) priority: Option<usize>,
+    pub(crate) systems: Option<Vec<System>>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq, Hash)]
 #[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct ManifestVariables(BTreeMap<String, String>);
 
+#[derive(
+    Debug,
+    Clone,
+    Serialize,
+    Deserialize,
+    Default,
+    PartialEq,
+    Eq,
+    Hash,
+    derive_more::Deref,
+    derive_more::DerefMut,
+)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct ManifestServices(BTreeMap<String, ServiceDescriptor>);
+
+impl ManifestServices {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// A service in the `[services]` table of a manifest
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+#[serde(rename_all = "kebab-case")]
+pub struct ServiceDescriptor {
+    /// The command that starts the service, run in a bash shell
+    pub command: String,
+    /// Variables that are set for the service in addition to `[vars]`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub vars: BTreeMap<String, String>,
+    /// Whether the service is restarted when it exits, defaults to [RestartPolicy::Never]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restart: Option<RestartPolicy>,
+}
+
+/// When a service is restarted after it exits
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    Never,
+    OnFailure,
+    Always,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq, Hash)]
 #[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 #[serde(rename_all = "kebab-case")]
@@ -193,22 +371,30 @@ pub struct ManifestOptions {
     pub(super) systems: Option<Vec<System>>,
     /// Options that control what types of packages are allowed.
     #[serde(default)]
-    allow: Allows,
+    pub(super) allow: Allows,
     /// Options that control how semver versions are resolved.
     #[serde(default)]
     pub semver: SemverOptions,
+    /// Binary caches that packages are substituted from when building,
+    /// in addition to those configured for nix.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(super) substituters: Vec<String>,
+    /// Public keys that signatures of substituted packages are trusted for,
+    /// in addition to those configured for nix.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(super) trusted_public_keys: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq, Hash)]
 #[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct Allows {
     /// Whether to allow packages that are marked as `unfree`
-    unfree: Option<bool>,
+    pub(super) unfree: Option<bool>,
     /// Whether to allow packages that are marked as `broken`
-    broken: Option<bool>,
+    pub(super) broken: Option<bool>,
     /// A list of license descriptors that are allowed
     #[serde(default)]
-    licenses: Vec<String>,
+    pub(super) licenses: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq, Hash)]
@@ -278,16 +464,38 @@ pub enum TomlEditError {
     /// The `[install]` table was missing entirely
     #[error("'install' table not found")]
     MissingInstallTable,
-    /// Tried to uninstall a package that wasn't installed
-    #[error("couldn't uninstall '{0}', wasn't previously installed")]
-    PackageNotFound(String),
+    /// Tried to uninstall packages none of which were installed
+    #[error("couldn't uninstall '{}', wasn't previously installed", .0.join("', '"))]
+    PackageNotFound(Vec<String>),
     #[error("'options' must be a table, but found {0} instead")]
     MalformedOptionsTable(String),
     #[error("'options' must be an array, but found {0} instead")]
     MalformedOptionsSystemsArray(String),
+    #[error("'vars' must be a table, but found {0} instead")]
+    MalformedVarsTable(String),
+    #[error("'options.{0}' must be a table, but found {1} instead")]
+    MalformedOption(String, String),
 
     #[error("'{0}' is not a supported attribute in manifest version 1")]
     UnsupportedAttributeV1(String),
+    #[error("installing '{0}' from a flake requires manifest version 1")]
+    FlakeRequiresV1(String),
+    #[error("installing store path '{0}' requires manifest version 1")]
+    StorePathRequiresV1(String),
+    #[error("configuring service '{0}' requires manifest version 1")]
+    ServicesRequireV1(String),
+    #[error("service '{0}' must have a command")]
+    EmptyServiceCommand(String),
+    #[error("couldn't remove service '{0}', it is not configured")]
+    ServiceNotFound(String),
+    #[error("'services' must be a table, but found {0} instead")]
+    MalformedServicesTable(String),
+    #[error("pinning '{0}' requires manifest version 1")]
+    PinRequiresV1(String),
+    #[error("couldn't pin '{0}', it is not installed")]
+    PinPackageNotFound(String),
+    #[error("couldn't pin '{0}', only packages from the catalog can be pinned")]
+    PinRequiresCatalogPackage(String),
 }
 
 /// Records the result of trying to install a collection of packages to the
@@ -297,18 +505,45 @@ pub struct PackageInsertion {
     pub already_installed: HashMap<String, bool>,
 }
 
+/// The outcome of uninstalling a package requested by its install ID
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UninstallResult {
+    /// The package was removed
+    Removed,
+    /// The package was removed and was the last package of its group,
+    /// so the group is dropped from the lockfile as well
+    RemovedWithGroupCleanup { group: String },
+    /// No package with the requested install ID is installed
+    NotFound,
+}
+
+/// Records the result of trying to remove a collection of packages from the
+/// manifest
+#[derive(Debug)]
+pub struct PackageRemoval {
+    pub new_toml: DocumentMut,
+    /// The outcome for each requested install ID, in the order requested
+    pub results: IndexMap<String, UninstallResult>,
+}
+
 /// A package to install.
 ///
 /// Users may specify a different install ID than the package name,
 /// especially when the package is nested. This struct is the common
 /// denominator for packages with specified IDs and packages with
 /// default IDs.
-#[derive(Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct PackageToInstall {
     pub id: String,
     pub pkg_path: String,
     pub version: Option<String>,
     pub input: Option<String>,
+    /// The flake installable to install the package from,
+    /// in which case `pkg_path` is the attribute path of the installable.
+    pub flake: Option<String>,
+    /// The store path or derivation to install the package from,
+    /// in which case `pkg_path` is the name of the store path.
+    pub store_path: Option<String>,
 }
 
 impl FromStr for PackageToInstall {
@@ -351,6 +586,55 @@ pub fn insert_packages(
     };
 
     for pkg in pkgs {
+        if manifest_version != Some(1) {
+            if let Some(ref flake) = pkg.flake {
+                Err(TomlEditError::FlakeRequiresV1(flake.clone()))?;
+            }
+            if let Some(ref store_path) = pkg.store_path {
+                Err(TomlEditError::StorePathRequiresV1(store_path.clone()))?;
+            }
+        }
+
+        // Packages from flakes and store paths are described by a single field
+        let source = match (&pkg.flake, &pkg.store_path) {
+            (Some(flake), _) => Some(("flake", flake)),
+            (None, Some(store_path)) => Some(("store-path", store_path)),
+            (None, None) => None,
+        };
+        if let Some((key, source)) = source {
+            if install_table.contains_key(&pkg.id) {
+                already_installed.insert(pkg.id.clone(), true);
+                debug!("package already installed: id={}", pkg.id);
+                continue;
+            }
+            let mut descriptor_table = InlineTable::new();
+            descriptor_table.insert(key, Value::String(Formatted::new(source.clone())));
+            descriptor_table.set_dotted(true);
+            install_table.insert(&pkg.id, Item::Value(Value::InlineTable(descriptor_table)));
+            already_installed.insert(pkg.id.clone(), false);
+            debug!("package newly installed: id={}, {key}={source}", pkg.id);
+            continue;
+        }
+
+        if let Some(version) = &pkg.version {
+            if let Some(descriptor) = install_table
+                .get_mut(&pkg.id)
+                .and_then(Item::as_table_like_mut)
+            {
+                // Installing an installed package with a different version constraint
+                // updates the constraint rather than requiring a manual edit.
+                if descriptor.get("version").and_then(Item::as_str) != Some(version.as_str()) {
+                    descriptor.insert("version", toml_edit::value(version.clone()));
+                    already_installed.insert(pkg.id.clone(), false);
+                    debug!(
+                        "package version constraint updated: id={}, version={}",
+                        pkg.id, version
+                    );
+                    continue;
+                }
+            }
+        }
+
         if !install_table.contains_key(&pkg.id) {
             let mut descriptor_table = InlineTable::new();
             descriptor_table.insert(
@@ -394,10 +678,14 @@ pub fn insert_packages(
 }
 
 /// Remove package names from the `[install]` table of a manifest
+///
+/// Packages that are not installed are reported as [UninstallResult::NotFound],
+/// unless none of the requested packages are installed,
+/// in which case [TomlEditError::PackageNotFound] is returned.
 pub fn remove_packages(
     manifest_contents: &str,
     pkgs: &[String],
-) -> Result<DocumentMut, TomlEditError> {
+) -> Result<PackageRemoval, TomlEditError> {
     debug!("attempting to remove packages from the manifest");
     let mut toml = manifest_contents
         .parse::<RawManifest>()
@@ -407,7 +695,7 @@ pub fn remove_packages(
     let installs_table = {
         let installs_field = toml
             .get_mut("install")
-            .ok_or(TomlEditError::PackageNotFound(pkgs[0].clone()))?;
+            .ok_or(TomlEditError::PackageNotFound(pkgs.to_vec()))?;
 
         let type_name = installs_field.type_name().into();
 
@@ -416,18 +704,56 @@ pub fn remove_packages(
             .ok_or(TomlEditError::MalformedInstallTable(type_name))?
     };
 
+    let pkg_group = |descriptor: &Item| {
+        descriptor
+            .as_table_like()
+            .and_then(|descriptor| descriptor.get("pkg-group"))
+            .and_then(Item::as_str)
+            .map(String::from)
+    };
+
+    let mut results = IndexMap::new();
     for pkg in pkgs {
         debug!("checking for presence of package '{pkg}'");
-        if !installs_table.contains_key(pkg) {
-            debug!("package '{pkg}' wasn't found");
-            return Err(TomlEditError::PackageNotFound(pkg.clone()));
-        } else {
-            installs_table.remove(pkg);
-            debug!("package '{pkg}' was removed");
+        match installs_table.remove(pkg) {
+            None => {
+                debug!("package '{pkg}' wasn't found");
+                results.insert(pkg.clone(), UninstallResult::NotFound);
+            },
+            Some(descriptor) => {
+                debug!("package '{pkg}' was removed");
+                let result = match pkg_group(&descriptor) {
+                    Some(group) => UninstallResult::RemovedWithGroupCleanup { group },
+                    None => UninstallResult::Removed,
+                };
+                results.insert(pkg.clone(), result);
+            },
         }
     }
 
-    Ok(toml)
+    if results
+        .values()
+        .all(|result| *result == UninstallResult::NotFound)
+    {
+        return Err(TomlEditError::PackageNotFound(pkgs.to_vec()));
+    }
+
+    // Groups that still have installed packages are not cleaned up
+    for result in results.values_mut() {
+        if let UninstallResult::RemovedWithGroupCleanup { group } = result {
+            let group_remains = installs_table
+                .iter()
+                .any(|(_, descriptor)| pkg_group(descriptor).as_ref() == Some(group));
+            if group_remains {
+                *result = UninstallResult::Removed;
+            }
+        }
+    }
+
+    Ok(PackageRemoval {
+        new_toml: toml,
+        results,
+    })
 }
 
 /// Check whether a TOML document contains a line declaring that the provided package
@@ -490,6 +816,375 @@ pub fn add_system(toml: &str, system: &str) -> Result<DocumentMut, TomlEditError
     Ok(doc)
 }
 
+/// A structured change to a manifest, applied by [apply_manifest_edits]
+#[derive(Debug, Clone)]
+pub enum ManifestEdit {
+    /// Set the variable `name` in `[vars]` to `value`
+    SetVar { name: String, value: String },
+    /// Remove the variable `name` from `[vars]`, if it is set
+    UnsetVar { name: String },
+    /// Install a package, see [insert_packages]
+    AddPackage(PackageToInstall),
+    /// Uninstall the package with the install ID `install_id`, see [remove_packages]
+    RemovePackage { install_id: String },
+    /// Set the option at the dot-separated `path` in `[options]`,
+    /// e.g. `allow.unfree`
+    SetOption { path: String, value: Value },
+    /// Remove the option at the dot-separated `path` in `[options]`, if it is set
+    UnsetOption { path: String },
+    /// Add the service `name` to `[services]`, or replace it if it exists
+    SetService {
+        name: String,
+        service: ServiceDescriptor,
+    },
+    /// Remove the service `name` from `[services]`
+    RemoveService { name: String },
+    /// Pin or unpin the catalog package with the install ID `install_id`
+    ///
+    /// Upgrades keep pinned packages at their locked derivation.
+    SetPinned { install_id: String, pinned: bool },
+}
+
+/// Apply `edits` to a manifest in order
+///
+/// Unlike replacing the manifest as a whole, only the edited entries change,
+/// comments and formatting of the rest of the manifest are preserved.
+/// The edited manifest is validated before it is returned.
+pub fn apply_manifest_edits(
+    manifest_contents: &str,
+    edits: &[ManifestEdit],
+) -> Result<DocumentMut, TomlEditError> {
+    debug!("attempting to apply edits to the manifest");
+    let mut toml = manifest_contents
+        .parse::<RawManifest>()
+        .map_err(TomlEditError::ParseManifest)?
+        .0;
+
+    for edit in edits {
+        debug!("applying manifest edit: {edit:?}");
+        match edit {
+            ManifestEdit::SetVar { name, value } => {
+                let vars = toml.entry("vars").or_insert(Item::Table(Table::new()));
+                let vars_type = vars.type_name().into();
+                let vars = vars
+                    .as_table_like_mut()
+                    .ok_or(TomlEditError::MalformedVarsTable(vars_type))?;
+                set_preserving_key(vars, name, toml_edit::value(value));
+            },
+            ManifestEdit::UnsetVar { name } => {
+                if let Some(vars) = toml.get_mut("vars").and_then(Item::as_table_like_mut) {
+                    vars.remove(name);
+                }
+            },
+            ManifestEdit::AddPackage(package) => {
+                let insertion = insert_packages(&toml.to_string(), std::slice::from_ref(package))?;
+                if let Some(new_toml) = insertion.new_toml {
+                    toml = new_toml;
+                }
+            },
+            ManifestEdit::RemovePackage { install_id } => {
+                toml =
+                    remove_packages(&toml.to_string(), std::slice::from_ref(install_id))?.new_toml;
+            },
+            ManifestEdit::SetOption { path, value } => {
+                let (parents, option) = match path.rsplit_once('.') {
+                    Some((parents, option)) => (Some(parents), option),
+                    None => (None, path.as_str()),
+                };
+                let mut options_table = Table::new();
+                options_table.set_implicit(true);
+                let options = toml.entry("options").or_insert(Item::Table(options_table));
+                let options_type = options.type_name().into();
+                let mut table = options
+                    .as_table_like_mut()
+                    .ok_or(TomlEditError::MalformedOptionsTable(options_type))?;
+                for (depth, key) in parents.into_iter().flat_map(|p| p.split('.')).enumerate() {
+                    let mut implicit_table = Table::new();
+                    implicit_table.set_implicit(true);
+                    let item = table.entry(key).or_insert(Item::Table(implicit_table));
+                    let item_type = item.type_name().into();
+                    table = item.as_table_like_mut().ok_or_else(|| {
+                        let key_path = path.split('.').take(depth + 1).collect::<Vec<_>>();
+                        TomlEditError::MalformedOption(key_path.join("."), item_type)
+                    })?;
+                }
+                set_preserving_key(table, option, Item::Value(value.clone()));
+            },
+            ManifestEdit::UnsetOption { path } => {
+                let (parents, option) = match path.rsplit_once('.') {
+                    Some((parents, option)) => (Some(parents), option),
+                    None => (None, path.as_str()),
+                };
+                let mut table = toml.get_mut("options").and_then(Item::as_table_like_mut);
+                for key in parents.into_iter().flat_map(|p| p.split('.')) {
+                    table = table
+                        .and_then(|table| table.get_mut(key))
+                        .and_then(Item::as_table_like_mut);
+                }
+                if let Some(table) = table {
+                    table.remove(option);
+                }
+            },
+            ManifestEdit::SetService { name, service } => {
+                if RawManifest(toml.clone()).get_version() != Some(1) {
+                    return Err(TomlEditError::ServicesRequireV1(name.clone()));
+                }
+                if service.command.trim().is_empty() {
+                    return Err(TomlEditError::EmptyServiceCommand(name.clone()));
+                }
+                let mut service_table = toml_edit::ser::to_document(service)
+                    .expect("service descriptors serialize to a table")
+                    .as_table()
+                    .clone();
+
+                let mut services_table = Table::new();
+                services_table.set_implicit(true);
+                let services = toml
+                    .entry("services")
+                    .or_insert(Item::Table(services_table));
+                let services_type = services.type_name().into();
+                // Services added to an explicit `[services]` table are written as dotted keys,
+                // otherwise as `[services.<name>]` tables
+                let dotted = services
+                    .as_table()
+                    .is_some_and(|table| !table.is_implicit());
+                let services = services
+                    .as_table_like_mut()
+                    .ok_or(TomlEditError::MalformedServicesTable(services_type))?;
+
+                // Update an existing service in place to keep its comments and formatting
+                match services.get_mut(name).and_then(Item::as_table_like_mut) {
+                    Some(existing) => {
+                        let stale = existing
+                            .iter()
+                            .map(|(key, _)| key.to_string())
+                            .filter(|key| !service_table.contains_key(key))
+                            .collect::<Vec<_>>();
+                        for key in stale {
+                            existing.remove(&key);
+                        }
+                        for (key, item) in service_table.iter() {
+                            set_preserving_key(existing, key, item.clone());
+                        }
+                    },
+                    None => {
+                        service_table.set_dotted(dotted);
+                        services.insert(name, Item::Table(service_table));
+                    },
+                }
+            },
+            ManifestEdit::RemoveService { name } => {
+                let removed = toml
+                    .get_mut("services")
+                    .and_then(Item::as_table_like_mut)
+                    .and_then(|services| services.remove(name));
+                if removed.is_none() {
+                    return Err(TomlEditError::ServiceNotFound(name.clone()));
+                }
+            },
+            ManifestEdit::SetPinned { install_id, pinned } => {
+                if RawManifest(toml.clone()).get_version() != Some(1) {
+                    return Err(TomlEditError::PinRequiresV1(install_id.clone()));
+                }
+                let descriptor = toml
+                    .get_mut("install")
+                    .and_then(Item::as_table_like_mut)
+                    .and_then(|install| install.get_mut(install_id))
+                    .and_then(Item::as_table_like_mut)
+                    .ok_or_else(|| TomlEditError::PinPackageNotFound(install_id.clone()))?;
+                if !descriptor.contains_key("pkg-path") {
+                    return Err(TomlEditError::PinRequiresCatalogPackage(install_id.clone()));
+                }
+                if *pinned {
+                    set_preserving_key(descriptor, "pinned", toml_edit::value(true));
+                } else {
+                    descriptor.remove("pinned");
+                }
+            },
+        }
+    }
+
+    // Validate the result, edits may produce a structurally invalid manifest,
+    // e.g. by setting an option to a value of the wrong type.
+    let _validate = toml
+        .to_string()
+        .parse::<RawManifest>()
+        .map_err(TomlEditError::ParseManifest)?;
+
+    Ok(toml)
+}
+
+/// Set `key` in `table` to `item`,
+/// keeping comments attached to the key if it is already set
+fn set_preserving_key(table: &mut dyn TableLike, key: &str, item: Item) {
+    match table.get_mut(key) {
+        Some(existing) => *existing = item,
+        None => {
+            table.insert(key, item);
+        },
+    }
+}
+
+/// An error encountered while migrating a manifest to version 1
+#[derive(Debug, thiserror::Error)]
+pub enum MigrateManifestError {
+    #[error("couldn't parse manifest contents: {0}")]
+    ParseManifest(toml_edit::de::Error),
+    #[error("manifest is already a version {0} manifest")]
+    AlreadyMigrated(i64),
+    #[error("'{0}' must be a table, but found {1} instead")]
+    MalformedTable(String, String),
+    #[error("package '{0}' doesn't specify a 'pkg-path' or 'name'")]
+    MissingPkgPath(String),
+    #[error("package '{install_id}' is installed from '{repository}', but manifest version 1 only supports packages from the catalog")]
+    UnsupportedPackageRepository {
+        install_id: String,
+        repository: String,
+    },
+    #[error("'{0}' is not a supported attribute in manifest version 1")]
+    UnsupportedAttribute(String),
+}
+
+/// The result of [migrate_manifest_to_catalog]
+#[derive(Debug)]
+pub struct MigratedManifest {
+    pub toml: DocumentMut,
+    /// Attributes that have no equivalent in manifest version 1
+    /// and were removed, e.g. `options.activation-strategy`
+    pub dropped: Vec<String>,
+}
+
+/// Translate a version 0 manifest, locked by pkgdb,
+/// to a version 1 manifest that is locked with the catalog
+///
+/// * `pkg-path`s given as a list of attributes are joined with `.`,
+///   and packages only identified by `name` use it as their `pkg-path`
+/// * `hook.script` is renamed to `hook.on-activate`
+/// * `options.semver.prefer-pre-releases` is renamed to `options.semver.allow-pre-releases`
+/// * the `registry` and options that only apply to pkgdb are dropped
+///
+/// Packages that are installed from a specific input or by absolute path
+/// can't be resolved with the catalog, so such manifests can't be migrated.
+/// Comments and formatting of the manifest are preserved.
+pub fn migrate_manifest_to_catalog(
+    manifest_contents: &str,
+) -> Result<MigratedManifest, MigrateManifestError> {
+    debug!("attempting to migrate manifest to version 1");
+    let manifest = manifest_contents
+        .parse::<RawManifest>()
+        .map_err(MigrateManifestError::ParseManifest)?;
+    if let Some(version) = manifest.get_version() {
+        return Err(MigrateManifestError::AlreadyMigrated(version));
+    }
+    let mut toml = manifest.0;
+    let mut dropped = vec![];
+
+    if toml.contains_key("env-base") {
+        return Err(MigrateManifestError::UnsupportedAttribute(
+            "env-base".to_string(),
+        ));
+    }
+    // Packages are resolved with the catalog rather than the inputs of the registry
+    if toml.remove("registry").is_some() {
+        dropped.push("registry".to_string());
+    }
+
+    if let Some(install) = toml.get_mut("install") {
+        let install_type = install.type_name().into();
+        let install = install
+            .as_table_like_mut()
+            .ok_or(MigrateManifestError::MalformedTable(
+                "install".to_string(),
+                install_type,
+            ))?;
+        for (install_id, descriptor) in install.iter_mut() {
+            migrate_descriptor(install_id.get(), descriptor)?;
+        }
+    }
+
+    if let Some(hook) = toml.get_mut("hook").and_then(Item::as_table_like_mut) {
+        if !hook.contains_key("on-activate") {
+            if let Some(script) = hook.remove("script") {
+                hook.insert("on-activate", script);
+            }
+        }
+    }
+
+    if let Some(options) = toml.get_mut("options").and_then(Item::as_table_like_mut) {
+        for option in ["package-grouping-strategy", "activation-strategy"] {
+            if options.remove(option).is_some() {
+                dropped.push(format!("options.{option}"));
+            }
+        }
+        if let Some(semver) = options.get_mut("semver").and_then(Item::as_table_like_mut) {
+            if let Some(prefer_pre_releases) = semver.remove("prefer-pre-releases") {
+                semver.insert("allow-pre-releases", prefer_pre_releases);
+            }
+        }
+    }
+
+    // Parsing validates the result against the schema of version 1
+    let migrated = format!("version = 1\n\n{toml}")
+        .parse::<RawManifest>()
+        .map_err(MigrateManifestError::ParseManifest)?;
+
+    Ok(MigratedManifest {
+        toml: migrated.0,
+        dropped,
+    })
+}
+
+/// Translate a single `[install]` entry of a version 0 manifest to version 1
+fn migrate_descriptor(install_id: &str, item: &mut Item) -> Result<(), MigrateManifestError> {
+    let descriptor_type = item.type_name().into();
+    let descriptor = item
+        .as_table_like_mut()
+        .ok_or(MigrateManifestError::MalformedTable(
+            format!("install.{install_id}"),
+            descriptor_type,
+        ))?;
+
+    if let Some(repository) = descriptor.get("package-repository") {
+        return Err(MigrateManifestError::UnsupportedPackageRepository {
+            install_id: install_id.to_string(),
+            repository: repository.to_string().trim().to_string(),
+        });
+    }
+    if descriptor.contains_key("abspath") {
+        return Err(MigrateManifestError::UnsupportedAttribute(format!(
+            "install.{install_id}.abspath"
+        )));
+    }
+
+    let name = descriptor.remove("name");
+    match descriptor.get_mut("pkg-path") {
+        // Version 0 also accepts a list of attributes
+        Some(pkg_path) if pkg_path.is_array() => {
+            let joined = pkg_path
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+                .collect::<Vec<_>>()
+                .join(".");
+            *pkg_path = toml_edit::value(joined);
+        },
+        Some(_) => {},
+        None => {
+            let name = name
+                .as_ref()
+                .and_then(Item::as_str)
+                .ok_or_else(|| MigrateManifestError::MissingPkgPath(install_id.to_string()))?;
+            descriptor.insert("pkg-path", toml_edit::value(name));
+        },
+    }
+    // Keys inserted into inline tables aren't formatted
+    if let Some(inline) = item.as_inline_table_mut() {
+        inline.fmt();
+    }
+    Ok(())
+}
+
 /// A parsed descriptor from `pkgdb parse descriptor --manifest`
 ///
 /// FIXME: this is currently a hack using a tool in `pkgdb` only meant for debugging.
@@ -511,16 +1206,147 @@ pub struct Input {
     pub id: String,
 }
 
+/// Split the version constraint off a shorthand descriptor
+///
+/// `<package>@<constraint>` constrains the version of `<package>`,
+/// where `<constraint>` is either an exact version prefixed with `=`, e.g. `=1.2.3`,
+/// or a semver range, e.g. `^20` or `3.11.*`.
+/// Versions that are not semver ranges, e.g. `unstable-2024-01-01`,
+/// can only match exactly and are prefixed with `=`.
+/// The constraint is written to the `version` field of the descriptor.
+/// An `@` within a quoted attribute is not a separator.
+fn split_version_constraint(descriptor: &str) -> Result<(&str, Option<String>), ManifestError> {
+    let mut quoted = false;
+    let mut separator = None;
+    for (i, c) in descriptor.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '@' if !quoted => separator = Some(i),
+            _ => {},
+        }
+    }
+    let Some(separator) = separator else {
+        return Ok((descriptor, None));
+    };
+    let (package, constraint) = (&descriptor[..separator], &descriptor[separator + 1..]);
+    if constraint.trim().is_empty() || constraint.trim() == "=" {
+        return Err(ManifestError::MalformedStringDescriptor {
+            msg: "descriptor had an empty version constraint".to_string(),
+            desc: descriptor.to_string(),
+        });
+    }
+    let constraint = constraint.trim();
+    if constraint.starts_with('=') || is_semver_range(constraint) {
+        Ok((package, Some(constraint.to_string())))
+    } else {
+        Ok((package, Some(format!("={constraint}"))))
+    }
+}
+
+/// Whether `constraint` is a semver range, e.g. `^20`, `>=1.2 <2`, or `1.x || 2.x`
+fn is_semver_range(constraint: &str) -> bool {
+    constraint.split("||").all(|alternative| {
+        let alternative = alternative.trim();
+        // hyphen ranges, e.g. `1.2.3 - 2.3.4`
+        let bounds = alternative
+            .split_once(" - ")
+            .map_or(vec![alternative], |(lower, upper)| vec![lower, upper]);
+        !alternative.is_empty()
+            && bounds
+                .into_iter()
+                .flat_map(str::split_whitespace)
+                .all(|comparator| semver::VersionReq::parse(comparator).is_ok())
+    })
+}
+
+/// URL schemes of flake references that can be installed
+const FLAKE_REF_SCHEMES: [&str; 9] = [
+    "github:",
+    "gitlab:",
+    "sourcehut:",
+    "git+",
+    "path:",
+    "tarball+",
+    "file+",
+    "http://",
+    "https://",
+];
+
+/// Parse a flake installable, e.g. `github:owner/repo#package`
+///
+/// Returns `None` if `descriptor` is not a flake installable.
+/// The install ID is the last attribute of the installable,
+/// or the last component of the flake reference if no attribute is given.
+fn parse_flake_installable(descriptor: &str) -> Option<PackageToInstall> {
+    if !FLAKE_REF_SCHEMES
+        .iter()
+        .any(|scheme| descriptor.starts_with(scheme))
+    {
+        return None;
+    }
+    let (flake, attr_path) = split_installable(descriptor);
+    let id = attr_path
+        .rsplit('.')
+        .next()
+        .filter(|id| *id != "default")
+        .or_else(|| {
+            flake
+                .split(['?', '#'])
+                .next()?
+                .trim_end_matches('/')
+                .rsplit(['/', ':'])
+                .next()
+        })
+        .unwrap_or(attr_path);
+    Some(PackageToInstall {
+        id: id.to_string(),
+        pkg_path: attr_path.to_string(),
+        version: None,
+        input: None,
+        flake: Some(descriptor.to_string()),
+        store_path: None,
+    })
+}
+
+/// Parse a store path or derivation, e.g. `/nix/store/<hash>-hello-2.12.1`
+///
+/// Returns `None` if `descriptor` is not a store path.
+/// The install ID is the name of the package without its version.
+fn parse_store_path(descriptor: &str) -> Option<PackageToInstall> {
+    let descriptor = descriptor.trim_end_matches('/');
+    if !is_store_path(descriptor) {
+        return None;
+    }
+    let name = store_path_name(descriptor);
+    let (pname, _version) = split_package_name(name);
+    Some(PackageToInstall {
+        id: pname.to_string(),
+        pkg_path: name.to_string(),
+        version: None,
+        input: None,
+        flake: None,
+        store_path: Some(descriptor.to_string()),
+    })
+}
+
 /// Parse a shorthand descriptor into structured data
 ///
 /// FIXME: this is currently a hack using a tool in `pkgdb` only meant for debugging.
+/// Version constraints are parsed by [split_version_constraint] already.
 pub fn temporary_parse_descriptor(descriptor: &str) -> Result<PackageToInstall, ManifestError> {
+    if let Some(package) = parse_flake_installable(descriptor) {
+        return Ok(package);
+    }
+    if let Some(package) = parse_store_path(descriptor) {
+        return Ok(package);
+    }
+    let (package, version) = split_version_constraint(descriptor)?;
     let output = Command::new(&*PKGDB_BIN)
         .arg("parse")
         .arg("descriptor")
         .arg("--to")
         .arg("manifest")
-        .arg(descriptor)
+        .arg(package)
         .output()
         .map_err(ManifestError::PkgDbCall)?;
     let parsed: Result<ParsedDescriptor, _> = serde_json::from_slice(&output.stdout);
@@ -552,19 +1378,14 @@ pub fn temporary_parse_descriptor(descriptor: &str) -> Result<PackageToInstall,
                 desc: descriptor.to_string(),
             });
         };
-        let version = if let Some(version) = parsed.version {
-            let mut v = "=".to_string();
-            v.push_str(&version);
-            Some(v)
-        } else {
-            parsed.semver
-        };
         let input = parsed.input.map(|input| input.id);
         Ok(PackageToInstall {
             id,
             pkg_path: path,
             version,
             input,
+            flake: None,
+            store_path: None,
         })
     } else {
         Err(ManifestError::MalformedStringDescriptor {
@@ -605,11 +1426,13 @@ ripgrep = {}
     pub fn empty_catalog_manifest() -> TypedManifestCatalog {
         TypedManifestCatalog {
             version: Version,
+            include: vec![],
             install: ManifestInstall::default(),
             vars: ManifestVariables::default(),
             hook: ManifestHook::default(),
             profile: ManifestProfile::default(),
             options: ManifestOptions::default(),
+            services: ManifestServices::default(),
         }
     }
 
@@ -710,18 +1533,304 @@ ripgrep = {}
     #[test]
     fn removes_all_requested_packages() {
         let test_packages = vec!["hello".to_owned(), "ripgrep".to_owned()];
-        let toml = remove_packages(DUMMY_MANIFEST, &test_packages).unwrap();
-        assert!(!contains_package(&toml, "hello").unwrap());
-        assert!(!contains_package(&toml, "ripgrep").unwrap());
+        let removal = remove_packages(DUMMY_MANIFEST, &test_packages).unwrap();
+        assert!(!contains_package(&removal.new_toml, "hello").unwrap());
+        assert!(!contains_package(&removal.new_toml, "ripgrep").unwrap());
+        assert_eq!(
+            removal.results,
+            IndexMap::from([
+                ("hello".to_string(), UninstallResult::Removed),
+                ("ripgrep".to_string(), UninstallResult::Removed),
+            ])
+        );
     }
 
     #[test]
-    fn error_when_removing_nonexistent_package() {
+    fn reports_nonexistent_package() {
         let test_packages = vec!["hello".to_owned(), "DOES_NOT_EXIST".to_owned()];
+        let removal = remove_packages(DUMMY_MANIFEST, &test_packages).unwrap();
+        assert!(!contains_package(&removal.new_toml, "hello").unwrap());
+        assert_eq!(removal.results["DOES_NOT_EXIST"], UninstallResult::NotFound);
+    }
+
+    #[test]
+    fn error_when_removing_only_nonexistent_packages() {
+        let test_packages = vec!["DOES_NOT_EXIST".to_owned()];
         let removal = remove_packages(DUMMY_MANIFEST, &test_packages);
         assert!(matches!(removal, Err(TomlEditError::PackageNotFound(_))));
     }
 
+    #[test]
+    fn applies_manifest_edits_preserving_comments() {
+        let manifest = indoc! {r#"
+            version = 1
+
+            # the packages
+            [install]
+            hello.pkg-path = "hello"
+
+            [vars]
+            # greeting used by the hook
+            GREETING = "hello"
+            UNUSED = "unused"
+        "#};
+
+        let edits = [
+            ManifestEdit::SetVar {
+                name: "GREETING".to_string(),
+                value: "howdy".to_string(),
+            },
+            ManifestEdit::UnsetVar {
+                name: "UNUSED".to_string(),
+            },
+            ManifestEdit::AddPackage(PackageToInstall {
+                id: "ripgrep".to_string(),
+                pkg_path: "ripgrep".to_string(),
+                version: None,
+                input: None,
+                flake: None,
+                store_path: None,
+            }),
+            ManifestEdit::RemovePackage {
+                install_id: "hello".to_string(),
+            },
+            ManifestEdit::SetOption {
+                path: "allow.unfree".to_string(),
+                value: Value::from(true),
+            },
+        ];
+        let toml = apply_manifest_edits(manifest, &edits).unwrap();
+        assert_eq!(toml.to_string(), indoc! {r#"
+            version = 1
+
+            # the packages
+            [install]
+            ripgrep.pkg-path = "ripgrep"
+
+            [vars]
+            # greeting used by the hook
+            GREETING = "howdy"
+
+            [options.allow]
+            unfree = true
+        "#});
+
+        let edits = [ManifestEdit::UnsetOption {
+            path: "allow.unfree".to_string(),
+        }];
+        let toml = apply_manifest_edits(&toml.to_string(), &edits).unwrap();
+        assert!(!toml.to_string().contains("unfree"));
+
+        let edits = [ManifestEdit::SetOption {
+            path: "systems".to_string(),
+            value: Value::from("x86_64-linux"),
+        }];
+        assert!(matches!(
+            apply_manifest_edits(manifest, &edits),
+            Err(TomlEditError::ParseManifest(_))
+        ));
+    }
+
+    #[test]
+    fn pins_catalog_packages() {
+        let manifest = indoc! {r#"
+            version = 1
+
+            [install]
+            # the compiler
+            gcc.pkg-path = "gcc"
+            hello.flake = "github:nixos/nixpkgs#hello"
+        "#};
+
+        let pin = |install_id: &str, pinned| ManifestEdit::SetPinned {
+            install_id: install_id.to_string(),
+            pinned,
+        };
+        let toml = apply_manifest_edits(manifest, &[pin("gcc", true)]).unwrap();
+        assert_eq!(toml.to_string(), indoc! {r#"
+            version = 1
+
+            [install]
+            # the compiler
+            gcc.pkg-path = "gcc"
+            gcc.pinned = true
+            hello.flake = "github:nixos/nixpkgs#hello"
+        "#});
+
+        let toml = apply_manifest_edits(&toml.to_string(), &[pin("gcc", false)]).unwrap();
+        assert_eq!(toml.to_string(), manifest);
+
+        assert_eq!(
+            apply_manifest_edits(manifest, &[pin("hello", true)]).unwrap_err(),
+            TomlEditError::PinRequiresCatalogPackage("hello".to_string())
+        );
+        assert_eq!(
+            apply_manifest_edits(manifest, &[pin("curl", true)]).unwrap_err(),
+            TomlEditError::PinPackageNotFound("curl".to_string())
+        );
+    }
+
+    #[test]
+    fn applies_service_edits() {
+        let manifest = indoc! {r#"
+            version = 1
+
+            [services]
+            # the database
+            postgres.command = "postgres"
+        "#};
+
+        let edits = [
+            ManifestEdit::SetService {
+                name: "postgres".to_string(),
+                service: ServiceDescriptor {
+                    command: "postgres -D $PGDATA".to_string(),
+                    vars: BTreeMap::from([("PGDATA".to_string(), "./data".to_string())]),
+                    restart: Some(RestartPolicy::OnFailure),
+                },
+            },
+            ManifestEdit::SetService {
+                name: "redis".to_string(),
+                service: ServiceDescriptor {
+                    command: "redis-server".to_string(),
+                    vars: BTreeMap::new(),
+                    restart: None,
+                },
+            },
+        ];
+        let toml = apply_manifest_edits(manifest, &edits).unwrap();
+        let TypedManifest::Catalog(typed) = RawManifest(toml.clone()).to_typed().unwrap() else {
+            panic!("expected a catalog manifest");
+        };
+        assert_eq!(typed.services.len(), 2);
+        assert_eq!(
+            typed.services["postgres"].restart,
+            Some(RestartPolicy::OnFailure)
+        );
+        assert_eq!(toml.to_string(), indoc! {r#"
+            version = 1
+
+            [services]
+            # the database
+            postgres.command = "postgres -D $PGDATA"
+            postgres.vars = { PGDATA = "./data" }
+            postgres.restart = "on-failure"
+            redis.command = "redis-server"
+        "#});
+
+        let edits = [ManifestEdit::RemoveService {
+            name: "redis".to_string(),
+        }];
+        let toml = apply_manifest_edits(&toml.to_string(), &edits).unwrap();
+        assert!(!toml.to_string().contains("redis"));
+
+        assert_eq!(
+            apply_manifest_edits(&toml.to_string(), &edits).unwrap_err(),
+            TomlEditError::ServiceNotFound("redis".to_string())
+        );
+        let empty_command = [ManifestEdit::SetService {
+            name: "broken".to_string(),
+            service: ServiceDescriptor {
+                command: " ".to_string(),
+                vars: BTreeMap::new(),
+                restart: None,
+            },
+        }];
+        assert_eq!(
+            apply_manifest_edits(manifest, &empty_command).unwrap_err(),
+            TomlEditError::EmptyServiceCommand("broken".to_string())
+        );
+    }
+
+    #[test]
+    fn migrates_manifest_to_catalog() {
+        let manifest = indoc! {r#"
+            # the packages
+            [install]
+            hello.pkg-path = ["hello"]
+            python = { name = "python3", version = "^3.11" }
+            ripgrep.pkg-path = "ripgrep"
+            ripgrep.pkg-group = "search"
+
+            [registry.inputs.nixpkgs]
+            from = { type = "github", owner = "NixOS", repo = "nixpkgs" }
+
+            [hook]
+            script = "echo hello"
+
+            [options]
+            systems = ["x86_64-linux"]
+            activation-strategy = "etc-profiles"
+            semver.prefer-pre-releases = true
+        "#};
+
+        let migrated = migrate_manifest_to_catalog(manifest).unwrap();
+        assert_eq!(migrated.toml.to_string(), indoc! {r#"
+            version = 1
+
+            # the packages
+            [install]
+            hello.pkg-path = "hello"
+            python = { version = "^3.11", pkg-path = "python3" }
+            ripgrep.pkg-path = "ripgrep"
+            ripgrep.pkg-group = "search"
+
+            [hook]
+            on-activate = "echo hello"
+
+            [options]
+            systems = ["x86_64-linux"]
+            semver.allow-pre-releases = true
+        "#});
+        assert_eq!(migrated.dropped, vec![
+            "registry".to_string(),
+            "options.activation-strategy".to_string()
+        ]);
+
+        let manifest = indoc! {r#"
+            [install]
+            hello = { pkg-path = "hello", package-repository = "nixpkgs" }
+        "#};
+        assert!(matches!(
+            migrate_manifest_to_catalog(manifest),
+            Err(MigrateManifestError::UnsupportedPackageRepository { install_id, .. }) if install_id == "hello"
+        ));
+        assert!(matches!(
+            migrate_manifest_to_catalog("version = 1"),
+            Err(MigrateManifestError::AlreadyMigrated(1))
+        ));
+    }
+
+    #[test]
+    fn reports_group_cleanup() {
+        let manifest = indoc! {r#"
+            version = 1
+
+            [install]
+            hello.pkg-path = "hello"
+            hello.pkg-group = "greeters"
+            cowsay.pkg-path = "cowsay"
+            cowsay.pkg-group = "greeters"
+            ripgrep.pkg-path = "ripgrep"
+            ripgrep.pkg-group = "search"
+        "#};
+
+        let removal =
+            remove_packages(manifest, &["hello".to_string(), "ripgrep".to_string()]).unwrap();
+        assert_eq!(
+            removal.results,
+            IndexMap::from([
+                ("hello".to_string(), UninstallResult::Removed),
+                (
+                    "ripgrep".to_string(),
+                    UninstallResult::RemovedWithGroupCleanup {
+                        group: "search".to_string()
+                    }
+                ),
+            ])
+        );
+    }
+
     #[test]
     fn inserts_package_needing_quotes() {
         let attrs = r#"foo."bar.baz".qux"#;
@@ -755,6 +1864,148 @@ ripgrep = {}
         )
     }
 
+    #[test]
+    fn splits_version_constraints() {
+        assert_eq!(
+            split_version_constraint("nodejs@^20").unwrap(),
+            ("nodejs", Some("^20".to_string()))
+        );
+        assert_eq!(
+            split_version_constraint("python3@3.11.*").unwrap(),
+            ("python3", Some("3.11.*".to_string()))
+        );
+        assert_eq!(
+            split_version_constraint("nixpkgs:foo.bar@=1.2.3").unwrap(),
+            ("nixpkgs:foo.bar", Some("=1.2.3".to_string()))
+        );
+        assert_eq!(
+            split_version_constraint("python3@>=3.11 <3.13").unwrap(),
+            ("python3", Some(">=3.11 <3.13".to_string()))
+        );
+        assert_eq!(
+            split_version_constraint("hello@unstable-2024-01-01").unwrap(),
+            ("hello", Some("=unstable-2024-01-01".to_string()))
+        );
+        assert_eq!(
+            split_version_constraint(r#"foo."bar@baz""#).unwrap(),
+            (r#"foo."bar@baz""#, None)
+        );
+        assert!(split_version_constraint("nodejs@").is_err());
+    }
+
+    #[test]
+    fn insert_writes_and_updates_version_constraints() {
+        let nodejs = |version: &str| PackageToInstall {
+            id: "nodejs".to_string(),
+            pkg_path: "nodejs".to_string(),
+            version: Some(version.to_string()),
+            input: None,
+            flake: None,
+            store_path: None,
+        };
+
+        let insertion = insert_packages(CATALOG_MANIFEST, &[nodejs("^20")]).unwrap();
+        let manifest = insertion.new_toml.unwrap().to_string();
+        let typed: TypedManifestCatalog = toml::from_str(&manifest).unwrap();
+        assert_eq!(
+            typed.install["nodejs"]
+                .as_catalog_descriptor_ref()
+                .unwrap()
+                .version
+                .as_deref(),
+            Some("^20")
+        );
+
+        let insertion = insert_packages(&manifest, &[nodejs("^20")]).unwrap();
+        assert!(insertion.new_toml.is_none());
+
+        let insertion = insert_packages(&manifest, &[nodejs("^22")]).unwrap();
+        assert!(!insertion.already_installed["nodejs"]);
+        let typed: TypedManifestCatalog =
+            toml::from_str(&insertion.new_toml.unwrap().to_string()).unwrap();
+        assert_eq!(
+            typed.install["nodejs"]
+                .as_catalog_descriptor_ref()
+                .unwrap()
+                .version
+                .as_deref(),
+            Some("^22")
+        );
+    }
+
+    #[test]
+    fn parses_flake_installables() {
+        assert_eq!(
+            temporary_parse_descriptor("github:owner/repo#hello").unwrap(),
+            PackageToInstall {
+                id: "hello".to_string(),
+                pkg_path: "hello".to_string(),
+                version: None,
+                input: None,
+                flake: Some("github:owner/repo#hello".to_string()),
+                store_path: None,
+            }
+        );
+        assert_eq!(
+            temporary_parse_descriptor("github:owner/repo").unwrap().id,
+            "repo"
+        );
+    }
+
+    #[test]
+    fn insert_flake_installable() {
+        let package = parse_flake_installable("github:owner/repo#hello").unwrap();
+        let insertion = insert_packages(CATALOG_MANIFEST, &[package]).unwrap();
+        let typed: TypedManifestCatalog =
+            toml::from_str(&insertion.new_toml.unwrap().to_string()).unwrap();
+        assert_eq!(
+            typed.install["hello"],
+            ManifestPackageDescriptor::FlakeRef(ManifestPackageDescriptorFlake {
+                flake: "github:owner/repo#hello".to_string(),
+                priority: None,
+                systems: None,
+            })
+        );
+
+        let package = parse_flake_installable("github:owner/repo#hello").unwrap();
+        assert_eq!(
+            insert_packages(DUMMY_MANIFEST, &[package]).unwrap_err(),
+            TomlEditError::FlakeRequiresV1("github:owner/repo#hello".to_string())
+        );
+    }
+
+    #[test]
+    fn insert_store_path() {
+        let store_path = "/nix/store/jqn7cb1w1cd2ylxkhfzmgmf8lsxn8zaf-hello-2.12.1";
+        let package = temporary_parse_descriptor(store_path).unwrap();
+        assert_eq!(package, PackageToInstall {
+            id: "hello".to_string(),
+            pkg_path: "hello-2.12.1".to_string(),
+            version: None,
+            input: None,
+            flake: None,
+            store_path: Some(store_path.to_string()),
+        });
+
+        let insertion = insert_packages(CATALOG_MANIFEST, &[package]).unwrap();
+        let typed: TypedManifestCatalog =
+            toml::from_str(&insertion.new_toml.unwrap().to_string()).unwrap();
+        assert_eq!(
+            typed.install["hello"],
+            ManifestPackageDescriptor::StorePath(ManifestPackageDescriptorStorePath {
+                store_path: store_path.to_string(),
+                priority: None,
+                systems: None,
+            })
+        );
+
+        let package = temporary_parse_descriptor(store_path).unwrap();
+        assert_eq!(
+            insert_packages(DUMMY_MANIFEST, &[package]).unwrap_err(),
+            TomlEditError::StorePathRequiresV1(store_path.to_string())
+        );
+    }
+
     #[test]
     fn parses_string_descriptor() {
         // FIXME: remove or update this test when `flox` can parse descriptors on its own
@@ -764,27 +2015,35 @@ ripgrep = {}
             pkg_path: "hello".to_string(),
             version: None,
             input: None,
+            flake: None,
+            store_path: None,
         });
         let parsed = temporary_parse_descriptor("nixpkgs:foo.bar@=1.2.3").unwrap();
         assert_eq!(parsed, PackageToInstall {
             id: "bar".to_string(),
             pkg_path: "foo.bar".to_string(),
             version: Some("=1.2.3".to_string()),
-            input: Some("nixpkgs".to_string())
+            input: Some("nixpkgs".to_string()),
+            flake: None,
+            store_path: None,
         });
         let parsed = temporary_parse_descriptor("nixpkgs:foo.bar@23.11").unwrap();
         assert_eq!(parsed, PackageToInstall {
             id: "bar".to_string(),
             pkg_path: "foo.bar".to_string(),
             version: Some("23.11".to_string()),
-            input: Some("nixpkgs".to_string())
+            input: Some("nixpkgs".to_string()),
+            flake: None,
+            store_path: None,
         });
         let parsed = temporary_parse_descriptor("nixpkgs:rubyPackages.\"http_parser.rb\"").unwrap();
         assert_eq!(parsed, PackageToInstall {
             id: "\"http_parser.rb\"".to_string(),
             pkg_path: "rubyPackages.\"http_parser.rb\"".to_string(),
             version: None,
-            input: Some("nixpkgs".to_string())
+            input: Some("nixpkgs".to_string()),
+            flake: None,
+            store_path: None,
         });
     }
 }
@@ -1,10 +1,15 @@
 //# An attempt at defining a domain model for flox
+pub mod build_failure;
 pub mod container_builder;
 pub mod env_registry;
 pub mod environment;
 pub mod environment_ref;
 pub mod floxmeta;
+pub mod hook_trust;
+pub mod include;
 pub mod lockfile;
 pub mod manifest;
 pub mod pkgdb;
+pub mod remote_builder;
+pub mod sbom;
 pub mod search;
@@ -1,3 +1,4 @@
+use std::collections::VecDeque;
 use std::env;
 use std::fmt::Display;
 use std::io::{BufRead, BufReader, Read};
@@ -63,6 +64,42 @@ pub struct BuildEnvResult {
     pub store_path: String,
 }
 
+/// The number of trailing stderr lines of a failed pkgdb call
+/// that are kept in [PkgDbError::log_tail]
+pub const LOG_TAIL_LINES: usize = 25;
+
+/// A line of pkgdb's stderr, e.g. build output of a package
+#[derive(Debug, Clone, PartialEq)]
+pub struct BuildLogLine {
+    /// The derivation that produced the line, if the line is build output.
+    ///
+    /// Nix prefixes build output with the name of the derivation, i.e. `<name>> <output>`
+    pub derivation: Option<String>,
+    pub line: String,
+}
+
+impl BuildLogLine {
+    /// The build directory of a failed build that nix kept,
+    /// if the line is nix's note about it
+    fn kept_build_dir(&self) -> Option<PathBuf> {
+        let (_, rest) = self.line.split_once("keeping build directory '")?;
+        let (dir, _) = rest.split_once('\'')?;
+        Some(PathBuf::from(dir))
+    }
+
+    pub(crate) fn parse(line: &str) -> Self {
+        let derivation = line
+            .split_once("> ")
+            .map(|(prefix, _)| prefix)
+            .filter(|prefix| !prefix.is_empty() && !prefix.contains(char::is_whitespace))
+            .map(ToString::to_string);
+        Self {
+            derivation,
+            line: line.to_string(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct UpgradeResult {
     pub packages: Vec<String>,
@@ -90,7 +127,18 @@ pub enum CallPkgDbError {
 /// Call pkgdb and try to parse JSON or error JSON.
 ///
 /// Error JSON is parsed into a [CallPkgDbError::PkgDbError].
-pub fn call_pkgdb(mut pkgdb_cmd: Command) -> Result<Value, CallPkgDbError> {
+pub fn call_pkgdb(pkgdb_cmd: Command) -> Result<Value, CallPkgDbError> {
+    call_pkgdb_with_log(pkgdb_cmd, |_| {})
+}
+
+/// Call pkgdb like [call_pkgdb], passing every line pkgdb writes to stderr to `on_line`
+///
+/// If pkgdb fails, the last [LOG_TAIL_LINES] lines are attached to the [PkgDbError],
+/// as well as the build directories that nix reported to keep.
+pub fn call_pkgdb_with_log(
+    mut pkgdb_cmd: Command,
+    mut on_line: impl FnMut(BuildLogLine) + Send,
+) -> Result<Value, CallPkgDbError> {
     // Configure pkgdb PATH with exact versions of everything it needs.
     //
     // Nix itself isn't pure, which is to say that it isn't built with a
@@ -117,12 +165,22 @@ pub fn call_pkgdb(mut pkgdb_cmd: Command) -> Result<Value, CallPkgDbError> {
 
     let pkgdb_output = std::thread::scope(|s| {
         let stderr_thread = s.spawn(move || {
+            let mut log_tail = VecDeque::with_capacity(LOG_TAIL_LINES);
+            let mut kept_build_dirs = vec![];
             stderr_reader
                 .lines()
                 .map_while(Result::ok)
                 .for_each(|line| {
                     debug!(target: "pkgdb", "{line}");
+                    let log_line = BuildLogLine::parse(&line);
+                    kept_build_dirs.extend(log_line.kept_build_dir());
+                    on_line(log_line);
+                    if log_tail.len() == LOG_TAIL_LINES {
+                        log_tail.pop_front();
+                    }
+                    log_tail.push_back(line);
                 });
+            (log_tail, kept_build_dirs)
         });
         let stdout_thread = s.spawn(move || {
             let mut contents = String::new();
@@ -131,12 +189,12 @@ pub fn call_pkgdb(mut pkgdb_cmd: Command) -> Result<Value, CallPkgDbError> {
             bytes_read.map(|_| contents)
         });
         tracing::trace!("waiting for background threads to finish");
-        let _ = stderr_thread.join();
+        let stderr_res = stderr_thread.join().unwrap_or_default();
         let stdout_res = stdout_thread.join();
         tracing::trace!("done waiting for background threads");
-        stdout_res
+        stdout_res.map(|stdout| (stdout, stderr_res))
     });
-    let Ok(stdout_contents) = pkgdb_output else {
+    let Ok((stdout_contents, (log_tail, kept_build_dirs))) = pkgdb_output else {
         // Something went wrong in one of the background threads
         return Err(CallPkgDbError::SomethingElse(
             "failed to process pkgdb output".into(),
@@ -146,7 +204,11 @@ pub fn call_pkgdb(mut pkgdb_cmd: Command) -> Result<Value, CallPkgDbError> {
     let _wait_res = proc.wait();
     match stdout_contents {
         Ok(json) => match serde_json::from_str::<PkgDbError>(&json) {
-            Ok(pkgdb_err) => Err(CallPkgDbError::PkgDbError(pkgdb_err)),
+            Ok(mut pkgdb_err) => {
+                pkgdb_err.log_tail = log_tail.into();
+                pkgdb_err.kept_build_dirs = kept_build_dirs;
+                Err(CallPkgDbError::PkgDbError(pkgdb_err))
+            },
             Err(_) => serde_json::from_str(&json).map_err(CallPkgDbError::ParseJSON),
         },
         Err(e) => Err(CallPkgDbError::PkgDbCall(e)),
@@ -163,6 +225,11 @@ pub struct PkgDbError {
     pub category_message: String,
     /// The more contextual message for the specific error that occurred.
     pub context_message: Option<ContextMsgError>,
+    /// The last lines pkgdb wrote to stderr before failing, see [call_pkgdb_with_log]
+    pub log_tail: Vec<String>,
+    /// The build directories nix kept of failed builds,
+    /// if `keep-failed` was enabled, see [call_pkgdb_with_log]
+    pub kept_build_dirs: Vec<PathBuf>,
 }
 
 impl<'de> Deserialize<'de> for PkgDbError {
@@ -215,6 +282,8 @@ impl<'de> Deserialize<'de> for PkgDbError {
             exit_code,
             category_message,
             context_message,
+            log_tail: vec![],
+            kept_build_dirs: vec![],
         })
     }
 }
@@ -286,3 +355,50 @@ pub fn scrape_input(input: &FlakeRef) -> Result<(), ScrapeError> {
     call_pkgdb(pkgdb_cmd)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_derivation_of_build_output() {
+        assert_eq!(
+            BuildLogLine::parse("hello> checking for gcc").derivation,
+            Some("hello".to_string())
+        );
+        assert_eq!(
+            BuildLogLine::parse("building '/nix/store/...-hello.drv'...").derivation,
+            None
+        );
+        assert_eq!(BuildLogLine::parse("error: a > b").derivation, None);
+    }
+
+    #[test]
+    fn call_pkgdb_streams_stderr_and_keeps_log_tail() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg(indoc::indoc! {r#"
+            echo "hello> building" >&2
+            echo "hello> failed" >&2
+            echo "note: keeping build directory '/tmp/nix-build-hello.drv-0'" >&2
+            echo '{"exit_code": 126, "category_message": "build failure"}'
+        "#});
+
+        let mut lines = vec![];
+        let err = call_pkgdb_with_log(cmd, |line| lines.push(line)).unwrap_err();
+
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].derivation, Some("hello".to_string()));
+        let CallPkgDbError::PkgDbError(err) = err else {
+            panic!("expected a pkgdb error, got {err:?}");
+        };
+        assert_eq!(err.exit_code, error_codes::PACKAGE_BUILD_FAILURE);
+        assert_eq!(err.log_tail, vec![
+            "hello> building",
+            "hello> failed",
+            "note: keeping build directory '/tmp/nix-build-hello.drv-0'"
+        ]);
+        assert_eq!(err.kept_build_dirs, vec![PathBuf::from(
+            "/tmp/nix-build-hello.drv-0"
+        )]);
+    }
+}
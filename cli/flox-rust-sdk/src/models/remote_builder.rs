@@ -0,0 +1,118 @@
+//! Remote machines that nix can delegate builds to
+//!
+//! Building an environment for a system other than the current one,
+//! e.g. a linux container on macOS, only succeeds if every package
+//! can be substituted from a binary cache or nix has a builder for that system.
+//! Builders are configured on [Flox](crate::flox::Flox)
+//! and passed to the nix evaluator in `pkgdb` through `NIX_CONFIG`.
+
+use std::path::PathBuf;
+use std::process::Command;
+
+use serde::{Deserialize, Serialize};
+
+use crate::data::System;
+use crate::utils::CommandExt;
+
+/// A remote machine that nix can delegate builds to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RemoteBuilder {
+    /// The store URI of the builder, e.g. `ssh://builder@linux.example.com`
+    pub uri: String,
+    /// The systems the builder can build for
+    pub systems: Vec<System>,
+    /// The ssh key used to connect to the builder,
+    /// defaults to the ssh configuration of the user
+    #[serde(default)]
+    pub ssh_key: Option<PathBuf>,
+    /// The maximum number of builds nix runs on the builder at once
+    #[serde(default)]
+    pub max_jobs: Option<u32>,
+}
+
+impl RemoteBuilder {
+    /// Format the builder as a machine specification of the nix `builders` setting
+    ///
+    /// Unset fields are written as `-` to use the nix defaults.
+    fn machine_spec(&self) -> String {
+        let systems = if self.systems.is_empty() {
+            "-".to_string()
+        } else {
+            self.systems.join(",")
+        };
+        let ssh_key = self
+            .ssh_key
+            .as_ref()
+            .map_or("-".to_string(), |key| key.to_string_lossy().to_string());
+        let max_jobs = self
+            .max_jobs
+            .map_or("-".to_string(), |max_jobs| max_jobs.to_string());
+        format!("{} {systems} {ssh_key} {max_jobs}", self.uri)
+    }
+}
+
+/// Whether any of `builders` can build for `system`
+pub fn supports_system(builders: &[RemoteBuilder], system: &str) -> bool {
+    builders
+        .iter()
+        .any(|builder| builder.systems.iter().any(|s| s == system))
+}
+
+/// The nix configuration that enables `builders`
+///
+/// Builders are allowed to fetch substitutes themselves,
+/// rather than copying every dependency from the local store.
+fn nix_config(builders: &[RemoteBuilder]) -> Option<String> {
+    if builders.is_empty() {
+        return None;
+    }
+    let machines = builders
+        .iter()
+        .map(RemoteBuilder::machine_spec)
+        .collect::<Vec<_>>()
+        .join(" ; ");
+    Some(format!(
+        "builders = {machines}\nbuilders-use-substitutes = true"
+    ))
+}
+
+/// Configure `command` to delegate builds to `builders`
+///
+/// The configuration is appended to any `NIX_CONFIG` of the current process.
+pub(crate) fn configure_command(command: &mut Command, builders: &[RemoteBuilder]) {
+    if let Some(config) = nix_config(builders) {
+        command.append_nix_config(&config);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_builders_setting() {
+        let builders = [
+            RemoteBuilder {
+                uri: "ssh://builder@linux".to_string(),
+                systems: vec!["x86_64-linux".to_string(), "aarch64-linux".to_string()],
+                ssh_key: Some(PathBuf::from("/keys/builder")),
+                max_jobs: Some(4),
+            },
+            RemoteBuilder {
+                uri: "ssh://other".to_string(),
+                systems: vec![],
+                ssh_key: None,
+                max_jobs: None,
+            },
+        ];
+
+        assert_eq!(
+            nix_config(&builders).unwrap(),
+            "builders = ssh://builder@linux x86_64-linux,aarch64-linux /keys/builder 4 ; ssh://other - - -\nbuilders-use-substitutes = true"
+        );
+        assert!(supports_system(&builders, "aarch64-linux"));
+        assert!(!supports_system(&builders, "aarch64-darwin"));
+        assert_eq!(nix_config(&[]), None);
+    }
+}
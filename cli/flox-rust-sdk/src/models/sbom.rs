@@ -0,0 +1,398 @@
+//! Software bills of materials (SBOMs) of locked environments
+//!
+//! Security teams track the software in use with SBOMs in standard formats.
+//! [Sbom::new] describes the packages of a [LockedManifestCatalog]
+//! as either an [SPDX](https://spdx.dev) 2.3 or a [CycloneDX](https://cyclonedx.org) 1.5
+//! document in their JSON encodings.
+//!
+//! Every locked package is listed once per system it is locked for,
+//! with its name, version, license, and derivation.
+//! Packages installed from flakes or store paths are not resolved by the catalog,
+//! so their versions and licenses are unknown and they are not listed.
+
+use std::str::FromStr;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use thiserror::Error;
+use uuid::Uuid;
+
+use super::lockfile::{LockedManifestCatalog, LockedPackageCatalog};
+use crate::flox::FLOX_VERSION;
+
+/// Value used by SPDX for fields that are unknown
+const NOASSERTION: &str = "NOASSERTION";
+
+#[derive(Debug, Error)]
+pub enum SbomError {
+    #[error("unknown SBOM format '{0}', expected 'spdx' or 'cyclonedx'")]
+    UnknownFormat(String),
+    #[error("couldn't serialize SBOM")]
+    Serialize(#[source] serde_json::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SbomFormat {
+    Spdx,
+    CycloneDx,
+}
+
+impl FromStr for SbomFormat {
+    type Err = SbomError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "spdx" => Ok(SbomFormat::Spdx),
+            "cyclonedx" => Ok(SbomFormat::CycloneDx),
+            _ => Err(SbomError::UnknownFormat(s.to_string())),
+        }
+    }
+}
+
+/// An SBOM document in one of the [SbomFormat]s
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Sbom {
+    Spdx(SpdxDocument),
+    CycloneDx(CycloneDxDocument),
+}
+
+impl Sbom {
+    /// Describe the packages of `lockfile` as an SBOM for the environment `name`
+    pub fn new(lockfile: &LockedManifestCatalog, name: &str, format: SbomFormat) -> Self {
+        Self::new_at(lockfile, name, format, Utc::now())
+    }
+
+    fn new_at(
+        lockfile: &LockedManifestCatalog,
+        name: &str,
+        format: SbomFormat,
+        created: DateTime<Utc>,
+    ) -> Self {
+        match format {
+            SbomFormat::Spdx => Sbom::Spdx(SpdxDocument::new(lockfile, name, created)),
+            SbomFormat::CycloneDx => {
+                Sbom::CycloneDx(CycloneDxDocument::new(lockfile, name, created))
+            },
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, SbomError> {
+        serde_json::to_string_pretty(self).map_err(SbomError::Serialize)
+    }
+}
+
+/// A package URL identifying a package of nixpkgs
+fn purl(package: &LockedPackageCatalog) -> String {
+    format!("pkg:nix/{}@{}", package.pname, package.version)
+}
+
+/// An identifier of `package` that is unique within a lockfile
+fn package_ref(package: &LockedPackageCatalog) -> String {
+    format!("{}-{}", package.install_id, package.system)
+}
+
+fn tool_name() -> String {
+    format!("flox-{}", &*FLOX_VERSION)
+}
+
+// region: SPDX
+
+/// An SPDX 2.3 document
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpdxDocument {
+    pub spdx_version: String,
+    pub data_license: String,
+    #[serde(rename = "SPDXID")]
+    pub spdx_id: String,
+    pub name: String,
+    pub document_namespace: String,
+    pub creation_info: SpdxCreationInfo,
+    pub packages: Vec<SpdxPackage>,
+    pub relationships: Vec<SpdxRelationship>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SpdxCreationInfo {
+    pub created: String,
+    pub creators: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpdxPackage {
+    #[serde(rename = "SPDXID")]
+    pub spdx_id: String,
+    pub name: String,
+    pub version_info: String,
+    pub download_location: String,
+    pub license_concluded: String,
+    pub license_declared: String,
+    pub copyright_text: String,
+    pub external_refs: Vec<SpdxExternalRef>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpdxExternalRef {
+    pub reference_category: String,
+    pub reference_type: String,
+    pub reference_locator: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpdxRelationship {
+    pub spdx_element_id: String,
+    pub relationship_type: String,
+    pub related_spdx_element: String,
+}
+
+impl SpdxDocument {
+    fn new(lockfile: &LockedManifestCatalog, name: &str, created: DateTime<Utc>) -> Self {
+        let packages = lockfile
+            .packages
+            .iter()
+            .map(SpdxPackage::new)
+            .collect::<Vec<_>>();
+        let relationships = packages
+            .iter()
+            .map(|package| SpdxRelationship {
+                spdx_element_id: "SPDXRef-DOCUMENT".to_string(),
+                relationship_type: "DESCRIBES".to_string(),
+                related_spdx_element: package.spdx_id.clone(),
+            })
+            .collect();
+
+        SpdxDocument {
+            spdx_version: "SPDX-2.3".to_string(),
+            data_license: "CC0-1.0".to_string(),
+            spdx_id: "SPDXRef-DOCUMENT".to_string(),
+            name: name.to_string(),
+            document_namespace: format!("https://flox.dev/spdx/{name}-{}", Uuid::new_v4()),
+            creation_info: SpdxCreationInfo {
+                created: created.to_rfc3339_opts(SecondsFormat::Secs, true),
+                creators: vec![format!("Tool: {}", tool_name())],
+            },
+            packages,
+            relationships,
+        }
+    }
+}
+
+impl SpdxPackage {
+    fn new(package: &LockedPackageCatalog) -> Self {
+        let license = package
+            .license
+            .clone()
+            .unwrap_or_else(|| NOASSERTION.to_string());
+        SpdxPackage {
+            // SPDX identifiers may only contain letters, numbers, `.` and `-`
+            spdx_id: format!("SPDXRef-Package-{}", package_ref(package)).replace(
+                |c: char| !c.is_ascii_alphanumeric() && c != '.' && c != '-',
+                "-",
+            ),
+            name: package.pname.clone(),
+            version_info: package.version.clone(),
+            download_location: NOASSERTION.to_string(),
+            license_concluded: NOASSERTION.to_string(),
+            license_declared: license,
+            copyright_text: NOASSERTION.to_string(),
+            external_refs: vec![
+                SpdxExternalRef {
+                    reference_category: "PACKAGE-MANAGER".to_string(),
+                    reference_type: "purl".to_string(),
+                    reference_locator: purl(package),
+                },
+                SpdxExternalRef {
+                    reference_category: "OTHER".to_string(),
+                    reference_type: "nix-derivation".to_string(),
+                    reference_locator: package.derivation.clone(),
+                },
+            ],
+        }
+    }
+}
+
+// endregion
+
+// region: CycloneDX
+
+/// A CycloneDX 1.5 document
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CycloneDxDocument {
+    pub bom_format: String,
+    pub spec_version: String,
+    pub serial_number: String,
+    pub version: u32,
+    pub metadata: CycloneDxMetadata,
+    pub components: Vec<CycloneDxComponent>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CycloneDxMetadata {
+    pub timestamp: String,
+    pub tools: Vec<CycloneDxTool>,
+    pub component: CycloneDxComponent,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CycloneDxTool {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CycloneDxComponent {
+    #[serde(rename = "type")]
+    pub component_type: String,
+    #[serde(rename = "bom-ref")]
+    pub bom_ref: String,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub licenses: Vec<CycloneDxLicense>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub purl: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub properties: Vec<CycloneDxProperty>,
+}
+
+/// A license given as an SPDX license expression
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CycloneDxLicense {
+    pub expression: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CycloneDxProperty {
+    pub name: String,
+    pub value: String,
+}
+
+impl CycloneDxDocument {
+    fn new(lockfile: &LockedManifestCatalog, name: &str, created: DateTime<Utc>) -> Self {
+        CycloneDxDocument {
+            bom_format: "CycloneDX".to_string(),
+            spec_version: "1.5".to_string(),
+            serial_number: format!("urn:uuid:{}", Uuid::new_v4()),
+            version: 1,
+            metadata: CycloneDxMetadata {
+                timestamp: created.to_rfc3339_opts(SecondsFormat::Secs, true),
+                tools: vec![CycloneDxTool { name: tool_name() }],
+                component: CycloneDxComponent {
+                    component_type: "application".to_string(),
+                    bom_ref: name.to_string(),
+                    name: name.to_string(),
+                    version: None,
+                    licenses: vec![],
+                    purl: None,
+                    properties: vec![],
+                },
+            },
+            components: lockfile
+                .packages
+                .iter()
+                .map(CycloneDxComponent::new)
+                .collect(),
+        }
+    }
+}
+
+impl CycloneDxComponent {
+    fn new(package: &LockedPackageCatalog) -> Self {
+        CycloneDxComponent {
+            component_type: "library".to_string(),
+            bom_ref: package_ref(package),
+            name: package.pname.clone(),
+            version: Some(package.version.clone()),
+            licenses: package
+                .license
+                .iter()
+                .map(|license| CycloneDxLicense {
+                    expression: license.clone(),
+                })
+                .collect(),
+            purl: Some(purl(package)),
+            properties: vec![
+                CycloneDxProperty {
+                    name: "nix:derivation".to_string(),
+                    value: package.derivation.clone(),
+                },
+                CycloneDxProperty {
+                    name: "nix:system".to_string(),
+                    value: package.system.clone(),
+                },
+            ],
+        }
+    }
+}
+
+// endregion
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::data::Version;
+    use crate::models::lockfile::tests::fake_package;
+    use crate::models::manifest;
+
+    fn lockfile() -> LockedManifestCatalog {
+        let (_, _, mut hello) = fake_package("hello", None);
+        hello.version = "2.12.1".to_string();
+        hello.license = Some("GPL-3.0-or-later".to_string());
+        LockedManifestCatalog {
+            version: Version,
+            manifest: manifest::test::empty_catalog_manifest(),
+            packages: vec![hello],
+            flake_packages: vec![],
+            store_path_packages: vec![],
+            includes: vec![],
+        }
+    }
+
+    #[test]
+    fn spdx_lists_locked_packages() {
+        let created = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let Sbom::Spdx(document) = Sbom::new_at(&lockfile(), "project", SbomFormat::Spdx, created)
+        else {
+            panic!("expected an SPDX document");
+        };
+
+        assert_eq!(document.creation_info.created, "2024-01-01T00:00:00Z");
+        assert_eq!(document.packages.len(), 1);
+        let package = &document.packages[0];
+        assert_eq!(package.spdx_id, "SPDXRef-Package-hello-install-id-system");
+        assert_eq!(package.name, "hello");
+        assert_eq!(package.version_info, "2.12.1");
+        assert_eq!(package.license_declared, "GPL-3.0-or-later");
+        assert_eq!(
+            package.external_refs[0].reference_locator,
+            "pkg:nix/hello@2.12.1"
+        );
+        assert_eq!(
+            document.relationships[0].related_spdx_element,
+            package.spdx_id
+        );
+    }
+
+    #[test]
+    fn cyclonedx_lists_locked_packages() {
+        let sbom = Sbom::new(&lockfile(), "project", SbomFormat::CycloneDx);
+        let json: serde_json::Value = serde_json::from_str(&sbom.to_json().unwrap()).unwrap();
+
+        assert_eq!(json["bomFormat"], "CycloneDX");
+        assert_eq!(json["metadata"]["component"]["name"], "project");
+        let component = &json["components"][0];
+        assert_eq!(component["bom-ref"], "hello_install_id-system");
+        assert_eq!(component["version"], "2.12.1");
+        assert_eq!(component["licenses"][0]["expression"], "GPL-3.0-or-later");
+        assert_eq!(component["properties"][0]["value"], "derivation");
+    }
+}
@@ -15,6 +15,7 @@ use catalog_api_v1::types::{
 };
 use catalog_api_v1::{Client as APIClient, Error as APIError, ResponseValue};
 use enum_dispatch::enum_dispatch;
+use fslock::LockFile;
 use futures::stream::Stream;
 use futures::{Future, TryStreamExt};
 use log::debug;
@@ -92,7 +93,7 @@ fn read_mock_responses(path: impl AsRef<Path>) -> Result<VecDeque<Response>, Moc
 
 /// Either a client for the actual catalog service,
 /// or a mock client for testing.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 #[enum_dispatch(ClientTrait)]
 pub enum Client {
     Catalog(CatalogClient),
@@ -102,7 +103,7 @@ pub enum Client {
 /// A client for the catalog service.
 ///
 /// This is a wrapper around the auto-generated APIClient.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CatalogClient {
     client: APIClient,
 }
@@ -138,11 +139,13 @@ impl Default for CatalogClient {
 }
 
 /// A catalog client that can be seeded with mock responses
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct MockClient {
     // We use a RefCell here so that we don't have to modify the trait to allow mutable access
     // to `self` just to get mock responses out.
     pub mock_responses: MockField<VecDeque<Response>>,
+    /// The number of resolve requests, shared between clones
+    resolve_calls: MockField<usize>,
 }
 
 impl MockClient {
@@ -155,9 +158,18 @@ impl MockClient {
         };
         Ok(Self {
             mock_responses: Arc::new(Mutex::new(mock_responses)),
+            resolve_calls: Default::default(),
         })
     }
 
+    /// The number of resolve requests made to this client or any of its clones
+    pub fn resolve_calls(&self) -> usize {
+        *self
+            .resolve_calls
+            .lock()
+            .expect("couldn't acquire mock lock")
+    }
+
     /// Push a new response into the list of mock responses
     pub fn push_resolve_response(&mut self, resp: ResolvedGroups) {
         self.mock_responses
@@ -343,6 +355,293 @@ impl ClientTrait for CatalogClient {
     }
 }
 
+/// Resolutions of package groups that were fetched ahead of time.
+///
+/// The cache is shared between clones,
+/// so it can be filled from a background thread.
+#[derive(Debug, Clone, Default)]
+pub struct ResolutionCache {
+    resolved: Arc<Mutex<Vec<(PackageGroup, ResolvedPackageGroup)>>>,
+}
+
+impl ResolutionCache {
+    /// Store the resolutions of `groups`.
+    ///
+    /// The catalog returns resolved groups by name,
+    /// so resolutions are matched to the requested groups by name and system.
+    pub fn insert(&self, groups: Vec<PackageGroup>, resolved: Vec<ResolvedPackageGroup>) {
+        let mut cache = self.resolved.lock().expect("couldn't acquire cache lock");
+        for group in groups {
+            let Some(resolved_group) = resolved
+                .iter()
+                .find(|resolved| resolved.name == group.name && resolved.system == group.system)
+            else {
+                continue;
+            };
+            cache.retain(|(cached, _)| *cached != group);
+            cache.push((group, resolved_group.clone()));
+        }
+    }
+
+    /// Get the resolution of a group that is identical to `group`
+    pub fn get(&self, group: &PackageGroup) -> Option<ResolvedPackageGroup> {
+        self.resolved
+            .lock()
+            .expect("couldn't acquire cache lock")
+            .iter()
+            .find(|(cached, _)| cached == group)
+            .map(|(_, resolved)| resolved.clone())
+    }
+
+    /// Forget all resolutions
+    pub fn clear(&self) {
+        self.resolved
+            .lock()
+            .expect("couldn't acquire cache lock")
+            .clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.resolved
+            .lock()
+            .expect("couldn't acquire cache lock")
+            .is_empty()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum CatalogSnapshotError {
+    #[error("couldn't read catalog snapshot")]
+    Read(#[source] std::io::Error),
+    #[error("couldn't parse catalog snapshot")]
+    Parse(#[source] serde_json::Error),
+    #[error("couldn't write catalog snapshot")]
+    Write(#[source] std::io::Error),
+    #[error("couldn't lock catalog snapshot")]
+    Lock(#[source] std::io::Error),
+}
+
+/// A group resolved by the catalog,
+/// together with the group it was resolved for
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SnapshotEntry {
+    group: PackageGroup,
+    resolved: ResolvedPackageGroup,
+}
+
+/// Resolutions of previous online locks, persisted on disk
+///
+/// Every successful resolution is recorded per package group,
+/// so that environments can be locked while the catalog is unreachable
+/// as long as none of their groups changed.
+/// Packages of a group are resolved together,
+/// so a group is only replayed for exactly the descriptors it was resolved for.
+#[derive(Debug, Clone)]
+pub struct CatalogSnapshot {
+    path: PathBuf,
+}
+
+impl CatalogSnapshot {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    fn read(&self) -> Result<Vec<SnapshotEntry>, CatalogSnapshotError> {
+        let contents = match std::fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(CatalogSnapshotError::Read(e)),
+        };
+        serde_json::from_str(&contents).map_err(CatalogSnapshotError::Parse)
+    }
+
+    /// Lock the snapshot against concurrent [Self::record]s,
+    /// which would otherwise drop each other's entries
+    fn lock(&self) -> Result<LockFile, CatalogSnapshotError> {
+        let mut lock = LockFile::open(self.path.with_extension("lock").as_os_str())
+            .map_err(CatalogSnapshotError::Lock)?;
+        lock.lock().map_err(CatalogSnapshotError::Lock)?;
+        Ok(lock)
+    }
+
+    /// Record the resolutions of `groups`
+    ///
+    /// Only the first page of a resolved group is recorded,
+    /// as that is the only page that is locked.
+    /// Entries for the same group are replaced.
+    pub fn record(
+        &self,
+        groups: &[PackageGroup],
+        resolved: &[ResolvedPackageGroup],
+    ) -> Result<(), CatalogSnapshotError> {
+        let parent = self.path.parent().unwrap_or(Path::new("."));
+        std::fs::create_dir_all(parent).map_err(CatalogSnapshotError::Write)?;
+        let _lock = self.lock()?;
+
+        let mut entries = self.read()?;
+        for group in groups {
+            let Some(resolved) = resolved
+                .iter()
+                .find(|resolved| resolved.name == group.name && resolved.system == group.system)
+            else {
+                continue;
+            };
+            entries.retain(|entry| entry.group != *group);
+            entries.push(SnapshotEntry {
+                group: group.clone(),
+                resolved: ResolvedPackageGroup {
+                    pages: resolved.pages.iter().take(1).cloned().collect(),
+                    ..resolved.clone()
+                },
+            });
+        }
+
+        let mut temp_file =
+            tempfile::NamedTempFile::new_in(parent).map_err(CatalogSnapshotError::Write)?;
+        serde_json::to_writer(&mut temp_file, &entries)
+            .map_err(|e| CatalogSnapshotError::Write(e.into()))?;
+        temp_file
+            .persist(&self.path)
+            .map_err(|e| CatalogSnapshotError::Write(e.error))?;
+        Ok(())
+    }
+
+    /// Resolve `groups` from recorded resolutions only
+    ///
+    /// Every group is resolved to the packages it was last resolved to.
+    /// Fails if any group has not been resolved online before.
+    pub fn resolve(
+        &self,
+        groups: Vec<PackageGroup>,
+    ) -> Result<Vec<ResolvedPackageGroup>, ResolveError> {
+        let entries = self.read()?;
+        groups
+            .into_iter()
+            .map(|group| {
+                entries
+                    .iter()
+                    .rev()
+                    .find(|entry| entry.group == group)
+                    .map(|entry| entry.resolved.clone())
+                    .ok_or(ResolveError::NotInSnapshot {
+                        group: group.name,
+                        system: group.system,
+                    })
+            })
+            .collect()
+    }
+}
+
+/// Whether `error` was caused by failing to reach the catalog,
+/// rather than by the catalog rejecting the request
+fn is_connection_error(error: &ResolveError) -> bool {
+    match error {
+        ResolveError::CatalogClientError(CatalogClientError::UnexpectedError(
+            APIError::CommunicationError(e),
+        )) => e.is_connect() || e.is_timeout(),
+        _ => false,
+    }
+}
+
+/// A client that answers resolution requests from a [ResolutionCache] if possible
+/// and forwards everything else to the wrapped client.
+///
+/// If a [CatalogSnapshot] is set, online resolutions are recorded in it,
+/// and it is used instead of the wrapped client
+/// when offline or when the catalog can't be reached.
+pub struct CachedResolutionClient<'a, C> {
+    client: &'a C,
+    cache: &'a ResolutionCache,
+    snapshot: Option<CatalogSnapshot>,
+    offline: bool,
+}
+
+impl<'a, C> CachedResolutionClient<'a, C> {
+    pub fn new(client: &'a C, cache: &'a ResolutionCache) -> Self {
+        Self {
+            client,
+            cache,
+            snapshot: None,
+            offline: false,
+        }
+    }
+
+    /// Record resolutions in `snapshot` and fall back to it
+    /// when the catalog can't be reached.
+    /// If `offline` is set, the catalog isn't contacted at all.
+    pub fn with_snapshot(mut self, snapshot: CatalogSnapshot, offline: bool) -> Self {
+        self.snapshot = Some(snapshot);
+        self.offline = offline;
+        self
+    }
+}
+
+#[async_trait]
+impl<C: ClientTrait + Sync> ClientTrait for CachedResolutionClient<'_, C> {
+    async fn resolve(
+        &self,
+        package_groups: Vec<PackageGroup>,
+    ) -> Result<Vec<ResolvedPackageGroup>, ResolveError> {
+        let mut resolved = vec![];
+        let mut unresolved = vec![];
+        for group in package_groups {
+            match self.cache.get(&group) {
+                Some(cached) => resolved.push(cached),
+                None => unresolved.push(group),
+            }
+        }
+
+        if !resolved.is_empty() {
+            debug!("using {} prefetched package group(s)", resolved.len());
+        }
+        if unresolved.is_empty() {
+            return Ok(resolved);
+        }
+
+        let Some(snapshot) = &self.snapshot else {
+            resolved.extend(self.client.resolve(unresolved).await?);
+            return Ok(resolved);
+        };
+
+        if self.offline {
+            debug!("resolving {} package group(s) offline", unresolved.len());
+            resolved.extend(snapshot.resolve(unresolved)?);
+            return Ok(resolved);
+        }
+
+        match self.client.resolve(unresolved.clone()).await {
+            Ok(online) => {
+                if let Err(e) = snapshot.record(&unresolved, &online) {
+                    debug!("couldn't record resolution in catalog snapshot: {e}");
+                }
+                resolved.extend(online);
+            },
+            Err(e) if is_connection_error(&e) => {
+                debug!("catalog unreachable, resolving from snapshot: {e}");
+                resolved.extend(snapshot.resolve(unresolved)?);
+            },
+            Err(e) => return Err(e),
+        }
+        Ok(resolved)
+    }
+
+    async fn search(
+        &self,
+        search_term: impl AsRef<str> + Send + Sync,
+        system: System,
+        limit: u8,
+    ) -> Result<SearchResults, SearchError> {
+        self.client.search(search_term, system, limit).await
+    }
+
+    async fn package_versions(
+        &self,
+        attr_path: impl AsRef<str> + Send + Sync,
+    ) -> Result<SearchResults, VersionsError> {
+        self.client.package_versions(attr_path).await
+    }
+}
+
 /// Take a function that takes a page_number and page_size and returns a
 /// total_count of results and a Vec of results on a page.
 ///
@@ -386,6 +685,10 @@ impl ClientTrait for MockClient {
         &self,
         _package_groups: Vec<PackageGroup>,
     ) -> Result<ResolvedGroups, ResolveError> {
+        *self
+            .resolve_calls
+            .lock()
+            .expect("couldn't acquire mock lock") += 1;
         let mock_resp = self
             .mock_responses
             .lock()
@@ -477,7 +780,7 @@ pub type PackageDescriptor = api_types::PackageDescriptor;
 pub type ApiErrorResponse = api_types::ErrorResponse;
 pub type ApiErrorResponseValue = ResponseValue<ApiErrorResponse>;
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct PackageGroup {
     pub descriptors: Vec<PackageDescriptor>,
     pub name: String,
@@ -514,6 +817,12 @@ pub enum ResolveError {
     Resolve(ApiErrorResponseValue),
     #[error(transparent)]
     CatalogClientError(#[from] CatalogClientError),
+    #[error(
+        "package group '{group}' for {system} has not been locked with the same packages before and can't be resolved offline"
+    )]
+    NotInSnapshot { group: String, system: System },
+    #[error(transparent)]
+    Snapshot(#[from] CatalogSnapshotError),
 }
 #[derive(Debug, Error)]
 pub enum VersionsError {
@@ -727,6 +1036,90 @@ mod tests {
         assert_eq!(collected, (1..=3).collect::<Vec<_>>());
     }
 
+    fn descriptor(install_id: &str, attr_path: &str) -> PackageDescriptor {
+        PackageDescriptor {
+            install_id: install_id.to_string(),
+            attr_path: attr_path.to_string(),
+            derivation: None,
+            version: None,
+            allow_pre_releases: None,
+        }
+    }
+
+    fn resolved_package(install_id: &str, attr_path: &str) -> PackageResolutionInfo {
+        let date = chrono::DateTime::parse_from_rfc3339("2021-08-31T00:00:00Z")
+            .unwrap()
+            .with_timezone(&chrono::offset::Utc);
+        PackageResolutionInfo {
+            attr_path: attr_path.to_string(),
+            broken: false,
+            derivation: format!("/nix/store/{attr_path}.drv"),
+            description: None,
+            install_id: install_id.to_string(),
+            license: None,
+            locked_url: "locked_url".to_string(),
+            name: attr_path.to_string(),
+            outputs: None,
+            outputs_to_install: None,
+            pname: attr_path.to_string(),
+            rev: "rev".to_string(),
+            rev_count: 1,
+            rev_date: date,
+            scrape_date: date,
+            stabilities: None,
+            unfree: None,
+            version: "1.0".to_string(),
+        }
+    }
+
+    /// Resolutions of online locks are recorded
+    /// and used to resolve the same groups offline
+    #[test]
+    fn resolves_recorded_groups_offline() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let snapshot = CatalogSnapshot::new(tempdir.path().join("snapshot.json"));
+        let cache = ResolutionCache::default();
+
+        let group = PackageGroup {
+            name: "toplevel".to_string(),
+            system: "x86_64-linux".to_string(),
+            descriptors: vec![descriptor("hello", "hello")],
+        };
+        let mut client = MockClient::new(None::<&PathBuf>).unwrap();
+        client.push_resolve_response(vec![ResolvedPackageGroup {
+            name: "toplevel".to_string(),
+            system: "x86_64-linux".to_string(),
+            pages: vec![CatalogPage {
+                packages: Some(vec![resolved_package("hello", "hello")]),
+                page: 1,
+                url: "url".to_string(),
+            }],
+        }]);
+        CachedResolutionClient::new(&client, &cache)
+            .with_snapshot(snapshot.clone(), false)
+            .resolve(vec![group.clone()])
+            .block_on()
+            .unwrap();
+
+        // The mock client has no responses left, so it can't be used anymore
+        let offline = CachedResolutionClient::new(&client, &cache).with_snapshot(snapshot, true);
+        let resolved = offline.resolve(vec![group]).block_on().unwrap();
+        let packages = resolved[0].packages().collect::<Vec<_>>();
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].install_id, "hello");
+        assert_eq!(packages[0].derivation, "/nix/store/hello.drv");
+        assert_eq!(resolved[0].pages[0].url, "url");
+
+        // A group with an added package may resolve differently as a whole
+        let changed = PackageGroup {
+            name: "toplevel".to_string(),
+            system: "x86_64-linux".to_string(),
+            descriptors: vec![descriptor("hello", "hello"), descriptor("curl", "curl")],
+        };
+        let err = offline.resolve(vec![changed]).block_on().unwrap_err();
+        assert!(matches!(err, ResolveError::NotInSnapshot { group, .. } if group == "toplevel"));
+    }
+
     #[test]
     fn mock_client_uses_seeded_responses() {
         let path: Option<&PathBuf> = None;
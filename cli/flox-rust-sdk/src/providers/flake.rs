@@ -0,0 +1,227 @@
+//! Lock packages installed from flake references
+//!
+//! Packages that are not provided by the catalog can be installed
+//! from a flake installable, e.g. `github:owner/repo#package`.
+//! Such packages are locked by evaluating the installable with nix,
+//! pinning the flake to a revision and recording the outputs of the package.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::process::Command;
+
+use log::debug;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::data::System;
+use crate::utils::CommandExt;
+
+// This is the full /path/to/bin/nix that we actually use.
+// This is set once and prefers to use the `NIX_BIN` env variable if set,
+// and falls back to the value observed at build time if it is unset.
+pub static NIX_BIN: Lazy<String> =
+    Lazy::new(|| env::var("NIX_BIN").unwrap_or(env!("NIX_BIN").to_string()));
+
+/// Evaluates the attributes of a package that are recorded in the lockfile
+const LOCKED_INSTALLABLE_EXPR: &str = r#"
+pkg: let
+  license = pkg.meta.license or null;
+in {
+  name = pkg.name;
+  pname = pkg.pname or null;
+  version = pkg.version or null;
+  description = pkg.meta.description or null;
+  license = if builtins.isAttrs license then license.spdxId or null else null;
+  broken = pkg.meta.broken or false;
+  unfree = pkg.meta.unfree or false;
+  derivation = pkg.drvPath;
+  outputs = builtins.listToAttrs (map (output: {
+    name = output;
+    value = pkg.${output}.outPath;
+  }) (pkg.outputs or [ "out" ]));
+  outputs_to_install = pkg.meta.outputsToInstall or null;
+}
+"#;
+
+#[derive(Debug, Error)]
+pub enum FlakeInstallableError {
+    #[error("failed to call nix")]
+    CallNix(#[source] std::io::Error),
+    #[error("failed to lock flake '{flake}':\n{stderr}")]
+    LockFlake { flake: String, stderr: String },
+    #[error("couldn't parse metadata of flake '{0}'")]
+    ParseMetadata(String, #[source] serde_json::Error),
+    #[error("flake installable '{installable}' does not provide a package for {system}")]
+    PackageNotFound { installable: String, system: System },
+    #[error("couldn't parse package '{0}'")]
+    ParsePackage(String, #[source] serde_json::Error),
+}
+
+/// A package installed from a flake, pinned to a locked flake reference
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct LockedInstallable {
+    /// The locked flake reference, e.g. `github:owner/repo/<rev>`
+    pub locked_url: String,
+    /// The attribute path of the package within the flake,
+    /// e.g. `packages.x86_64-linux.hello`
+    pub locked_flake_attr_path: String,
+    pub derivation: String,
+    pub outputs: BTreeMap<String, String>,
+    pub outputs_to_install: Option<Vec<String>>,
+    pub name: String,
+    pub pname: Option<String>,
+    pub version: Option<String>,
+    pub description: Option<String>,
+    pub license: Option<String>,
+    pub broken: bool,
+    pub unfree: bool,
+}
+
+/// The parts of the output of `nix flake metadata --json` that are used
+#[derive(Debug, Deserialize)]
+struct FlakeMetadata {
+    url: String,
+}
+
+/// The attributes of a package evaluated with [LOCKED_INSTALLABLE_EXPR]
+#[derive(Debug, Deserialize)]
+struct EvaluatedPackage {
+    name: String,
+    pname: Option<String>,
+    version: Option<String>,
+    description: Option<String>,
+    license: Option<String>,
+    broken: bool,
+    unfree: bool,
+    derivation: String,
+    outputs: BTreeMap<String, String>,
+    outputs_to_install: Option<Vec<String>>,
+}
+
+/// Split a flake installable into its flake reference and attribute path
+///
+/// The attribute path defaults to `default`.
+pub fn split_installable(installable: &str) -> (&str, &str) {
+    match installable.split_once('#') {
+        Some((flake, attr_path)) if !attr_path.is_empty() => (flake, attr_path),
+        Some((flake, _)) => (flake, "default"),
+        None => (installable, "default"),
+    }
+}
+
+/// The attribute paths nix would try to find the package `attr_path` at,
+/// for `system`
+fn candidate_attr_paths(attr_path: &str, system: &System) -> Vec<String> {
+    if attr_path.starts_with("packages.") || attr_path.starts_with("legacyPackages.") {
+        return vec![attr_path.to_string()];
+    }
+    vec![
+        format!("packages.{system}.{attr_path}"),
+        format!("legacyPackages.{system}.{attr_path}"),
+    ]
+}
+
+pub(crate) fn nix_command() -> Command {
+    let mut command = Command::new(&*NIX_BIN);
+    command.args(["--extra-experimental-features", "nix-command flakes"]);
+    command
+}
+
+/// Lock the flake installable `installable` for `system`
+///
+/// The flake is locked to its current revision,
+/// and the package is evaluated (but not built) for `system`.
+pub fn lock_installable(
+    installable: &str,
+    system: &System,
+) -> Result<LockedInstallable, FlakeInstallableError> {
+    let (flake, attr_path) = split_installable(installable);
+
+    let mut metadata_command = nix_command();
+    metadata_command.args(["flake", "metadata", "--json", flake]);
+    debug!("locking flake with command: {}", metadata_command.display());
+    let output = metadata_command
+        .output()
+        .map_err(FlakeInstallableError::CallNix)?;
+    if !output.status.success() {
+        return Err(FlakeInstallableError::LockFlake {
+            flake: flake.to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        });
+    }
+    let metadata: FlakeMetadata = serde_json::from_slice(&output.stdout)
+        .map_err(|e| FlakeInstallableError::ParseMetadata(flake.to_string(), e))?;
+
+    for candidate in candidate_attr_paths(attr_path, system) {
+        let mut eval_command = nix_command();
+        eval_command
+            .args(["eval", "--json", "--apply", LOCKED_INSTALLABLE_EXPR])
+            .arg(format!("{}#{candidate}", metadata.url));
+        debug!(
+            "evaluating package with command: {}",
+            eval_command.display()
+        );
+        let output = eval_command
+            .output()
+            .map_err(FlakeInstallableError::CallNix)?;
+        if !output.status.success() {
+            debug!(
+                "no package at {candidate}: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            continue;
+        }
+        let package: EvaluatedPackage = serde_json::from_slice(&output.stdout)
+            .map_err(|e| FlakeInstallableError::ParsePackage(installable.to_string(), e))?;
+
+        return Ok(LockedInstallable {
+            locked_url: metadata.url,
+            locked_flake_attr_path: candidate,
+            derivation: package.derivation,
+            outputs: package.outputs,
+            outputs_to_install: package.outputs_to_install,
+            name: package.name,
+            pname: package.pname,
+            version: package.version,
+            description: package.description,
+            license: package.license,
+            broken: package.broken,
+            unfree: package.unfree,
+        });
+    }
+
+    Err(FlakeInstallableError::PackageNotFound {
+        installable: installable.to_string(),
+        system: system.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_installables() {
+        assert_eq!(
+            split_installable("github:owner/repo#hello"),
+            ("github:owner/repo", "hello")
+        );
+        assert_eq!(
+            split_installable("github:owner/repo"),
+            ("github:owner/repo", "default")
+        );
+        assert_eq!(
+            candidate_attr_paths("hello", &"x86_64-linux".to_string()),
+            [
+                "packages.x86_64-linux.hello",
+                "legacyPackages.x86_64-linux.hello"
+            ]
+        );
+        assert_eq!(
+            candidate_attr_paths("packages.aarch64-darwin.hello", &"x86_64-linux".to_string()),
+            ["packages.aarch64-darwin.hello"]
+        );
+    }
+}
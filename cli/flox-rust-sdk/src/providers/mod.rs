@@ -1,2 +1,5 @@
 pub mod catalog;
+pub mod flake;
 pub mod git;
+pub mod store_path;
+pub mod vulnerabilities;
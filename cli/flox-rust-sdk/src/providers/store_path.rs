@@ -0,0 +1,220 @@
+//! Lock packages installed from store paths
+//!
+//! Packages that are built outside of flox, e.g. by internal tooling,
+//! can be installed from their store path or the store path of their derivation.
+//! Such packages are locked by recording their outputs,
+//! and the derivation that produced them if it is known.
+
+use std::collections::BTreeMap;
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::flake::nix_command;
+use crate::data::System;
+use crate::utils::CommandExt;
+
+/// The directory of the nix store
+pub const NIX_STORE_DIR: &str = "/nix/store/";
+
+#[derive(Debug, Error)]
+pub enum StorePathError {
+    #[error("failed to call nix")]
+    CallNix(#[source] std::io::Error),
+    #[error("'{0}' is not a path in {NIX_STORE_DIR}")]
+    NotAStorePath(String),
+    #[error("store path '{path}' is not valid:\n{stderr}")]
+    InvalidStorePath { path: String, stderr: String },
+    #[error("couldn't parse derivation '{0}'")]
+    ParseDerivation(String, #[source] serde_json::Error),
+    #[error("derivation '{0}' has outputs without a known store path")]
+    FloatingOutputs(String),
+}
+
+/// A package installed from a store path
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct LockedStorePath {
+    /// The store path the package was installed from,
+    /// either an output or a derivation
+    pub store_path: String,
+    /// The derivation that produces [Self::outputs], if known
+    pub derivation: Option<String>,
+    /// The system the derivation builds for, if known
+    pub derivation_system: Option<System>,
+    /// The outputs of the package that are installed
+    pub outputs: BTreeMap<String, String>,
+    pub name: String,
+}
+
+/// The parts of the output of `nix derivation show` that are used
+#[derive(Debug, Deserialize)]
+struct ShownDerivation {
+    name: String,
+    system: System,
+    outputs: BTreeMap<String, ShownOutput>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ShownOutput {
+    path: Option<String>,
+}
+
+/// Whether `path` is a path in the nix store
+pub fn is_store_path(path: &str) -> bool {
+    path.strip_prefix(NIX_STORE_DIR)
+        .is_some_and(|name| !name.is_empty() && !name.contains('/'))
+}
+
+/// The name of a store path without its hash and `.drv` extension,
+/// e.g. `hello-2.12.1` for `/nix/store/<hash>-hello-2.12.1.drv`
+pub fn store_path_name(path: &str) -> &str {
+    let base_name = path
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or(path);
+    let name = base_name
+        .split_once('-')
+        .map_or(base_name, |(_hash, name)| name);
+    name.strip_suffix(".drv").unwrap_or(name)
+}
+
+/// Split a package name into its pname and version,
+/// using the nix convention that the version starts at the first dash followed by a digit
+pub fn split_package_name(name: &str) -> (&str, Option<&str>) {
+    let version_start = name
+        .match_indices('-')
+        .find(|(i, _)| name[i + 1..].starts_with(|c: char| c.is_ascii_digit()))
+        .map(|(i, _)| i);
+    match version_start {
+        Some(i) => (&name[..i], Some(&name[i + 1..])),
+        None => (name, None),
+    }
+}
+
+/// Lock the package at `store_path`
+///
+/// If `store_path` is a derivation, all of its outputs are installed.
+/// If it is an output, only that output is installed,
+/// and its derivation is recorded if it is known to the nix store.
+pub fn lock_store_path(store_path: &str) -> Result<LockedStorePath, StorePathError> {
+    if !is_store_path(store_path) {
+        return Err(StorePathError::NotAStorePath(store_path.to_string()));
+    }
+    let is_derivation = store_path.ends_with(".drv");
+
+    let mut show_command = nix_command();
+    show_command.args(["derivation", "show", store_path]);
+    debug!(
+        "reading derivation with command: {}",
+        show_command.display()
+    );
+    let output = show_command.output().map_err(StorePathError::CallNix)?;
+
+    if !output.status.success() {
+        if is_derivation {
+            return Err(StorePathError::InvalidStorePath {
+                path: store_path.to_string(),
+                stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+            });
+        }
+        // The deriver of an output may not be known,
+        // e.g. if the output was copied from another store.
+        debug!(
+            "no derivation known for {store_path}: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        ensure_valid(store_path)?;
+        return Ok(LockedStorePath {
+            store_path: store_path.to_string(),
+            derivation: None,
+            derivation_system: None,
+            outputs: BTreeMap::from([("out".to_string(), store_path.to_string())]),
+            name: store_path_name(store_path).to_string(),
+        });
+    }
+
+    let derivations: BTreeMap<String, ShownDerivation> = serde_json::from_slice(&output.stdout)
+        .map_err(|e| StorePathError::ParseDerivation(store_path.to_string(), e))?;
+    let Some((derivation_path, derivation)) = derivations.into_iter().next() else {
+        return Err(StorePathError::InvalidStorePath {
+            path: store_path.to_string(),
+            stderr: "no derivation found".to_string(),
+        });
+    };
+
+    let mut outputs = BTreeMap::new();
+    for (output_name, output) in derivation.outputs {
+        let Some(path) = output.path else {
+            return Err(StorePathError::FloatingOutputs(derivation_path));
+        };
+        if is_derivation || path == store_path {
+            outputs.insert(output_name, path);
+        }
+    }
+    if outputs.is_empty() {
+        // `store_path` is not an output of the derivation nix found for it
+        ensure_valid(store_path)?;
+        outputs.insert("out".to_string(), store_path.to_string());
+    }
+
+    Ok(LockedStorePath {
+        store_path: store_path.to_string(),
+        derivation: Some(derivation_path),
+        derivation_system: Some(derivation.system),
+        outputs,
+        name: derivation.name,
+    })
+}
+
+/// Check that `store_path` is valid in, or can be substituted into, the nix store
+fn ensure_valid(store_path: &str) -> Result<(), StorePathError> {
+    let mut path_info_command = nix_command();
+    path_info_command.args(["path-info", store_path]);
+    debug!(
+        "checking store path with command: {}",
+        path_info_command.display()
+    );
+    let output = path_info_command
+        .output()
+        .map_err(StorePathError::CallNix)?;
+    if !output.status.success() {
+        return Err(StorePathError::InvalidStorePath {
+            path: store_path.to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_store_path_names() {
+        assert!(is_store_path("/nix/store/abc-hello-2.12.1"));
+        assert!(!is_store_path("/nix/store/abc-hello-2.12.1/bin/hello"));
+        assert!(!is_store_path("/tmp/hello"));
+
+        assert_eq!(
+            store_path_name("/nix/store/abc-hello-2.12.1"),
+            "hello-2.12.1"
+        );
+        assert_eq!(
+            store_path_name("/nix/store/abc-hello-2.12.1.drv"),
+            "hello-2.12.1"
+        );
+        assert_eq!(
+            split_package_name("hello-2.12.1"),
+            ("hello", Some("2.12.1"))
+        );
+        assert_eq!(split_package_name("gnu-hello"), ("gnu-hello", None));
+        assert_eq!(
+            split_package_name("python3.11-foo-1.0"),
+            ("python3.11-foo", Some("1.0"))
+        );
+    }
+}
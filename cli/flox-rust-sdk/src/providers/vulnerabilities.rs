@@ -0,0 +1,245 @@
+//! Known vulnerabilities of packages
+//!
+//! Locked packages can be audited against a [VulnerabilityDatabase],
+//! see [LockedManifestCatalog::audit](crate::models::lockfile::LockedManifestCatalog::audit).
+//! [OsvClient] queries the [OSV](https://osv.dev) database,
+//! which aggregates advisories of many ecosystems and assigns CVE aliases.
+
+use async_trait::async_trait;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+pub const DEFAULT_OSV_URL: &str = "https://api.osv.dev";
+
+#[derive(Debug, Error)]
+pub enum VulnerabilityError {
+    #[error("failed to query vulnerabilities of '{package}'")]
+    Query {
+        package: String,
+        #[source]
+        err: reqwest::Error,
+    },
+}
+
+/// A known vulnerability affecting a package version
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Vulnerability {
+    /// The identifier of the advisory, e.g. `GHSA-...` or `CVE-...`
+    pub id: String,
+    /// Other identifiers of the same vulnerability, usually including its CVE
+    pub aliases: Vec<String>,
+    pub summary: Option<String>,
+    /// The severity as rated by the advisory, e.g. `HIGH` or a CVSS vector
+    pub severity: Option<String>,
+    /// Versions that fix the vulnerability
+    pub fixed_versions: Vec<String>,
+}
+
+/// A source of known vulnerabilities
+#[async_trait]
+pub trait VulnerabilityDatabase {
+    /// The vulnerabilities known to affect version `version` of the package `pname`
+    async fn query(
+        &self,
+        pname: &str,
+        version: &str,
+    ) -> Result<Vec<Vulnerability>, VulnerabilityError>;
+}
+
+/// A client for the OSV vulnerability database
+#[derive(Debug, Clone)]
+pub struct OsvClient {
+    client: reqwest::Client,
+    base_url: String,
+    /// The OSV ecosystem packages are looked up in,
+    /// if `None` packages are matched by name across ecosystems
+    ecosystem: Option<String>,
+}
+
+impl OsvClient {
+    pub fn new(base_url: impl Into<String>, ecosystem: Option<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url: base_url.into(),
+            ecosystem,
+        }
+    }
+}
+
+impl Default for OsvClient {
+    fn default() -> Self {
+        Self::new(DEFAULT_OSV_URL, None)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct OsvQuery<'a> {
+    version: &'a str,
+    package: OsvPackage<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    page_token: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct OsvPackage<'a> {
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ecosystem: Option<&'a str>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct OsvResponse {
+    #[serde(default)]
+    vulns: Vec<OsvVulnerability>,
+    next_page_token: Option<String>,
+}
+
+/// The parts of an OSV advisory that are used
+#[derive(Debug, Deserialize)]
+struct OsvVulnerability {
+    id: String,
+    #[serde(default)]
+    aliases: Vec<String>,
+    summary: Option<String>,
+    #[serde(default)]
+    severity: Vec<OsvSeverity>,
+    #[serde(default)]
+    affected: Vec<OsvAffected>,
+    #[serde(default)]
+    database_specific: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OsvSeverity {
+    score: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct OsvAffected {
+    #[serde(default)]
+    ranges: Vec<OsvRange>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OsvRange {
+    #[serde(default)]
+    events: Vec<serde_json::Map<String, serde_json::Value>>,
+}
+
+impl From<OsvVulnerability> for Vulnerability {
+    fn from(vuln: OsvVulnerability) -> Self {
+        // Advisories rated by their database (e.g. GitHub) carry a plain severity,
+        // others only a CVSS vector
+        let severity = vuln
+            .database_specific
+            .as_ref()
+            .and_then(|specific| specific.get("severity"))
+            .and_then(|severity| severity.as_str())
+            .map(ToString::to_string)
+            .or_else(|| vuln.severity.first().map(|severity| severity.score.clone()));
+        let mut fixed_versions = vuln
+            .affected
+            .iter()
+            .flat_map(|affected| &affected.ranges)
+            .flat_map(|range| &range.events)
+            .filter_map(|event| event.get("fixed")?.as_str().map(ToString::to_string))
+            .collect::<Vec<_>>();
+        fixed_versions.sort();
+        fixed_versions.dedup();
+
+        Vulnerability {
+            id: vuln.id,
+            aliases: vuln.aliases,
+            summary: vuln.summary,
+            severity,
+            fixed_versions,
+        }
+    }
+}
+
+#[async_trait]
+impl VulnerabilityDatabase for OsvClient {
+    async fn query(
+        &self,
+        pname: &str,
+        version: &str,
+    ) -> Result<Vec<Vulnerability>, VulnerabilityError> {
+        let query_err = |err| VulnerabilityError::Query {
+            package: format!("{pname}@{version}"),
+            err,
+        };
+
+        let mut vulnerabilities = vec![];
+        let mut page_token = None;
+        loop {
+            let query = OsvQuery {
+                version,
+                package: OsvPackage {
+                    name: pname,
+                    ecosystem: self.ecosystem.as_deref(),
+                },
+                page_token: page_token.take(),
+            };
+            debug!("querying vulnerabilities of {pname}@{version}");
+            let response: OsvResponse = self
+                .client
+                .post(format!("{}/v1/query", self.base_url))
+                .json(&query)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(query_err)?
+                .json()
+                .await
+                .map_err(query_err)?;
+
+            vulnerabilities.extend(response.vulns.into_iter().map(Vulnerability::from));
+            match response.next_page_token {
+                Some(token) => page_token = Some(token),
+                None => break,
+            }
+        }
+        Ok(vulnerabilities)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_osv_advisories() {
+        let response: OsvResponse = serde_json::from_str(
+            r#"{
+                "vulns": [{
+                    "id": "GHSA-xxxx",
+                    "aliases": ["CVE-2024-0001"],
+                    "summary": "buffer overflow",
+                    "severity": [{"type": "CVSS_V3", "score": "CVSS:3.1/AV:N"}],
+                    "affected": [{
+                        "ranges": [{
+                            "type": "ECOSYSTEM",
+                            "events": [{"introduced": "0"}, {"fixed": "3.0.2"}]
+                        }]
+                    }],
+                    "database_specific": {"severity": "HIGH"}
+                }]
+            }"#,
+        )
+        .unwrap();
+
+        let vulnerabilities = response
+            .vulns
+            .into_iter()
+            .map(Vulnerability::from)
+            .collect::<Vec<_>>();
+        assert_eq!(vulnerabilities, vec![Vulnerability {
+            id: "GHSA-xxxx".to_string(),
+            aliases: vec!["CVE-2024-0001".to_string()],
+            summary: Some("buffer overflow".to_string()),
+            severity: Some("HIGH".to_string()),
+            fixed_versions: vec!["3.0.2".to_string()],
+        }]);
+    }
+}
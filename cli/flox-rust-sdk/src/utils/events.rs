@@ -0,0 +1,107 @@
+//! Outcomes of environment operations
+//!
+//! Unlike [progress](super::progress), which reports the steps of a running operation,
+//! events report how long an operation took and whether it succeeded.
+//! Consumers can register an [EventSink] on [Flox](crate::flox::Flox)
+//! to collect metrics about install, uninstall, upgrade, and build operations.
+
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// An operation on an environment that is reported to an [EventSink]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Install,
+    Uninstall,
+    Upgrade,
+    Build,
+}
+
+/// The kind of error an operation failed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// The manifest is invalid or couldn't be read or modified
+    Manifest,
+    /// Packages couldn't be resolved or locked
+    Resolution,
+    /// The environment or one of its packages failed to build
+    Build,
+    /// The environment couldn't be replaced with its modified copy
+    Transaction,
+    Other,
+}
+
+/// Whether an operation succeeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Success,
+    Failure(ErrorCategory),
+}
+
+/// A completed operation
+#[derive(Debug, Clone, PartialEq)]
+pub struct OperationEvent {
+    pub operation: Operation,
+    pub duration: Duration,
+    pub outcome: Outcome,
+}
+
+/// Receives an [OperationEvent] whenever an operation completes
+///
+/// Operations may be nested, e.g. an install builds the environment,
+/// in which case the inner operation is reported first.
+/// Events are emitted synchronously on the thread running the operation,
+/// so implementations should return quickly.
+pub trait EventSink: Send + Sync {
+    fn on_event(&self, event: OperationEvent);
+}
+
+/// The [EventSink] of a [Flox](crate::flox::Flox) instance
+///
+/// The default discards all events.
+#[derive(Clone, Default)]
+pub struct Events(Option<Arc<dyn EventSink>>);
+
+impl Events {
+    pub fn new(sink: impl EventSink + 'static) -> Self {
+        Self(Some(Arc::new(sink)))
+    }
+
+    /// Notify the sink, if any, about `event`
+    pub fn emit(&self, event: OperationEvent) {
+        if let Some(sink) = &self.0 {
+            sink.on_event(event);
+        }
+    }
+
+    /// Run `operation` and report its duration and outcome,
+    /// categorizing errors with `categorize`
+    pub(crate) fn record<T, E>(
+        &self,
+        operation: Operation,
+        categorize: impl FnOnce(&E) -> ErrorCategory,
+        run: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, E> {
+        let start = Instant::now();
+        let result = run();
+        let outcome = match &result {
+            Ok(_) => Outcome::Success,
+            Err(err) => Outcome::Failure(categorize(err)),
+        };
+        self.emit(OperationEvent {
+            operation,
+            duration: start.elapsed(),
+            outcome,
+        });
+        result
+    }
+}
+
+impl Debug for Events {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Events")
+            .field(&self.0.as_ref().map(|_| "..."))
+            .finish()
+    }
+}
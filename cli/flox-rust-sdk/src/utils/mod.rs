@@ -1,5 +1,7 @@
 pub mod errors;
+pub mod events;
 pub mod guard;
+pub mod progress;
 use std::fmt::Display;
 use std::path::Path;
 use std::time::SystemTime;
@@ -98,6 +100,93 @@ pub fn copy_file_without_permissions(
     Ok(())
 }
 
+/// Whether `a` and `b` are on the same filesystem
+///
+/// Files can only be renamed or reflinked within a filesystem.
+/// Returns `false` if either path can't be accessed.
+pub fn same_filesystem(a: impl AsRef<Path>, b: impl AsRef<Path>) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev(),
+        _ => false,
+    }
+}
+
+/// Copy a file by sharing its blocks with the original (a "reflink"),
+/// on filesystems that support copy-on-write clones (e.g. btrfs, xfs, APFS)
+///
+/// The clone can be written without affecting the original.
+/// Fails with [io::ErrorKind::Unsupported] if the filesystem or platform
+/// doesn't support reflinks, in which case `to` is not created.
+pub fn reflink_file(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), io::Error> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+
+        let from_file = fs::File::open(&from)?;
+        let to_file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&to)?;
+        // SAFETY: both file descriptors are open for the duration of the call
+        let result = unsafe {
+            libc::ioctl(
+                to_file.as_raw_fd(),
+                libc::FICLONE as _,
+                from_file.as_raw_fd(),
+            )
+        };
+        if result != 0 {
+            let err = io::Error::last_os_error();
+            drop(to_file);
+            let _ = fs::remove_file(&to);
+            return Err(match err.raw_os_error() {
+                Some(libc::EOPNOTSUPP | libc::EXDEV | libc::EINVAL | libc::ENOTTY) => {
+                    io::Error::new(io::ErrorKind::Unsupported, err)
+                },
+                _ => err,
+            });
+        }
+        to_file.set_permissions(from_file.metadata()?.permissions())?;
+        Ok(())
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let to_c_string = |path: &Path| {
+            CString::new(path.as_os_str().as_bytes())
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+        };
+        let from_c = to_c_string(from.as_ref())?;
+        let to_c = to_c_string(to.as_ref())?;
+        // SAFETY: both paths are valid nul terminated strings
+        let result = unsafe { libc::clonefile(from_c.as_ptr(), to_c.as_ptr(), 0) };
+        if result != 0 {
+            let err = io::Error::last_os_error();
+            return Err(match err.raw_os_error() {
+                Some(libc::ENOTSUP | libc::EXDEV) => {
+                    io::Error::new(io::ErrorKind::Unsupported, err)
+                },
+                _ => err,
+            });
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    {
+        let _ = (from, to);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "reflinks are not supported on this platform",
+        ))
+    }
+}
+
 /// Get the mtime of a file, directory or symlink
 ///
 /// Unlike `std::fs::metadata`, this function will not follow symlinks,
@@ -130,14 +219,34 @@ pub(crate) trait CommandExt {
     /// Provide a [DisplayCommand] that can be used to display
     /// POSIX like formatting of the command.
     fn display(&self) -> DisplayCommand;
+
+    /// Append `config` to the nix configuration passed through `NIX_CONFIG`,
+    /// keeping configuration that was set on the command or the current process
+    fn append_nix_config(&mut self, config: &str) -> &mut Self;
 }
 
 impl CommandExt for std::process::Command {
     fn display(&self) -> DisplayCommand {
         DisplayCommand(self)
     }
+
+    fn append_nix_config(&mut self, config: &str) -> &mut Self {
+        let existing = self
+            .get_envs()
+            .find(|(key, _)| *key == NIX_CONFIG_VAR)
+            .and_then(|(_, value)| value.map(|value| value.to_string_lossy().to_string()))
+            .or_else(|| std::env::var(NIX_CONFIG_VAR).ok());
+        let config = match existing {
+            Some(existing) if !existing.is_empty() => format!("{existing}\n{config}"),
+            _ => config.to_string(),
+        };
+        self.env(NIX_CONFIG_VAR, config)
+    }
 }
 
+/// The environment variable nix reads additional configuration from
+const NIX_CONFIG_VAR: &str = "NIX_CONFIG";
+
 pub(crate) struct DisplayCommand<'a>(&'a std::process::Command);
 
 impl Display for DisplayCommand<'_> {
@@ -0,0 +1,60 @@
+//! Progress reporting for long running environment operations
+//!
+//! Locking and building an environment can take minutes.
+//! Consumers can register a [ProgressObserver] on [Flox](crate::flox::Flox)
+//! to be notified about the steps of these operations,
+//! e.g. to render a spinner with the current step.
+
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// A step of locking, building, or changing an environment
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProgressEvent {
+    /// Resolving a package group with the catalog
+    ResolvingGroup { name: String },
+    /// Locking the manifest with pkgdb
+    LockingWithPkgdb,
+    /// Building the environment
+    Building,
+    /// Linking a built environment to an out-link
+    Linking { out_link: PathBuf },
+    /// Replacing the environment with its modified copy at the end of a transaction
+    Replacing,
+}
+
+/// Receives [ProgressEvent]s while an operation is running
+///
+/// Events are emitted synchronously on the thread running the operation,
+/// so implementations should return quickly.
+pub trait ProgressObserver: Send + Sync {
+    fn on_progress(&self, event: ProgressEvent);
+}
+
+/// The [ProgressObserver] of a [Flox](crate::flox::Flox) instance
+///
+/// The default discards all events.
+#[derive(Clone, Default)]
+pub struct Progress(Option<Arc<dyn ProgressObserver>>);
+
+impl Progress {
+    pub fn new(observer: impl ProgressObserver + 'static) -> Self {
+        Self(Some(Arc::new(observer)))
+    }
+
+    /// Notify the observer, if any, about `event`
+    pub fn emit(&self, event: ProgressEvent) {
+        if let Some(observer) = &self.0 {
+            observer.on_progress(event);
+        }
+    }
+}
+
+impl Debug for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Progress")
+            .field(&self.0.as_ref().map(|_| "..."))
+            .finish()
+    }
+}
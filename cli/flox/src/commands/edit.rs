@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::env;
 use std::fs::File;
 use std::io::stdin;
@@ -12,6 +13,8 @@ use flox_rust_sdk::models::environment::{
     EditResult,
     Environment,
     EnvironmentError,
+    ManifestChanges,
+    RequiredAction,
 };
 use itertools::Itertools;
 use log::debug;
@@ -77,17 +80,13 @@ impl Edit {
             EditAction::Rename { name } => {
                 let span = tracing::info_span!("rename");
                 let _guard = span.enter();
-                if let ConcreteEnvironment::Path(mut environment) = detected_environment {
-                    let old_name = environment.name();
-                    if name == old_name {
-                        bail!("environment already named '{name}'");
-                    }
-                    environment.rename(name.clone())?;
-                    message::updated(format!("renamed environment '{old_name}' to '{name}'"));
-                } else {
-                    // todo: handle remote environments in the future
-                    bail!("Cannot rename environments on FloxHub");
+                let mut environment = detected_environment.into_dyn_environment();
+                let old_name = environment.name();
+                if name == old_name {
+                    bail!("environment already named '{name}'");
                 }
+                environment.rename(&flox, name.clone())?;
+                message::updated(format!("renamed environment '{old_name}' to '{name}'"));
             },
         }
 
@@ -114,30 +113,48 @@ impl Edit {
             None => Self::interactive_edit(flox, environment.as_mut()).await?,
         };
 
-        // outside the match to avoid rustfmt falling on its face
-        let reactivate_required_note = indoc::indoc! {"
-            Your manifest has changes that cannot be automatically applied.
-
-            Please 'exit' the environment and run 'flox activate' to see these changes.
-       "};
-
-        match result {
-            EditResult::Unchanged => {
+        let is_active = activated_environments().is_active(&active_environment);
+        match result.changes() {
+            None => {
                 message::warning("No changes made to environment.");
             },
-            EditResult::ReActivateRequired { .. }
-                if activated_environments().is_active(&active_environment) =>
-            {
-                message::warning(reactivate_required_note)
+            Some(changes) if is_active && !changes.activation.is_empty() => {
+                message::warning(Self::activation_changes_note(changes))
             },
-            EditResult::ReActivateRequired { .. } => {
-                message::updated("Environment successfully updated.")
-            },
-            EditResult::Success { .. } => message::updated("Environment successfully updated."),
+            Some(_) => message::updated("Environment successfully updated."),
         }
         Ok(())
     }
 
+    /// Describe which changes of an active environment need manual action to apply
+    fn activation_changes_note(changes: &ManifestChanges) -> String {
+        let mut sections_by_action: BTreeMap<RequiredAction, Vec<String>> = BTreeMap::new();
+        for change in &changes.activation {
+            sections_by_action
+                .entry(change.required_action())
+                .or_default()
+                .push(format!("'{change}'"));
+        }
+
+        let mut note =
+            "Your manifest has changes that cannot be automatically applied.\n".to_string();
+        for (action, sections) in sections_by_action {
+            let sections = sections.join(", ");
+            let instruction = match action {
+                RequiredAction::ReActivate => format!(
+                    "Please 'exit' the environment and run 'flox activate' to see changes to {sections}."
+                ),
+                RequiredAction::RestartServices => format!(
+                    "Please restart the services of the environment to see changes to {sections}."
+                ),
+            };
+            note.push('\n');
+            note.push_str(&instruction);
+            note.push('\n');
+        }
+        note
+    }
+
     /// Interactively edit the manifest file
     async fn interactive_edit(
         flox: &Flox,
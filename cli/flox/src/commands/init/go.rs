@@ -146,6 +146,8 @@ impl InitHook for Go {
                 pkg_path: "go".to_string(),
                 version: go_version,
                 input: None,
+                flake: None,
+                store_path: None,
             }]),
         }
     }
@@ -8,10 +8,13 @@ use bpaf::Bpaf;
 use flox_rust_sdk::data::{AttrPath, CanonicalPath};
 use flox_rust_sdk::flox::{EnvironmentName, Flox, DEFAULT_NAME};
 use flox_rust_sdk::models::environment::path_environment::{InitCustomization, PathEnvironment};
+use flox_rust_sdk::models::environment::templates::find_template;
 use flox_rust_sdk::models::environment::{
     global_manifest_lockfile_path,
     global_manifest_path,
+    CoreEnvironmentError,
     Environment,
+    EnvironmentError,
     PathPointer,
 };
 use flox_rust_sdk::models::lockfile::{
@@ -94,6 +97,11 @@ pub struct Init {
     /// are being used in the containing directory
     #[bpaf(long)]
     auto_setup: bool,
+
+    /// Start from a template manifest for a language (python, node, rust, go)
+    /// instead of applying Flox recommendations
+    #[bpaf(long, argument("name"))]
+    template: Option<String>,
 }
 
 impl Init {
@@ -121,8 +129,19 @@ impl Init {
             EnvironmentName::from_str(&name)?
         };
 
-        // Don't run language hooks in home dir
-        let customization = if dir != home_dir || self.auto_setup {
+        if let Some(ref template) = self.template {
+            if find_template(template).is_none() {
+                Err(EnvironmentError::Core(
+                    CoreEnvironmentError::TemplateNotFound(template.clone()),
+                ))?;
+            }
+        }
+
+        // Don't run language hooks in home dir or when starting from a template
+        let customization = if self.template.is_some() {
+            debug!("Skipping language hooks for template");
+            InitCustomization::default()
+        } else if dir != home_dir || self.auto_setup {
             // Some language hooks run searches, so scrape with pkgdb if necessary
             if flox.catalog_client.is_none() {
                 tracing::debug!("using pkgdb for init");
@@ -162,7 +181,7 @@ impl Init {
             InitCustomization::default()
         };
 
-        let env = if customization.packages.is_some() {
+        let mut env = if customization.packages.is_some() {
             Dialog {
                 message: "Installing Flox suggested packages...",
                 help_message: None,
@@ -189,6 +208,15 @@ impl Init {
             )?
         };
 
+        if let Some(ref template) = self.template {
+            Dialog {
+                message: &format!("Applying template '{template}'..."),
+                help_message: None,
+                typed: Spinner::new(|| env.init_from_template(&flox, template)),
+            }
+            .spin()?;
+        }
+
         message::created(format!(
             "Created environment '{name}' ({system})",
             name = env.name(),
@@ -467,6 +495,8 @@ impl From<ProvidedPackage> for PackageToInstall {
             pkg_path: value.rel_path.into(),
             input: None,
             version: value.version,
+            flake: None,
+            store_path: None,
         }
     }
 }
@@ -832,12 +862,16 @@ mod tests {
                         pkg_path: "python311Packages.pip".to_string(),
                         version: None,
                         input: None,
+                        flake: None,
+                        store_path: None,
                     },
                     PackageToInstall {
                         id: "package2".to_string(),
                         pkg_path: "path2".to_string(),
                         version: None,
                         input: None,
+                        flake: None,
+                        store_path: None,
                     },
                 ]),
             },
@@ -852,12 +886,16 @@ mod tests {
                         pkg_path: "python311Packages.pip".to_string(),
                         version: None,
                         input: None,
+                        flake: None,
+                        store_path: None,
                     },
                     PackageToInstall {
                         id: "package1".to_string(),
                         pkg_path: "path1".to_string(),
                         version: None,
                         input: None,
+                        flake: None,
+                        store_path: None,
                     },
                 ]),
             },
@@ -917,18 +955,24 @@ mod tests {
                     pkg_path: "path1".to_string(),
                     version: None,
                     input: None,
+                    flake: None,
+                    store_path: None,
                 },
                 PackageToInstall {
                     id: "package2".to_string(),
                     pkg_path: "path2".to_string(),
                     version: None,
                     input: None,
+                    flake: None,
+                    store_path: None,
                 },
                 PackageToInstall {
                     id: "pip".to_string(),
                     pkg_path: "python311Packages.pip".to_string(),
                     version: None,
                     input: None,
+                    flake: None,
+                    store_path: None,
                 },
             ]),
         });
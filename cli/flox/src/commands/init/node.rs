@@ -641,6 +641,8 @@ impl InitHook for Node {
                     // providing the default
                     version: yarn_install.yarn.version.clone(),
                     input: None,
+                    flake: None,
+                    store_path: None,
                 });
                 Some(YARN_HOOK.to_string())
             },
@@ -654,12 +656,16 @@ impl InitHook for Node {
                         pkg_path: result.rel_path.clone().into(),
                         version: result.version.clone(),
                         input: None,
+                        flake: None,
+                        store_path: None,
                     },
                     None => PackageToInstall {
                         id: "nodejs".to_string(),
                         pkg_path: "nodejs".to_string(),
                         version: None,
                         input: None,
+                        flake: None,
+                        store_path: None,
                     },
                 };
                 packages.push(nodejs_to_install);
@@ -774,6 +780,8 @@ mod tests {
                     pkg_path: "yarn.path".to_string(),
                     version: Some("1".to_string()),
                     input: None,
+                    flake: None,
+                    store_path: None,
                 }]),
                 hook_on_activate: Some(YARN_HOOK.to_string()),
                 profile_common: None,
@@ -824,6 +832,8 @@ mod tests {
                     pkg_path: "nodejs.path".to_string(),
                     version: Some("1".to_string()),
                     input: None,
+                    flake: None,
+                    store_path: None,
                 }]),
                 hook_on_activate: Some(NPM_HOOK.to_string()),
                 profile_common: None,
@@ -856,6 +866,8 @@ mod tests {
                     pkg_path: "nodejs.path".to_string(),
                     version: Some("1".to_string()),
                     input: None,
+                    flake: None,
+                    store_path: None,
                 }]),
                 hook_on_activate: None,
                 profile_common: None,
@@ -450,12 +450,16 @@ impl Provider for PoetryPyProject {
                     pkg_path: "python3".to_string(),
                     version: python_version,
                     input: None,
+                    flake: None,
+                    store_path: None,
                 },
                 PackageToInstall {
                     id: "poetry".to_string(),
                     pkg_path: "poetry".to_string(),
                     version: None,
                     input: None,
+                    flake: None,
+                    store_path: None,
                 },
             ]),
         }
@@ -641,6 +645,8 @@ impl Provider for PyProject {
                 pkg_path: "python3".to_string(),
                 version: python_version,
                 input: None,
+                flake: None,
+                store_path: None,
             }]),
         }
     }
@@ -782,6 +788,8 @@ impl Provider for Requirements {
                 pkg_path: "python3".to_string(),
                 version: None,
                 input: None,
+                flake: None,
+                store_path: None,
             }]),
         }
     }
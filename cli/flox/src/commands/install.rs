@@ -109,6 +109,8 @@ impl Install {
             pkg_path: p.path.clone(),
             version: None,
             input: None,
+            flake: None,
+            store_path: None,
         }));
         if packages.is_empty() {
             bail!("Must specify at least one package");
@@ -325,6 +325,21 @@ impl FloxArgs {
             floxhub_token,
             floxhub,
             catalog_client,
+            resolution_cache: Default::default(),
+            progress: Default::default(),
+            events: Default::default(),
+            remote_builders: config
+                .nix
+                .as_ref()
+                .map(|nix_config| nix_config.builders.clone())
+                .unwrap_or_default(),
+            keep_failed: config
+                .nix
+                .as_ref()
+                .is_some_and(|nix_config| nix_config.keep_failed),
+            offline: config.flox.offline,
+            transaction_dir: config.flox.transaction_dir.clone(),
+            hook_check_shell: config.flox.hook_check_shell.clone(),
         };
 
         // in debug mode keep the tempdir to reproduce nix commands
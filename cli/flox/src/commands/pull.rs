@@ -370,7 +370,8 @@ impl Pull {
             },
             Err(EnvironmentError::Core(
                 ref core_err @ CoreEnvironmentError::LockedManifest(
-                    ref builder_error @ LockedManifestError::BuildEnv(_),
+                    ref builder_error @ (LockedManifestError::BuildEnv(_)
+                    | LockedManifestError::BuildFailure(_)),
                 ),
             )) if core_err.is_incompatible_package_error() => {
                 debug!(
@@ -535,6 +536,8 @@ mod tests {
                     exit_code: error_codes::LOCKFILE_INCOMPATIBLE_SYSTEM,
                     category_message: "category_message".to_string(),
                     context_message: None,
+                    log_tail: vec![],
+                    kept_build_dirs: vec![],
                 }),
             )),
         ))
@@ -547,6 +550,8 @@ mod tests {
                     exit_code: PACKAGE_BUILD_FAILURE,
                     category_message: "category_message".to_string(),
                     context_message: None,
+                    log_tail: vec![],
+                    kept_build_dirs: vec![],
                 }),
             )),
         ))
@@ -2,6 +2,7 @@ use anyhow::{bail, Result};
 use bpaf::Bpaf;
 use flox_rust_sdk::flox::Flox;
 use flox_rust_sdk::models::environment::EnvironmentError;
+use flox_rust_sdk::models::manifest::UninstallResult;
 use indoc::formatdoc;
 use itertools::Itertools;
 use log::debug;
@@ -76,7 +77,7 @@ impl Uninstall {
         let description = environment_description(&concrete_environment)?;
         let mut environment = concrete_environment.into_dyn_environment();
 
-        let _ = Dialog {
+        let attempt = Dialog {
             message: &format!("Uninstalling packages from environment {description}..."),
             help_message: None,
             typed: Spinner::new(|| environment.uninstall(self.packages.clone(), &flox)),
@@ -85,9 +86,20 @@ impl Uninstall {
 
         // Note, you need two spaces between this emoji and the package name
         // otherwise they appear right next to each other.
-        self.packages.iter().for_each(|p| {
-            message::deleted(format!("'{p}' uninstalled from environment {description}"))
-        });
+        for (p, result) in attempt.results {
+            match result {
+                UninstallResult::Removed => {
+                    message::deleted(format!("'{p}' uninstalled from environment {description}"))
+                },
+                UninstallResult::RemovedWithGroupCleanup { group } => {
+                    debug!("package group '{group}' has no packages left");
+                    message::deleted(format!("'{p}' uninstalled from environment {description}"))
+                },
+                UninstallResult::NotFound => message::warning(format!(
+                    "'{p}' is not installed in environment {description}"
+                )),
+            }
+        }
         Ok(())
     }
 }
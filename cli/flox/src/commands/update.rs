@@ -2,7 +2,7 @@ use anyhow::Result;
 use bpaf::Bpaf;
 use flox_rust_sdk::flox::Flox;
 use flox_rust_sdk::models::environment::UpdateResult;
-use flox_rust_sdk::models::lockfile::{Input, LockedManifestPkgdb, TypedLockedManifestPkgdb};
+use flox_rust_sdk::models::lockfile::{Input, InputChange, LockedManifestPkgdb};
 use flox_rust_sdk::models::pkgdb::{self, ScrapeError};
 use tracing::instrument;
 
@@ -41,7 +41,7 @@ impl Update {
     pub async fn handle(self, flox: Flox) -> Result<()> {
         subcommand_metric!("update");
 
-        let (old_lockfile, new_lockfile, global, description) = match self.environment_or_global {
+        let (diff, global, description) = match self.environment_or_global {
             EnvironmentOrGlobalSelect::Environment(ref environment_select) => {
                 let span = tracing::info_span!("update_local");
                 let _guard = span.enter();
@@ -50,35 +50,20 @@ impl Update {
                     environment_select.detect_concrete_environment(&flox, "Update")?;
 
                 let description = Some(environment_description(&concrete_environment)?);
-                let UpdateResult {
-                    new_lockfile,
-                    old_lockfile,
-                    ..
-                } = Dialog {
+                let update_result = Dialog {
                     message: "Updating environment...",
                     help_message: None,
                     typed: Spinner::new(|| self.update_manifest(flox, concrete_environment)),
                 }
                 .spin()?;
 
-                (
-                    old_lockfile
-                        .map(TypedLockedManifestPkgdb::try_from)
-                        .transpose()?,
-                    TypedLockedManifestPkgdb::try_from(new_lockfile)?,
-                    false,
-                    description,
-                )
+                (update_result.diff()?, false, description)
             },
             EnvironmentOrGlobalSelect::Global => {
                 let span = tracing::info_span!("update_global");
                 let _guard = span.enter();
 
-                let UpdateResult {
-                    new_lockfile,
-                    old_lockfile,
-                    ..
-                } = Dialog {
+                let update_result = Dialog {
                     message: "Updating global-manifest...",
                     help_message: None,
                     typed: Spinner::new(|| {
@@ -87,66 +72,52 @@ impl Update {
                 }
                 .spin()?;
 
-                (
-                    old_lockfile
-                        .map(TypedLockedManifestPkgdb::try_from)
-                        .transpose()?,
-                    TypedLockedManifestPkgdb::try_from(new_lockfile)?,
-                    true,
-                    None,
-                )
+                (update_result.diff()?, true, None)
             },
         };
 
-        if let Some(ref old_lockfile) = old_lockfile {
-            if new_lockfile.registry().inputs == old_lockfile.registry().inputs {
-                if global {
-                    message::plain("ℹ️  All global inputs are up-to-date.");
-                } else {
-                    message::plain(format!(
-                        "ℹ️  All inputs are up-to-date in environment {}.",
-                        description.as_ref().unwrap()
-                    ));
-                }
-
-                return Ok(());
+        if !diff.inputs_changed() {
+            if global {
+                message::plain("ℹ️  All global inputs are up-to-date.");
+            } else {
+                message::plain(format!(
+                    "ℹ️  All inputs are up-to-date in environment {}.",
+                    description.as_ref().unwrap()
+                ));
             }
+
+            return Ok(());
         }
 
         let mut inputs_to_scrape: Vec<&Input> = vec![];
 
-        for (input_name, new_input) in &new_lockfile.registry().inputs {
-            let old_input = old_lockfile
-                .as_ref()
-                .and_then(|old| old.registry().inputs.get(input_name));
-            match old_input {
-                // unchanged input
-                Some(old_input) if old_input == new_input => continue, // dont need to scrape
-                // updated input
-                Some(_) if global => {
-                    message::plain(format!("⬆️  Updated global input '{}'.", input_name))
+        for (input_name, change) in &diff.inputs {
+            match change {
+                InputChange::Updated { new, .. } => {
+                    if global {
+                        message::plain(format!("⬆️  Updated global input '{}'.", input_name))
+                    } else {
+                        message::plain(format!(
+                            "⬆️  Updated input '{}' in environment {}.",
+                            input_name,
+                            description.as_ref().unwrap()
+                        ))
+                    }
+                    inputs_to_scrape.push(new);
                 },
-                Some(_) => message::plain(format!(
-                    "⬆️  Updated input '{}' in environment {}.",
-                    input_name,
-                    description.as_ref().unwrap()
-                )),
-                // new input
-                None if global => {
-                    message::plain(format!("🔒️  Locked global input '{}'.", input_name))
+                InputChange::Added(new) => {
+                    if global {
+                        message::plain(format!("🔒️  Locked global input '{}'.", input_name))
+                    } else {
+                        message::plain(format!(
+                            "🔒️  Locked input '{}' in environment {}.",
+                            input_name,
+                            description.as_ref().unwrap(),
+                        ))
+                    }
+                    inputs_to_scrape.push(new);
                 },
-                None => message::plain(format!(
-                    "🔒️  Locked input '{}' in environment {}.",
-                    input_name,
-                    description.as_ref().unwrap(),
-                )),
-            }
-            inputs_to_scrape.push(new_input);
-        }
-
-        if let Some(old_lockfile) = old_lockfile {
-            for input_name in old_lockfile.registry().inputs.keys() {
-                if !new_lockfile.registry().inputs.contains_key(input_name) {
+                InputChange::Removed(_) => {
                     if global {
                         message::deleted(format!(
                             "Removed unused input '{}' from global lockfile.",
@@ -159,7 +130,7 @@ impl Update {
                             description.as_ref().unwrap()
                         ));
                     }
-                }
+                },
             }
         }
 
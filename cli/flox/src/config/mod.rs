@@ -7,6 +7,7 @@ use std::{env, fs};
 use anyhow::{Context, Result};
 use config::{Config as HierarchicalConfig, Environment};
 use flox_rust_sdk::flox::EnvironmentRef;
+use flox_rust_sdk::models::remote_builder::RemoteBuilder;
 use itertools::{Either, Itertools};
 use log::{debug, trace};
 use once_cell::sync::OnceCell;
@@ -80,6 +81,18 @@ pub struct FloxConfig {
 
     /// Rule whether to change the shell prompt in activated environments
     pub shell_prompt: Option<EnvironmentPromptConfig>,
+
+    /// Lock environments using only packages resolved by previous locks,
+    /// without contacting the catalog
+    #[serde(default)]
+    pub offline: bool,
+
+    /// Directory in which transactions prepare modified copies of environments,
+    /// defaults to the temporary directory or the directory containing the environment
+    pub transaction_dir: Option<PathBuf>,
+
+    /// Shell that checks the syntax of `profile.common` scripts when they are edited
+    pub hook_check_shell: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -106,6 +119,12 @@ pub enum EnvironmentPromptConfig {
 #[derive(Clone, Debug, Deserialize, Serialize, Default)]
 pub struct NixConfig {
     pub access_tokens: HashMap<String, String>,
+    /// Remote machines nix may delegate builds to
+    #[serde(default)]
+    pub builders: Vec<RemoteBuilder>,
+    /// Keep the build directories of failed builds for inspection
+    #[serde(default)]
+    pub keep_failed: bool,
 }
 
 pub mod features;
@@ -63,6 +63,14 @@ impl FloxCompletionExt for Flox {
             floxhub_token: None,
             floxhub: Floxhub::new(DEFAULT_FLOXHUB_URL.clone(), None)?,
             catalog_client,
+            resolution_cache: Default::default(),
+            progress: Default::default(),
+            events: Default::default(),
+            remote_builders: Vec::new(),
+            keep_failed: false,
+            offline: false,
+            transaction_dir: None,
+            hook_check_shell: None,
         })
     }
 }
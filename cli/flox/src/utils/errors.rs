@@ -1,9 +1,11 @@
 use flox_rust_sdk::data::CanonicalizeError;
+use flox_rust_sdk::models::build_failure::BuildFailure;
 use flox_rust_sdk::models::environment::managed_environment::{
     ManagedEnvironmentError,
     GENERATION_LOCK_FILENAME,
 };
 use flox_rust_sdk::models::environment::remote_environment::RemoteEnvironmentError;
+use flox_rust_sdk::models::environment::templates::TEMPLATES;
 use flox_rust_sdk::models::environment::{
     CoreEnvironmentError,
     EnvironmentError,
@@ -22,6 +24,8 @@ pub fn apply_doc_link_for_unsupported_packages(err: EnvironmentError) -> Environ
             exit_code: error_codes::PACKAGE_EVAL_INCOMPATIBLE_SYSTEM,
             category_message,
             context_message,
+            log_tail,
+            kept_build_dirs,
         })),
     )) = err
     {
@@ -32,6 +36,8 @@ pub fn apply_doc_link_for_unsupported_packages(err: EnvironmentError) -> Environ
                     exit_code: error_codes::PACKAGE_EVAL_INCOMPATIBLE_SYSTEM,
                     category_message,
                     context_message,
+                    log_tail,
+                    kept_build_dirs,
                 },
             )),
         ))
@@ -192,6 +198,8 @@ pub fn format_error(err: &EnvironmentError) -> String {
 
             Try manually deleting the '.flox' directory.
         "},
+        EnvironmentError::RenameUnsupported => display_chain(err),
+        EnvironmentError::RelocateUnsupported => display_chain(err),
         // todo: enrich with path
         EnvironmentError::ReadManifest(err) => formatdoc! {"
             Failed to read manifest: {err}
@@ -273,6 +281,34 @@ pub fn format_core_error(err: &CoreEnvironmentError) -> String {
 
             Please ensure that you have write permissions to '.flox/*'.
         "},
+        CoreEnvironmentError::KeepIgnored(err) => formatdoc! {"
+            Failed to move files ignored by '.floxignore' into the modified environment: {err}
+
+            The previous environment, including the ignored files, was kept in '.flox/env.tmp'.
+        "},
+        CoreEnvironmentError::TransactionLock(_) => display_chain(err),
+        CoreEnvironmentError::EnvironmentBusy(path) => formatdoc! {"
+            The environment at {path:?} is busy with another operation.
+
+            Please wait for the other operation to finish and try again.
+        "},
+        CoreEnvironmentError::Generations(_) => display_chain(err),
+        CoreEnvironmentError::Snapshots(_) => display_chain(err),
+        CoreEnvironmentError::Relocate(path, err) => formatdoc! {"
+            Failed to move environment to {path:?}: {err}
+
+            Please make sure that you have write permissions
+            to the current and the new location of the environment.
+        "},
+        CoreEnvironmentError::RelocateTargetExists(path) => formatdoc! {"
+            Cannot move environment to {path:?}, it already exists.
+        "},
+        CoreEnvironmentError::ManifestModifiedConcurrently(path) => formatdoc! {"
+            The manifest at {path:?} was modified while the environment was being changed.
+
+            Your changes were kept and the operation was not applied.
+            Please merge your changes and try again.
+        "},
 
         // these are out of our user's control as these errors are within the transaction
         // todo: adapt wordnig?
@@ -305,6 +341,8 @@ pub fn format_core_error(err: &CoreEnvironmentError) -> String {
 
         CoreEnvironmentError::ContainerizeUnsupportedSystem(system) => formatdoc! {"
             'containerize' is currently only supported on linux (found {system}).
+
+            Configure a remote builder for linux to build containers on {system}.
         "},
 
         CoreEnvironmentError::CatalogClientMissing => formatdoc! {"
@@ -312,6 +350,83 @@ pub fn format_core_error(err: &CoreEnvironmentError) -> String {
 
             Please enable the catalog feature and try again.
         "},
+        CoreEnvironmentError::DryRunRequiresCatalog => formatdoc! {"
+            Previewing changes requires the (experimental) catalog feature.
+
+            Please enable the catalog feature and try again.
+        "},
+        CoreEnvironmentError::InvalidUpgradeConstraint(constraint) => formatdoc! {"
+            Invalid version constraint '{constraint}'.
+
+            Constraints are written as '<install id>@<version>', e.g. 'nodejs@^20'.
+        "},
+        CoreEnvironmentError::UpgradeConstraintRequiresCatalog => formatdoc! {"
+            Upgrading within a version constraint requires the (experimental) catalog feature.
+
+            Please enable the catalog feature and try again.
+        "},
+        CoreEnvironmentError::UpgradeConstraintPackageNotFound(_) => display_chain(err),
+        CoreEnvironmentError::UpgradeConstraintConflictsWithManifest(install_id, version) => {
+            formatdoc! {"
+                Can't constrain the version of '{install_id}',
+                the manifest already requires version '{version}'.
+
+                Change the version of '{install_id}' with 'flox edit' instead.
+            "}
+        },
+        CoreEnvironmentError::GroupNotFound(_) => display_chain(err),
+        CoreEnvironmentError::GroupBuildRequiresCatalog => formatdoc! {"
+            Building a single pkg-group requires the (experimental) catalog feature.
+
+            Please enable the catalog feature and try again.
+        "},
+        CoreEnvironmentError::TemplateNotFound(name) => formatdoc! {"
+            There is no template called '{name}'.
+
+            Available templates:
+            {templates}
+        ",
+            templates = TEMPLATES
+                .iter()
+                .map(|template| format!("  {}: {}", template.name, template.description))
+                .collect::<Vec<_>>()
+                .join("\n")
+        },
+        CoreEnvironmentError::MigrateManifest(migrate_error) => formatdoc! {"
+            Failed to migrate the manifest to version 1.
+
+            {migrate_error}
+        "},
+        CoreEnvironmentError::Include(_) => display_chain(err),
+        CoreEnvironmentError::VerifyStore(_) => display_chain(err),
+        CoreEnvironmentError::PackageStorePathsRequireCatalog => formatdoc! {"
+            Listing the store paths of packages requires the (experimental) catalog feature.
+
+            Please enable the catalog feature and try again.
+        "},
+        CoreEnvironmentError::ReproducibilityRequiresCatalog => formatdoc! {"
+            Checking reproducibility requires the (experimental) catalog feature.
+
+            Please enable the catalog feature and try again.
+        "},
+        CoreEnvironmentError::Reproducibility(_) => display_chain(err),
+        CoreEnvironmentError::HookSyntax(errors) => {
+            let errors = errors
+                .iter()
+                .map(|error| format!("* {error}"))
+                .collect::<Vec<_>>()
+                .join("\n");
+            formatdoc! {"
+                The manifest contains scripts with syntax errors:
+
+                {errors}
+
+                Activating the environment would fail,
+                so the changes were not applied.
+                Please fix the scripts and try again.
+            "}
+        },
+        CoreEnvironmentError::HookCheck(_) => display_chain(err),
     }
 }
 
@@ -570,6 +685,20 @@ pub fn format_locked_manifest_error(err: &LockedManifestError) -> String {
 
             {err}
         "},
+        LockedManifestError::PolicyViolations(violations) => {
+            let violations = violations
+                .iter()
+                .map(|violation| format!("  - {violation}"))
+                .collect::<Vec<_>>()
+                .join("\n");
+            formatdoc! {"
+                Some packages are not allowed by the license policy of the manifest:
+
+                {violations}
+
+                Remove these packages, or change 'options.allow' in manifest.toml to allow them.
+            "}
+        },
         // endregion
 
         // region: errors returned by pkgdb
@@ -664,22 +793,7 @@ pub fn format_locked_manifest_error(err: &LockedManifestError) -> String {
             "encountered an internal error".into()
         },
         // catch package eval and build errors
-        LockedManifestError::BuildEnv(CallPkgDbError::PkgDbError(PkgDbError {
-            exit_code,
-            context_message:
-                Some(ContextMsgError {
-                    message,
-                    caught: Some(caught),
-                }),
-            ..
-        })) if [
-            error_codes::PACKAGE_EVAL_FAILURE,
-            error_codes::PACKAGE_BUILD_FAILURE,
-        ]
-        .contains(exit_code) =>
-        {
-            format!("{message}: {caught}")
-        },
+        LockedManifestError::BuildFailure(failure) => format_build_failure(failure),
         LockedManifestError::BuildEnv(pkgdb_error) => {
             format_pkgdb_error(pkgdb_error, err, "Failed to build environment.")
         },
@@ -714,10 +828,62 @@ pub fn format_locked_manifest_error(err: &LockedManifestError) -> String {
             Please ensure that you have write permissions to '~/.config/flox/global-manifest.lock'.
         "},
 
+        LockedManifestError::LockGlobalLockfile(_) => formatdoc! {"
+            Failed to lock the global lockfile: {err}
+
+            Please ensure that you have write permissions to '~/.config/flox'.
+        "},
+
         LockedManifestError::ParseCheckWarnings(_) => display_chain(err),
         LockedManifestError::UnsupportedLockfileForUpdate => display_chain(err),
         LockedManifestError::NoPackagesOnFirstPage(_, _) => display_chain(err),
+        LockedManifestError::LockFlakeInstallable(_) => display_chain(err),
+        LockedManifestError::LockStorePath(_) => display_chain(err),
+    }
+}
+
+/// Format a package that failed to build,
+/// with the relevant build output and how to fix the failure if known
+fn format_build_failure(failure: &BuildFailure) -> String {
+    let mut formatted = match &failure.error.context_message {
+        Some(ContextMsgError {
+            message,
+            caught: Some(caught),
+        }) => format!("{message}: {caught}"),
+        _ => formatdoc! {"
+            Failed to build environment.
+
+            {err}", err = display_chain(&failure.error)},
+    };
+
+    if !failure.log_excerpt.is_empty() {
+        formatted.push_str(&formatdoc! {"
+
+
+            Last lines of the build log:
+            {log}", log = failure.log_excerpt.join("\n")});
+    }
+    if !failure.kept_build_dirs.is_empty() {
+        let dirs = failure
+            .kept_build_dirs
+            .iter()
+            .map(|dir| format!("  {}", dir.display()))
+            .collect::<Vec<_>>()
+            .join("\n");
+        formatted.push_str(&formatdoc! {"
+
+
+            The build directories of the failed builds were kept for inspection:
+            {dirs}"});
+    }
+    // pkgdb already suggests how to allow packages that are disallowed
+    if let Some(remediation) = &failure.remediation {
+        let remediation = remediation.to_string();
+        if !formatted.contains(&remediation) {
+            formatted.push_str(&format!("\n\n{remediation}"));
+        }
     }
+    formatted
 }
 
 fn format_pkgdb_error(
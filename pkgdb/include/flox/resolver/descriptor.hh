@@ -98,6 +98,12 @@ public:
   /** Whether resoution is allowed to fail without producing errors. */
   std::optional<bool> optional;
 
+  /**
+   * Whether upgrades keep the package at its locked derivation.
+   * Only used when locking with the catalog.
+   */
+  std::optional<bool> pinned;
+
   // TODO: Not implemented.
   /** Named _group_ that the package is a member of. */
   std::optional<GroupName> pkgGroup;
@@ -130,6 +130,11 @@ struct Options
 
   std::optional<std::string> packageGroupingStrategy;
   std::optional<std::string> activationStrategy;
+
+  /** Extra binary caches, passed to `nix` by the caller when building. */
+  std::optional<std::vector<std::string>> substituters;
+  /** Extra public keys for substituted paths, see @a substituters. */
+  std::optional<std::vector<std::string>> trustedPublicKeys;
   // TODO: Other options
 
 
@@ -825,12 +825,24 @@ evalCacheCursorForInput( nix::ref<nix::EvalState> &             state,
 {
 
   /**
-   * Ensure the input is fetched with `flox-nixpkgs`.
+   * Ensure `nixpkgs` inputs are fetched with `flox-nixpkgs`.
    * Currently, the 'flox-nixpkgs' fetcher requires the original input to be
    * a rev or ref of `github:nixos/nixpkgs` or `github:flox/nixpkgs`.
+   * Packages installed from other flakes are fetched as is.
    */
-  auto floxNixpkgsAttrs = flox::githubAttrsToFloxNixpkgsAttrs( input.attrs );
-  auto packageInputRef  = nix::FlakeRef::fromAttrs( floxNixpkgsAttrs );
+  nix::fetchers::Attrs attrs = input.attrs;
+  auto                 type  = nix::fetchers::maybeGetStrAttr( attrs, "type" );
+  auto                 owner = nix::fetchers::maybeGetStrAttr( attrs, "owner" );
+  auto                 repo  = nix::fetchers::maybeGetStrAttr( attrs, "repo" );
+  bool isNixpkgs = ( type == "github" ) && owner.has_value()
+                   && ( ( nix::toLower( *owner ) == "nixos" )
+                        || ( nix::toLower( *owner ) == "flox" ) )
+                   && repo.has_value() && ( nix::toLower( *repo ) == "nixpkgs" );
+
+  auto packageInputRef
+    = isNixpkgs ? nix::FlakeRef::fromAttrs(
+        flox::githubAttrsToFloxNixpkgsAttrs( attrs ) )
+                : nix::FlakeRef::fromAttrs( attrs );
 
   auto packageFlake = nix::flake::lockFlake( *state,
                                              packageInputRef,
@@ -909,6 +921,79 @@ collectRealisedPackages(
 }
 
 
+/* -------------------------------------------------------------------------- */
+
+/**
+ * @brief Realise a package installed from a store path.
+ *
+ * The outputs of the package are recorded in the lockfile,
+ * so nothing needs to be evaluated.
+ * Outputs that are not valid are substituted,
+ * or built from the recorded derivation if there is one.
+ */
+static std::vector<std::pair<buildenv::RealisedPackage, nix::StorePath>>
+getRealisedStorePathPackages( nix::ref<nix::EvalState> &         state,
+                              const std::string &                packageName,
+                              const resolver::LockedPackageRaw & lockedPackage )
+{
+  std::unordered_map<std::string, std::string> outputsToOutpaths
+    = lockedPackage.info.at( "outputs" );
+  if ( outputsToOutpaths.empty() )
+    {
+      throw PackageEvalFailure(
+        nix::fmt( "package '%s' had no outputs", packageName ) );
+    }
+  auto out           = outputsToOutpaths.find( "out" );
+  auto parentOutpath = out != outputsToOutpaths.end()
+                         ? out->second
+                         : outputsToOutpaths.begin()->second;
+
+  auto pkgs = collectRealisedPackages( state,
+                                       packageName,
+                                       lockedPackage,
+                                       parentOutpath,
+                                       outputsToOutpaths );
+
+  auto derivation = lockedPackage.info.find( "derivation" );
+  bool hasDerivation
+    = derivation != lockedPackage.info.end() && derivation->is_string();
+  for ( const auto & [pkg, outPath] : pkgs )
+    {
+      try
+        {
+          state->store->ensurePath( outPath );
+        }
+      catch ( const nix::Error & e )
+        {
+          if ( ! hasDerivation )
+            {
+              throw PackageBuildFailure( "Failed to fetch store path of '"
+                                           + packageName + "'",
+                                         nix::filterANSIEscapes( e.what(),
+                                                                 true ) );
+            }
+          debugLog( "failed to ensure path: " + std::string( e.what() ) );
+          try
+            {
+              auto drvPath = state->store->parseStorePath(
+                derivation->get<std::string>() );
+              state->store->buildPaths( nix::toDerivedPaths(
+                { nix::StorePathWithOutputs { drvPath, {} } } ) );
+            }
+          catch ( const nix::Error & e )
+            {
+              throw PackageBuildFailure( "Failed to build package '"
+                                           + packageName + "'",
+                                         nix::filterANSIEscapes( e.what(),
+                                                                 true ) );
+            }
+          break;  // building the derivation realises all outputs
+        }
+    }
+  return pkgs;
+}
+
+
 /* -------------------------------------------------------------------------- */
 
 std::vector<std::pair<buildenv::RealisedPackage, nix::StorePath>>
@@ -917,6 +1002,12 @@ getRealisedPackages( nix::ref<nix::EvalState> &         state,
                      const resolver::LockedPackageRaw & lockedPackage,
                      const System &                     system )
 {
+  /* Packages installed from store paths are not evaluated */
+  if ( lockedPackage.attrPath.empty() )
+    {
+      return getRealisedStorePathPackages( state, packageName, lockedPackage );
+    }
+
   debugLog( nix::fmt( "getting cursor for %s", lockedPackage.attrPath[0] ) );
   auto timeEvalStart = std::chrono::high_resolution_clock::now();
   auto cursor        = evalCacheCursorForInput( state,
@@ -51,6 +51,7 @@ ManifestDescriptorRaw::clear()
   this->absPath           = std::nullopt;
   this->systems           = std::nullopt;
   this->optional          = std::nullopt;
+  this->pinned            = std::nullopt;
   this->pkgGroup          = std::nullopt;
   this->packageRepository = std::nullopt;
   this->priority          = std::nullopt;
@@ -374,6 +375,19 @@ from_json( const nlohmann::json & jfrom, ManifestDescriptorRaw & descriptor )
                 flox::extract_json_errmsg( e ) );
             }
         }
+      else if ( key == "pinned" )
+        {
+          try
+            {
+              value.get_to( descriptor.pinned );
+            }
+          catch ( nlohmann::json::exception & e )
+            {
+              throw ParseManifestDescriptorRawException(
+                "couldn't interpret field 'pinned'",
+                flox::extract_json_errmsg( e ) );
+            }
+        }
       else if ( key == "pkg-group" )
         {
           try
@@ -447,6 +461,10 @@ to_json( nlohmann::json & jto, const ManifestDescriptorRaw & descriptor )
     {
       jto["optional"] = *descriptor.optional;
     }
+  if ( descriptor.pinned.has_value() )
+    {
+      jto["pinned"] = *descriptor.pinned;
+    }
   if ( descriptor.pkgGroup.has_value() )
     {
       jto["pkg-group"] = *descriptor.pkgGroup;
@@ -513,6 +513,50 @@ lockedPackageFromCatalogDescriptor( const nlohmann::json & jfrom,
   pkg.input.url = nix::FlakeRef::fromAttrs( pkg.input.attrs ).to_string();
 }
 
+/**
+ * @brief Load a package installed from a flake installable.
+ *
+ * Unlike catalog packages, the flake is not restricted to `nixpkgs`,
+ * its locked reference and attribute path are used as is.
+ */
+static void
+lockedPackageFromFlakeDescriptor( const nlohmann::json & jfrom,
+                                  LockedPackageRaw &     pkg )
+{
+  std::string attrPath   = jfrom["locked_flake_attr_path"];
+  std::string locked_url = jfrom["locked_url"];
+
+  pkg.attrPath = splitAttrPath( attrPath );
+  pkg.priority = jfrom["priority"];
+  pkg.info     = jfrom;
+  pkg.input    = LockedInputRaw();
+
+  auto flakeRef   = nix::parseFlakeRef( locked_url );
+  pkg.input.attrs = nix::fetchers::attrsToJSON( flakeRef.toAttrs() );
+  pkg.input.url   = flakeRef.to_string();
+}
+
+/**
+ * @brief Load a package installed from a store path.
+ *
+ * The package is not evaluated, so its attribute path is left empty.
+ * Its outputs (and derivation, if known) are recorded in @a info.
+ */
+static void
+lockedPackageFromStorePathDescriptor( const nlohmann::json & jfrom,
+                                      LockedPackageRaw &     pkg )
+{
+  pkg.attrPath = AttrPath();
+  pkg.priority = jfrom["priority"];
+  pkg.info     = jfrom;
+  pkg.input    = LockedInputRaw();
+
+  /* Store paths carry no metadata and are installed deliberately,
+   * so they are neither considered broken nor unfree. */
+  if ( ! pkg.info.contains( "broken" ) ) { pkg.info["broken"] = false; }
+  if ( ! pkg.info.contains( "unfree" ) ) { pkg.info["unfree"] = false; }
+}
+
 void
 LockfileRaw::from_v1_content( const nlohmann::json & jfrom )
 {
@@ -614,6 +658,55 @@ LockfileRaw::from_v1_content( const nlohmann::json & jfrom )
         extract_json_errmsg( err ) );
     }
 
+  // load packages installed from flake installables, if any
+  if ( jfrom.contains( "flake_packages" ) )
+    {
+      try
+        {
+          for ( const auto & [idx, package] : jfrom["flake_packages"].items() )
+            {
+              LockedPackageRaw pkg = LockedPackageRaw();
+              lockedPackageFromFlakeDescriptor( package, pkg );
+
+              std::string installId = package["install_id"];
+              std::string system    = package["system"];
+              this->packages[system].insert(
+                { installId, std::make_optional( pkg ) } );
+            }
+        }
+      catch ( nlohmann::json::exception & err )
+        {
+          throw InvalidLockfileException(
+            "couldn't parse lockfile field 'flake_packages'",
+            extract_json_errmsg( err ) );
+        }
+    }
+
+  // load packages installed from store paths, if any
+  if ( jfrom.contains( "store_path_packages" ) )
+    {
+      try
+        {
+          for ( const auto & [idx, package] :
+                jfrom["store_path_packages"].items() )
+            {
+              LockedPackageRaw pkg = LockedPackageRaw();
+              lockedPackageFromStorePathDescriptor( package, pkg );
+
+              std::string installId = package["install_id"];
+              std::string system    = package["system"];
+              this->packages[system].insert(
+                { installId, std::make_optional( pkg ) } );
+            }
+        }
+      catch ( nlohmann::json::exception & err )
+        {
+          throw InvalidLockfileException(
+            "couldn't parse lockfile field 'store_path_packages'",
+            extract_json_errmsg( err ) );
+        }
+    }
+
   // load options
   try
     {
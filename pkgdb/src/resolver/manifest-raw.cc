@@ -84,6 +84,16 @@ Options::merge( const Options & overrides )
     {
       this->activationStrategy = overrides.activationStrategy;
     }
+
+  if ( overrides.substituters.has_value() )
+    {
+      this->substituters = overrides.substituters;
+    }
+
+  if ( overrides.trustedPublicKeys.has_value() )
+    {
+      this->trustedPublicKeys = overrides.trustedPublicKeys;
+    }
 }
 
 
@@ -310,6 +320,34 @@ from_json( const nlohmann::json & jfrom, Options & opts )
                 + value.dump() );
             }
         }
+      else if ( key == "substituters" )
+        {
+          try
+            {
+              value.get_to( opts.substituters );
+            }
+          catch ( const nlohmann::json::exception & )
+            {
+              throw InvalidManifestFileException(
+                "failed to parse manifest field 'options.substituters' with "
+                "value: "
+                + value.dump() );
+            }
+        }
+      else if ( key == "trusted-public-keys" )
+        {
+          try
+            {
+              value.get_to( opts.trustedPublicKeys );
+            }
+          catch ( const nlohmann::json::exception & )
+            {
+              throw InvalidManifestFileException(
+                "failed to parse manifest field 'options.trusted-public-keys' "
+                "with value: "
+                + value.dump() );
+            }
+        }
       else
         {
           throw InvalidManifestFileException(
@@ -338,6 +376,16 @@ to_json( nlohmann::json & jto, const Options & opts )
     {
       jto.emplace( "activation-strategy", *opts.activationStrategy );
     }
+
+  if ( opts.substituters.has_value() )
+    {
+      jto.emplace( "substituters", *opts.substituters );
+    }
+
+  if ( opts.trustedPublicKeys.has_value() )
+    {
+      jto.emplace( "trusted-public-keys", *opts.trustedPublicKeys );
+    }
 }
 
 
@@ -50,7 +50,7 @@
       # rather than relying on or modifying the user's `PATH` variable
       GIT_PKG = gitMinimal;
       NIX_PKG = nix;
-      NIX_BIN = "${nix}/bin/nix"; # only used for nix invocations in tests
+      NIX_BIN = "${nix}/bin/nix"; # used to lock flake installables and for nix invocations in tests
       PKGDB_BIN =
         if flox-pkgdb == null
         then "pkgdb"